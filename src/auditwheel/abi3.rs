@@ -0,0 +1,155 @@
+//! Checking that abi3 extension modules only use the stable ABI of their minimum python version
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use goblin::mach::{Mach, MachO, SingleArch};
+use goblin::Object;
+use once_cell::sync::Lazy;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+
+/// The symbols of the stable ABI and the python 3 minor version that added them to it
+static STABLE_ABI: Lazy<HashMap<String, u8>> = Lazy::new(|| {
+    // Generated from https://github.com/python/cpython/blob/main/Misc/stable_abi.toml
+    // with generate-stable-abi.py
+    let symbols: HashMap<String, String> =
+        serde_json::from_slice(include_bytes!("stable-abi.json"))
+            .expect("invalid stable-abi.json file");
+    symbols
+        .into_iter()
+        .map(|(name, version)| {
+            let minor = version
+                .strip_prefix("3.")
+                .and_then(|minor| minor.parse().ok())
+                .expect("invalid python version in stable-abi.json");
+            (name, minor)
+        })
+        .collect()
+});
+
+/// A symbol used by an abi3 extension module that isn't available in the stable ABI of its
+/// minimum python version
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Abi3Violation {
+    /// The name of the symbol
+    pub symbol: String,
+    /// The python 3 minor version that added the symbol to the stable ABI, `None` if it isn't
+    /// part of the stable ABI
+    pub added: Option<u8>,
+}
+
+impl fmt::Display for Abi3Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.added {
+            Some(minor) => write!(f, "{} (added in Python 3.{})", self.symbol, minor),
+            None => write!(f, "{} (not part of the stable ABI)", self.symbol),
+        }
+    }
+}
+
+fn add_macho_imports(macho: &MachO, symbols: &mut BTreeSet<String>) -> Result<()> {
+    for symbol in macho.symbols() {
+        let (name, nlist) = symbol?;
+        if nlist.is_global() && nlist.is_undefined() {
+            // C symbols have a leading underscore in Mach-O files
+            symbols.insert(name.strip_prefix('_').unwrap_or(name).to_string());
+        }
+    }
+    Ok(())
+}
+
+/// The python symbols imported by an ELF, Mach-O or PE file
+fn python_imports(data: &[u8]) -> Result<BTreeSet<String>> {
+    let mut symbols = BTreeSet::new();
+    match Object::parse(data)? {
+        Object::Elf(elf) => {
+            for sym in elf.dynsyms.iter().filter(|sym| sym.is_import()) {
+                if let Some(name) = elf.dynstrtab.get_at(sym.st_name) {
+                    symbols.insert(name.to_string());
+                }
+            }
+        }
+        Object::Mach(Mach::Binary(macho)) => add_macho_imports(&macho, &mut symbols)?,
+        Object::Mach(Mach::Fat(fat)) => {
+            for index in 0..fat.narches {
+                if let SingleArch::MachO(macho) = fat.get(index)? {
+                    add_macho_imports(&macho, &mut symbols)?;
+                }
+            }
+        }
+        Object::PE(pe) => {
+            for import in &pe.imports {
+                if import.dll.to_ascii_lowercase().starts_with("python3") {
+                    symbols.insert(import.name.to_string());
+                }
+            }
+        }
+        _ => bail!("Unsupported file format"),
+    }
+    // Unlike PE files, the ELF and Mach-O symbol tables don't tell which library a symbol is
+    // expected to come from
+    symbols.retain(|name| name.starts_with("Py") || name.starts_with("_Py"));
+    Ok(symbols)
+}
+
+/// Finds the python symbols used by the extension module at `path` that are not part of the
+/// stable ABI of python 3.`min_minor`
+pub fn audit_abi3(path: &Path, min_minor: u8) -> Result<Vec<Abi3Violation>> {
+    let data = fs::read(path)?;
+    let symbols = python_imports(&data)
+        .with_context(|| format!("Failed to read the symbols of {}", path.display()))?;
+    Ok(symbols
+        .into_iter()
+        .filter_map(|symbol| {
+            let added = STABLE_ABI.get(&symbol).copied();
+            if added.map_or(true, |added| added > min_minor) {
+                Some(Abi3Violation { symbol, added })
+            } else {
+                None
+            }
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{audit_abi3, Abi3Violation, STABLE_ABI};
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    #[test]
+    fn test_audit_abi3() {
+        let fixtures = Path::new("tests/fixtures/abi3");
+        for file in ["elf.so", "macho.so", "pe.pyd"] {
+            let violations = audit_abi3(&fixtures.join(file), 7).unwrap();
+            assert_eq!(
+                violations,
+                [
+                    Abi3Violation {
+                        symbol: "PyType_GetName".to_string(),
+                        added: Some(11)
+                    },
+                    Abi3Violation {
+                        symbol: "_PyObject_GetDictPtr".to_string(),
+                        added: None
+                    }
+                ],
+                "{file}"
+            );
+            let violations = audit_abi3(&fixtures.join(file), 11).unwrap();
+            assert_eq!(violations.len(), 1, "{file}");
+            assert_eq!(
+                violations[0].to_string(),
+                "_PyObject_GetDictPtr (not part of the stable ABI)"
+            );
+        }
+    }
+
+    #[test]
+    fn test_stable_abi_versions() {
+        // Exception groups were added to the stable ABI in 3.11, not with the other exceptions
+        assert_eq!(STABLE_ABI["PyExc_BaseExceptionGroup"], 11);
+        assert_eq!(STABLE_ABI["PyExc_BaseException"], 2);
+        assert_eq!(STABLE_ABI["PySlice_Unpack"], 7);
+    }
+}
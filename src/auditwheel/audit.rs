@@ -1,5 +1,5 @@
 use super::musllinux::{find_musl_libc, get_musl_version};
-use super::policy::{Policy, MANYLINUX_POLICIES, MUSLLINUX_POLICIES};
+use super::policy::{Policy, PolicyOverrides};
 use crate::auditwheel::{find_external_libs, PlatformTag};
 use crate::compile::BuildArtifact;
 use crate::target::Target;
@@ -9,6 +9,7 @@ use goblin::elf::{sym::STT_FUNC, Elf};
 use lddtree::Library;
 use once_cell::sync::Lazy;
 use regex::Regex;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::io;
 use std::io::Read;
@@ -54,13 +55,29 @@ pub enum AuditWheelError {
     #[error("Your library is not {0} compliant because it has unsupported architecture: {1}")]
     UnsupportedArchitecture(Policy, String),
     /// This platform tag isn't defined by auditwheel yet
-    #[error("{0} compatibility policy is not defined by auditwheel yet, pass `--skip-auditwheel` to proceed anyway")]
+    #[error("{0} compatibility policy is not defined by auditwheel yet, pass `--auditwheel skip` to proceed anyway")]
     UndefinedPolicy(String),
     /// Failed to analyze external shared library dependencies of the wheel
     #[error("Failed to analyze external shared library dependencies of the wheel")]
     DependencyAnalysisError(#[source] lddtree::Error),
 }
 
+/// What to do with the external shared libraries and compliance problems found while auditing
+/// the wheel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AuditWheelMode {
+    /// Audit the wheel and bundle external shared libraries into it
+    #[default]
+    Repair,
+    /// Audit the wheel and fail if it needs to be repaired
+    Check,
+    /// Audit the wheel and only print warnings for problems, without changing it
+    Warn,
+    /// Don't audit the wheel
+    Skip,
+}
+
 #[derive(Clone, Debug)]
 pub struct VersionedLibrary {
     /// library name
@@ -176,7 +193,11 @@ fn policy_is_satisfied(
                 .insert(version.to_string());
         }
         for (name, versions_needed) in versions.iter() {
-            let versions_allowed = &arch_versions[name];
+            // Versions of user whitelisted libraries are unknown to the policy
+            let versions_allowed = match arch_versions.get(name) {
+                Some(versions_allowed) => versions_allowed,
+                None => continue,
+            };
             if !versions_needed.is_subset(versions_allowed) {
                 let offending_versions: Vec<&str> = versions_needed
                     .difference(versions_allowed)
@@ -236,19 +257,19 @@ fn policy_is_satisfied(
     }
 }
 
-fn get_default_platform_policies() -> Vec<Policy> {
+fn get_default_platform_policies(overrides: &PolicyOverrides) -> Vec<Policy> {
     if let Ok(Some(musl_libc)) = find_musl_libc() {
         if let Ok(Some((major, minor))) = get_musl_version(musl_libc) {
-            return MUSLLINUX_POLICIES
-                .iter()
+            return overrides
+                .policies(true)
+                .into_iter()
                 .filter(|policy| {
                     policy.name == "linux" || policy.name == format!("musllinux_{major}_{minor}")
                 })
-                .cloned()
                 .collect();
         }
     }
-    MANYLINUX_POLICIES.clone()
+    overrides.policies(false)
 }
 
 /// An reimplementation of auditwheel, which checks elf files for
@@ -262,12 +283,15 @@ fn get_default_platform_policies() -> Vec<Policy> {
 /// a higher version would be possible.
 ///
 /// Does nothing for `platform_tag` set to `Off`/`Linux` or non-linux platforms.
+///
+/// Libraries excluded by `overrides` are ignored, as if they were whitelisted.
 #[allow(clippy::result_large_err)]
 pub fn auditwheel_rs(
     artifact: &BuildArtifact,
     target: &Target,
     platform_tag: Option<PlatformTag>,
     allow_linking_libpython: bool,
+    overrides: &PolicyOverrides,
 ) -> Result<(Policy, bool), AuditWheelError> {
     if !target.is_linux() || platform_tag == Some(PlatformTag::Linux) {
         return Ok((Policy::default(), false));
@@ -280,14 +304,22 @@ pub fn auditwheel_rs(
         .map_err(AuditWheelError::IoError)?;
     let elf = Elf::parse(&buffer).map_err(AuditWheelError::GoblinError)?;
     // This returns essentially the same as ldd
-    let deps: Vec<String> = elf.libraries.iter().map(ToString::to_string).collect();
-    let versioned_libraries = find_versioned_libraries(&elf);
+    let deps: Vec<String> = elf
+        .libraries
+        .iter()
+        .filter(|lib| !overrides.is_excluded(lib))
+        .map(ToString::to_string)
+        .collect();
+    let versioned_libraries: Vec<_> = find_versioned_libraries(&elf)
+        .into_iter()
+        .filter(|lib| !overrides.is_excluded(&lib.name))
+        .collect();
 
     // Find the highest possible policy, if any
     let platform_policies = match platform_tag {
-        Some(PlatformTag::Manylinux { .. }) => MANYLINUX_POLICIES.clone(),
-        Some(PlatformTag::Musllinux { x, y }) => MUSLLINUX_POLICIES
-            .clone()
+        Some(PlatformTag::Manylinux { .. }) => overrides.policies(false),
+        Some(PlatformTag::Musllinux { x, y }) => overrides
+            .policies(true)
             .into_iter()
             .filter(|policy| policy.name == "linux" || policy.name == format!("musllinux_{x}_{y}"))
             .map(|mut policy| {
@@ -296,7 +328,7 @@ pub fn auditwheel_rs(
             })
             .collect(),
         None => {
-            let mut policies = get_default_platform_policies();
+            let mut policies = get_default_platform_policies(overrides);
             for policy in &mut policies {
                 policy.fixup_musl_libc_so_name(target.target_arch());
             }
@@ -338,7 +370,9 @@ pub fn auditwheel_rs(
 
     let policy = if let Some(platform_tag) = platform_tag {
         let tag = platform_tag.to_string();
-        let mut policy = Policy::from_name(&tag).ok_or(AuditWheelError::UndefinedPolicy(tag))?;
+        let mut policy = overrides
+            .policy(&tag)
+            .ok_or(AuditWheelError::UndefinedPolicy(tag))?;
         policy.fixup_musl_libc_so_name(target.target_arch());
 
         if let Some(highest_policy) = highest_policy {
@@ -445,28 +479,34 @@ pub fn get_policy_and_libs(
     platform_tag: Option<PlatformTag>,
     target: &Target,
     allow_linking_libpython: bool,
+    overrides: &PolicyOverrides,
 ) -> Result<(Policy, Vec<Library>)> {
-    let (policy, should_repair) =
-        auditwheel_rs(artifact, target, platform_tag, allow_linking_libpython).with_context(
-            || {
-                if let Some(platform_tag) = platform_tag {
-                    format!("Error ensuring {platform_tag} compliance")
-                } else {
-                    "Error checking for manylinux/musllinux compliance".to_string()
-                }
-            },
-        )?;
+    let (policy, should_repair) = auditwheel_rs(
+        artifact,
+        target,
+        platform_tag,
+        allow_linking_libpython,
+        overrides,
+    )
+    .with_context(|| {
+        if let Some(platform_tag) = platform_tag {
+            format!("Error ensuring {platform_tag} compliance")
+        } else {
+            "Error checking for manylinux/musllinux compliance".to_string()
+        }
+    })?;
     let external_libs = if should_repair {
         let sysroot = get_sysroot_path(target).unwrap_or_else(|_| PathBuf::from("/"));
         let ld_paths = artifact.linked_paths.iter().map(PathBuf::from).collect();
-        let external_libs = find_external_libs(&artifact.path, &policy, sysroot, ld_paths)
-            .with_context(|| {
-                if let Some(platform_tag) = platform_tag {
-                    format!("Error repairing wheel for {platform_tag} compliance")
-                } else {
-                    "Error repairing wheel for manylinux/musllinux compliance".to_string()
-                }
-            })?;
+        let external_libs =
+            find_external_libs(&artifact.path, &policy, overrides, sysroot, ld_paths)
+                .with_context(|| {
+                    if let Some(platform_tag) = platform_tag {
+                        format!("Error repairing wheel for {platform_tag} compliance")
+                    } else {
+                        "Error repairing wheel for manylinux/musllinux compliance".to_string()
+                    }
+                })?;
         if allow_linking_libpython {
             external_libs
                 .into_iter()
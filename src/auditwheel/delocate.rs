@@ -0,0 +1,345 @@
+//! Bundling of external dylibs into macOS wheels, similar to
+//! [delocate](https://github.com/matthew-brett/delocate)
+use super::audit::relpath;
+use super::macho_rewriter::{read_slices, MachORewriter, MachOSlice};
+use super::policy::PolicyOverrides;
+use super::repair::is_rpath_within_wheel;
+use crate::module_writer::ModuleWriter;
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use lddtree::Library;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+
+/// Dylibs that are part of every macOS installation
+fn is_system_dylib(install_name: &str) -> bool {
+    install_name.starts_with("/usr/lib/") || install_name.starts_with("/System/")
+}
+
+/// libpython is provided by the interpreter that loads the extension module
+fn is_libpython(install_name: &str) -> bool {
+    install_name.contains("Python.framework/")
+        || Path::new(install_name).file_name().map_or(false, |name| {
+            name.to_string_lossy().starts_with("libpython")
+        })
+}
+
+fn read_macho(path: &Path) -> Result<Vec<MachOSlice>> {
+    read_slices(&fs::read(path)?)
+        .with_context(|| format!("Failed to read {} as Mach-O file", path.display()))
+}
+
+/// Finds the file of a linked dylib the way dyld does, using the directory of the loading file
+/// for `@loader_path` and `@executable_path`
+///
+/// Dylibs that are not at their install location, e.g. when cross compiling, are looked up by
+/// their file name in `ld_paths`.
+fn resolve_install_name(
+    install_name: &str,
+    loader_dir: &Path,
+    rpaths: &[String],
+    ld_paths: &[PathBuf],
+) -> Option<PathBuf> {
+    let expand = |path: &str| {
+        for prefix in ["@loader_path", "@executable_path"] {
+            if let Some(rest) = path.strip_prefix(prefix) {
+                return loader_dir.join(rest.trim_start_matches('/'));
+            }
+        }
+        PathBuf::from(path)
+    };
+    let file_name = Path::new(install_name).file_name()?;
+    let mut candidates = Vec::new();
+    if let Some(rest) = install_name.strip_prefix("@rpath/") {
+        candidates.extend(rpaths.iter().map(|rpath| expand(rpath).join(rest)));
+    } else {
+        candidates.push(expand(install_name));
+    }
+    candidates.extend(ld_paths.iter().map(|dir| dir.join(file_name)));
+    candidates.into_iter().find(|path| path.is_file())
+}
+
+/// Find the dylibs outside of macOS that `artifact` links, including their dependencies
+///
+/// Every returned [Library] is named after the install name it is linked with. Each dylib must
+/// contain all the architectures it is loaded from, so e.g. universal2 extension modules can
+/// only link universal2 dylibs.
+pub fn find_external_dylibs(
+    artifact: &Path,
+    ld_paths: &[PathBuf],
+    overrides: &PolicyOverrides,
+) -> Result<Vec<Library>> {
+    let mut ext_libs = Vec::new();
+    let mut visited = HashSet::new();
+    let mut scanned = HashSet::new();
+    let mut queue = VecDeque::from([artifact.to_path_buf()]);
+    while let Some(path) = queue.pop_front() {
+        let loader_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        for slice in read_macho(&path)? {
+            for install_name in &slice.libs {
+                let file_name = match Path::new(install_name).file_name() {
+                    Some(file_name) => file_name.to_string_lossy(),
+                    None => continue,
+                };
+                if is_system_dylib(install_name)
+                    || is_libpython(install_name)
+                    || overrides.is_excluded(&file_name)
+                {
+                    continue;
+                }
+                let lib_path =
+                    resolve_install_name(install_name, loader_dir, &slice.rpaths, ld_paths);
+                let realpath = lib_path.as_ref().and_then(|path| path.canonicalize().ok());
+                if !visited.insert((install_name.clone(), realpath.clone())) {
+                    continue;
+                }
+                let mut library = Library {
+                    name: install_name.clone(),
+                    path: lib_path.unwrap_or_else(|| PathBuf::from(install_name)),
+                    realpath: realpath.clone(),
+                    needed: Vec::new(),
+                    rpath: Vec::new(),
+                    runpath: Vec::new(),
+                };
+                if let Some(realpath) = realpath {
+                    let lib_slices = read_macho(&realpath)?;
+                    if !lib_slices.iter().any(|lib| lib.arch == slice.arch) {
+                        bail!(
+                            "{} links {}, which doesn't contain the {} architecture",
+                            path.display(),
+                            realpath.display(),
+                            slice.arch
+                        );
+                    }
+                    for lib_slice in lib_slices {
+                        for needed in lib_slice.libs {
+                            if !library.needed.contains(&needed) {
+                                library.needed.push(needed);
+                            }
+                        }
+                        for rpath in lib_slice.rpaths {
+                            if !library.rpath.contains(&rpath) {
+                                library.rpath.push(rpath);
+                            }
+                        }
+                    }
+                    if scanned.insert(realpath.clone()) {
+                        queue.push_back(realpath);
+                    }
+                }
+                ext_libs.push(library);
+            }
+        }
+    }
+    Ok(ext_libs)
+}
+
+/// Rewrites the install names of a Mach-O file in place and removes the rpath entries that
+/// point outside of the wheel, given the directory the file is placed in inside the wheel
+fn rewrite_install_names(
+    path: &Path,
+    wheel_dir: &Path,
+    install_names: &BTreeMap<&str, String>,
+    id: Option<&str>,
+    keep_rpath: bool,
+) -> Result<()> {
+    let data = fs::read(path)?;
+    let mut rewriter = MachORewriter::new(data.clone());
+    if let Some(id) = id {
+        rewriter.set_id(id);
+    }
+    for (old, new) in install_names {
+        rewriter.change_install_name(old, new);
+    }
+    if !keep_rpath {
+        for slice in read_slices(&data)? {
+            for rpath in slice.rpaths {
+                let origin_rpath = rpath.replacen("@loader_path", "$ORIGIN", 1);
+                if !is_rpath_within_wheel(&origin_rpath, wheel_dir) {
+                    rewriter.delete_rpath(&rpath);
+                }
+            }
+        }
+    }
+    let data = rewriter
+        .finish()
+        .with_context(|| format!("Failed to change the install names of {}", path.display()))?;
+    fs::write(path, data)?;
+    Ok(())
+}
+
+/// Copies the external dylibs into `libs_dir` of the wheel and changes the install names in
+/// the artifacts and the copied dylibs to load them relative to `@loader_path`.
+///
+/// `artifacts` are pairs of the Mach-O file on disk and the directory it is placed in inside
+/// the wheel, `ext_libs` contains the external dylibs of each artifact as returned by
+/// [find_external_dylibs]. Like in [super::graft_external_libs], rpath entries pointing outside
+/// of the wheel are removed unless `keep_rpath` is set.
+pub fn graft_external_dylibs(
+    writer: &mut impl ModuleWriter,
+    libs_dir: &Path,
+    artifacts: &[(&Path, &Path)],
+    ext_libs: &[Vec<Library>],
+    keep_rpath: bool,
+) -> Result<()> {
+    writer.add_directory(libs_dir)?;
+
+    // The file name in libs_dir of every dylib and the dylib of every install name
+    let mut file_names: BTreeMap<&Path, String> = BTreeMap::new();
+    let mut targets: BTreeMap<&str, &Path> = BTreeMap::new();
+    for lib in ext_libs.iter().flatten() {
+        let realpath = lib.realpath.as_deref().with_context(|| {
+            format!(
+                "Cannot repair wheel, because required library {} could not be located.",
+                lib.name
+            )
+        })?;
+        if let Some(other) = targets.insert(&lib.name, realpath) {
+            if other != realpath {
+                bail!(
+                    "Cannot repair wheel, because {} refers to both {} and {}",
+                    lib.name,
+                    other.display(),
+                    realpath.display()
+                );
+            }
+        }
+        if file_names.contains_key(realpath) {
+            continue;
+        }
+        let file_name = lib
+            .path
+            .file_name()
+            .context("Invalid library path")?
+            .to_string_lossy()
+            .to_string();
+        if let Some((other, _)) = file_names.iter().find(|(_, name)| **name == file_name) {
+            bail!(
+                "Cannot repair wheel, because {} and {} have the same file name",
+                other.display(),
+                realpath.display()
+            );
+        }
+        file_names.insert(realpath, file_name);
+    }
+
+    // All copied dylibs are in the same directory
+    let install_names: BTreeMap<&str, String> = targets
+        .iter()
+        .map(|(name, realpath)| (*name, format!("@loader_path/{}", file_names[realpath])))
+        .collect();
+    let temp_dir = tempfile::tempdir()?;
+    for (realpath, file_name) in &file_names {
+        let dest_path = temp_dir.path().join(file_name);
+        fs::copy(realpath, &dest_path)?;
+        // fs::copy copies permissions as well, and the original
+        // file may have been read-only
+        let mut perms = fs::metadata(&dest_path)?.permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        perms.set_readonly(false);
+        fs::set_permissions(&dest_path, perms)?;
+
+        let id = format!("@loader_path/{file_name}");
+        rewrite_install_names(&dest_path, libs_dir, &install_names, Some(&id), keep_rpath)?;
+        writer.add_file_with_permissions(libs_dir.join(file_name), &dest_path, 0o755)?;
+    }
+
+    for (artifact, artifact_dir) in artifacts {
+        let prefix = Path::new("@loader_path").join(relpath(libs_dir, artifact_dir));
+        let install_names: BTreeMap<&str, String> = targets
+            .iter()
+            .map(|(name, realpath)| {
+                let new_name = prefix.join(&file_names[realpath]);
+                (*name, new_name.to_string_lossy().to_string())
+            })
+            .collect();
+        rewrite_install_names(artifact, artifact_dir, &install_names, None, keep_rpath)?;
+    }
+
+    eprintln!(
+        "🖨  Copied external shared libraries to package {} directory:",
+        libs_dir.display()
+    );
+    for realpath in file_names.keys() {
+        eprintln!("    {}", realpath.display());
+    }
+    // The copied libraries are only read when the wheel is written
+    writer.keep_temp_dir(temp_dir);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_external_dylibs, graft_external_dylibs};
+    use crate::auditwheel::macho_rewriter::read_slices;
+    use crate::auditwheel::PolicyOverrides;
+    use crate::module_writer::PathWriter;
+    use fs_err as fs;
+    use pretty_assertions::assert_eq;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_find_external_dylibs() {
+        let fixtures = PathBuf::from("tests/fixtures/macho");
+        let libs = find_external_dylibs(
+            &fixtures.join("ext.so"),
+            std::slice::from_ref(&fixtures),
+            &PolicyOverrides::default(),
+        )
+        .unwrap();
+        let names: Vec<_> = libs.iter().map(|lib| lib.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "/opt/homebrew/opt/foo/lib/libfoo.1.dylib",
+                "@rpath/libbar.dylib"
+            ]
+        );
+        assert_eq!(
+            libs[1].realpath,
+            Some(fixtures.join("libbar.dylib").canonicalize().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_graft_external_dylibs() {
+        let fixtures = PathBuf::from("tests/fixtures/macho");
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let artifact = tmp_dir.path().join("ext.so");
+        fs::copy(fixtures.join("ext.so"), &artifact).unwrap();
+        let libs =
+            find_external_dylibs(&artifact, &[fixtures], &PolicyOverrides::default()).unwrap();
+
+        let wheel_dir = tmp_dir.path().join("wheel");
+        let mut writer = PathWriter::from_path(&wheel_dir);
+        graft_external_dylibs(
+            &mut writer,
+            Path::new("ext.dylibs"),
+            &[(&artifact, Path::new("ext"))],
+            &[libs],
+            false,
+        )
+        .unwrap();
+
+        for slice in read_slices(&fs::read(&artifact).unwrap()).unwrap() {
+            assert_eq!(
+                slice.libs,
+                [
+                    "@loader_path/../ext.dylibs/libfoo.1.dylib",
+                    "/usr/lib/libSystem.B.dylib"
+                ]
+            );
+            assert!(slice.rpaths.is_empty());
+        }
+        let libfoo = fs::read(wheel_dir.join("ext.dylibs/libfoo.1.dylib")).unwrap();
+        for slice in read_slices(&libfoo).unwrap() {
+            assert_eq!(slice.id.as_deref(), Some("@loader_path/libfoo.1.dylib"));
+            assert_eq!(
+                slice.libs,
+                ["@loader_path/libbar.dylib", "/usr/lib/libSystem.B.dylib"]
+            );
+            assert_eq!(slice.rpaths, ["@loader_path"]);
+        }
+        assert!(wheel_dir.join("ext.dylibs/libbar.dylib").is_file());
+    }
+}
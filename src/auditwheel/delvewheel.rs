@@ -0,0 +1,337 @@
+//! Bundling of external DLLs into Windows wheels, similar to
+//! [delvewheel](https://github.com/adang1345/delvewheel)
+use super::pe_rewriter::{read_imports, PeImports, PeRewriter};
+use super::policy::PolicyOverrides;
+use crate::build_context::hash_file;
+use crate::module_writer::ModuleWriter;
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use lddtree::Library;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// DLLs that are part of every Windows installation
+///
+/// The list is not exhaustive, DLLs found in the Windows directory are treated as system DLLs too.
+const SYSTEM_DLLS: &[&str] = &[
+    "advapi32.dll",
+    "bcrypt.dll",
+    "comctl32.dll",
+    "comdlg32.dll",
+    "crypt32.dll",
+    "d3d11.dll",
+    "d3d12.dll",
+    "dbghelp.dll",
+    "dnsapi.dll",
+    "dwmapi.dll",
+    "dxgi.dll",
+    "gdi32.dll",
+    "imm32.dll",
+    "iphlpapi.dll",
+    "kernel32.dll",
+    "kernelbase.dll",
+    "msvcrt.dll",
+    "mswsock.dll",
+    "ncrypt.dll",
+    "netapi32.dll",
+    "normaliz.dll",
+    "ntdll.dll",
+    "ole32.dll",
+    "oleaut32.dll",
+    "opengl32.dll",
+    "powrprof.dll",
+    "psapi.dll",
+    "rpcrt4.dll",
+    "secur32.dll",
+    "setupapi.dll",
+    "shell32.dll",
+    "shlwapi.dll",
+    "synchronization.dll",
+    "ucrtbase.dll",
+    "user32.dll",
+    "userenv.dll",
+    "uxtheme.dll",
+    "version.dll",
+    "winmm.dll",
+    "winspool.drv",
+    "wldap32.dll",
+    "ws2_32.dll",
+];
+
+/// DLLs that don't need to be bundled, because they are part of Windows or are provided by the
+/// python interpreter that loads the extension module
+fn is_system_dll(name: &str) -> bool {
+    let name = name.to_ascii_lowercase();
+    SYSTEM_DLLS.contains(&name.as_str())
+        || name.starts_with("api-ms-win-")
+        || name.starts_with("ext-ms-")
+        // The stable ABI and the version specific python dlls, e.g. python3.dll and python311.dll
+        || (name.starts_with("python3") && name.ends_with(".dll"))
+        // Shipped with CPython
+        || name == "vcruntime140.dll"
+        || name == "vcruntime140_1.dll"
+}
+
+fn read_pe(path: &Path) -> Result<PeImports> {
+    read_imports(&fs::read(path)?)
+        .with_context(|| format!("Failed to read {} as PE file", path.display()))
+}
+
+/// Finds a DLL by its case insensitive name in `dirs` that is built for `machine`
+fn find_dll(name: &str, dirs: &[PathBuf], machine: u16) -> Result<Option<PathBuf>> {
+    for dir in dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries {
+            let path = entry?.path();
+            let matches_name = path
+                .file_name()
+                .map_or(false, |file_name| file_name.eq_ignore_ascii_case(name));
+            // DLLs for other architectures may be in the same directory, e.g. in PATH
+            if matches_name && path.is_file() && read_pe(&path)?.machine == machine {
+                return Ok(Some(path));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Find the DLLs outside of Windows that `artifact` imports, including delay loaded DLLs and
+/// the dependencies of the found DLLs
+///
+/// DLLs are looked up in the directory of `artifact`, in `ld_paths` and the `bin` directories
+/// next to them, since libraries usually have their import library in `lib` and their DLL in
+/// `bin`, and in `PATH`. DLLs that can't be found are assumed to be system DLLs missing from
+/// the list of known system DLLs, which happens when cross compiling. Every returned [Library]
+/// is named after the DLL name it is imported with.
+pub fn find_external_dlls(
+    artifact: &Path,
+    ld_paths: &[PathBuf],
+    overrides: &PolicyOverrides,
+) -> Result<Vec<Library>> {
+    let mut dirs: Vec<PathBuf> = artifact
+        .parent()
+        .map(Path::to_path_buf)
+        .into_iter()
+        .collect();
+    for ld_path in ld_paths {
+        dirs.push(ld_path.clone());
+        dirs.push(ld_path.join("..").join("bin"));
+    }
+    if let Some(paths) = env::var_os("PATH") {
+        dirs.extend(env::split_paths(&paths));
+    }
+    let system_root = env::var_os("SystemRoot").map(PathBuf::from);
+
+    let imports = read_pe(artifact)?;
+    let mut ext_libs = Vec::new();
+    let mut visited = HashSet::new();
+    let mut queue: VecDeque<String> = imports.dlls.into_iter().collect();
+    while let Some(name) = queue.pop_front() {
+        if !visited.insert(name.to_ascii_lowercase())
+            || is_system_dll(&name)
+            || overrides.is_excluded(&name)
+        {
+            continue;
+        }
+        let lib_path = match find_dll(&name, &dirs, imports.machine)? {
+            Some(lib_path) => lib_path,
+            None => {
+                eprintln!(
+                    "⚠️  Warning: Could not find {name} imported by {}, assuming it's part of Windows",
+                    artifact.display()
+                );
+                continue;
+            }
+        };
+        if let Some(system_root) = &system_root {
+            if lib_path.starts_with(system_root) {
+                continue;
+            }
+        }
+        let realpath = lib_path.canonicalize().ok();
+        let needed = match &realpath {
+            Some(realpath) => read_pe(realpath)?.dlls,
+            None => Vec::new(),
+        };
+        queue.extend(needed.iter().cloned());
+        ext_libs.push(Library {
+            name: name.clone(),
+            path: lib_path,
+            realpath,
+            needed,
+            rpath: Vec::new(),
+            runpath: Vec::new(),
+        });
+    }
+    Ok(ext_libs)
+}
+
+/// Changes the imported DLLs of a PE file in place
+fn rewrite_imports(path: &Path, dll_names: &BTreeMap<String, String>) -> Result<()> {
+    let mut rewriter = PeRewriter::new(fs::read(path)?);
+    for (old, new) in dll_names {
+        rewriter.replace_import(old, new);
+    }
+    let data = rewriter
+        .finish()
+        .with_context(|| format!("Failed to change the imports of {}", path.display()))?;
+    fs::write(path, data)?;
+    Ok(())
+}
+
+/// Copies the external DLLs into `libs_dir` of the wheel under a new name containing a short
+/// hash, so they can't clash with other versions of the same DLL loaded into the python
+/// process, and changes the imports of the artifacts and the copied DLLs to the new names.
+///
+/// `artifacts` are the PE files on disk, `ext_libs` contains the external DLLs of each artifact
+/// as returned by [find_external_dlls]. Since Windows doesn't support anything like rpath,
+/// `libs_dir` needs to be added to the DLL search path, e.g. with `os.add_dll_directory`.
+pub fn graft_external_dlls(
+    writer: &mut impl ModuleWriter,
+    libs_dir: &Path,
+    artifacts: &[&Path],
+    ext_libs: &[Vec<Library>],
+) -> Result<()> {
+    writer.add_directory(libs_dir)?;
+
+    // The DLL of every lowercase import name and the new name of every DLL
+    let mut targets: BTreeMap<String, &Path> = BTreeMap::new();
+    let mut file_names: BTreeMap<&Path, String> = BTreeMap::new();
+    for lib in ext_libs.iter().flatten() {
+        let realpath = lib.realpath.as_deref().with_context(|| {
+            format!(
+                "Cannot repair wheel, because required library {} could not be located.",
+                lib.name
+            )
+        })?;
+        if let Some(other) = targets.insert(lib.name.to_ascii_lowercase(), realpath) {
+            if other != realpath {
+                bail!(
+                    "Cannot repair wheel, because {} refers to both {} and {}",
+                    lib.name,
+                    other.display(),
+                    realpath.display()
+                );
+            }
+        }
+        if file_names.contains_key(realpath) {
+            continue;
+        }
+        let short_hash = &hash_file(realpath)?[..8];
+        let name = Path::new(&lib.name);
+        let file_stem = name.file_stem().unwrap_or_default().to_string_lossy();
+        let file_name = match name.extension() {
+            Some(ext) => format!("{file_stem}-{short_hash}.{}", ext.to_string_lossy()),
+            None => format!("{file_stem}-{short_hash}"),
+        };
+        file_names.insert(realpath, file_name);
+    }
+
+    let dll_names: BTreeMap<String, String> = targets
+        .iter()
+        .map(|(name, realpath)| (name.clone(), file_names[realpath].clone()))
+        .collect();
+    let temp_dir = tempfile::tempdir()?;
+    for (realpath, file_name) in &file_names {
+        let dest_path = temp_dir.path().join(file_name);
+        fs::copy(realpath, &dest_path)?;
+        // fs::copy copies permissions as well, and the original
+        // file may have been read-only
+        let mut perms = fs::metadata(&dest_path)?.permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        perms.set_readonly(false);
+        fs::set_permissions(&dest_path, perms)?;
+
+        rewrite_imports(&dest_path, &dll_names)?;
+        writer.add_file_with_permissions(libs_dir.join(file_name), &dest_path, 0o755)?;
+    }
+    for artifact in artifacts {
+        rewrite_imports(artifact, &dll_names)?;
+    }
+
+    eprintln!(
+        "🖨  Copied external shared libraries to package {} directory:",
+        libs_dir.display()
+    );
+    for realpath in file_names.keys() {
+        eprintln!("    {}", realpath.display());
+    }
+    // The copied libraries are only read when the wheel is written
+    writer.keep_temp_dir(temp_dir);
+    Ok(())
+}
+
+/// Python code that adds `libs_dir` next to the package directory to the DLL search path
+pub fn add_dll_directory_code(libs_dir: &str) -> String {
+    format!(
+        r#"# start maturin dll directory patch
+def _maturin_add_dll_directory():
+    import os
+    libs_dir = os.path.abspath(os.path.join(os.path.dirname(__file__), os.pardir, {libs_dir:?}))
+    if os.path.isdir(libs_dir):
+        if hasattr(os, "add_dll_directory"):
+            os.add_dll_directory(libs_dir)
+        else:
+            os.environ["PATH"] = os.pathsep.join([libs_dir, os.environ.get("PATH", "")])
+
+
+_maturin_add_dll_directory()
+del _maturin_add_dll_directory
+# end maturin dll directory patch
+"#
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_external_dlls, graft_external_dlls};
+    use crate::auditwheel::pe_rewriter::read_imports;
+    use crate::auditwheel::PolicyOverrides;
+    use crate::module_writer::PathWriter;
+    use fs_err as fs;
+    use pretty_assertions::assert_eq;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_graft_external_dlls() {
+        let fixtures = PathBuf::from("tests/fixtures/pe");
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let artifact = tmp_dir.path().join("ext.pyd");
+        fs::copy(fixtures.join("ext.pyd"), &artifact).unwrap();
+        let libs = find_external_dlls(
+            &artifact,
+            std::slice::from_ref(&fixtures),
+            &PolicyOverrides::default(),
+        )
+        .unwrap();
+        let names: Vec<_> = libs.iter().map(|lib| lib.name.as_str()).collect();
+        assert_eq!(names, ["foo.dll", "bar.dll"]);
+        assert_eq!(
+            libs[0].realpath,
+            Some(fixtures.join("foo.dll").canonicalize().unwrap())
+        );
+
+        let wheel_dir = tmp_dir.path().join("wheel");
+        let mut writer = PathWriter::from_path(&wheel_dir);
+        graft_external_dlls(&mut writer, Path::new("ext.libs"), &[&artifact], &[libs]).unwrap();
+
+        let mut bundled: Vec<_> = fs::read_dir(wheel_dir.join("ext.libs"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        bundled.sort();
+        assert_eq!(bundled.len(), 2);
+        let (bar, foo) = (&bundled[0], &bundled[1]);
+        assert!(bar.starts_with("bar-") && foo.starts_with("foo-"));
+        assert_eq!(
+            read_imports(&fs::read(&artifact).unwrap()).unwrap().dlls,
+            [foo.as_str(), "python3.dll", "KERNEL32.dll", bar.as_str()]
+        );
+        let foo_imports = read_imports(&fs::read(wheel_dir.join("ext.libs").join(foo)).unwrap());
+        assert_eq!(foo_imports.unwrap().dlls, [bar.as_str(), "KERNEL32.dll"]);
+    }
+}
@@ -0,0 +1,768 @@
+//! An in-process implementation of the parts of `patchelf` we need for repairing wheels
+//!
+//! Changes that fit into the existing `.dynamic` and `.dynstr` sections are done in place.
+//! Otherwise a new `PT_LOAD` segment is appended to the file which contains a copy of the
+//! program headers, the grown string table and/or the grown dynamic section, and the
+//! headers are updated to point to the new locations.
+use anyhow::{bail, Context, Result};
+use goblin::elf::dynamic::{
+    DT_NEEDED, DT_NULL, DT_RPATH, DT_RUNPATH, DT_SONAME, DT_STRSZ, DT_STRTAB, DT_VERNEED,
+    DT_VERNEEDNUM,
+};
+use goblin::elf::header::ET_EXEC;
+use goblin::elf::program_header::{PF_R, PF_W, PT_DYNAMIC, PT_INTERP, PT_LOAD, PT_PHDR};
+use goblin::elf::section_header::{SHT_DYNAMIC, SHT_STRTAB};
+use goblin::elf::{Elf, ProgramHeader};
+use std::collections::HashMap;
+
+/// Reads and writes integers with the word size and byte order of an elf file
+#[derive(Debug, Clone, Copy)]
+struct Layout {
+    is_64: bool,
+    little_endian: bool,
+}
+
+impl Layout {
+    fn read<const N: usize>(&self, data: &[u8], offset: usize) -> Result<[u8; N]> {
+        let bytes = data
+            .get(offset..offset + N)
+            .with_context(|| format!("Offset {offset:#x} is out of bounds"))?;
+        let mut buf = [0; N];
+        buf.copy_from_slice(bytes);
+        if !self.little_endian {
+            buf.reverse();
+        }
+        Ok(buf)
+    }
+
+    fn write<const N: usize>(&self, data: &mut [u8], offset: usize, mut value: [u8; N]) {
+        if !self.little_endian {
+            value.reverse();
+        }
+        data[offset..offset + N].copy_from_slice(&value);
+    }
+
+    fn read_u32(&self, data: &[u8], offset: usize) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.read(data, offset)?))
+    }
+
+    fn read_word(&self, data: &[u8], offset: usize) -> Result<u64> {
+        if self.is_64 {
+            Ok(u64::from_le_bytes(self.read(data, offset)?))
+        } else {
+            Ok(u32::from_le_bytes(self.read(data, offset)?) as u64)
+        }
+    }
+
+    fn write_u16(&self, data: &mut [u8], offset: usize, value: u16) {
+        self.write(data, offset, value.to_le_bytes());
+    }
+
+    fn write_u32(&self, data: &mut [u8], offset: usize, value: u32) {
+        self.write(data, offset, value.to_le_bytes());
+    }
+
+    fn write_word(&self, data: &mut [u8], offset: usize, value: u64) {
+        if self.is_64 {
+            self.write(data, offset, value.to_le_bytes());
+        } else {
+            self.write(data, offset, (value as u32).to_le_bytes());
+        }
+    }
+
+    fn word_size(&self) -> usize {
+        if self.is_64 {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn dyn_size(&self) -> usize {
+        2 * self.word_size()
+    }
+
+    fn write_program_header(&self, data: &mut [u8], offset: usize, ph: &ProgramHeader) {
+        if self.is_64 {
+            self.write_u32(data, offset, ph.p_type);
+            self.write_u32(data, offset + 4, ph.p_flags);
+            for (i, value) in [
+                ph.p_offset,
+                ph.p_vaddr,
+                ph.p_paddr,
+                ph.p_filesz,
+                ph.p_memsz,
+                ph.p_align,
+            ]
+            .into_iter()
+            .enumerate()
+            {
+                self.write_word(data, offset + 8 + 8 * i, value);
+            }
+        } else {
+            self.write_u32(data, offset, ph.p_type);
+            for (i, value) in [ph.p_offset, ph.p_vaddr, ph.p_paddr, ph.p_filesz, ph.p_memsz]
+                .into_iter()
+                .enumerate()
+            {
+                self.write_word(data, offset + 4 + 4 * i, value);
+            }
+            self.write_u32(data, offset + 24, ph.p_flags);
+            self.write_word(data, offset + 28, ph.p_align);
+        }
+    }
+
+    /// Updates `sh_addr`, `sh_offset` and `sh_size` of a section header
+    fn write_section_location(
+        &self,
+        data: &mut [u8],
+        offset: usize,
+        addr: u64,
+        file_offset: u64,
+        size: u64,
+    ) {
+        let (addr_offset, step) = if self.is_64 { (0x10, 8) } else { (0x0c, 4) };
+        self.write_word(data, offset + addr_offset, addr);
+        self.write_word(data, offset + addr_offset + step, file_offset);
+        self.write_word(data, offset + addr_offset + 2 * step, size);
+    }
+}
+
+/// A `.dynstr` string table that new strings can be appended to
+struct StringTable {
+    data: Vec<u8>,
+    original_len: usize,
+}
+
+impl StringTable {
+    fn get(&self, offset: u64) -> Result<&str> {
+        let start = offset as usize;
+        let bytes = self
+            .data
+            .get(start..)
+            .context("String table offset is out of bounds")?;
+        let end = bytes
+            .iter()
+            .position(|&b| b == 0)
+            .context("Unterminated string in string table")?;
+        std::str::from_utf8(&bytes[..end]).context("Invalid utf-8 in string table")
+    }
+
+    /// Returns the offset of `value`, reusing an existing (suffix of a) string if possible
+    fn add(&mut self, value: &str) -> u64 {
+        let mut needle = value.as_bytes().to_vec();
+        needle.push(0);
+        if let Some(pos) = self
+            .data
+            .windows(needle.len())
+            .position(|window| window == needle.as_slice())
+        {
+            return pos as u64;
+        }
+        let offset = self.data.len();
+        self.data.extend_from_slice(&needle);
+        offset as u64
+    }
+
+    fn grew(&self) -> bool {
+        self.data.len() > self.original_len
+    }
+}
+
+fn round_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) / align * align
+}
+
+fn vaddr_to_offset(program_headers: &[ProgramHeader], vaddr: u64) -> Result<usize> {
+    program_headers
+        .iter()
+        .find(|ph| ph.p_type == PT_LOAD && ph.p_vaddr <= vaddr && vaddr < ph.p_vaddr + ph.p_filesz)
+        .map(|ph| (ph.p_offset + vaddr - ph.p_vaddr) as usize)
+        .with_context(|| format!("Address {vaddr:#x} is not mapped by any segment"))
+}
+
+/// Modifies the dynamic section of an elf file
+#[derive(Debug)]
+pub struct ElfRewriter {
+    data: Vec<u8>,
+    soname: Option<String>,
+    needed: HashMap<String, String>,
+    rpath: Option<Option<String>>,
+}
+
+impl ElfRewriter {
+    /// Wraps the contents of an elf file
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            soname: None,
+            needed: HashMap::new(),
+            rpath: None,
+        }
+    }
+
+    /// Sets `DT_SONAME`, adding it if it doesn't exist yet
+    pub fn set_soname(&mut self, soname: &str) -> &mut Self {
+        self.soname = Some(soname.to_string());
+        self
+    }
+
+    /// Replaces the `DT_NEEDED` entry `old` with `new`
+    pub fn replace_needed(&mut self, old: &str, new: &str) -> &mut Self {
+        self.needed.insert(old.to_string(), new.to_string());
+        self
+    }
+
+    /// Replaces all `DT_RPATH` and `DT_RUNPATH` entries with a single `DT_RPATH`, or removes
+    /// them if `rpath` is `None`
+    pub fn set_rpath(&mut self, rpath: Option<&str>) -> &mut Self {
+        self.rpath = Some(rpath.map(ToString::to_string));
+        self
+    }
+
+    /// Applies the modifications and returns the new contents of the elf file
+    pub fn finish(&self) -> Result<Vec<u8>> {
+        let elf = Elf::parse(&self.data).context("Failed to parse elf file")?;
+        let layout = Layout {
+            is_64: elf.is_64,
+            little_endian: elf.little_endian,
+        };
+        let program_headers = elf.program_headers.clone();
+        let dynamic_ph = program_headers
+            .iter()
+            .find(|ph| ph.p_type == PT_DYNAMIC)
+            .context("The elf file has no dynamic section")?;
+        let dynamic_offset = dynamic_ph.p_offset as usize;
+        let dynamic_slots = dynamic_ph.p_filesz as usize / layout.dyn_size();
+
+        let mut dyns = Vec::new();
+        for i in 0..dynamic_slots {
+            let offset = dynamic_offset + i * layout.dyn_size();
+            let tag = layout.read_word(&self.data, offset)?;
+            if tag == DT_NULL {
+                break;
+            }
+            let value = layout.read_word(&self.data, offset + layout.word_size())?;
+            dyns.push((tag, value));
+        }
+        let strtab_addr = find_dyn(&dyns, DT_STRTAB).context("Missing DT_STRTAB")?;
+        let strtab_size = find_dyn(&dyns, DT_STRSZ).context("Missing DT_STRSZ")? as usize;
+        let strtab_offset = vaddr_to_offset(&program_headers, strtab_addr)?;
+        let mut strtab = StringTable {
+            data: self
+                .data
+                .get(strtab_offset..strtab_offset + strtab_size)
+                .context("The string table is out of bounds")?
+                .to_vec(),
+            original_len: strtab_size,
+        };
+
+        // Apply the modifications to the dynamic entries
+        let mut replaced_needed = HashMap::new();
+        let mut has_soname = false;
+        for (tag, value) in dyns.iter_mut() {
+            match *tag {
+                DT_NEEDED => {
+                    let name = strtab.get(*value)?.to_string();
+                    if let Some(new_name) = self.needed.get(&name) {
+                        *value = strtab.add(new_name);
+                        replaced_needed.insert(name, *value);
+                    }
+                }
+                DT_SONAME => {
+                    has_soname = true;
+                    if let Some(soname) = &self.soname {
+                        *value = strtab.add(soname);
+                    }
+                }
+                _ => {}
+            }
+        }
+        if let (Some(soname), false) = (&self.soname, has_soname) {
+            let value = strtab.add(soname);
+            dyns.insert(0, (DT_SONAME, value));
+        }
+        if let Some(rpath) = &self.rpath {
+            dyns.retain(|(tag, _)| *tag != DT_RPATH && *tag != DT_RUNPATH);
+            if let Some(rpath) = rpath {
+                let value = strtab.add(rpath);
+                dyns.push((DT_RPATH, value));
+            }
+        }
+
+        let mut data = self.data.clone();
+
+        // The version requirements reference the file names of the needed libraries.
+        // The new string table keeps the old one as prefix, so only replaced names change.
+        if let (Some(verneed_addr), Some(verneed_num)) =
+            (find_dyn(&dyns, DT_VERNEED), find_dyn(&dyns, DT_VERNEEDNUM))
+        {
+            let mut offset = vaddr_to_offset(&program_headers, verneed_addr)?;
+            for _ in 0..verneed_num {
+                let file = layout.read_u32(&data, offset + 4)?;
+                if let Some(new_file) = replaced_needed.get(strtab.get(file as u64)?) {
+                    layout.write_u32(&mut data, offset + 4, *new_file as u32);
+                }
+                let next = layout.read_u32(&data, offset + 12)?;
+                if next == 0 {
+                    break;
+                }
+                offset += next as usize;
+            }
+        }
+
+        let strtab_grew = strtab.grew();
+        // One additional slot for the DT_NULL terminator
+        let dynamic_grew = dyns.len() + 1 > dynamic_slots;
+        if !strtab_grew && !dynamic_grew {
+            write_dynamic(&layout, &mut data, dynamic_offset, dynamic_slots, &dyns);
+            return Ok(data);
+        }
+
+        // Find a place for the new segment after everything else in the file and in memory
+        let loads: Vec<_> = program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .collect();
+        let align = loads
+            .iter()
+            .map(|ph| ph.p_align)
+            .max()
+            .unwrap_or_default()
+            .max(0x1000);
+        let max_vaddr = loads
+            .iter()
+            .map(|ph| ph.p_vaddr + ph.p_memsz)
+            .max()
+            .context("The elf file has no loadable segments")?;
+        let is_executable =
+            elf.header.e_type == ET_EXEC || program_headers.iter().any(|ph| ph.p_type == PT_INTERP);
+        let (segment_offset, segment_vaddr) = if is_executable {
+            // Older kernels compute the address of the program headers from the first
+            // segment and `e_phoff`, so the new segment must be mapped with the same
+            // displacement between file offset and address
+            let first = loads
+                .iter()
+                .min_by_key(|ph| ph.p_vaddr)
+                .context("The elf file has no loadable segments")?;
+            let displacement = first
+                .p_vaddr
+                .checked_sub(first.p_offset)
+                .context("Unsupported segment layout")?;
+            let offset = round_up(
+                (data.len() as u64).max(max_vaddr.saturating_sub(displacement)),
+                align,
+            );
+            (offset, offset + displacement)
+        } else {
+            (
+                round_up(data.len() as u64, align),
+                round_up(max_vaddr, align),
+            )
+        };
+
+        let phentsize = elf.header.e_phentsize as u64;
+        let phnum = program_headers.len() + 1;
+        let phdr_size = phnum as u64 * phentsize;
+        let mut segment_size = phdr_size;
+        let strtab_location = if strtab_grew {
+            let location = round_up(segment_size, 8);
+            segment_size = location + strtab.data.len() as u64;
+            Some(location)
+        } else {
+            None
+        };
+        let new_dynamic_slots = dyns.len() + 1;
+        let dynamic_location = if dynamic_grew {
+            let location = round_up(segment_size, 8);
+            segment_size = location + (new_dynamic_slots * layout.dyn_size()) as u64;
+            Some(location)
+        } else {
+            None
+        };
+        if !layout.is_64 && segment_vaddr + segment_size > u32::MAX as u64 {
+            bail!("Not enough address space to add a new segment");
+        }
+
+        data.resize((segment_offset + segment_size) as usize, 0);
+
+        if let Some(location) = strtab_location {
+            let offset = (segment_offset + location) as usize;
+            data[offset..offset + strtab.data.len()].copy_from_slice(&strtab.data);
+            for (tag, value) in dyns.iter_mut() {
+                match *tag {
+                    DT_STRTAB => *value = segment_vaddr + location,
+                    DT_STRSZ => *value = strtab.data.len() as u64,
+                    _ => {}
+                }
+            }
+        }
+
+        // Update the section headers so that tools like readelf or strip keep working
+        let shoff = elf.header.e_shoff as usize;
+        let shentsize = elf.header.e_shentsize as usize;
+        for (i, sh) in elf.section_headers.iter().enumerate() {
+            let offset = shoff + i * shentsize;
+            if let (SHT_STRTAB, Some(location)) = (sh.sh_type, strtab_location) {
+                if sh.sh_addr == strtab_addr {
+                    layout.write_section_location(
+                        &mut data,
+                        offset,
+                        segment_vaddr + location,
+                        segment_offset + location,
+                        strtab.data.len() as u64,
+                    );
+                }
+            } else if let (SHT_DYNAMIC, Some(location)) = (sh.sh_type, dynamic_location) {
+                layout.write_section_location(
+                    &mut data,
+                    offset,
+                    segment_vaddr + location,
+                    segment_offset + location,
+                    (new_dynamic_slots * layout.dyn_size()) as u64,
+                );
+            }
+        }
+
+        match dynamic_location {
+            Some(location) => write_dynamic(
+                &layout,
+                &mut data,
+                (segment_offset + location) as usize,
+                new_dynamic_slots,
+                &dyns,
+            ),
+            None => write_dynamic(&layout, &mut data, dynamic_offset, dynamic_slots, &dyns),
+        }
+
+        // Copy the program headers into the new segment and add the segment itself
+        let mut new_program_headers = Vec::with_capacity(phnum);
+        for ph in &program_headers {
+            let mut ph = ph.clone();
+            match ph.p_type {
+                PT_PHDR => {
+                    ph.p_offset = segment_offset;
+                    ph.p_vaddr = segment_vaddr;
+                    ph.p_paddr = segment_vaddr;
+                    ph.p_filesz = phdr_size;
+                    ph.p_memsz = phdr_size;
+                }
+                PT_DYNAMIC => {
+                    if let Some(location) = dynamic_location {
+                        let size = (new_dynamic_slots * layout.dyn_size()) as u64;
+                        ph.p_offset = segment_offset + location;
+                        ph.p_vaddr = segment_vaddr + location;
+                        ph.p_paddr = segment_vaddr + location;
+                        ph.p_filesz = size;
+                        ph.p_memsz = size;
+                    }
+                }
+                _ => {}
+            }
+            new_program_headers.push(ph);
+        }
+        // Loadable segments must be sorted by address
+        let last_load = new_program_headers
+            .iter()
+            .rposition(|ph| ph.p_type == PT_LOAD)
+            .unwrap_or_default();
+        new_program_headers.insert(
+            last_load + 1,
+            ProgramHeader {
+                p_type: PT_LOAD,
+                p_flags: PF_R | PF_W,
+                p_offset: segment_offset,
+                p_vaddr: segment_vaddr,
+                p_paddr: segment_vaddr,
+                p_filesz: segment_size,
+                p_memsz: segment_size,
+                p_align: align,
+            },
+        );
+        for (i, ph) in new_program_headers.iter().enumerate() {
+            let offset = segment_offset as usize + i * phentsize as usize;
+            layout.write_program_header(&mut data, offset, ph);
+        }
+
+        // Point the elf header to the new program headers
+        if layout.is_64 {
+            layout.write_word(&mut data, 0x20, segment_offset);
+            layout.write_u16(&mut data, 0x38, phnum as u16);
+        } else {
+            layout.write_word(&mut data, 0x1c, segment_offset);
+            layout.write_u16(&mut data, 0x2c, phnum as u16);
+        }
+
+        Ok(data)
+    }
+}
+
+fn find_dyn(dyns: &[(u64, u64)], tag: u64) -> Option<u64> {
+    dyns.iter()
+        .find(|(t, _)| *t == tag)
+        .map(|(_, value)| *value)
+}
+
+/// Writes the dynamic entries followed by `DT_NULL` until all `slots` are filled
+fn write_dynamic(
+    layout: &Layout,
+    data: &mut [u8],
+    offset: usize,
+    slots: usize,
+    dyns: &[(u64, u64)],
+) {
+    for i in 0..slots {
+        let (tag, value) = dyns.get(i).copied().unwrap_or((DT_NULL, 0));
+        let entry = offset + i * layout.dyn_size();
+        layout.write_word(data, entry, tag);
+        layout.write_word(data, entry + layout.word_size(), value);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find_dyn, ElfRewriter};
+    use goblin::elf::dynamic::{DT_STRSZ, DT_STRTAB};
+    use goblin::elf::program_header::{PT_DYNAMIC, PT_LOAD};
+    use goblin::elf::Elf;
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    /// The directories of the fixtures in tests/fixtures/elf with whether they are 64-bit and
+    /// little-endian, see generate.sh
+    const ARCHS: [(&str, bool, bool); 4] = [
+        ("x86_64", true, true),
+        ("i686", false, true),
+        ("powerpc64", true, false),
+        ("powerpc", false, false),
+    ];
+
+    /// Longer than all strings in the fixtures, so that `.dynstr` has to be moved
+    const LONG_RPATH: &str = "$ORIGIN/../foo.libs:$ORIGIN/a/path/that/does/not/fit/into/dynstr";
+
+    fn fixture(arch: &str, name: &str) -> Vec<u8> {
+        fs_err::read(Path::new("tests/fixtures/elf").join(arch).join(name)).unwrap()
+    }
+
+    /// Parses the rewritten file and checks that the string table and the dynamic section are
+    /// mapped and that the section headers still describe them
+    fn parse(data: &[u8], is_64: bool, little_endian: bool) -> Elf<'_> {
+        let elf = Elf::parse(data).unwrap();
+        assert_eq!(elf.is_64, is_64);
+        assert_eq!(elf.little_endian, little_endian);
+        assert_eq!(elf.header.e_phnum as usize, elf.program_headers.len());
+        let loads: Vec<_> = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == PT_LOAD)
+            .collect();
+        let is_mapped = |vaddr: u64, size: u64| {
+            loads
+                .iter()
+                .any(|ph| ph.p_vaddr <= vaddr && vaddr + size <= ph.p_vaddr + ph.p_filesz)
+        };
+        let dynamic = elf
+            .program_headers
+            .iter()
+            .find(|ph| ph.p_type == PT_DYNAMIC)
+            .unwrap();
+        assert!(is_mapped(dynamic.p_vaddr, dynamic.p_filesz));
+        let dyns: Vec<_> = elf
+            .dynamic
+            .as_ref()
+            .unwrap()
+            .dyns
+            .iter()
+            .map(|dyn_| (dyn_.d_tag, dyn_.d_val))
+            .collect();
+        let strtab = find_dyn(&dyns, DT_STRTAB).unwrap();
+        let strsz = find_dyn(&dyns, DT_STRSZ).unwrap();
+        assert!(is_mapped(strtab, strsz));
+        let dynstr = elf
+            .section_headers
+            .iter()
+            .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(".dynstr"))
+            .unwrap();
+        assert_eq!((dynstr.sh_addr, dynstr.sh_size), (strtab, strsz));
+        let dynamic_section = elf
+            .section_headers
+            .iter()
+            .find(|sh| elf.shdr_strtab.get_at(sh.sh_name) == Some(".dynamic"))
+            .unwrap();
+        assert_eq!(
+            (dynamic_section.sh_addr, dynamic_section.sh_offset),
+            (dynamic.p_vaddr, dynamic.p_offset)
+        );
+        elf
+    }
+
+    /// The file names of the version requirements
+    fn verneed_files<'a>(elf: &'a Elf) -> Vec<&'a str> {
+        elf.verneed
+            .as_ref()
+            .unwrap()
+            .iter()
+            .map(|verneed| elf.dynstrtab.get_at(verneed.vn_file).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_set_soname() {
+        for (arch, is_64, little_endian) in ARCHS {
+            let data = fixture(arch, "libfoo.so");
+
+            // A suffix of an existing string is reused
+            let rewritten = ElfRewriter::new(data.clone())
+                .set_soname("foo.so")
+                .finish()
+                .unwrap();
+            assert_eq!(rewritten.len(), data.len(), "{arch}");
+            let elf = parse(&rewritten, is_64, little_endian);
+            assert_eq!(elf.soname, Some("foo.so"), "{arch}");
+            assert_eq!(elf.libraries, vec!["libbar.so"], "{arch}");
+
+            let rewritten = ElfRewriter::new(data.clone())
+                .set_soname("libfoo-0123abcd.so")
+                .finish()
+                .unwrap();
+            assert!(rewritten.len() > data.len(), "{arch}");
+            let elf = parse(&rewritten, is_64, little_endian);
+            assert_eq!(elf.soname, Some("libfoo-0123abcd.so"), "{arch}");
+            assert_eq!(elf.libraries, vec!["libbar.so"], "{arch}");
+            assert_eq!(elf.runpaths, vec!["/build/lib"], "{arch}");
+            assert_eq!(verneed_files(&elf), vec!["libbar.so"], "{arch}");
+        }
+    }
+
+    #[test]
+    fn test_replace_needed() {
+        for (arch, is_64, little_endian) in ARCHS {
+            let data = ElfRewriter::new(fixture(arch, "libfoo.so"))
+                .replace_needed("libbar.so", "libbar-0123abcd.so")
+                .replace_needed("libmissing.so", "libother.so")
+                .finish()
+                .unwrap();
+            let elf = parse(&data, is_64, little_endian);
+            assert_eq!(elf.libraries, vec!["libbar-0123abcd.so"], "{arch}");
+            // The version requirements reference the library by its new name
+            assert_eq!(verneed_files(&elf), vec!["libbar-0123abcd.so"], "{arch}");
+            assert_eq!(elf.soname, Some("libfoo.so"), "{arch}");
+            assert_eq!(elf.runpaths, vec!["/build/lib"], "{arch}");
+        }
+    }
+
+    #[test]
+    fn test_set_rpath() {
+        for (arch, is_64, little_endian) in ARCHS {
+            // The runpath is replaced by an rpath, which doesn't fit into `.dynstr`
+            let data = fixture(arch, "libfoo.so");
+            let phnum = Elf::parse(&data).unwrap().program_headers.len();
+            let data = ElfRewriter::new(data)
+                .set_rpath(Some(LONG_RPATH))
+                .finish()
+                .unwrap();
+            let elf = parse(&data, is_64, little_endian);
+            assert_eq!(elf.program_headers.len(), phnum + 1, "{arch}");
+            assert_eq!(elf.rpaths, vec![LONG_RPATH], "{arch}");
+            assert!(elf.runpaths.is_empty(), "{arch}");
+            assert_eq!(elf.libraries, vec!["libbar.so"], "{arch}");
+            assert_eq!(verneed_files(&elf), vec!["libbar.so"], "{arch}");
+
+            // Removing it again fits into the existing sections
+            let len = data.len();
+            let data = ElfRewriter::new(data).set_rpath(None).finish().unwrap();
+            assert_eq!(data.len(), len, "{arch}");
+            let elf = parse(&data, is_64, little_endian);
+            assert!(elf.rpaths.is_empty(), "{arch}");
+            assert!(elf.runpaths.is_empty(), "{arch}");
+
+            // libbar.so has no rpath, so `.dynamic` has to grow as well
+            let data = fixture(arch, "libbar.so");
+            let dynamic_size = |elf: &Elf| {
+                elf.program_headers
+                    .iter()
+                    .find(|ph| ph.p_type == PT_DYNAMIC)
+                    .unwrap()
+                    .p_filesz
+            };
+            let old_dynamic_size = dynamic_size(&Elf::parse(&data).unwrap());
+            let data = ElfRewriter::new(data)
+                .set_rpath(Some(LONG_RPATH))
+                .finish()
+                .unwrap();
+            let elf = parse(&data, is_64, little_endian);
+            assert!(dynamic_size(&elf) > old_dynamic_size, "{arch}");
+            assert_eq!(elf.rpaths, vec![LONG_RPATH], "{arch}");
+            assert_eq!(elf.soname, Some("libbar.so"), "{arch}");
+        }
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_arch = "x86_64", target_env = "gnu"))]
+    fn test_rewritten_libraries_load() {
+        // The dynamic loader resolves the renamed dependency through the new rpath and
+        // checks the version requirements and relocations
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let libfoo = ElfRewriter::new(fixture("x86_64", "libfoo.so"))
+            .set_soname("libfoo-4567cdef.so")
+            .replace_needed("libbar.so", "libbar-0123abcd.so")
+            .set_rpath(Some(&format!("{LONG_RPATH}:$ORIGIN")))
+            .finish()
+            .unwrap();
+        let libbar = ElfRewriter::new(fixture("x86_64", "libbar.so"))
+            .set_soname("libbar-0123abcd.so")
+            .finish()
+            .unwrap();
+        let libfoo_path = tmp_dir.path().join("libfoo-4567cdef.so");
+        let libbar_path = tmp_dir.path().join("libbar-0123abcd.so");
+        fs_err::write(&libfoo_path, libfoo).unwrap();
+        fs_err::write(&libbar_path, libbar).unwrap();
+
+        let output = std::process::Command::new("ldd")
+            .arg("-r")
+            .arg(&libfoo_path)
+            .output()
+            .unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(output.status.success(), "{stdout}{stderr}");
+        assert!(stderr.is_empty(), "{stderr}");
+        assert!(
+            stdout.contains(&format!("libbar-0123abcd.so => {}", libbar_path.display())),
+            "{stdout}"
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_rewrite_dynamic_section() {
+        // The test binary itself is a dynamically linked elf file
+        let data = fs_err::read(std::env::current_exe().unwrap()).unwrap();
+        let elf = Elf::parse(&data).unwrap();
+        let old_needed = elf.libraries[0].to_string();
+        let libraries = elf.libraries.len();
+
+        let new_needed = format!("{old_needed}-0123abcd-very-long-name-that-does-not-fit");
+        let data = ElfRewriter::new(data.clone())
+            .set_soname("libmaturin-test.so")
+            .replace_needed(&old_needed, &new_needed)
+            .set_rpath(Some("$ORIGIN/../maturin.libs"))
+            .finish()
+            .unwrap();
+        let elf = Elf::parse(&data).unwrap();
+        assert_eq!(elf.soname, Some("libmaturin-test.so"));
+        assert_eq!(elf.libraries.len(), libraries);
+        assert!(elf.libraries.contains(&new_needed.as_str()));
+        assert!(!elf.libraries.contains(&old_needed.as_str()));
+        assert_eq!(elf.rpaths, vec!["$ORIGIN/../maturin.libs"]);
+        assert!(elf.runpaths.is_empty());
+
+        // Removing the rpath again fits into the existing sections
+        let len = data.len();
+        let data = ElfRewriter::new(data).set_rpath(None).finish().unwrap();
+        assert_eq!(data.len(), len);
+        let elf = Elf::parse(&data).unwrap();
+        assert!(elf.rpaths.is_empty());
+        assert_eq!(elf.soname, Some("libmaturin-test.so"));
+    }
+}
@@ -0,0 +1,37 @@
+#!/usr/bin/env python3
+"""Regenerates stable-abi.json from CPython's Misc/stable_abi.toml
+
+Usage: generate-stable-abi.py [path or url of stable_abi.toml]
+
+Every exported function and data symbol of the stable ABI is mapped to the python version that
+added it. Requires python 3.11 or later for tomllib.
+"""
+import json
+import sys
+import tomllib
+from pathlib import Path
+from urllib.request import urlopen
+
+STABLE_ABI_TOML = "https://raw.githubusercontent.com/python/cpython/main/Misc/stable_abi.toml"
+
+
+def main():
+    source = sys.argv[1] if len(sys.argv) > 1 else STABLE_ABI_TOML
+    if source.startswith("https://"):
+        with urlopen(source) as response:
+            stable_abi = tomllib.load(response)
+    else:
+        with open(source, "rb") as file:
+            stable_abi = tomllib.load(file)
+
+    symbols = {}
+    for kind in ("function", "data"):
+        for name, item in stable_abi.get(kind, {}).items():
+            symbols[name] = item["added"]
+
+    output = Path(__file__).parent.joinpath("stable-abi.json")
+    output.write_text(json.dumps(symbols, indent=2, sort_keys=True) + "\n")
+
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,386 @@
+//! An in-process implementation of the parts of `install_name_tool` we need for bundling
+//! dylibs into macOS wheels
+//!
+//! The load commands are rebuilt in place, using the padding between the load commands and the
+//! first section, so the rest of the file keeps its layout. Every architecture of a fat binary
+//! is rewritten separately. The page hashes of existing code signatures are updated since arm64
+//! macOS refuses to load files with an invalid ad-hoc signature, files signed with a certificate
+//! need to be signed again afterwards.
+use anyhow::{bail, Context, Result};
+use goblin::mach::constants::{SECTION_TYPE, S_GB_ZEROFILL, S_THREAD_LOCAL_ZEROFILL, S_ZEROFILL};
+use goblin::mach::cputype::get_arch_name_from_types;
+use goblin::mach::load_command::{
+    LC_CODE_SIGNATURE, LC_ID_DYLIB, LC_LAZY_LOAD_DYLIB, LC_LOAD_DYLIB, LC_LOAD_UPWARD_DYLIB,
+    LC_LOAD_WEAK_DYLIB, LC_REEXPORT_DYLIB, LC_RPATH,
+};
+use goblin::mach::{Mach, MachO};
+use sha2::{Digest, Sha256, Sha384};
+use std::collections::{HashMap, HashSet};
+
+const CSMAGIC_EMBEDDED_SIGNATURE: u32 = 0xfade_0cc0;
+const CSMAGIC_CODEDIRECTORY: u32 = 0xfade_0c02;
+const CS_HASHTYPE_SHA256: u8 = 2;
+const CS_HASHTYPE_SHA256_TRUNCATED: u8 = 3;
+const CS_HASHTYPE_SHA384: u8 = 4;
+
+/// The install name, linked dylibs and rpaths of one architecture of a Mach-O file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MachOSlice {
+    /// The architecture, e.g. `x86_64` or `arm64`
+    pub arch: String,
+    /// The install name of a dylib (`LC_ID_DYLIB`)
+    pub id: Option<String>,
+    /// The install names of the linked dylibs
+    pub libs: Vec<String>,
+    /// The `LC_RPATH` entries
+    pub rpaths: Vec<String>,
+}
+
+impl MachOSlice {
+    fn from_macho(macho: &MachO) -> Self {
+        Self {
+            arch: get_arch_name_from_types(macho.header.cputype(), macho.header.cpusubtype())
+                .unwrap_or("unknown")
+                .to_string(),
+            id: macho.name.map(ToString::to_string),
+            // The first entry is the dylib itself
+            libs: macho.libs[1..].iter().map(ToString::to_string).collect(),
+            rpaths: macho.rpaths.iter().map(ToString::to_string).collect(),
+        }
+    }
+}
+
+/// Reads the dynamic linking information of every architecture of a (fat) Mach-O file
+pub fn read_slices(data: &[u8]) -> Result<Vec<MachOSlice>> {
+    match Mach::parse(data)? {
+        Mach::Binary(macho) => Ok(vec![MachOSlice::from_macho(&macho)]),
+        Mach::Fat(fat) => {
+            let mut slices = Vec::new();
+            for arch in fat.iter_arches() {
+                let macho = MachO::parse(arch?.slice(data), 0)?;
+                slices.push(MachOSlice::from_macho(&macho));
+            }
+            Ok(slices)
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize, little_endian: bool) -> Result<u32> {
+    let bytes: [u8; 4] = data
+        .get(offset..offset + 4)
+        .with_context(|| format!("Offset {offset:#x} is out of bounds"))?
+        .try_into()
+        .unwrap();
+    if little_endian {
+        Ok(u32::from_le_bytes(bytes))
+    } else {
+        Ok(u32::from_be_bytes(bytes))
+    }
+}
+
+fn u32_bytes(value: u32, little_endian: bool) -> [u8; 4] {
+    if little_endian {
+        value.to_le_bytes()
+    } else {
+        value.to_be_bytes()
+    }
+}
+
+/// Reads the `lc_str` at offset 8 of a load command
+fn read_lc_str(command: &[u8], little_endian: bool) -> Result<&str> {
+    let offset = read_u32(command, 8, little_endian)? as usize;
+    let bytes = command
+        .get(offset..)
+        .context("Load command string is out of bounds")?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    std::str::from_utf8(&bytes[..end]).context("Load command string is not valid utf-8")
+}
+
+/// Builds a `dylib_command` with the fields of `command` but another name
+fn dylib_command(command: &[u8], name: &str, little_endian: bool, align: usize) -> Vec<u8> {
+    // cmd, cmdsize, name offset, timestamp, current version, compatibility version
+    let header_size = 24;
+    let size = (header_size + name.len() + 1 + align - 1) / align * align;
+    let mut new = Vec::with_capacity(size);
+    new.extend_from_slice(&command[..4]);
+    new.extend_from_slice(&u32_bytes(size as u32, little_endian));
+    new.extend_from_slice(&u32_bytes(header_size as u32, little_endian));
+    new.extend_from_slice(&command[12..header_size]);
+    new.extend_from_slice(name.as_bytes());
+    new.resize(size, 0);
+    new
+}
+
+/// Changes the install names and rpaths of a (fat) Mach-O file
+#[derive(Debug)]
+pub struct MachORewriter {
+    data: Vec<u8>,
+    id: Option<String>,
+    install_names: HashMap<String, String>,
+    removed_rpaths: HashSet<String>,
+}
+
+impl MachORewriter {
+    /// Create a new rewriter for the contents of a Mach-O file
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            id: None,
+            install_names: HashMap::new(),
+            removed_rpaths: HashSet::new(),
+        }
+    }
+
+    /// Set the install name of a dylib, like `install_name_tool -id`
+    pub fn set_id(&mut self, id: &str) -> &mut Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// Change the install name of a linked dylib, like `install_name_tool -change`
+    pub fn change_install_name(&mut self, old: &str, new: &str) -> &mut Self {
+        self.install_names.insert(old.to_string(), new.to_string());
+        self
+    }
+
+    /// Remove an `LC_RPATH` entry, like `install_name_tool -delete_rpath`
+    pub fn delete_rpath(&mut self, rpath: &str) -> &mut Self {
+        self.removed_rpaths.insert(rpath.to_string());
+        self
+    }
+
+    /// Apply the changes to every architecture and return the new file contents
+    pub fn finish(&self) -> Result<Vec<u8>> {
+        let slices: Vec<(usize, usize)> = match Mach::parse(&self.data)? {
+            Mach::Binary(_) => vec![(0, self.data.len())],
+            Mach::Fat(fat) => fat
+                .iter_arches()
+                .map(|arch| arch.map(|arch| (arch.offset as usize, arch.size as usize)))
+                .collect::<Result<_, _>>()?,
+        };
+        let mut data = self.data.clone();
+        for (offset, size) in slices {
+            let slice = data
+                .get_mut(offset..offset + size)
+                .context("Architecture of fat binary is out of bounds")?;
+            self.rewrite_slice(slice)?;
+        }
+        Ok(data)
+    }
+
+    fn rewrite_slice(&self, data: &mut [u8]) -> Result<()> {
+        let (little_endian, is_64, ncmds, sizeofcmds, limit) = {
+            let macho = MachO::parse(data, 0)?;
+            // The load commands may grow until the first section or segment with file contents
+            let mut limit = data.len();
+            for segment in macho.segments.iter() {
+                for (section, _) in segment.sections()? {
+                    let section_type = section.flags & SECTION_TYPE;
+                    if section.offset != 0
+                        && section.size != 0
+                        && ![S_ZEROFILL, S_GB_ZEROFILL, S_THREAD_LOCAL_ZEROFILL]
+                            .contains(&section_type)
+                    {
+                        limit = limit.min(section.offset as usize);
+                    }
+                }
+                if segment.fileoff != 0 && segment.filesize != 0 {
+                    limit = limit.min(segment.fileoff as usize);
+                }
+            }
+            (
+                macho.little_endian,
+                macho.is_64,
+                macho.header.ncmds,
+                macho.header.sizeofcmds as usize,
+                limit,
+            )
+        };
+        let (header_size, align) = if is_64 { (32, 8) } else { (28, 4) };
+
+        let mut commands = Vec::with_capacity(sizeofcmds);
+        let mut new_ncmds = 0usize;
+        let mut code_signature = None;
+        let mut offset = header_size;
+        for _ in 0..ncmds {
+            let cmd = read_u32(data, offset, little_endian)?;
+            let cmdsize = read_u32(data, offset + 4, little_endian)? as usize;
+            let command = data
+                .get(offset..offset + cmdsize)
+                .with_context(|| format!("Load command at {offset:#x} is out of bounds"))?;
+            offset += cmdsize;
+            match cmd {
+                LC_ID_DYLIB | LC_LOAD_DYLIB | LC_LOAD_WEAK_DYLIB | LC_REEXPORT_DYLIB
+                | LC_LAZY_LOAD_DYLIB | LC_LOAD_UPWARD_DYLIB => {
+                    let name = read_lc_str(command, little_endian)?;
+                    let new_name = if cmd == LC_ID_DYLIB {
+                        self.id.as_deref()
+                    } else {
+                        self.install_names.get(name).map(String::as_str)
+                    };
+                    match new_name {
+                        Some(new_name) if new_name != name => {
+                            commands.extend(dylib_command(command, new_name, little_endian, align))
+                        }
+                        _ => commands.extend_from_slice(command),
+                    }
+                }
+                LC_RPATH
+                    if self
+                        .removed_rpaths
+                        .contains(read_lc_str(command, little_endian)?) =>
+                {
+                    continue;
+                }
+                LC_CODE_SIGNATURE => {
+                    code_signature = Some((
+                        read_u32(command, 8, little_endian)? as usize,
+                        read_u32(command, 12, little_endian)? as usize,
+                    ));
+                    commands.extend_from_slice(command);
+                }
+                _ => commands.extend_from_slice(command),
+            }
+            new_ncmds += 1;
+        }
+
+        if new_ncmds == ncmds && commands == data[header_size..header_size + sizeofcmds] {
+            return Ok(());
+        }
+        if header_size + commands.len() > limit {
+            bail!(
+                "Not enough space in the Mach-O header to change the install names, \
+                 the file needs to be linked with `-headerpad_max_install_names`"
+            );
+        }
+        data[header_size..header_size + sizeofcmds.max(commands.len())].fill(0);
+        data[header_size..header_size + commands.len()].copy_from_slice(&commands);
+        data[16..20].copy_from_slice(&u32_bytes(new_ncmds as u32, little_endian));
+        data[20..24].copy_from_slice(&u32_bytes(commands.len() as u32, little_endian));
+
+        if let Some((dataoff, datasize)) = code_signature {
+            update_code_signature(data, dataoff, datasize)?;
+        }
+        Ok(())
+    }
+}
+
+/// Recomputes the page hashes of every code directory in the embedded signature
+fn update_code_signature(data: &mut [u8], dataoff: usize, datasize: usize) -> Result<()> {
+    // Code signatures are always big endian
+    let signature = data
+        .get(dataoff..dataoff + datasize)
+        .context("Code signature is out of bounds")?;
+    if read_u32(signature, 0, false)? != CSMAGIC_EMBEDDED_SIGNATURE {
+        bail!("Unsupported code signature format");
+    }
+    let count = read_u32(signature, 8, false)? as usize;
+    let mut code_directories = Vec::new();
+    for index in 0..count {
+        let blob = dataoff + read_u32(signature, 12 + index * 8 + 4, false)? as usize;
+        if read_u32(data, blob, false)? == CSMAGIC_CODEDIRECTORY {
+            code_directories.push(blob);
+        }
+    }
+
+    for directory in code_directories {
+        let hash_offset = directory + read_u32(data, directory + 16, false)? as usize;
+        let code_slots = read_u32(data, directory + 28, false)? as usize;
+        let code_limit = read_u32(data, directory + 32, false)? as usize;
+        let hash_size = data[directory + 36] as usize;
+        let hash_type = data[directory + 37];
+        let page_size = 1usize << data[directory + 39];
+        for slot in 0..code_slots {
+            let start = slot * page_size;
+            let page = data
+                .get(start..code_limit.min(start + page_size))
+                .context("Code signature page is out of bounds")?;
+            let hash = match hash_type {
+                CS_HASHTYPE_SHA256 | CS_HASHTYPE_SHA256_TRUNCATED => Sha256::digest(page).to_vec(),
+                CS_HASHTYPE_SHA384 => Sha384::digest(page).to_vec(),
+                _ => bail!("Unsupported code signature hash type {hash_type}"),
+            };
+            let slot_offset = hash_offset + slot * hash_size;
+            data[slot_offset..slot_offset + hash_size].copy_from_slice(&hash[..hash_size]);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_slices, read_u32, MachORewriter};
+    use fs_err as fs;
+    use goblin::mach::{Mach, MachO};
+    use pretty_assertions::assert_eq;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_rewrite_install_names() {
+        let data = fs::read("tests/fixtures/macho/libfoo.1.dylib").unwrap();
+        let slices = read_slices(&data).unwrap();
+        assert_eq!(
+            slices.iter().map(|s| s.arch.as_str()).collect::<Vec<_>>(),
+            ["x86_64", "arm64"]
+        );
+        assert_eq!(
+            slices[0].id.as_deref(),
+            Some("/opt/homebrew/opt/foo/lib/libfoo.1.dylib")
+        );
+        assert_eq!(slices[0].rpaths, ["@loader_path"]);
+
+        let rewritten = MachORewriter::new(data)
+            .set_id("@loader_path/libfoo.1.dylib")
+            .change_install_name("@rpath/libbar.dylib", "@loader_path/libbar-renamed.dylib")
+            .delete_rpath("@loader_path")
+            .finish()
+            .unwrap();
+        for slice in read_slices(&rewritten).unwrap() {
+            assert_eq!(slice.id.as_deref(), Some("@loader_path/libfoo.1.dylib"));
+            assert_eq!(
+                slice.libs,
+                [
+                    "@loader_path/libbar-renamed.dylib",
+                    "/usr/lib/libSystem.B.dylib"
+                ]
+            );
+            assert!(slice.rpaths.is_empty());
+        }
+
+        // The ad-hoc signature of the arm64 slice must match the new header
+        let fat = match Mach::parse(&rewritten).unwrap() {
+            Mach::Fat(fat) => fat,
+            Mach::Binary(_) => panic!("Expected a fat binary"),
+        };
+        let arm64 = fat.iter_arches().nth(1).unwrap().unwrap().slice(&rewritten);
+        let macho = MachO::parse(arm64, 0).unwrap();
+        let (dataoff, _) = macho
+            .load_commands
+            .iter()
+            .find_map(|command| match command.command {
+                goblin::mach::load_command::CommandVariant::CodeSignature(signature) => {
+                    Some((signature.dataoff as usize, signature.datasize))
+                }
+                _ => None,
+            })
+            .unwrap();
+        let directory = dataoff + read_u32(arm64, dataoff + 16, false).unwrap() as usize;
+        let hash_offset = directory + read_u32(arm64, directory + 16, false).unwrap() as usize;
+        let page_size = 1 << arm64[directory + 39];
+        assert_eq!(
+            arm64[hash_offset..hash_offset + 32],
+            Sha256::digest(&arm64[..page_size])[..]
+        );
+    }
+
+    #[test]
+    fn test_header_padding_exhausted() {
+        let data = fs::read("tests/fixtures/macho/libbar.dylib").unwrap();
+        let long_name = format!("@loader_path/{}", "x".repeat(0x4000));
+        let err = MachORewriter::new(data)
+            .set_id(&long_name)
+            .finish()
+            .unwrap_err();
+        assert!(err.to_string().contains("-headerpad_max_install_names"));
+    }
+}
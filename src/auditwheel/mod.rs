@@ -1,11 +1,24 @@
+mod abi3;
 mod audit;
+mod delocate;
+mod delvewheel;
+mod elf_rewriter;
+mod macho_rewriter;
 mod musllinux;
 pub mod patchelf;
+mod pe_rewriter;
 mod platform_tag;
 mod policy;
 mod repair;
+mod report;
+mod wheel;
 
+pub use abi3::audit_abi3;
 pub use audit::*;
+pub use delocate::{find_external_dylibs, graft_external_dylibs};
+pub use delvewheel::{add_dll_directory_code, find_external_dlls, graft_external_dlls};
 pub use platform_tag::PlatformTag;
-pub use policy::{Policy, MANYLINUX_POLICIES, MUSLLINUX_POLICIES};
-pub use repair::find_external_libs;
+pub use policy::{Policy, PolicyOverrides};
+pub use repair::{find_external_libs, graft_external_libs, remove_foreign_rpaths};
+pub use report::{report_wheel, ElfReport, ReportFormat, WheelReport};
+pub use wheel::{audit_wheel, repair_wheel, retag_wheel, ElfAudit, WheelAudit};
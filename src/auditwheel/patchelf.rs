@@ -1,7 +1,9 @@
+use super::elf_rewriter::ElfRewriter;
 use anyhow::{bail, Context, Result};
 use std::ffi::OsStr;
 use std::path::Path;
 use std::process::Command;
+use tracing::debug;
 
 static MISSING_PATCHELF_ERROR: &str = "Failed to execute 'patchelf', did you install it? Hint: Try `pip install maturin[patchelf]` (or just `pip install patchelf`)";
 
@@ -28,16 +30,71 @@ pub fn verify_patchelf() -> Result<()> {
     Ok(())
 }
 
+/// Edits the elf file in-process, falling back to the `patchelf` executable if that fails,
+/// e.g. because of an unusual file layout
+fn patch_with_fallback(
+    file: &Path,
+    patch: impl FnOnce(&mut ElfRewriter) -> Result<()>,
+    fallback: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    let result = fs_err::read(file)
+        .map_err(anyhow::Error::from)
+        .and_then(|data| {
+            let mut rewriter = ElfRewriter::new(data);
+            patch(&mut rewriter)?;
+            let data = rewriter.finish()?;
+            fs_err::write(file, data)?;
+            Ok(())
+        });
+    match result {
+        Ok(()) => Ok(()),
+        Err(err) => {
+            if verify_patchelf().is_err() {
+                return Err(err.context(format!("Failed to patch {}", file.display())));
+            }
+            debug!(
+                "Failed to patch {} in-process, falling back to patchelf: {:?}",
+                file.display(),
+                err
+            );
+            fallback()
+        }
+    }
+}
+
+fn os_str_to_str(value: &OsStr) -> Result<&str> {
+    value
+        .to_str()
+        .with_context(|| format!("{value:?} is not valid utf-8"))
+}
+
 /// Replace a declared dependency on a dynamic library with another one (`DT_NEEDED`)
 pub fn replace_needed<O: AsRef<OsStr>, N: AsRef<OsStr>>(
     file: impl AsRef<Path>,
     old_new_pairs: &[(O, N)],
+) -> Result<()> {
+    let file = file.as_ref();
+    patch_with_fallback(
+        file,
+        |elf| {
+            for (old, new) in old_new_pairs {
+                elf.replace_needed(os_str_to_str(old.as_ref())?, os_str_to_str(new.as_ref())?);
+            }
+            Ok(())
+        },
+        || patchelf_replace_needed(file, old_new_pairs),
+    )
+}
+
+fn patchelf_replace_needed<O: AsRef<OsStr>, N: AsRef<OsStr>>(
+    file: &Path,
+    old_new_pairs: &[(O, N)],
 ) -> Result<()> {
     let mut cmd = Command::new("patchelf");
     for (old, new) in old_new_pairs {
         cmd.arg("--replace-needed").arg(old).arg(new);
     }
-    cmd.arg(file.as_ref());
+    cmd.arg(file);
     let output = cmd.output().context(MISSING_PATCHELF_ERROR)?;
     if !output.status.success() {
         bail!(
@@ -50,8 +107,20 @@ pub fn replace_needed<O: AsRef<OsStr>, N: AsRef<OsStr>>(
 
 /// Change `SONAME` of a dynamic library
 pub fn set_soname<S: AsRef<OsStr>>(file: impl AsRef<Path>, soname: &S) -> Result<()> {
+    let file = file.as_ref();
+    patch_with_fallback(
+        file,
+        |elf| {
+            elf.set_soname(os_str_to_str(soname.as_ref())?);
+            Ok(())
+        },
+        || patchelf_set_soname(file, soname),
+    )
+}
+
+fn patchelf_set_soname<S: AsRef<OsStr>>(file: &Path, soname: &S) -> Result<()> {
     let mut cmd = Command::new("patchelf");
-    cmd.arg("--set-soname").arg(soname).arg(file.as_ref());
+    cmd.arg("--set-soname").arg(soname).arg(file);
     let output = cmd.output().context(MISSING_PATCHELF_ERROR)?;
     if !output.status.success() {
         bail!(
@@ -63,9 +132,9 @@ pub fn set_soname<S: AsRef<OsStr>>(file: impl AsRef<Path>, soname: &S) -> Result
 }
 
 /// Remove a `RPATH` from executables and libraries
-pub fn remove_rpath(file: impl AsRef<Path>) -> Result<()> {
+fn patchelf_remove_rpath(file: &Path) -> Result<()> {
     let mut cmd = Command::new("patchelf");
-    cmd.arg("--remove-rpath").arg(file.as_ref());
+    cmd.arg("--remove-rpath").arg(file);
     let output = cmd.output().context(MISSING_PATCHELF_ERROR)?;
     if !output.status.success() {
         bail!(
@@ -78,12 +147,37 @@ pub fn remove_rpath(file: impl AsRef<Path>) -> Result<()> {
 
 /// Change the `RPATH` of executables and libraries
 pub fn set_rpath<S: AsRef<OsStr>>(file: impl AsRef<Path>, rpath: &S) -> Result<()> {
-    remove_rpath(&file)?;
+    let file = file.as_ref();
+    patch_with_fallback(
+        file,
+        |elf| {
+            elf.set_rpath(Some(os_str_to_str(rpath.as_ref())?));
+            Ok(())
+        },
+        || patchelf_set_rpath(file, rpath),
+    )
+}
+
+/// Remove the `RPATH` and `RUNPATH` of executables and libraries
+pub fn remove_rpath(file: impl AsRef<Path>) -> Result<()> {
+    let file = file.as_ref();
+    patch_with_fallback(
+        file,
+        |elf| {
+            elf.set_rpath(None);
+            Ok(())
+        },
+        || patchelf_remove_rpath(file),
+    )
+}
+
+fn patchelf_set_rpath<S: AsRef<OsStr>>(file: &Path, rpath: &S) -> Result<()> {
+    patchelf_remove_rpath(file)?;
     let mut cmd = Command::new("patchelf");
     cmd.arg("--force-rpath")
         .arg("--set-rpath")
         .arg(rpath)
-        .arg(file.as_ref());
+        .arg(file);
     let output = cmd.output().context(MISSING_PATCHELF_ERROR)?;
     if !output.status.success() {
         bail!(
@@ -0,0 +1,418 @@
+//! Changing the names of imported DLLs in PE files, for bundling DLLs into Windows wheels
+//!
+//! Names that fit are changed in place, longer names are written to a new section that is
+//! appended to the file. Since any change invalidates them, bound imports and Authenticode
+//! signatures are removed.
+use anyhow::{bail, Context, Result};
+use goblin::pe::header::Header;
+use std::collections::HashMap;
+
+const IMAGE_DIRECTORY_ENTRY_IMPORT: usize = 1;
+const IMAGE_DIRECTORY_ENTRY_SECURITY: usize = 4;
+const IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT: usize = 11;
+const IMAGE_DIRECTORY_ENTRY_DELAY_IMPORT: usize = 13;
+const IMAGE_SCN_CNT_INITIALIZED_DATA: u32 = 0x0000_0040;
+const IMAGE_SCN_MEM_READ: u32 = 0x4000_0000;
+const SIZEOF_SECTION_HEADER: usize = 40;
+
+fn read_u16(data: &[u8], offset: usize) -> Result<u16> {
+    let bytes = data
+        .get(offset..offset + 2)
+        .with_context(|| format!("Offset {offset:#x} is out of bounds"))?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .with_context(|| format!("Offset {offset:#x} is out of bounds"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn write_u32(data: &mut [u8], offset: usize, value: u32) {
+    data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn read_cstr(data: &[u8], offset: usize) -> Result<&str> {
+    let bytes = data
+        .get(offset..)
+        .with_context(|| format!("Offset {offset:#x} is out of bounds"))?;
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    std::str::from_utf8(&bytes[..end]).context("DLL name is not valid utf-8")
+}
+
+fn round_up(value: usize, align: usize) -> usize {
+    if align <= 1 {
+        value
+    } else {
+        (value + align - 1) / align * align
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Section {
+    virtual_size: u32,
+    virtual_address: u32,
+    size_of_raw_data: u32,
+    pointer_to_raw_data: u32,
+}
+
+/// The offsets of the headers we need to read and change
+#[derive(Debug)]
+struct Layout {
+    machine: u16,
+    number_of_sections: usize,
+    optional_header: usize,
+    section_table: usize,
+    data_directories: usize,
+    number_of_data_directories: usize,
+    image_base: u64,
+    section_alignment: usize,
+    file_alignment: usize,
+    size_of_headers: usize,
+    sections: Vec<Section>,
+}
+
+impl Layout {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let header = Header::parse(data).context("Failed to parse PE header")?;
+        let optional = header
+            .optional_header
+            .context("PE file has no optional header")?;
+        let optional_header = header.dos_header.pe_pointer as usize + 4 + 20;
+        let is_64 = read_u16(data, optional_header)? == 0x20b;
+        let section_table = optional_header + header.coff_header.size_of_optional_header as usize;
+        let number_of_sections = header.coff_header.number_of_sections as usize;
+        let mut sections = Vec::with_capacity(number_of_sections);
+        for index in 0..number_of_sections {
+            let offset = section_table + index * SIZEOF_SECTION_HEADER;
+            sections.push(Section {
+                virtual_size: read_u32(data, offset + 8)?,
+                virtual_address: read_u32(data, offset + 12)?,
+                size_of_raw_data: read_u32(data, offset + 16)?,
+                pointer_to_raw_data: read_u32(data, offset + 20)?,
+            });
+        }
+        Ok(Self {
+            machine: header.coff_header.machine,
+            number_of_sections,
+            optional_header,
+            section_table,
+            data_directories: optional_header + if is_64 { 112 } else { 96 },
+            number_of_data_directories: optional.windows_fields.number_of_rva_and_sizes as usize,
+            image_base: optional.windows_fields.image_base,
+            section_alignment: optional.windows_fields.section_alignment as usize,
+            file_alignment: optional.windows_fields.file_alignment as usize,
+            size_of_headers: optional.windows_fields.size_of_headers as usize,
+            sections,
+        })
+    }
+
+    fn rva_to_offset(&self, rva: u32) -> Result<usize> {
+        if (rva as usize) < self.size_of_headers {
+            return Ok(rva as usize);
+        }
+        self.sections
+            .iter()
+            .find(|section| {
+                let size = section.virtual_size.max(section.size_of_raw_data);
+                section.virtual_address <= rva && rva < section.virtual_address + size
+            })
+            .map(|section| (rva - section.virtual_address + section.pointer_to_raw_data) as usize)
+            .with_context(|| format!("RVA {rva:#x} is not part of any section"))
+    }
+
+    /// The offset of the data directory entry, if the file has it
+    fn data_directory(&self, index: usize) -> Option<usize> {
+        if index < self.number_of_data_directories {
+            Some(self.data_directories + index * 8)
+        } else {
+            None
+        }
+    }
+}
+
+/// A field that points to the name of an imported DLL
+#[derive(Debug)]
+struct ImportName {
+    /// The file offset of the field
+    field: usize,
+    /// The field contains a virtual address instead of an RVA (old style delay imports)
+    is_va: bool,
+    /// The file offset of the name
+    offset: usize,
+    name: String,
+}
+
+/// Collects the names of the regular and the delay loaded DLL imports
+fn import_names(data: &[u8], layout: &Layout) -> Result<Vec<ImportName>> {
+    let mut names = Vec::new();
+    if let Some(directory) = layout.data_directory(IMAGE_DIRECTORY_ENTRY_IMPORT) {
+        let rva = read_u32(data, directory)?;
+        if rva != 0 {
+            // IMAGE_IMPORT_DESCRIPTOR: OriginalFirstThunk, TimeDateStamp, ForwarderChain,
+            // Name, FirstThunk
+            let mut descriptor = layout.rva_to_offset(rva)?;
+            loop {
+                let name_rva = read_u32(data, descriptor + 12)?;
+                if name_rva == 0 {
+                    break;
+                }
+                let offset = layout.rva_to_offset(name_rva)?;
+                names.push(ImportName {
+                    field: descriptor + 12,
+                    is_va: false,
+                    offset,
+                    name: read_cstr(data, offset)?.to_string(),
+                });
+                descriptor += 20;
+            }
+        }
+    }
+    if let Some(directory) = layout.data_directory(IMAGE_DIRECTORY_ENTRY_DELAY_IMPORT) {
+        let rva = read_u32(data, directory)?;
+        if rva != 0 {
+            // ImgDelayDescr: Attributes, DllNameRVA, ...
+            let mut descriptor = layout.rva_to_offset(rva)?;
+            loop {
+                let name_field = read_u32(data, descriptor + 4)?;
+                if name_field == 0 {
+                    break;
+                }
+                let is_va = read_u32(data, descriptor)? & 1 == 0;
+                let name_rva = if is_va {
+                    (name_field as u64).wrapping_sub(layout.image_base) as u32
+                } else {
+                    name_field
+                };
+                let offset = layout.rva_to_offset(name_rva)?;
+                names.push(ImportName {
+                    field: descriptor + 4,
+                    is_va,
+                    offset,
+                    name: read_cstr(data, offset)?.to_string(),
+                });
+                descriptor += 32;
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// The machine type and the imported DLLs of a PE file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeImports {
+    /// The `IMAGE_FILE_MACHINE_*` value from the COFF header
+    pub machine: u16,
+    /// The names of the imported DLLs, including delay loaded ones
+    pub dlls: Vec<String>,
+}
+
+/// Reads the machine type and the imported DLLs of a PE file
+pub fn read_imports(data: &[u8]) -> Result<PeImports> {
+    let layout = Layout::parse(data)?;
+    let mut dlls: Vec<String> = Vec::new();
+    for import in import_names(data, &layout)? {
+        if !dlls
+            .iter()
+            .any(|dll| dll.eq_ignore_ascii_case(&import.name))
+        {
+            dlls.push(import.name);
+        }
+    }
+    Ok(PeImports {
+        machine: layout.machine,
+        dlls,
+    })
+}
+
+/// Computes the checksum of the optional header
+fn checksum(data: &[u8], checksum_offset: usize) -> u32 {
+    let mut sum = 0u64;
+    for (index, word) in data.chunks(2).enumerate() {
+        if index * 2 == checksum_offset || index * 2 == checksum_offset + 2 {
+            continue;
+        }
+        sum += u16::from_le_bytes([word[0], word.get(1).copied().unwrap_or(0)]) as u64;
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum = (sum & 0xffff) + (sum >> 16);
+    sum as u32 + data.len() as u32
+}
+
+/// Changes the names of imported DLLs of a PE file
+#[derive(Debug)]
+pub struct PeRewriter {
+    data: Vec<u8>,
+    imports: HashMap<String, String>,
+}
+
+impl PeRewriter {
+    /// Create a new rewriter for the contents of a PE file
+    pub fn new(data: Vec<u8>) -> Self {
+        Self {
+            data,
+            imports: HashMap::new(),
+        }
+    }
+
+    /// Import `new` instead of the DLL `old`, which is matched case insensitively
+    pub fn replace_import(&mut self, old: &str, new: &str) -> &mut Self {
+        self.imports
+            .insert(old.to_ascii_lowercase(), new.to_string());
+        self
+    }
+
+    /// Apply the changes and return the new file contents
+    pub fn finish(&self) -> Result<Vec<u8>> {
+        let mut data = self.data.clone();
+        let layout = Layout::parse(&data)?;
+
+        // Names that don't fit in place go into a new section, with the offsets in it
+        let mut new_names = Vec::new();
+        let mut moved = Vec::new();
+        let mut changed = false;
+        for import in import_names(&data, &layout)? {
+            let new_name = match self.imports.get(&import.name.to_ascii_lowercase()) {
+                Some(new_name) if *new_name != import.name => new_name,
+                _ => continue,
+            };
+            changed = true;
+            if new_name.len() <= import.name.len() {
+                let end = import.offset + import.name.len();
+                data[import.offset..end].fill(0);
+                data[import.offset..import.offset + new_name.len()]
+                    .copy_from_slice(new_name.as_bytes());
+            } else {
+                moved.push((import.field, import.is_va, new_names.len()));
+                new_names.extend_from_slice(new_name.as_bytes());
+                new_names.push(0);
+            }
+        }
+        if !changed {
+            return Ok(data);
+        }
+
+        for index in [
+            IMAGE_DIRECTORY_ENTRY_SECURITY,
+            IMAGE_DIRECTORY_ENTRY_BOUND_IMPORT,
+        ] {
+            if let Some(directory) = layout.data_directory(index) {
+                let (address, size) =
+                    (read_u32(&data, directory)?, read_u32(&data, directory + 4)?);
+                // The address of the certificate table is a file offset
+                if index == IMAGE_DIRECTORY_ENTRY_SECURITY
+                    && size != 0
+                    && address as usize + size as usize >= data.len()
+                {
+                    data.truncate(address as usize);
+                }
+                data[directory..directory + 8].fill(0);
+            }
+        }
+
+        if !new_names.is_empty() {
+            let header = layout.section_table + layout.number_of_sections * SIZEOF_SECTION_HEADER;
+            if header + SIZEOF_SECTION_HEADER > layout.size_of_headers
+                || data[header..header + SIZEOF_SECTION_HEADER]
+                    .iter()
+                    .any(|&b| b != 0)
+            {
+                bail!("Not enough space in the PE header to add a section for the new DLL names");
+            }
+            let virtual_address = round_up(
+                layout
+                    .sections
+                    .iter()
+                    .map(|section| {
+                        (section.virtual_address
+                            + section.virtual_size.max(section.size_of_raw_data))
+                            as usize
+                    })
+                    .max()
+                    .unwrap_or(layout.size_of_headers),
+                layout.section_alignment,
+            );
+            let pointer_to_raw_data = round_up(data.len(), layout.file_alignment);
+            let size_of_raw_data = round_up(new_names.len(), layout.file_alignment);
+            data.resize(pointer_to_raw_data, 0);
+            data.extend_from_slice(&new_names);
+            data.resize(pointer_to_raw_data + size_of_raw_data, 0);
+
+            data[header..header + 8].copy_from_slice(b".dllname");
+            write_u32(&mut data, header + 8, new_names.len() as u32);
+            write_u32(&mut data, header + 12, virtual_address as u32);
+            write_u32(&mut data, header + 16, size_of_raw_data as u32);
+            write_u32(&mut data, header + 20, pointer_to_raw_data as u32);
+            write_u32(
+                &mut data,
+                header + 36,
+                IMAGE_SCN_CNT_INITIALIZED_DATA | IMAGE_SCN_MEM_READ,
+            );
+
+            let coff_header = layout.optional_header - 20;
+            data[coff_header + 2..coff_header + 4]
+                .copy_from_slice(&(layout.number_of_sections as u16 + 1).to_le_bytes());
+            let size_of_initialized_data = read_u32(&data, layout.optional_header + 8)?;
+            write_u32(
+                &mut data,
+                layout.optional_header + 8,
+                size_of_initialized_data + size_of_raw_data as u32,
+            );
+            let size_of_image =
+                round_up(virtual_address + new_names.len(), layout.section_alignment);
+            write_u32(&mut data, layout.optional_header + 56, size_of_image as u32);
+
+            for (field, is_va, offset) in moved {
+                let rva = (virtual_address + offset) as u32;
+                let value = if is_va {
+                    (layout.image_base + rva as u64) as u32
+                } else {
+                    rva
+                };
+                write_u32(&mut data, field, value);
+            }
+        }
+
+        let checksum_offset = layout.optional_header + 64;
+        if read_u32(&data, checksum_offset)? != 0 {
+            let checksum = checksum(&data, checksum_offset);
+            write_u32(&mut data, checksum_offset, checksum);
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_imports, PeRewriter};
+    use fs_err as fs;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_replace_imports() {
+        let data = fs::read("tests/fixtures/pe/ext.pyd").unwrap();
+        let imports = read_imports(&data).unwrap();
+        // IMAGE_FILE_MACHINE_AMD64
+        assert_eq!(imports.machine, 0x8664);
+        assert_eq!(
+            imports.dlls,
+            ["foo.dll", "python3.dll", "KERNEL32.dll", "bar.dll"]
+        );
+
+        let rewritten = PeRewriter::new(data)
+            .replace_import("FOO.dll", "foo-0123abcd.dll")
+            .replace_import("bar.dll", "b.dll")
+            .finish()
+            .unwrap();
+        assert_eq!(
+            read_imports(&rewritten).unwrap().dlls,
+            ["foo-0123abcd.dll", "python3.dll", "KERNEL32.dll", "b.dll"]
+        );
+        let pe = goblin::pe::PE::parse(&rewritten).unwrap();
+        assert!(pe
+            .sections
+            .iter()
+            .any(|section| section.name().unwrap() == ".dllname"));
+    }
+}
@@ -1,11 +1,15 @@
 use crate::auditwheel::PlatformTag;
+use crate::pyproject_toml::AuditwheelConfig;
 use crate::target::Arch;
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
 use once_cell::sync::Lazy;
 use serde::Deserialize;
 use std::cmp::{Ordering, PartialOrd};
 use std::collections::{HashMap, HashSet};
 use std::fmt;
 use std::fmt::{Display, Formatter};
+use std::path::Path;
 
 /// The policies (allowed symbols) for the different manylinux tags, sorted from highest
 /// priority to lowest
@@ -113,9 +117,96 @@ impl Policy {
     }
 }
 
+/// User supplied changes to the builtin policies from `[tool.maturin.auditwheel]`
+#[derive(Debug, Clone, Default)]
+pub struct PolicyOverrides {
+    /// Glob patterns of sonames that are never grafted into the wheel
+    pub exclude: Vec<glob::Pattern>,
+    /// Libraries that are added to the whitelist of every policy
+    pub whitelist: HashSet<String>,
+    /// Additional policies, which replace builtin policies with the same name
+    pub policies: Vec<Policy>,
+}
+
+impl PolicyOverrides {
+    /// Reads the overrides from pyproject.toml, with the policy file relative to `project_root`
+    pub fn from_config(config: &AuditwheelConfig, project_root: &Path) -> Result<Self> {
+        let exclude = config
+            .exclude
+            .iter()
+            .map(|pattern| {
+                glob::Pattern::new(pattern)
+                    .with_context(|| format!("Invalid auditwheel exclude pattern `{pattern}`"))
+            })
+            .collect::<Result<_>>()?;
+        let policies = match &config.policy_file {
+            Some(policy_file) => {
+                let path = project_root.join(policy_file);
+                let policies: Vec<Policy> = serde_json::from_slice(&fs::read(&path)?)
+                    .with_context(|| format!("Invalid policy file {}", path.display()))?;
+                for policy in &policies {
+                    if let Err(err) = policy.name.parse::<PlatformTag>() {
+                        bail!(
+                            "Invalid policy name `{}` in {}: {err}",
+                            policy.name,
+                            path.display()
+                        );
+                    }
+                }
+                policies
+            }
+            None => Vec::new(),
+        };
+        Ok(Self {
+            exclude,
+            whitelist: config.whitelist.iter().cloned().collect(),
+            policies,
+        })
+    }
+
+    /// Whether the library must not be grafted into the wheel
+    pub fn is_excluded(&self, soname: &str) -> bool {
+        self.exclude.iter().any(|pattern| pattern.matches(soname))
+    }
+
+    /// The manylinux or musllinux policies including the overrides, sorted from highest
+    /// priority to lowest
+    pub fn policies(&self, musllinux: bool) -> Vec<Policy> {
+        let builtin = if musllinux {
+            &MUSLLINUX_POLICIES
+        } else {
+            &MANYLINUX_POLICIES
+        };
+        let is_family = |policy: &Policy| {
+            policy.name == "linux" || policy.name.starts_with("musllinux") == musllinux
+        };
+        let mut policies: Vec<Policy> = builtin
+            .iter()
+            .filter(|policy| !self.policies.iter().any(|extra| extra.name == policy.name))
+            .chain(self.policies.iter().filter(|policy| is_family(policy)))
+            .cloned()
+            .map(|mut policy| {
+                if policy.name != "linux" {
+                    policy.lib_whitelist.extend(self.whitelist.iter().cloned());
+                }
+                policy
+            })
+            .collect();
+        policies.sort_by_key(|policy| -policy.priority);
+        policies
+    }
+
+    /// Get policy by it's platform tag name, see [Policy::from_name]
+    pub fn policy(&self, name: &str) -> Option<Policy> {
+        self.policies(name.starts_with("musllinux"))
+            .into_iter()
+            .find(|p| p.name == name || p.aliases.iter().any(|alias| alias == name))
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::{Arch, Policy, MANYLINUX_POLICIES, MUSLLINUX_POLICIES};
+    use super::{Arch, Policy, PolicyOverrides, MANYLINUX_POLICIES, MUSLLINUX_POLICIES};
     use pretty_assertions::assert_eq;
 
     #[test]
@@ -154,4 +245,30 @@ mod test {
         policy.fixup_musl_libc_so_name(Arch::Aarch64);
         assert!(policy.lib_whitelist.contains("libc.musl-aarch64.so.1"));
     }
+
+    #[test]
+    fn test_policy_overrides() {
+        let mut manylinux_2_99 = Policy::from_name("manylinux_2_35").unwrap();
+        manylinux_2_99.name = "manylinux_2_99".to_string();
+        manylinux_2_99.priority -= 1;
+        let overrides = PolicyOverrides {
+            exclude: vec![glob::Pattern::new("libcuda.so*").unwrap()],
+            whitelist: ["libvendor.so.1".to_string()].into_iter().collect(),
+            policies: vec![manylinux_2_99],
+        };
+        assert!(overrides.is_excluded("libcuda.so.1"));
+        assert!(!overrides.is_excluded("libssl.so.3"));
+
+        let policies = overrides.policies(false);
+        assert_eq!(policies.len(), MANYLINUX_POLICIES.len() + 1);
+        assert_eq!(policies.last().unwrap().name, "linux");
+        assert_eq!(policies[policies.len() - 2].name, "manylinux_2_99");
+        assert!(overrides
+            .policy("manylinux2014")
+            .unwrap()
+            .lib_whitelist
+            .contains("libvendor.so.1"));
+        assert!(overrides.policy("manylinux_2_99").is_some());
+        assert_eq!(overrides.policies(true).len(), MUSLLINUX_POLICIES.len());
+    }
 }
@@ -1,14 +1,25 @@
-use super::audit::AuditWheelError;
+use super::audit::{relpath, AuditWheelError};
+use super::patchelf;
+use super::policy::PolicyOverrides;
+use super::wheel::is_elf;
 use crate::auditwheel::Policy;
-use anyhow::Result;
-use lddtree::DependencyAnalyzer;
-use std::path::{Path, PathBuf};
+use crate::build_context::hash_file;
+use crate::module_writer::ModuleWriter;
+use anyhow::{Context, Result};
+use fs_err as fs;
+use lddtree::{DependencyAnalyzer, Library};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Component, Path, PathBuf};
+use tracing::debug;
 
 /// Find external shared library dependencies
+///
+/// Libraries excluded by `overrides` are skipped together with the libraries only they depend on.
 #[allow(clippy::result_large_err)]
 pub fn find_external_libs(
     artifact: impl AsRef<Path>,
     policy: &Policy,
+    overrides: &PolicyOverrides,
     sysroot: PathBuf,
     ld_paths: Vec<PathBuf>,
 ) -> Result<Vec<lddtree::Library>, AuditWheelError> {
@@ -17,8 +28,17 @@ pub fn find_external_libs(
         .analyze(artifact)
         .map_err(AuditWheelError::DependencyAnalysisError)?;
     let mut ext_libs = Vec::new();
-    for (_, lib) in deps.libraries {
-        let name = &lib.name;
+    let mut visited = HashSet::new();
+    let mut queue: VecDeque<&String> = deps.needed.iter().collect();
+    while let Some(name) = queue.pop_front() {
+        if !visited.insert(name) || overrides.is_excluded(name) {
+            continue;
+        }
+        let lib = match deps.libraries.get(name) {
+            Some(lib) => lib,
+            None => continue,
+        };
+        queue.extend(&lib.needed);
         // Skip dynamic linker/loader and white-listed libs
         if name.starts_with("ld-linux")
             || name == "ld64.so.2"
@@ -30,7 +50,268 @@ pub fn find_external_libs(
         {
             continue;
         }
-        ext_libs.push(lib);
+        ext_libs.push(lib.clone());
     }
     Ok(ext_libs)
 }
+
+/// Copies the external shared libraries into `libs_dir` of the wheel under a new soname
+/// containing a short hash, then patches `DT_NEEDED` and the rpath of the artifacts so that
+/// the grafted copies are loaded.
+///
+/// `artifacts` are pairs of the elf file on disk and the directory it is placed in inside
+/// the wheel, `ext_libs` contains the external libraries of each artifact.
+///
+/// Like auditwheel, rpath entries pointing outside of the wheel are removed from the
+/// artifacts and the grafted libraries unless `keep_rpath` is set.
+pub fn graft_external_libs(
+    writer: &mut impl ModuleWriter,
+    libs_dir: &Path,
+    artifacts: &[(&Path, &Path)],
+    ext_libs: &[Vec<Library>],
+    keep_rpath: bool,
+) -> Result<()> {
+    // Put external libs to ${module_name}.libs directory
+    // See https://github.com/pypa/auditwheel/issues/89
+    writer.add_directory(libs_dir)?;
+
+    let temp_dir = tempfile::tempdir()?;
+    let mut soname_map = HashMap::new();
+    let mut libs_copied = HashSet::new();
+    for lib in ext_libs.iter().flatten() {
+        let lib_path = lib.realpath.clone().with_context(|| {
+            format!(
+                "Cannot repair wheel, because required library {} could not be located.",
+                lib.path.display()
+            )
+        })?;
+        // Generate a new soname with a short hash
+        let short_hash = &hash_file(&lib_path)?[..8];
+        let (file_stem, file_ext) = lib.name.split_once('.').unwrap();
+        let new_soname = if !file_stem.ends_with(&format!("-{short_hash}")) {
+            format!("{file_stem}-{short_hash}.{file_ext}")
+        } else {
+            format!("{file_stem}.{file_ext}")
+        };
+
+        // Copy the original lib to a tmpdir and modify some of its properties
+        // for example soname and rpath
+        let dest_path = temp_dir.path().join(&new_soname);
+        fs::copy(&lib_path, &dest_path)?;
+        libs_copied.insert(lib_path);
+
+        // fs::copy copies permissions as well, and the original
+        // file may have been read-only
+        let mut perms = fs::metadata(&dest_path)?.permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        perms.set_readonly(false);
+        fs::set_permissions(&dest_path, perms)?;
+
+        patchelf::set_soname(&dest_path, &new_soname)?;
+        if !lib.rpath.is_empty() || !lib.runpath.is_empty() {
+            // All grafted libraries are in the same directory
+            let mut new_rpaths = if keep_rpath {
+                split_rpaths(patchelf::get_rpath(&dest_path)?)
+            } else {
+                Vec::new()
+            };
+            new_rpaths.push("$ORIGIN".to_string());
+            patchelf::set_rpath(&dest_path, &new_rpaths.join(":"))?;
+        }
+        soname_map.insert(
+            lib.name.clone(),
+            (new_soname.clone(), dest_path.clone(), lib.needed.clone()),
+        );
+    }
+
+    for ((artifact, _), artifact_ext_libs) in artifacts.iter().zip(ext_libs) {
+        let artifact_deps: HashSet<_> = artifact_ext_libs.iter().map(|lib| &lib.name).collect();
+        let replacements = soname_map
+            .iter()
+            .filter_map(|(k, v)| {
+                if artifact_deps.contains(k) {
+                    Some((k, v.0.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if !replacements.is_empty() {
+            patchelf::replace_needed(artifact, &replacements[..])?;
+        }
+    }
+
+    // we grafted in a bunch of libraries and modified their sonames, but
+    // they may have internal dependencies (DT_NEEDED) on one another, so
+    // we need to update those records so each now knows about the new
+    // name of the other.
+    for (new_soname, path, needed) in soname_map.values() {
+        let mut replacements = Vec::new();
+        for n in needed {
+            if soname_map.contains_key(n) {
+                replacements.push((n, soname_map[n].0.clone()));
+            }
+        }
+        if !replacements.is_empty() {
+            patchelf::replace_needed(path, &replacements[..])?;
+        }
+        writer.add_file_with_permissions(libs_dir.join(new_soname), path, 0o755)?;
+    }
+
+    eprintln!(
+        "🖨  Copied external shared libraries to package {} directory:",
+        libs_dir.display()
+    );
+    for lib_path in libs_copied {
+        eprintln!("    {}", lib_path.display());
+    }
+
+    for (artifact, artifact_dir) in artifacts {
+        let rpaths = split_rpaths(patchelf::get_rpath(artifact)?);
+        let mut new_rpaths = if keep_rpath {
+            rpaths
+        } else {
+            rpaths_within_wheel(artifact, artifact_dir, rpaths)
+        };
+        let new_rpath = Path::new("$ORIGIN").join(relpath(libs_dir, artifact_dir));
+        let new_rpath = new_rpath.to_str().unwrap().to_string();
+        if !new_rpaths.contains(&new_rpath) {
+            new_rpaths.push(new_rpath);
+        }
+        patchelf::set_rpath(artifact, &new_rpaths.join(":"))?;
+    }
+    // The grafted libraries are only read when the wheel is written
+    writer.keep_temp_dir(temp_dir);
+    Ok(())
+}
+
+/// Removes the rpath entries that don't point to a location within the wheel from the elf files
+/// among `artifacts`, so that paths of the build machine don't end up in the wheel when there
+/// are no external libraries to graft
+///
+/// `artifacts` are pairs of the file on disk and the directory it is placed in inside the wheel.
+pub fn remove_foreign_rpaths(artifacts: &[(&Path, &Path)]) -> Result<()> {
+    for (artifact, artifact_dir) in artifacts {
+        if !is_elf(artifact)? {
+            continue;
+        }
+        let rpaths = split_rpaths(patchelf::get_rpath(artifact)?);
+        let count = rpaths.len();
+        let new_rpaths = rpaths_within_wheel(artifact, artifact_dir, rpaths);
+        if new_rpaths.len() == count {
+            continue;
+        }
+        if new_rpaths.is_empty() {
+            patchelf::remove_rpath(artifact)?;
+        } else {
+            patchelf::set_rpath(artifact, &new_rpaths.join(":"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Cleans existing rpath entries that don't point to a location within the wheel
+///
+/// See https://github.com/pypa/auditwheel/blob/353c24250d66951d5ac7e60b97471a6da76c123f/src/auditwheel/repair.py#L160
+fn rpaths_within_wheel(artifact: &Path, artifact_dir: &Path, rpaths: Vec<String>) -> Vec<String> {
+    rpaths
+        .into_iter()
+        .filter(|rpath| {
+            let within_wheel = is_rpath_within_wheel(rpath, artifact_dir);
+            if !within_wheel {
+                debug!("Removing rpath {} from {}", rpath, artifact.display());
+            }
+            within_wheel
+        })
+        .collect()
+}
+
+/// Splits `DT_RPATH`/`DT_RUNPATH` values into their colon separated entries
+fn split_rpaths(rpaths: Vec<String>) -> Vec<String> {
+    rpaths
+        .iter()
+        .flat_map(|rpath| rpath.split(':'))
+        .filter(|rpath| !rpath.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// Whether an rpath entry of a file placed at `artifact_dir` inside the wheel is relative to
+/// `$ORIGIN` and doesn't leave the wheel
+pub(super) fn is_rpath_within_wheel(rpath: &str, artifact_dir: &Path) -> bool {
+    let relative = match rpath
+        .strip_prefix("$ORIGIN")
+        .or_else(|| rpath.strip_prefix("${ORIGIN}"))
+    {
+        Some(relative) => relative.trim_start_matches('/'),
+        None => return false,
+    };
+    let mut depth = 0usize;
+    for component in artifact_dir.join(relative).components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return false,
+            },
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_rpath_within_wheel, remove_foreign_rpaths, split_rpaths};
+    use crate::auditwheel::patchelf;
+    use std::path::Path;
+
+    #[test]
+    fn test_is_rpath_within_wheel() {
+        let artifact_dir = Path::new("foo/bar");
+        assert!(is_rpath_within_wheel("$ORIGIN", artifact_dir));
+        assert!(is_rpath_within_wheel(
+            "$ORIGIN/../../foo.libs",
+            artifact_dir
+        ));
+        assert!(is_rpath_within_wheel("${ORIGIN}/lib", artifact_dir));
+        assert!(!is_rpath_within_wheel("$ORIGIN/../../..", artifact_dir));
+        assert!(!is_rpath_within_wheel("/opt/build/lib", artifact_dir));
+        assert!(!is_rpath_within_wheel("lib", artifact_dir));
+    }
+
+    #[test]
+    fn test_split_rpaths() {
+        assert_eq!(
+            split_rpaths(vec!["/opt/lib:$ORIGIN".to_string(), "/usr/lib".to_string()]),
+            ["/opt/lib", "$ORIGIN", "/usr/lib"]
+        );
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_remove_foreign_rpaths() {
+        // The test binary itself is a dynamically linked elf file without external libraries
+        let temp_dir = tempfile::tempdir().unwrap();
+        let artifact = temp_dir.path().join("foo.so");
+        fs_err::copy(std::env::current_exe().unwrap(), &artifact).unwrap();
+        let artifact_dir = Path::new("foo");
+
+        patchelf::set_rpath(&artifact, &"/opt/build/lib:$ORIGIN/../foo.libs").unwrap();
+        remove_foreign_rpaths(&[(&artifact, artifact_dir)]).unwrap();
+        assert_eq!(
+            patchelf::get_rpath(&artifact).unwrap(),
+            ["$ORIGIN/../foo.libs"]
+        );
+
+        patchelf::set_rpath(&artifact, &"/opt/build/lib").unwrap();
+        remove_foreign_rpaths(&[(&artifact, artifact_dir)]).unwrap();
+        assert!(patchelf::get_rpath(&artifact).unwrap().is_empty());
+
+        // Files that aren't elf files are skipped
+        let data_file = temp_dir.path().join("data.txt");
+        fs_err::write(&data_file, "not an elf file").unwrap();
+        remove_foreign_rpaths(&[(&data_file, artifact_dir)]).unwrap();
+    }
+}
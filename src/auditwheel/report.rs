@@ -0,0 +1,602 @@
+use super::wheel::{ElfAudit, WheelAudit};
+use super::Policy;
+use anyhow::{Context, Result};
+use fs_err as fs;
+use goblin::elf::header::EM_X86_64;
+use goblin::elf::reloc::{R_X86_64_GLOB_DAT, R_X86_64_JUMP_SLOT};
+use goblin::elf::section_header::{SHF_EXECINSTR, SHN_UNDEF, SHT_NOBITS};
+use goblin::elf::sym::STT_FUNC;
+use goblin::elf::Elf;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// The output format of the compliance report
+#[derive(Debug, Clone, Copy, Eq, PartialEq, clap::ValueEnum)]
+#[clap(rename_all = "lower")]
+pub enum ReportFormat {
+    /// Human readable text
+    Text,
+    /// JSON for machine consumption
+    Json,
+}
+
+/// A detailed, `auditwheel show`-like compliance report of a wheel
+#[derive(Debug, Clone, Serialize)]
+pub struct WheelReport {
+    /// The filename of the wheel
+    pub wheel: String,
+    /// The most restrictive policy of all elf files
+    pub policy: String,
+    /// Whether there are external libraries that need to be grafted
+    pub needs_repair: bool,
+    /// The policy the wheel has been audited against, if any
+    pub requested: Option<String>,
+    /// The reports of every elf file in the wheel
+    pub elf_files: Vec<ElfReport>,
+}
+
+/// The compliance report of a single elf file
+#[derive(Debug, Clone, Serialize)]
+pub struct ElfReport {
+    /// The path of the elf file relative to the wheel root
+    pub path: PathBuf,
+    /// The highest policy the file is compliant with, or the requested one
+    pub policy: String,
+    /// Why the file doesn't comply with the requested policy
+    pub error: Option<String>,
+    /// The versioned symbols which the requested policy doesn't allow, e.g. `exp@GLIBC_2.29`
+    pub disqualifying_symbols: Vec<String>,
+    /// The `DT_NEEDED` entries of the file
+    pub needed: Vec<NeededLibrary>,
+    /// All external libraries that would be grafted, including transitive dependencies
+    pub grafted: Vec<GraftedLibrary>,
+    /// The undefined symbols which require a specific library version
+    pub versioned_symbols: Vec<VersionedSymbol>,
+}
+
+/// A `DT_NEEDED` entry of an elf file
+#[derive(Debug, Clone, Serialize)]
+pub struct NeededLibrary {
+    /// The soname of the library
+    pub name: String,
+    /// Whether the policy of the file allows linking the library
+    pub whitelisted: bool,
+    /// Whether the library would be grafted into the wheel
+    pub grafted: bool,
+}
+
+/// An external library that would be copied into the wheel
+#[derive(Debug, Clone, Serialize)]
+pub struct GraftedLibrary {
+    /// The soname of the library
+    pub name: String,
+    /// Where the library has been found on this system
+    pub path: Option<PathBuf>,
+}
+
+/// An undefined symbol with a version requirement, e.g. `memcpy@GLIBC_2.14`
+#[derive(Debug, Clone, Serialize)]
+pub struct VersionedSymbol {
+    /// The library providing the symbol
+    pub library: String,
+    /// The symbol name
+    pub symbol: String,
+    /// The required version, e.g. `GLIBC_2.14`
+    pub version: String,
+    /// The policies that don't allow this symbol version
+    pub disqualifies: Vec<String>,
+    /// The (demangled) functions calling the symbol, only available for x86_64
+    pub callers: Vec<String>,
+}
+
+/// Creates the detailed compliance report for an audited wheel
+pub fn report_wheel(audit: &WheelAudit) -> Result<WheelReport> {
+    let arch = audit.target.target_arch();
+    let policies: Vec<Policy> = audit
+        .overrides
+        .policies(audit.target.is_musl_target())
+        .into_iter()
+        .filter(|policy| policy.name != "linux")
+        .map(|mut policy| {
+            policy.fixup_musl_libc_so_name(arch);
+            policy
+        })
+        .collect();
+    let requested = audit.requested.and_then(|tag| {
+        let tag = tag.to_string();
+        policies
+            .iter()
+            .find(|policy| policy.name == tag || policy.aliases.contains(&tag))
+            .map(|policy| policy.name.clone())
+    });
+    let mut elf_files = Vec::new();
+    for elf in &audit.elf_files {
+        let path = audit.wheel.root().join(&elf.path);
+        let report = report_elf(
+            elf,
+            &fs::read(&path)?,
+            &arch.to_string(),
+            &policies,
+            requested.as_deref(),
+        )
+        .with_context(|| format!("Failed to create a report for {}", elf.path.display()))?;
+        elf_files.push(report);
+    }
+    Ok(WheelReport {
+        wheel: audit.wheel.filename(),
+        policy: audit.policy().name,
+        needs_repair: audit.needs_repair(),
+        requested,
+        elf_files,
+    })
+}
+
+fn report_elf(
+    audit: &ElfAudit,
+    data: &[u8],
+    arch: &str,
+    policies: &[Policy],
+    requested: Option<&str>,
+) -> Result<ElfReport> {
+    let elf = Elf::parse(data)?;
+    let needed = elf
+        .libraries
+        .iter()
+        .map(|name| NeededLibrary {
+            name: name.to_string(),
+            whitelisted: audit.policy.lib_whitelist.contains(*name),
+            grafted: audit.external_libs.iter().any(|lib| lib.name == *name),
+        })
+        .collect();
+    let mut grafted: Vec<_> = audit
+        .external_libs
+        .iter()
+        .map(|lib| GraftedLibrary {
+            name: lib.name.clone(),
+            path: lib.realpath.clone(),
+        })
+        .collect();
+    grafted.sort_by(|a, b| a.name.cmp(&b.name));
+
+    // Maps the version indices of `.gnu.version` to the library and version name
+    let mut versions = HashMap::new();
+    if let Some(verneed) = &elf.verneed {
+        for need_file in verneed.iter() {
+            let library = elf.dynstrtab.get_at(need_file.vn_file).unwrap_or_default();
+            for need_ver in need_file.iter() {
+                if let Some(version) = elf.dynstrtab.get_at(need_ver.vna_name) {
+                    versions.insert(need_ver.vna_other, (library, version));
+                }
+            }
+        }
+    }
+
+    let callers = find_callers(&elf, data);
+    let mut versioned_symbols = Vec::new();
+    if let Some(versym) = &elf.versym {
+        for (index, sym) in elf.dynsyms.iter().enumerate() {
+            if sym.st_shndx != SHN_UNDEF as usize {
+                continue;
+            }
+            let symbol = match elf.dynstrtab.get_at(sym.st_name) {
+                Some(symbol) if !symbol.is_empty() => symbol,
+                _ => continue,
+            };
+            let (library, version) = match versym
+                .get_at(index)
+                .and_then(|versym| versions.get(&versym.version()))
+            {
+                Some(entry) => *entry,
+                None => continue,
+            };
+            let disqualifies = policies
+                .iter()
+                .filter(|policy| disqualifies(policy, arch, library, symbol, version))
+                .map(|policy| policy.name.clone())
+                .collect();
+            versioned_symbols.push(VersionedSymbol {
+                library: library.to_string(),
+                symbol: symbol.to_string(),
+                version: version.to_string(),
+                disqualifies,
+                callers: callers
+                    .get(symbol)
+                    .map(|callers| callers.iter().cloned().collect())
+                    .unwrap_or_default(),
+            });
+        }
+    }
+    versioned_symbols.sort_by(|a, b| {
+        (&a.library, &a.version, &a.symbol).cmp(&(&b.library, &b.version, &b.symbol))
+    });
+    let disqualifying_symbols = match requested {
+        Some(requested) if audit.error.is_some() => versioned_symbols
+            .iter()
+            .filter(|symbol| symbol.disqualifies.iter().any(|name| name == requested))
+            .map(|symbol| format!("{}@{}", symbol.symbol, symbol.version))
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    Ok(ElfReport {
+        path: audit.path.clone(),
+        policy: audit.policy.name.clone(),
+        error: audit.error.clone(),
+        disqualifying_symbols,
+        needed,
+        grafted,
+        versioned_symbols,
+    })
+}
+
+/// Whether `policy` forbids using `symbol` in `version` from `library`
+///
+/// Libraries which aren't whitelisted don't disqualify a policy since they can be grafted.
+fn disqualifies(policy: &Policy, arch: &str, library: &str, symbol: &str, version: &str) -> bool {
+    if !policy.lib_whitelist.contains(library) {
+        return false;
+    }
+    let arch_versions = match policy.symbol_versions.get(arch) {
+        Some(arch_versions) => arch_versions,
+        // The policy doesn't support this architecture at all
+        None => return false,
+    };
+    if policy
+        .blacklist
+        .get(library)
+        .map_or(false, |blacklist| blacklist.contains(symbol))
+    {
+        return true;
+    }
+    match version.split_once('_') {
+        Some((name, version)) => arch_versions
+            .get(name)
+            .map_or(true, |allowed| !allowed.contains(version)),
+        None => false,
+    }
+}
+
+/// Finds the functions calling imported symbols by scanning the code for calls through the
+/// GOT or the PLT. There is no full disassembler, so this only works for x86_64, where such
+/// calls are `call/jmp *disp32(%rip)` or a `call rel32` to a PLT stub.
+fn find_callers(elf: &Elf, data: &[u8]) -> HashMap<String, BTreeSet<String>> {
+    let mut callers: HashMap<String, BTreeSet<String>> = HashMap::new();
+    if elf.header.e_machine != EM_X86_64 {
+        return callers;
+    }
+
+    // The GOT slots of imported symbols
+    let mut slots = HashMap::new();
+    for reloc in elf.dynrelas.iter().chain(elf.pltrelocs.iter()) {
+        if reloc.r_sym == 0 || !matches!(reloc.r_type, R_X86_64_GLOB_DAT | R_X86_64_JUMP_SLOT) {
+            continue;
+        }
+        if let Some(name) = elf
+            .dynsyms
+            .get(reloc.r_sym)
+            .and_then(|sym| elf.dynstrtab.get_at(sym.st_name))
+        {
+            slots.insert(reloc.r_offset, name);
+        }
+    }
+    if slots.is_empty() {
+        return callers;
+    }
+
+    // Function address ranges to map call sites to their callers
+    let mut functions: Vec<(u64, u64, &str)> = elf
+        .syms
+        .iter()
+        .filter_map(|sym| {
+            let name = elf.strtab.get_at(sym.st_name)?;
+            Some((sym, name))
+        })
+        .chain(elf.dynsyms.iter().filter_map(|sym| {
+            let name = elf.dynstrtab.get_at(sym.st_name)?;
+            Some((sym, name))
+        }))
+        .filter(|(sym, name)| {
+            sym.st_type() == STT_FUNC && sym.st_value != 0 && sym.st_size != 0 && !name.is_empty()
+        })
+        .map(|(sym, name)| (sym.st_value, sym.st_value + sym.st_size, name))
+        .collect();
+    functions.sort_unstable();
+    functions.dedup_by_key(|(start, _, _)| *start);
+
+    let mut plt_sections = Vec::new();
+    let mut code_sections = Vec::new();
+    for section in &elf.section_headers {
+        if section.sh_flags & u64::from(SHF_EXECINSTR) == 0 || section.sh_type == SHT_NOBITS {
+            continue;
+        }
+        let bytes = match data.get(section.file_range().unwrap_or_default()) {
+            Some(bytes) => bytes,
+            None => continue,
+        };
+        let name = elf.shdr_strtab.get_at(section.sh_name).unwrap_or_default();
+        if name.starts_with(".plt") {
+            plt_sections.push((section.sh_addr, bytes));
+        } else {
+            code_sections.push((section.sh_addr, bytes));
+        }
+    }
+
+    // Resolves a PLT stub to its GOT slot, skipping `endbr64` and the `bnd` prefix
+    let plt_slot = |addr: u64| -> Option<u64> {
+        let (start, bytes) = plt_sections
+            .iter()
+            .find(|(start, bytes)| addr >= *start && addr < start + bytes.len() as u64)?;
+        let mut offset = (addr - start) as usize;
+        if bytes.get(offset..offset + 4) == Some(&[0xf3, 0x0f, 0x1e, 0xfa]) {
+            offset += 4;
+        }
+        if bytes.get(offset) == Some(&0xf2) {
+            offset += 1;
+        }
+        if bytes.get(offset..offset + 2) != Some(&[0xff, 0x25]) {
+            return None;
+        }
+        let disp = read_i32(bytes, offset + 2)?;
+        Some(rip_relative(start + offset as u64 + 6, disp))
+    };
+
+    for (start, bytes) in &code_sections {
+        for offset in 0..bytes.len() {
+            let site = start + offset as u64;
+            let slot = match bytes[offset..] {
+                // call/jmp *disp32(%rip)
+                [0xff, 0x15 | 0x25, ..] => {
+                    read_i32(bytes, offset + 2).map(|disp| rip_relative(site + 6, disp))
+                }
+                // call rel32
+                [0xe8, ..] => read_i32(bytes, offset + 1)
+                    .and_then(|rel| plt_slot(rip_relative(site + 5, rel))),
+                _ => None,
+            };
+            let symbol = match slot.and_then(|slot| slots.get(&slot)) {
+                Some(symbol) => symbol,
+                None => continue,
+            };
+            let index = functions.partition_point(|(start, _, _)| *start <= site);
+            if let Some((_, end, name)) = index.checked_sub(1).map(|index| functions[index]) {
+                if site < end {
+                    callers
+                        .entry(symbol.to_string())
+                        .or_default()
+                        .insert(demangle(name));
+                }
+            }
+        }
+    }
+    callers
+}
+
+fn read_i32(bytes: &[u8], offset: usize) -> Option<i32> {
+    let bytes = bytes.get(offset..offset + 4)?;
+    Some(i32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+fn rip_relative(next_instruction: u64, disp: i32) -> u64 {
+    next_instruction.wrapping_add(disp as i64 as u64)
+}
+
+/// Demangles legacy rust symbol names, e.g. `_ZN3foo3bar17h0123456789abcdefE` becomes
+/// `foo::bar`. Other names, including v0 mangled rust symbols, are returned unchanged.
+pub fn demangle(symbol: &str) -> String {
+    demangle_legacy(symbol).unwrap_or_else(|| symbol.to_string())
+}
+
+fn demangle_legacy(symbol: &str) -> Option<String> {
+    let mut rest = symbol
+        .strip_prefix("_ZN")
+        .or_else(|| symbol.strip_prefix("__ZN"))?;
+    let mut components = Vec::new();
+    loop {
+        if let Some(suffix) = rest.strip_prefix('E') {
+            // LLVM may add suffixes like `.llvm.123456`
+            if suffix.is_empty() || suffix.starts_with('.') {
+                break;
+            }
+            return None;
+        }
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        let len: usize = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        if len == 0 {
+            return None;
+        }
+        components.push(rest.get(..len)?);
+        rest = &rest[len..];
+    }
+    // Drop the hash
+    if let Some(hash) = components.last() {
+        if hash.len() == 17
+            && hash.starts_with('h')
+            && hash[1..].bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            components.pop();
+        }
+    }
+    if components.is_empty() {
+        return None;
+    }
+    Some(
+        components
+            .into_iter()
+            .map(unescape)
+            .collect::<Vec<_>>()
+            .join("::"),
+    )
+}
+
+/// Decodes the escapes of legacy rust symbol names like `$LT$` or `$u20$`
+fn unescape(component: &str) -> String {
+    // Components starting with `$` get an extra underscore
+    let mut rest = if component.starts_with("_$") {
+        &component[1..]
+    } else {
+        component
+    };
+    let mut unescaped = String::new();
+    while let Some(c) = rest.chars().next() {
+        if let Some(tail) = rest.strip_prefix("..") {
+            unescaped.push_str("::");
+            rest = tail;
+            continue;
+        }
+        if c == '$' {
+            if let Some(end) = rest[1..].find('$') {
+                let escape = &rest[1..end + 1];
+                let decoded = match escape {
+                    "SP" => Some('@'),
+                    "BP" => Some('*'),
+                    "RF" => Some('&'),
+                    "LT" => Some('<'),
+                    "GT" => Some('>'),
+                    "LP" => Some('('),
+                    "RP" => Some(')'),
+                    "C" => Some(','),
+                    _ => escape
+                        .strip_prefix('u')
+                        .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+                        .and_then(char::from_u32),
+                };
+                if let Some(decoded) = decoded {
+                    unescaped.push(decoded);
+                    rest = &rest[end + 2..];
+                    continue;
+                }
+            }
+        }
+        unescaped.push(c);
+        rest = &rest[c.len_utf8()..];
+    }
+    unescaped
+}
+
+impl Display for WheelReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}", self.wheel, self.policy)?;
+        for elf in &self.elf_files {
+            writeln!(f)?;
+            write!(f, "{elf}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Display for ElfReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}: {}", self.path.display(), self.policy)?;
+        if let Some(error) = &self.error {
+            writeln!(f, "    Not compliant: {error}")?;
+            if !self.disqualifying_symbols.is_empty() {
+                writeln!(
+                    f,
+                    "    Disqualifying symbols: {}",
+                    self.disqualifying_symbols.join(", ")
+                )?;
+            }
+        }
+        if !self.needed.is_empty() {
+            writeln!(f, "    Needed libraries:")?;
+            for lib in &self.needed {
+                let status = if lib.grafted {
+                    " (grafted)"
+                } else if lib.whitelisted {
+                    " (whitelisted)"
+                } else {
+                    ""
+                };
+                writeln!(f, "        {}{}", lib.name, status)?;
+            }
+        }
+        if !self.grafted.is_empty() {
+            writeln!(f, "    Grafted libraries:")?;
+            for lib in &self.grafted {
+                match &lib.path {
+                    Some(path) => writeln!(f, "        {} => {}", lib.name, path.display())?,
+                    None => writeln!(f, "        {} => not found", lib.name)?,
+                }
+            }
+        }
+        if !self.versioned_symbols.is_empty() {
+            writeln!(f, "    Versioned symbols:")?;
+            for symbol in &self.versioned_symbols {
+                writeln!(
+                    f,
+                    "        {}@{} ({})",
+                    symbol.symbol, symbol.version, symbol.library
+                )?;
+                if !symbol.disqualifies.is_empty() {
+                    writeln!(
+                        f,
+                        "            disqualifies: {}",
+                        symbol.disqualifies.join(", ")
+                    )?;
+                }
+                for caller in &symbol.callers {
+                    writeln!(f, "            called from: {caller}")?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{demangle, disqualifies};
+    use crate::auditwheel::Policy;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_demangle() {
+        assert_eq!(demangle("_ZN3foo3bar17h0123456789abcdefE"), "foo::bar");
+        assert_eq!(
+            demangle(
+                "_ZN58_$LT$alloc..string..String$u20$as$u20$core..fmt..Debug$GT$3fmt17h0123456789abcdefE"
+            ),
+            "<alloc::string::String as core::fmt::Debug>::fmt"
+        );
+        assert_eq!(
+            demangle("_ZN3std2rt10lang_start17h0123456789abcdefE.llvm.42"),
+            "std::rt::lang_start"
+        );
+        // Not legacy rust mangling
+        assert_eq!(demangle("memcpy"), "memcpy");
+        assert_eq!(
+            demangle("_ZNSt6vectorIiSaIiEE9push_backERKi"),
+            "_ZNSt6vectorIiSaIiEE9push_backERKi"
+        );
+        assert_eq!(demangle("_RNvCs1234_3foo3bar"), "_RNvCs1234_3foo3bar");
+    }
+
+    #[test]
+    fn test_disqualifies() {
+        let manylinux2014 = Policy::from_name("manylinux_2_17").unwrap();
+        let manylinux_2_31 = Policy::from_name("manylinux_2_31").unwrap();
+        let cases = [
+            ("libc.so.6", "memcpy", "GLIBC_2.14", false, false),
+            ("libm.so.6", "exp", "GLIBC_2.29", true, false),
+            ("libc.so.6", "getrandom", "GLIBC_2.25", true, false),
+            // Libraries that will be grafted don't disqualify any policy
+            ("libssl.so.3", "SSL_new", "OPENSSL_3.0.0", false, false),
+        ];
+        for (library, symbol, version, disqualifies_2014, disqualifies_2_31) in cases {
+            assert_eq!(
+                disqualifies(&manylinux2014, "x86_64", library, symbol, version),
+                disqualifies_2014,
+                "{symbol}@{version}"
+            );
+            assert_eq!(
+                disqualifies(&manylinux_2_31, "x86_64", library, symbol, version),
+                disqualifies_2_31,
+                "{symbol}@{version}"
+            );
+        }
+    }
+}
@@ -0,0 +1,636 @@
+use super::audit::get_policy_and_libs;
+use super::{graft_external_libs, PlatformTag, Policy, PolicyOverrides};
+use crate::compile::BuildArtifact;
+use crate::module_writer::PathWriter;
+use crate::unpacked_wheel::UnpackedWheel;
+use crate::Target;
+use anyhow::{anyhow, bail, Context, Result};
+use fs_err as fs;
+use fs_err::File;
+use goblin::Object;
+use lddtree::Library;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+static LINUX_PLATFORM_TAG: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(many|musl)?linux(?:1|2010|2014|_\d+_\d+)?_([a-z0-9_]+)$").unwrap());
+
+/// The audit result of a single elf file in a wheel
+#[derive(Debug, Clone)]
+pub struct ElfAudit {
+    /// The path of the elf file relative to the wheel root
+    pub path: PathBuf,
+    /// The highest policy the file is compliant with, or the requested one
+    pub policy: Policy,
+    /// The external libraries that need to be grafted into the wheel
+    pub external_libs: Vec<Library>,
+    /// Why the file doesn't comply with the requested policy. `policy` and `external_libs`
+    /// are then those of the highest policy the file complies with
+    pub error: Option<String>,
+}
+
+/// The audit result of a wheel which has been built before
+#[derive(Debug)]
+pub struct WheelAudit {
+    /// The extracted wheel
+    pub wheel: UnpackedWheel,
+    /// The target the wheel was built for, derived from its platform tag
+    pub target: Target,
+    /// The architecture part of the wheel's platform tag
+    pub arch: String,
+    /// The audit results of every elf file in the wheel
+    pub elf_files: Vec<ElfAudit>,
+    /// The policy overrides the wheel has been audited with
+    pub overrides: PolicyOverrides,
+    /// The tag the wheel has been audited against, if any
+    pub requested: Option<PlatformTag>,
+}
+
+impl WheelAudit {
+    /// The most restrictive policy of all elf files, which determines the platform tag of
+    /// the wheel
+    pub fn policy(&self) -> Policy {
+        self.elf_files
+            .iter()
+            .map(|elf| &elf.policy)
+            .min_by_key(|policy| policy.priority)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Fails if any elf file doesn't comply with the requested policy
+    pub fn ensure_compliant(&self) -> Result<()> {
+        for elf in &self.elf_files {
+            if let Some(error) = &elf.error {
+                bail!("Failed to audit {}: {error}", elf.path.display());
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any elf file links external libraries that need to be grafted
+    pub fn needs_repair(&self) -> bool {
+        self.elf_files
+            .iter()
+            .any(|elf| !elf.external_libs.is_empty())
+    }
+
+    /// The `<module>.libs` directory the external libraries are grafted into
+    fn libs_dir(&self) -> PathBuf {
+        let module = self
+            .elf_files
+            .iter()
+            .map(|elf| &elf.path)
+            .find(|path| {
+                !path.components().any(|component| {
+                    let component = component.as_os_str().to_string_lossy();
+                    component.ends_with(".data") || component.ends_with(".libs")
+                })
+            })
+            .and_then(|path| {
+                let mut components = path.components();
+                match (components.next(), components.next()) {
+                    // A module inside a package, e.g. `foo/_foo.cpython-310-x86_64-linux-gnu.so`
+                    (Some(Component::Normal(package)), Some(_)) => {
+                        Some(package.to_string_lossy().to_string())
+                    }
+                    // A top level module, e.g. `foo.cpython-310-x86_64-linux-gnu.so`
+                    (Some(Component::Normal(module)), None) => module
+                        .to_string_lossy()
+                        .split('.')
+                        .next()
+                        .map(ToString::to_string),
+                    _ => None,
+                }
+            })
+            .unwrap_or_else(|| self.wheel.name.clone());
+        PathBuf::from(format!("{module}.libs"))
+    }
+}
+
+/// Picks the tag to audit against from the requested tags, in the same way as for
+/// building wheels
+fn requested_tag(platform_tag: &[PlatformTag], musllinux: bool) -> Option<PlatformTag> {
+    let mut tags: Vec<_> = platform_tag
+        .iter()
+        .filter(|tag| tag.is_musllinux() == musllinux || **tag == PlatformTag::Linux)
+        .copied()
+        .collect();
+    tags.sort();
+    tags.first().copied()
+}
+
+/// Guesses the rust target from the platform tag of a linux wheel
+fn target_from_platform_tag(platform_tag: &str) -> Result<(Target, String)> {
+    let first_tag = platform_tag.split('.').next().unwrap_or_default();
+    let captures = LINUX_PLATFORM_TAG.captures(first_tag).with_context(|| {
+        format!("Only linux wheels can be audited, but the platform tag is {platform_tag}")
+    })?;
+    let env = if captures.get(1).map(|m| m.as_str()) == Some("musl") {
+        "musl"
+    } else {
+        "gnu"
+    };
+    let arch = captures[2].to_string();
+    let triple = match arch.as_str() {
+        "x86_64" | "i686" | "aarch64" | "s390x" => format!("{arch}-unknown-linux-{env}"),
+        "armv7l" => format!("armv7-unknown-linux-{env}eabihf"),
+        "armv6l" => format!("arm-unknown-linux-{env}eabihf"),
+        "ppc64le" => format!("powerpc64le-unknown-linux-{env}"),
+        "ppc64" => format!("powerpc64-unknown-linux-{env}"),
+        _ => bail!("Unsupported architecture {arch} in platform tag {platform_tag}"),
+    };
+    Ok((Target::from_target_triple(Some(triple))?, arch))
+}
+
+pub(super) fn is_elf(path: &Path) -> Result<bool> {
+    let mut magic = [0; 4];
+    let mut file = File::open(path)?;
+    Ok(file.read_exact(&mut magic).is_ok() && magic == *b"\x7fELF")
+}
+
+/// Checks every elf file in the extracted wheel against `tag`, or for the highest matching
+/// policy if there is none. Files that don't comply with `tag` are audited for the highest
+/// matching policy and the reason is recorded, so that they can be reported.
+fn audit_elf_files(
+    wheel: &UnpackedWheel,
+    tag: Option<PlatformTag>,
+    target: &Target,
+    overrides: &PolicyOverrides,
+) -> Result<Vec<ElfAudit>> {
+    let mut elf_files = Vec::new();
+    for file in wheel.files()? {
+        let path = wheel.root().join(&file);
+        if !is_elf(&path)? {
+            continue;
+        }
+        // Only executables in the scripts directory may link libpython
+        let allow_linking_libpython = file
+            .parent()
+            .map_or(false, |parent| parent.ends_with("scripts"));
+        let artifact = BuildArtifact {
+            path,
+            linked_paths: Vec::new(),
+        };
+        let (policy, external_libs, error) =
+            match get_policy_and_libs(&artifact, tag, target, allow_linking_libpython, overrides) {
+                Ok((policy, external_libs)) => (policy, external_libs, None),
+                Err(err) if tag.is_some() => {
+                    let (policy, external_libs) = get_policy_and_libs(
+                        &artifact,
+                        None,
+                        target,
+                        allow_linking_libpython,
+                        overrides,
+                    )
+                    .with_context(|| format!("Failed to audit {}", file.display()))?;
+                    (policy, external_libs, Some(format!("{err:#}")))
+                }
+                Err(err) => return Err(err.context(format!("Failed to audit {}", file.display()))),
+            };
+        // Libraries that are already part of the wheel, e.g. from an earlier repair, are fine
+        let external_libs = external_libs
+            .into_iter()
+            .filter(|lib| {
+                !lib.realpath
+                    .as_ref()
+                    .map_or(false, |realpath| realpath.starts_with(wheel.root()))
+            })
+            .collect();
+        elf_files.push(ElfAudit {
+            path: file,
+            policy,
+            external_libs,
+            error,
+        });
+    }
+    Ok(elf_files)
+}
+
+/// Extracts the wheel and checks every elf file in it for manylinux/musllinux compliance
+///
+/// If `platform_tag` is empty, the highest matching policy is determined for each file,
+/// otherwise compliance with the requested tag is checked. Files that don't comply are
+/// recorded in [ElfAudit::error] instead of failing, use [WheelAudit::ensure_compliant] to
+/// fail on them.
+pub fn audit_wheel(
+    wheel_path: &Path,
+    platform_tag: &[PlatformTag],
+    overrides: &PolicyOverrides,
+) -> Result<WheelAudit> {
+    let wheel = UnpackedWheel::open(wheel_path)
+        .with_context(|| format!("Failed to open wheel {}", wheel_path.display()))?;
+    let (target, arch) = target_from_platform_tag(&wheel.platform_tag)?;
+    let tag = requested_tag(platform_tag, target.is_musl_target());
+
+    let elf_files = audit_elf_files(&wheel, tag, &target, overrides)?;
+
+    Ok(WheelAudit {
+        wheel,
+        target,
+        arch,
+        elf_files,
+        overrides: overrides.clone(),
+        requested: tag,
+    })
+}
+
+/// Grafts the external libraries into the audited wheel and writes it to `out_dir` with the
+/// platform tag of the most restrictive policy, or the requested tags
+///
+/// rpath entries pointing outside of the wheel are removed unless `keep_rpath` is set.
+pub fn repair_wheel(
+    mut audit: WheelAudit,
+    platform_tag: &[PlatformTag],
+    out_dir: &Path,
+    keep_rpath: bool,
+) -> Result<PathBuf> {
+    audit.ensure_compliant()?;
+    if audit.needs_repair() {
+        let libs_dir = audit.libs_dir();
+        let mut writer = PathWriter::from_path(audit.wheel.root());
+        let paths: Vec<_> = audit
+            .elf_files
+            .iter()
+            .map(|elf| {
+                let path = audit.wheel.root().join(&elf.path);
+                let dir = elf.path.parent().unwrap_or(Path::new("")).to_path_buf();
+                (path, dir)
+            })
+            .collect();
+        let artifacts: Vec<_> = paths
+            .iter()
+            .map(|(path, dir)| (path.as_path(), dir.as_path()))
+            .collect();
+        let ext_libs: Vec<_> = audit
+            .elf_files
+            .iter()
+            .map(|elf| elf.external_libs.clone())
+            .collect();
+        graft_external_libs(&mut writer, &libs_dir, &artifacts, &ext_libs, keep_rpath)?;
+    }
+
+    let mut platform_tags = if platform_tag.is_empty() {
+        vec![audit.policy().platform_tag()]
+    } else {
+        platform_tag.to_vec()
+    };
+    platform_tags.sort();
+    let mut tags = Vec::new();
+    for platform_tag in platform_tags {
+        tags.push(format!("{platform_tag}_{}", audit.arch));
+        for alias in platform_tag.aliases() {
+            tags.push(format!("{alias}_{}", audit.arch));
+        }
+    }
+    audit.wheel.platform_tag = tags.join(".");
+    audit.wheel.write(out_dir)
+}
+
+/// The kinds of native binaries in a wheel, which determine the platform tags it can have
+#[derive(Debug, Default)]
+struct NativeBinaries {
+    elf: Option<PathBuf>,
+    macho: Option<PathBuf>,
+    pe: Option<PathBuf>,
+}
+
+impl NativeBinaries {
+    fn find(wheel: &UnpackedWheel) -> Result<Self> {
+        let mut binaries = Self::default();
+        for file in wheel.files()? {
+            let data = fs::read(wheel.root().join(&file))?;
+            let slot = match Object::parse(&data) {
+                Ok(Object::Elf(_)) => &mut binaries.elf,
+                Ok(Object::Mach(_)) => &mut binaries.macho,
+                Ok(Object::PE(_)) => &mut binaries.pe,
+                _ => continue,
+            };
+            slot.get_or_insert(file);
+        }
+        Ok(binaries)
+    }
+
+    /// The first binary which can't be loaded on a platform accepting only `allowed`
+    fn other_than(&self, allowed: Option<&PathBuf>) -> Option<&PathBuf> {
+        [&self.elf, &self.macho, &self.pe]
+            .into_iter()
+            .flatten()
+            .find(|binary| Some(*binary) != allowed)
+    }
+}
+
+/// Merges the dotted tag set `current` with `requested`, which replaces it unless `add` is set
+fn merge_tags(kind: &str, current: &str, requested: &[String], add: bool) -> Result<String> {
+    let mut tags: Vec<String> = if add || requested.is_empty() {
+        current.split('.').map(ToString::to_string).collect()
+    } else {
+        Vec::new()
+    };
+    for tag in requested.iter().flat_map(|tag| tag.split('.')) {
+        if tag.is_empty()
+            || !tag
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        {
+            bail!("Invalid {kind} tag `{tag}`, it may only contain a-z, 0-9 and _");
+        }
+        if !tags.iter().any(|existing| existing == tag) {
+            tags.push(tag.to_string());
+        }
+    }
+    Ok(tags.join("."))
+}
+
+/// Checks that the binaries in the wheel can be loaded on `platform_tag` and, for
+/// `manylinux`/`musllinux` tags, that every elf file complies with the policy the same way
+/// `maturin build` checks it
+fn check_platform_tag(
+    wheel: &UnpackedWheel,
+    binaries: &NativeBinaries,
+    platform_tag: &str,
+    overrides: &PolicyOverrides,
+) -> Result<()> {
+    let (allowed, platform) = if platform_tag == "any" {
+        (None, "pure python")
+    } else if LINUX_PLATFORM_TAG.is_match(platform_tag) {
+        (binaries.elf.as_ref(), "linux")
+    } else if platform_tag.starts_with("macosx_") {
+        (binaries.macho.as_ref(), "macOS")
+    } else if platform_tag.starts_with("win") {
+        (binaries.pe.as_ref(), "windows")
+    } else {
+        // No other platform has a binary format we could check
+        return Ok(());
+    };
+    if let Some(binary) = binaries.other_than(allowed) {
+        bail!(
+            "{} can't be loaded on {platform} platforms, refusing to tag the wheel {platform_tag}",
+            binary.display()
+        );
+    }
+    if binaries.elf.is_none() || !LINUX_PLATFORM_TAG.is_match(platform_tag) {
+        return Ok(());
+    }
+
+    let (target, arch) = target_from_platform_tag(platform_tag)?;
+    let policy_name = &platform_tag[..platform_tag.len() - arch.len() - 1];
+    let tag = PlatformTag::from_str(policy_name)
+        .map_err(|err| anyhow!("Invalid platform tag {platform_tag}: {err}"))?;
+    if tag == PlatformTag::Linux {
+        return Ok(());
+    }
+    for elf in audit_elf_files(wheel, Some(tag), &target, overrides)? {
+        if let Some(error) = &elf.error {
+            bail!("Failed to audit {}: {error}", elf.path.display());
+        }
+        if let Some(lib) = elf.external_libs.first() {
+            bail!(
+                "{} links the external library {}, which isn't allowed by {platform_tag}. \
+                 Run `maturin repair` to graft it into the wheel instead",
+                elf.path.display(),
+                lib.name
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Changes the python, abi and platform tags of an existing wheel without rebuilding it and
+/// writes it to `out_dir`
+///
+/// Empty tag lists keep the current tags, otherwise they are replaced, or extended if `add`
+/// is set, e.g. to add a `py3` alias. Platform tags the binaries in the wheel can't comply
+/// with are refused, using the same manylinux/musllinux audit as `maturin build`.
+pub fn retag_wheel(
+    wheel_path: &Path,
+    python_tag: &[String],
+    abi_tag: &[String],
+    platform_tag: &[String],
+    add: bool,
+    overrides: &PolicyOverrides,
+    out_dir: &Path,
+) -> Result<PathBuf> {
+    let mut wheel = UnpackedWheel::open(wheel_path)
+        .with_context(|| format!("Failed to open wheel {}", wheel_path.display()))?;
+    wheel.python_tag = merge_tags("python", &wheel.python_tag, python_tag, add)?;
+    wheel.abi_tag = merge_tags("abi", &wheel.abi_tag, abi_tag, add)?;
+    wheel.platform_tag = merge_tags("platform", &wheel.platform_tag, platform_tag, add)?;
+
+    let binaries = NativeBinaries::find(&wheel)?;
+    for tag in wheel.platform_tag.split('.') {
+        check_platform_tag(&wheel, &binaries, tag, overrides)?;
+    }
+    wheel.write(out_dir)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{audit_wheel, repair_wheel, requested_tag, retag_wheel, LINUX_PLATFORM_TAG};
+    use crate::auditwheel::report_wheel;
+    use crate::module_writer::{ModuleWriter, WheelWriter};
+    use crate::{Metadata21, PlatformTag, PolicyOverrides};
+    use fs_err as fs;
+    use pep440_rs::Version;
+    use pretty_assertions::assert_eq;
+    use std::path::{Path, PathBuf};
+
+    fn write_wheel(dir: &Path, platform: &str, extension: &str) -> PathBuf {
+        write_wheel_with(
+            dir,
+            platform,
+            &Path::new("tests/fixtures/abi3").join(extension),
+        )
+    }
+
+    fn write_wheel_with(dir: &Path, platform: &str, extension: &Path) -> PathBuf {
+        let metadata = Metadata21::new("foo".to_string(), Version::from_release(vec![1, 0]));
+        let tag = format!("cp311-cp311-{platform}");
+        fs::create_dir_all(dir).unwrap();
+        let mut writer =
+            WheelWriter::new(&tag, dir, &metadata, std::slice::from_ref(&tag), None).unwrap();
+        writer
+            .add_file_with_permissions("foo/ext.so", extension, 0o755)
+            .unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_audit_non_compliant_wheel() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let overrides = PolicyOverrides::default();
+        let wheel = write_wheel_with(
+            tmp_dir.path(),
+            "linux_x86_64",
+            Path::new("tests/fixtures/elf/x86_64/exp.so"),
+        );
+
+        // exp@GLIBC_2.29 is allowed by manylinux_2_31
+        let audit = audit_wheel(&wheel, &[], &overrides).unwrap();
+        assert_eq!(audit.policy().name, "manylinux_2_31");
+        audit.ensure_compliant().unwrap();
+
+        // A non-compliant file doesn't fail the audit, so that it can be reported
+        let requested = [PlatformTag::manylinux2014()];
+        let audit = audit_wheel(&wheel, &requested, &overrides).unwrap();
+        assert_eq!(audit.policy().name, "manylinux_2_31");
+        assert!(audit.elf_files[0].error.is_some());
+        let err = audit.ensure_compliant().unwrap_err();
+        assert!(err.to_string().starts_with("Failed to audit foo/ext.so"));
+
+        let report = report_wheel(&audit).unwrap();
+        assert_eq!(report.requested.as_deref(), Some("manylinux_2_17"));
+        let elf = &report.elf_files[0];
+        assert!(elf.error.is_some());
+        assert_eq!(elf.disqualifying_symbols, ["exp@GLIBC_2.29"]);
+        assert!(report
+            .to_string()
+            .contains("Disqualifying symbols: exp@GLIBC_2.29"));
+
+        // Repairing and retagging still require compliance
+        let out = tmp_dir.path().join("out");
+        assert!(repair_wheel(audit, &requested, &out, false).is_err());
+        assert!(retag_wheel(
+            &wheel,
+            &[],
+            &[],
+            &["manylinux2014_x86_64".to_string()],
+            false,
+            &overrides,
+            &out,
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_linux_platform_tag_arch() {
+        let cases = [
+            ("linux_x86_64", None, "x86_64"),
+            ("manylinux2014_aarch64", Some("many"), "aarch64"),
+            ("manylinux_2_17_ppc64le", Some("many"), "ppc64le"),
+            ("musllinux_1_2_armv7l", Some("musl"), "armv7l"),
+        ];
+        for (tag, kind, arch) in cases {
+            let captures = LINUX_PLATFORM_TAG.captures(tag).unwrap();
+            assert_eq!(captures.get(1).map(|m| m.as_str()), kind);
+            assert_eq!(&captures[2], arch);
+        }
+        assert!(LINUX_PLATFORM_TAG.captures("win_amd64").is_none());
+        assert!(LINUX_PLATFORM_TAG.captures("macosx_10_12_x86_64").is_none());
+    }
+
+    #[test]
+    fn test_requested_tag() {
+        let tags = [
+            PlatformTag::Manylinux { x: 2, y: 28 },
+            PlatformTag::Manylinux { x: 2, y: 17 },
+            PlatformTag::Musllinux { x: 1, y: 2 },
+        ];
+        assert_eq!(
+            requested_tag(&tags, false),
+            Some(PlatformTag::Manylinux { x: 2, y: 17 })
+        );
+        assert_eq!(
+            requested_tag(&tags, true),
+            Some(PlatformTag::Musllinux { x: 1, y: 2 })
+        );
+        assert_eq!(requested_tag(&[], false), None);
+    }
+
+    #[test]
+    fn test_retag_wheel() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let out = tmp_dir.path().join("out");
+        let overrides = PolicyOverrides::default();
+        let linux = write_wheel(tmp_dir.path(), "linux_x86_64", "elf.so");
+
+        let retagged = retag_wheel(
+            &linux,
+            &[],
+            &[],
+            &["manylinux_2_17_x86_64.manylinux2014_x86_64".to_string()],
+            false,
+            &overrides,
+            &out,
+        )
+        .unwrap();
+        assert_eq!(
+            retagged.file_name().unwrap(),
+            "foo-1.0-cp311-cp311-manylinux_2_17_x86_64.manylinux2014_x86_64.whl"
+        );
+        let inspection = crate::inspect(&retagged).unwrap();
+        assert!(inspection.record_errors.is_empty());
+        assert_eq!(
+            inspection.tags,
+            [
+                "cp311-cp311-manylinux_2_17_x86_64",
+                "cp311-cp311-manylinux2014_x86_64"
+            ]
+        );
+
+        let aliased = retag_wheel(
+            &retagged,
+            &["py3".to_string()],
+            &["none".to_string()],
+            &[],
+            true,
+            &overrides,
+            &out,
+        )
+        .unwrap();
+        assert_eq!(
+            aliased.file_name().unwrap(),
+            "foo-1.0-cp311.py3-cp311.none-manylinux_2_17_x86_64.manylinux2014_x86_64.whl"
+        );
+
+        let err = retag_wheel(
+            &linux,
+            &[],
+            &[],
+            &["any".to_string()],
+            false,
+            &overrides,
+            &out,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "foo/ext.so can't be loaded on pure python platforms, refusing to tag the wheel any"
+        );
+        let macho = write_wheel(
+            &tmp_dir.path().join("macho"),
+            "macosx_10_12_x86_64",
+            "macho.so",
+        );
+        let err = retag_wheel(
+            &macho,
+            &[],
+            &[],
+            &["manylinux_2_17_x86_64".to_string()],
+            false,
+            &overrides,
+            &out,
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("can't be loaded on linux platforms"));
+        let err = retag_wheel(
+            &linux,
+            &["CP311".to_string()],
+            &[],
+            &[],
+            false,
+            &overrides,
+            &out,
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid python tag `CP311`, it may only contain a-z, 0-9 and _"
+        );
+    }
+}
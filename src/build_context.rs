@@ -1,18 +1,25 @@
-use crate::auditwheel::{get_policy_and_libs, patchelf, relpath};
-use crate::auditwheel::{PlatformTag, Policy};
-use crate::build_options::CargoOptions;
+use crate::auditwheel::{
+    add_dll_directory_code, audit_abi3, find_external_dlls, find_external_dylibs,
+    get_policy_and_libs, graft_external_dlls, graft_external_dylibs, graft_external_libs, patchelf,
+    remove_foreign_rpaths,
+};
+use crate::auditwheel::{AuditWheelMode, PlatformTag, Policy, PolicyOverrides};
+use crate::build_options::{BuildOptions, CargoOptions};
 use crate::compile::{warn_missing_py_init, CompileTarget};
 use crate::module_writer::{
     add_data, write_bin, write_bindings_module, write_cffi_module, write_python_part,
-    write_uniffi_module, write_wasm_launcher, WheelWriter,
+    write_uniffi_module, write_wasm_launcher, ElfCollector, WheelWriter,
 };
 use crate::project_layout::ProjectLayout;
 use crate::python_interpreter::InterpreterKind;
+use crate::reproducible::archive_differences;
+use crate::sbom::{target_cfgs, write_sboms, LinkedCrates};
 use crate::source_distribution::source_distribution;
 use crate::target::{Arch, Os};
+use crate::third_party_licenses::write_third_party_licenses;
 use crate::{
-    compile, pyproject_toml::Format, BuildArtifact, Metadata21, ModuleWriter, PyProjectToml,
-    PythonInterpreter, Target,
+    compile, pyproject_toml::Format, BuildArtifact, Metadata21, PyProjectToml, PythonInterpreter,
+    SbomFormat, Target,
 };
 use anyhow::{anyhow, bail, Context, Result};
 use cargo_metadata::Metadata;
@@ -24,12 +31,13 @@ use normpath::PathExt;
 use pep508_rs::Requirement;
 use platform_info::*;
 use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::env;
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use tempfile::TempDir;
 
 /// The way the rust code is used in the wheel
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -171,8 +179,23 @@ pub struct BuildContext {
     pub release: bool,
     /// Strip the library for minimum file size
     pub strip: bool,
-    /// Skip checking the linked libraries for manylinux/musllinux compliance
-    pub skip_auditwheel: bool,
+    /// Whether to repair, check, only warn about or skip checking the linked libraries
+    pub auditwheel: AuditWheelMode,
+    /// Excluded libraries and additional policies from `[tool.maturin.auditwheel]`
+    pub policy_overrides: PolicyOverrides,
+    /// Keep rpath entries pointing outside of the wheel when grafting external libraries
+    pub keep_rpath: bool,
+    /// The formats of the SBOMs in `.dist-info/sboms`, empty if disabled
+    pub sbom: Vec<SbomFormat>,
+    /// Add the license texts of the linked Rust crates to `.dist-info/licenses/third-party`
+    pub third_party_licenses: bool,
+    /// Vendor the Rust dependencies into the source distribution
+    pub sdist_vendor: bool,
+    /// The build options given on the command line, which are embedded into the `[tool.maturin]`
+    /// of the source distribution. `None` with `--no-embed-build-options`
+    pub sdist_build_options: Option<BuildOptions>,
+    /// Whether `--strip` was requested explicitly, so it's embedded into the source distribution
+    pub sdist_strip: bool,
     /// When compiling for manylinux, use zig as linker to ensure glibc version compliance
     #[cfg(feature = "zig")]
     pub zig: bool,
@@ -190,6 +213,33 @@ pub struct BuildContext {
     pub cargo_options: CargoOptions,
 }
 
+/// Prebuilt elf files that [write_python_part] and [add_data] add to the wheel, e.g. shared
+/// libraries in the python source directory. They are audited and repaired like the cargo
+/// artifacts, using copies so that the original files are left untouched.
+#[derive(Default)]
+struct PrebuiltFiles {
+    /// Holds the copies with the same layout as in the wheel
+    _temp_dir: Option<TempDir>,
+    /// The path in the wheel and the copy of every file
+    files: Vec<(PathBuf, BuildArtifact)>,
+    /// The policy of every file
+    policies: Vec<Policy>,
+    /// The external libraries of every file
+    external_libs: Vec<Vec<Library>>,
+}
+
+impl PrebuiltFiles {
+    /// The most restrictive of `policy` and the policies of the prebuilt files
+    fn restrict(&self, policy: Policy) -> Policy {
+        self.policies
+            .iter()
+            .cloned()
+            .chain(Some(policy))
+            .min_by_key(|policy| policy.priority)
+            .unwrap()
+    }
+}
+
 /// The wheel file location and its Python version tag (e.g. `py3`).
 ///
 /// For bindings the version tag contains the Python interpreter version
@@ -277,15 +327,100 @@ impl BuildContext {
         }
     }
 
+    /// Builds the wheels, and the source distribution if `built` contains one, a second time
+    /// into a temporary directory and fails listing the differing archive entries unless the
+    /// second build is identical to `built`
+    ///
+    /// The second build uses a fresh cargo target directory, so it compiles everything again
+    /// instead of reusing the artifacts of the first build
+    pub fn check_reproducible(&self, built: &[BuiltWheelMetadata]) -> Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let mut context = self.clone();
+        context.out = temp_dir.path().join("wheels");
+        context.target_dir = temp_dir.path().join("target");
+        context.cargo_options.target_dir = Some(context.target_dir.clone());
+        eprintln!("🔁 Building again to check that the build is reproducible");
+        let mut rebuilt = Vec::new();
+        if built.iter().any(|(_, tag)| tag == "source") {
+            rebuilt.extend(context.build_source_distribution()?);
+        }
+        rebuilt.extend(context.build_wheels()?);
+
+        let mut differences = Vec::new();
+        for (path, _) in built {
+            let file_name = path.file_name().unwrap_or_default();
+            let second = match rebuilt
+                .iter()
+                .find(|(rebuilt, _)| rebuilt.file_name() == Some(file_name))
+            {
+                Some((second, _)) => second,
+                None => {
+                    differences.push(format!(
+                        "{}: missing from the second build",
+                        file_name.to_string_lossy()
+                    ));
+                    continue;
+                }
+            };
+            for difference in archive_differences(path, second)? {
+                differences.push(format!("{}: {difference}", file_name.to_string_lossy()));
+            }
+        }
+        if !differences.is_empty() {
+            bail!(
+                "The build is not reproducible, building again resulted in these differences:\n    {}",
+                differences.join("\n    ")
+            );
+        }
+        eprintln!("✔ The build is reproducible");
+        Ok(())
+    }
+
+    /// Returns the platform tags of the wheel given the policy all artifacts comply with
+    fn wheel_platform_tags(&self, policy: Policy) -> Vec<PlatformTag> {
+        wheel_platform_tags(self.auditwheel, &self.platform_tag, policy)
+    }
+
+    /// Audits the artifact according to the [AuditWheelMode], returning the policy it complies
+    /// with and the external libraries that need to be bundled into the wheel
     fn auditwheel(
         &self,
         artifact: &BuildArtifact,
         platform_tag: &[PlatformTag],
         python_interpreter: Option<&PythonInterpreter>,
     ) -> Result<(Policy, Vec<Library>)> {
-        if self.skip_auditwheel {
+        if self.auditwheel == AuditWheelMode::Skip {
             return Ok((Policy::default(), Vec::new()));
         }
+        let result = self.audit_artifact(artifact, platform_tag, python_interpreter);
+        apply_audit_mode(self.auditwheel, platform_tag, &artifact.path, result)
+    }
+
+    fn audit_artifact(
+        &self,
+        artifact: &BuildArtifact,
+        platform_tag: &[PlatformTag],
+        python_interpreter: Option<&PythonInterpreter>,
+    ) -> Result<(Policy, Vec<Library>)> {
+        if self.target.is_macos() {
+            let ld_paths: Vec<_> = artifact.linked_paths.iter().map(PathBuf::from).collect();
+            let external_libs =
+                find_external_dylibs(&artifact.path, &ld_paths, &self.policy_overrides)
+                    .context("Failed to find the dylibs linked by the artifact")?;
+            return Ok((Policy::default(), external_libs));
+        }
+
+        if self.target.is_windows() {
+            // Only python packages can add the bundled DLLs to the DLL search path
+            if self.bridge().is_bin() {
+                return Ok((Policy::default(), Vec::new()));
+            }
+            let ld_paths: Vec<_> = artifact.linked_paths.iter().map(PathBuf::from).collect();
+            let external_libs =
+                find_external_dlls(&artifact.path, &ld_paths, &self.policy_overrides)
+                    .context("Failed to find the DLLs imported by the artifact")?;
+            return Ok((Policy::default(), external_libs));
+        }
 
         if let Some(python_interpreter) = python_interpreter {
             if platform_tag.is_empty()
@@ -320,11 +455,113 @@ impl BuildContext {
                 Some(musllinux[0]),
                 &self.target,
                 allow_linking_libpython,
+                &self.policy_overrides,
             );
         }
 
         let tag = others.get(0).or_else(|| musllinux.get(0)).copied();
-        get_policy_and_libs(artifact, tag, &self.target, allow_linking_libpython)
+        get_policy_and_libs(
+            artifact,
+            tag,
+            &self.target,
+            allow_linking_libpython,
+            &self.policy_overrides,
+        )
+    }
+
+    /// Checks that an abi3 extension module only uses the stable ABI of its minimum python
+    /// version, failing unless the audit mode is warn or skip
+    fn audit_abi3(&self, artifact: &BuildArtifact, major: u8, min_minor: u8) -> Result<()> {
+        if major != 3 || self.auditwheel == AuditWheelMode::Skip {
+            return Ok(());
+        }
+        let violations = audit_abi3(&artifact.path, min_minor)?;
+        if violations.is_empty() {
+            return Ok(());
+        }
+        let symbols: Vec<_> = violations.iter().map(ToString::to_string).collect();
+        let message = format!(
+            "The abi3 wheel for Python ≥ {major}.{min_minor} uses symbols that are not part of the \
+            stable ABI of Python {major}.{min_minor}: {}",
+            symbols.join(", ")
+        );
+        if self.auditwheel == AuditWheelMode::Warn {
+            eprintln!("⚠️  Warning: {message}");
+            Ok(())
+        } else {
+            bail!("{message}. Raise the minimum python version with the abi3-py3x feature of pyo3 or use `--auditwheel warn` to build the wheel anyway")
+        }
+    }
+
+    /// Audits the prebuilt elf files that [write_python_part] and [add_data] add to the wheel
+    fn audit_prebuilt_files(
+        &self,
+        python_interpreter: Option<&PythonInterpreter>,
+    ) -> Result<PrebuiltFiles> {
+        if self.editable || self.auditwheel == AuditWheelMode::Skip || !self.target.is_linux() {
+            return Ok(PrebuiltFiles::default());
+        }
+        let mut collector = ElfCollector::new(self.excludes(Format::Wheel)?);
+        write_python_part(
+            &mut collector,
+            &self.project_layout,
+            self.pyproject_toml.as_ref(),
+        )?;
+        add_data(&mut collector, self.project_layout.data.as_deref())?;
+        if collector.files.is_empty() {
+            return Ok(PrebuiltFiles::default());
+        }
+
+        // auditwheel repair edits the files, so we need to copy them. Keeping the layout of
+        // the wheel allows resolving dependencies between the prebuilt files through $ORIGIN
+        let temp_dir = tempfile::tempdir()?;
+        let mut files = Vec::new();
+        for (target, source) in collector.files {
+            let path = temp_dir.path().join(&target);
+            fs::create_dir_all(path.parent().unwrap())?;
+            fs::copy(&source, &path)?;
+            let mut perms = fs::metadata(&path)?.permissions();
+            #[allow(clippy::permissions_set_readonly_false)]
+            perms.set_readonly(false);
+            fs::set_permissions(&path, perms)?;
+            files.push((
+                target,
+                BuildArtifact {
+                    path,
+                    linked_paths: Vec::new(),
+                },
+            ));
+        }
+
+        let mut policies = Vec::new();
+        let mut external_libs = Vec::new();
+        for (target, artifact) in &files {
+            let result = self
+                .audit_artifact(artifact, &self.platform_tag, python_interpreter)
+                .map(|(policy, libs)| {
+                    // Other prebuilt files are part of the wheel already
+                    let libs = libs
+                        .into_iter()
+                        .filter(|lib| {
+                            !lib.realpath
+                                .as_ref()
+                                .map_or(false, |realpath| realpath.starts_with(temp_dir.path()))
+                        })
+                        .collect();
+                    (policy, libs)
+                });
+            let (policy, libs) =
+                apply_audit_mode(self.auditwheel, &self.platform_tag, target, result)
+                    .with_context(|| format!("Failed to audit {}", target.display()))?;
+            policies.push(policy);
+            external_libs.push(libs);
+        }
+        Ok(PrebuiltFiles {
+            _temp_dir: Some(temp_dir),
+            files,
+            policies,
+            external_libs,
+        })
     }
 
     /// Add library search paths in Cargo target directory rpath when building in editable mode
@@ -359,17 +596,45 @@ impl BuildContext {
         writer: &mut WheelWriter,
         artifacts: &[&BuildArtifact],
         ext_libs: &[Vec<Library>],
+        prebuilt: &PrebuiltFiles,
     ) -> Result<()> {
         if self.editable {
             return self.add_rpath(artifacts);
         }
-        if ext_libs.iter().all(|libs| libs.is_empty()) {
+        // Only repair mode changes the wheel
+        if self.auditwheel != AuditWheelMode::Repair {
             return Ok(());
         }
+        for (target, artifact) in &prebuilt.files {
+            writer.replace_file(target, &artifact.path);
+        }
 
-        patchelf::verify_patchelf()?;
+        // Currently artifact .so file always resides at ${module_name}/${module_name}.so
+        let artifact_dir = Path::new(&self.module_name);
+        let mut artifacts: Vec<_> = artifacts
+            .iter()
+            .map(|artifact| (artifact.path.as_path(), artifact_dir))
+            .collect();
+        artifacts.extend(prebuilt.files.iter().map(|(target, artifact)| {
+            (
+                artifact.path.as_path(),
+                target.parent().unwrap_or_else(|| Path::new("")),
+            )
+        }));
+        if ext_libs
+            .iter()
+            .chain(&prebuilt.external_libs)
+            .all(|libs| libs.is_empty())
+        {
+            // Grafting also cleans the rpath, without anything to graft we still need to remove
+            // the rpath entries pointing to the build machine
+            if !self.keep_rpath && !self.target.is_macos() && !self.target.is_windows() {
+                remove_foreign_rpaths(&artifacts)?;
+            }
+            return Ok(());
+        }
 
-        // Put external libs to ${module_name}.libs directory
+        // Put external libs to ${module_name}.libs directory, or ${module_name}.dylibs on macOS
         // See https://github.com/pypa/auditwheel/issues/89
         let mut libs_dir = self
             .project_layout
@@ -377,106 +642,33 @@ impl BuildContext {
             .as_ref()
             .and_then(|py| py.file_name().map(|s| s.to_os_string()))
             .unwrap_or_else(|| self.module_name.clone().into());
-        libs_dir.push(".libs");
+        if self.target.is_macos() {
+            libs_dir.push(".dylibs");
+        } else {
+            libs_dir.push(".libs");
+        }
         let libs_dir = PathBuf::from(libs_dir);
-        writer.add_directory(&libs_dir)?;
-
-        let temp_dir = tempfile::tempdir()?;
-        let mut soname_map = HashMap::new();
-        let mut libs_copied = HashSet::new();
-        for lib in ext_libs.iter().flatten() {
-            let lib_path = lib.realpath.clone().with_context(|| {
-                format!(
-                    "Cannot repair wheel, because required library {} could not be located.",
-                    lib.path.display()
-                )
-            })?;
-            // Generate a new soname with a short hash
-            let short_hash = &hash_file(&lib_path)?[..8];
-            let (file_stem, file_ext) = lib.name.split_once('.').unwrap();
-            let new_soname = if !file_stem.ends_with(&format!("-{short_hash}")) {
-                format!("{file_stem}-{short_hash}.{file_ext}")
-            } else {
-                format!("{file_stem}.{file_ext}")
-            };
 
-            // Copy the original lib to a tmpdir and modify some of its properties
-            // for example soname and rpath
-            let dest_path = temp_dir.path().join(&new_soname);
-            fs::copy(&lib_path, &dest_path)?;
-            libs_copied.insert(lib_path);
-
-            // fs::copy copies permissions as well, and the original
-            // file may have been read-only
-            let mut perms = fs::metadata(&dest_path)?.permissions();
-            #[allow(clippy::permissions_set_readonly_false)]
-            perms.set_readonly(false);
-            fs::set_permissions(&dest_path, perms)?;
-
-            patchelf::set_soname(&dest_path, &new_soname)?;
-            if !lib.rpath.is_empty() || !lib.runpath.is_empty() {
-                patchelf::set_rpath(&dest_path, &libs_dir)?;
-            }
-            soname_map.insert(
-                lib.name.clone(),
-                (new_soname.clone(), dest_path.clone(), lib.needed.clone()),
+        let ext_libs: Vec<_> = ext_libs
+            .iter()
+            .chain(&prebuilt.external_libs)
+            .cloned()
+            .collect();
+        if self.target.is_macos() {
+            graft_external_dylibs(writer, &libs_dir, &artifacts, &ext_libs, self.keep_rpath)
+        } else if self.target.is_windows() {
+            let artifacts: Vec<_> = artifacts.iter().map(|(artifact, _)| *artifact).collect();
+            graft_external_dlls(writer, &libs_dir, &artifacts, &ext_libs)?;
+            // Windows has no rpath, so the package has to add the DLLs to the search path
+            let package = libs_dir.with_extension("");
+            writer.inject_code(
+                package.join("__init__.py"),
+                add_dll_directory_code(&libs_dir.to_string_lossy()),
             );
+            Ok(())
+        } else {
+            graft_external_libs(writer, &libs_dir, &artifacts, &ext_libs, self.keep_rpath)
         }
-
-        for (artifact, artifact_ext_libs) in artifacts.iter().zip(ext_libs) {
-            let artifact_deps: HashSet<_> = artifact_ext_libs.iter().map(|lib| &lib.name).collect();
-            let replacements = soname_map
-                .iter()
-                .filter_map(|(k, v)| {
-                    if artifact_deps.contains(k) {
-                        Some((k, v.0.clone()))
-                    } else {
-                        None
-                    }
-                })
-                .collect::<Vec<_>>();
-            if !replacements.is_empty() {
-                patchelf::replace_needed(&artifact.path, &replacements[..])?;
-            }
-        }
-
-        // we grafted in a bunch of libraries and modified their sonames, but
-        // they may have internal dependencies (DT_NEEDED) on one another, so
-        // we need to update those records so each now knows about the new
-        // name of the other.
-        for (new_soname, path, needed) in soname_map.values() {
-            let mut replacements = Vec::new();
-            for n in needed {
-                if soname_map.contains_key(n) {
-                    replacements.push((n, soname_map[n].0.clone()));
-                }
-            }
-            if !replacements.is_empty() {
-                patchelf::replace_needed(path, &replacements[..])?;
-            }
-            writer.add_file_with_permissions(libs_dir.join(new_soname), path, 0o755)?;
-        }
-
-        eprintln!(
-            "🖨  Copied external shared libraries to package {} directory:",
-            libs_dir.display()
-        );
-        for lib_path in libs_copied {
-            eprintln!("    {}", lib_path.display());
-        }
-
-        // Currently artifact .so file always resides at ${module_name}/${module_name}.so
-        let artifact_dir = Path::new(&self.module_name);
-        for artifact in artifacts {
-            let mut new_rpaths = patchelf::get_rpath(&artifact.path)?;
-            // TODO: clean existing rpath entries if it's not pointed to a location within the wheel
-            // See https://github.com/pypa/auditwheel/blob/353c24250d66951d5ac7e60b97471a6da76c123f/src/auditwheel/repair.py#L160
-            let new_rpath = Path::new("$ORIGIN").join(relpath(&libs_dir, artifact_dir));
-            new_rpaths.push(new_rpath.to_str().unwrap().to_string());
-            let new_rpath = new_rpaths.join(":");
-            patchelf::set_rpath(&artifact.path, &new_rpath)?;
-        }
-        Ok(())
     }
 
     fn add_pth(&self, writer: &mut WheelWriter) -> Result<()> {
@@ -486,6 +678,30 @@ impl BuildContext {
         Ok(())
     }
 
+    /// Adds the SBOMs and the license texts of the Rust crates that are linked for the target
+    fn add_linked_crates(&self, writer: &mut WheelWriter) -> Result<()> {
+        if self.sbom.is_empty() && !self.third_party_licenses {
+            return Ok(());
+        }
+        let target_triples = if self.universal2 {
+            vec!["x86_64-apple-darwin", "aarch64-apple-darwin"]
+        } else {
+            vec![self.target.target_triple()]
+        };
+        let targets = target_triples
+            .into_iter()
+            .map(|triple| Ok((triple, target_cfgs(triple)?)))
+            .collect::<Result<Vec<_>>>()?;
+        let linked_crates = LinkedCrates::resolve(&self.cargo_metadata, &targets)
+            .context("Failed to resolve the linked crates")?;
+        let dist_info_dir = self.metadata21.get_dist_info_dir();
+        write_sboms(writer, &dist_info_dir, &linked_crates, &self.sbom)?;
+        if self.third_party_licenses {
+            write_third_party_licenses(writer, &dist_info_dir, &linked_crates)?;
+        }
+        Ok(())
+    }
+
     fn excludes(&self, format: Format) -> Result<Option<Override>> {
         if let Some(pyproject) = self.pyproject_toml.as_ref() {
             let pyproject_dir = self
@@ -647,6 +863,7 @@ impl BuildContext {
         artifact: BuildArtifact,
         platform_tags: &[PlatformTag],
         ext_libs: Vec<Library>,
+        prebuilt: &PrebuiltFiles,
         major: u8,
         min_minor: u8,
     ) -> Result<BuiltWheelMetadata> {
@@ -660,7 +877,7 @@ impl BuildContext {
             &[tag.clone()],
             self.excludes(Format::Wheel)?,
         )?;
-        self.add_external_libs(&mut writer, &[&artifact], &[ext_libs])?;
+        self.add_external_libs(&mut writer, &[&artifact], &[ext_libs], prebuilt)?;
 
         write_bindings_module(
             &mut writer,
@@ -675,6 +892,7 @@ impl BuildContext {
         .context("Failed to add the files to the wheel")?;
 
         self.add_pth(&mut writer)?;
+        self.add_linked_crates(&mut writer)?;
         add_data(&mut writer, self.project_layout.data.as_deref())?;
         let wheel_path = writer.finish()?;
         Ok((wheel_path, format!("cp{major}{min_minor}")))
@@ -696,17 +914,16 @@ impl BuildContext {
             python_interpreter,
             Some(&self.project_layout.extension_name),
         )?;
+        self.audit_abi3(&artifact, major, min_minor)?;
         let (policy, external_libs) =
             self.auditwheel(&artifact, &self.platform_tag, python_interpreter)?;
-        let platform_tags = if self.platform_tag.is_empty() {
-            vec![policy.platform_tag()]
-        } else {
-            self.platform_tag.clone()
-        };
+        let prebuilt = self.audit_prebuilt_files(python_interpreter)?;
+        let platform_tags = self.wheel_platform_tags(prebuilt.restrict(policy));
         let (wheel_path, tag) = self.write_binding_wheel_abi3(
             artifact,
             &platform_tags,
             external_libs,
+            &prebuilt,
             major,
             min_minor,
         )?;
@@ -728,6 +945,7 @@ impl BuildContext {
         artifact: BuildArtifact,
         platform_tags: &[PlatformTag],
         ext_libs: Vec<Library>,
+        prebuilt: &PrebuiltFiles,
     ) -> Result<BuiltWheelMetadata> {
         let tag = python_interpreter.get_tag(self, platform_tags)?;
 
@@ -738,7 +956,7 @@ impl BuildContext {
             &[tag.clone()],
             self.excludes(Format::Wheel)?,
         )?;
-        self.add_external_libs(&mut writer, &[&artifact], &[ext_libs])?;
+        self.add_external_libs(&mut writer, &[&artifact], &[ext_libs], prebuilt)?;
 
         write_bindings_module(
             &mut writer,
@@ -753,6 +971,7 @@ impl BuildContext {
         .context("Failed to add the files to the wheel")?;
 
         self.add_pth(&mut writer)?;
+        self.add_linked_crates(&mut writer)?;
         add_data(&mut writer, self.project_layout.data.as_deref())?;
         let wheel_path = writer.finish()?;
         Ok((
@@ -780,16 +999,14 @@ impl BuildContext {
             )?;
             let (policy, external_libs) =
                 self.auditwheel(&artifact, &self.platform_tag, Some(python_interpreter))?;
-            let platform_tags = if self.platform_tag.is_empty() {
-                vec![policy.platform_tag()]
-            } else {
-                self.platform_tag.clone()
-            };
+            let prebuilt = self.audit_prebuilt_files(Some(python_interpreter))?;
+            let platform_tags = self.wheel_platform_tags(prebuilt.restrict(policy));
             let (wheel_path, tag) = self.write_binding_wheel(
                 python_interpreter,
                 artifact,
                 &platform_tags,
                 external_libs,
+                &prebuilt,
             )?;
             eprintln!(
                 "📦 Built wheel for {} {}.{}{} to {}",
@@ -832,7 +1049,7 @@ impl BuildContext {
             let _ = warn_missing_py_init(&artifact.path, extension_name);
         }
 
-        if self.editable || self.skip_auditwheel {
+        if self.editable || self.auditwheel != AuditWheelMode::Repair {
             return Ok(artifact);
         }
         // auditwheel repair will edit the file, so we need to copy it to avoid errors in reruns
@@ -850,6 +1067,7 @@ impl BuildContext {
         artifact: BuildArtifact,
         platform_tags: &[PlatformTag],
         ext_libs: Vec<Library>,
+        prebuilt: &PrebuiltFiles,
     ) -> Result<BuiltWheelMetadata> {
         let (tag, tags) = self.get_universal_tags(platform_tags)?;
 
@@ -860,7 +1078,7 @@ impl BuildContext {
             &tags,
             self.excludes(Format::Wheel)?,
         )?;
-        self.add_external_libs(&mut writer, &[&artifact], &[ext_libs])?;
+        self.add_external_libs(&mut writer, &[&artifact], &[ext_libs], prebuilt)?;
 
         write_cffi_module(
             &mut writer,
@@ -875,6 +1093,7 @@ impl BuildContext {
         )?;
 
         self.add_pth(&mut writer)?;
+        self.add_linked_crates(&mut writer)?;
         add_data(&mut writer, self.project_layout.data.as_deref())?;
         let wheel_path = writer.finish()?;
         Ok((wheel_path, "py3".to_string()))
@@ -885,12 +1104,10 @@ impl BuildContext {
         let mut wheels = Vec::new();
         let artifact = self.compile_cdylib(None, None)?;
         let (policy, external_libs) = self.auditwheel(&artifact, &self.platform_tag, None)?;
-        let platform_tags = if self.platform_tag.is_empty() {
-            vec![policy.platform_tag()]
-        } else {
-            self.platform_tag.clone()
-        };
-        let (wheel_path, tag) = self.write_cffi_wheel(artifact, &platform_tags, external_libs)?;
+        let prebuilt = self.audit_prebuilt_files(None)?;
+        let platform_tags = self.wheel_platform_tags(prebuilt.restrict(policy));
+        let (wheel_path, tag) =
+            self.write_cffi_wheel(artifact, &platform_tags, external_libs, &prebuilt)?;
 
         // Warn if cffi isn't specified in the requirements
         if !self
@@ -916,6 +1133,7 @@ impl BuildContext {
         artifact: BuildArtifact,
         platform_tags: &[PlatformTag],
         ext_libs: Vec<Library>,
+        prebuilt: &PrebuiltFiles,
     ) -> Result<BuiltWheelMetadata> {
         let (tag, tags) = self.get_universal_tags(platform_tags)?;
 
@@ -926,7 +1144,7 @@ impl BuildContext {
             &tags,
             self.excludes(Format::Wheel)?,
         )?;
-        self.add_external_libs(&mut writer, &[&artifact], &[ext_libs])?;
+        self.add_external_libs(&mut writer, &[&artifact], &[ext_libs], prebuilt)?;
 
         write_uniffi_module(
             &mut writer,
@@ -941,6 +1159,7 @@ impl BuildContext {
         )?;
 
         self.add_pth(&mut writer)?;
+        self.add_linked_crates(&mut writer)?;
         add_data(&mut writer, self.project_layout.data.as_deref())?;
         let wheel_path = writer.finish()?;
         Ok((wheel_path, "py3".to_string()))
@@ -951,12 +1170,10 @@ impl BuildContext {
         let mut wheels = Vec::new();
         let artifact = self.compile_cdylib(None, None)?;
         let (policy, external_libs) = self.auditwheel(&artifact, &self.platform_tag, None)?;
-        let platform_tags = if self.platform_tag.is_empty() {
-            vec![policy.platform_tag()]
-        } else {
-            self.platform_tag.clone()
-        };
-        let (wheel_path, tag) = self.write_uniffi_wheel(artifact, &platform_tags, external_libs)?;
+        let prebuilt = self.audit_prebuilt_files(None)?;
+        let platform_tags = self.wheel_platform_tags(prebuilt.restrict(policy));
+        let (wheel_path, tag) =
+            self.write_uniffi_wheel(artifact, &platform_tags, external_libs, &prebuilt)?;
 
         eprintln!("📦 Built wheel to {}", wheel_path.display());
         wheels.push((wheel_path, tag));
@@ -970,6 +1187,7 @@ impl BuildContext {
         artifacts: &[BuildArtifact],
         platform_tags: &[PlatformTag],
         ext_libs: &[Vec<Library>],
+        prebuilt: &PrebuiltFiles,
     ) -> Result<BuiltWheelMetadata> {
         let (tag, tags) = match (self.bridge(), python_interpreter) {
             (BridgeModel::Bin(None), _) => self.get_universal_tags(platform_tags)?,
@@ -1026,6 +1244,13 @@ impl BuildContext {
             // without clashes?
             bail!("Sorry, adding python code to a wasm binary is currently not supported")
         }
+        // The binaries and prebuilt files need to be repaired before they are added
+        let artifacts_ref: Vec<_> = artifacts_and_files
+            .iter()
+            .map(|(artifact, _)| *artifact)
+            .collect();
+        self.add_external_libs(&mut writer, &artifacts_ref, ext_libs, prebuilt)?;
+
         if !self.editable {
             write_python_part(
                 &mut writer,
@@ -1035,17 +1260,15 @@ impl BuildContext {
             .context("Failed to add the python module to the package")?;
         }
 
-        let mut artifacts_ref = Vec::with_capacity(artifacts.len());
         for (artifact, bin_name) in &artifacts_and_files {
-            artifacts_ref.push(*artifact);
             write_bin(&mut writer, &artifact.path, &self.metadata21, bin_name)?;
             if self.target.is_wasi() {
                 write_wasm_launcher(&mut writer, &self.metadata21, bin_name)?;
             }
         }
-        self.add_external_libs(&mut writer, &artifacts_ref, ext_libs)?;
 
         self.add_pth(&mut writer)?;
+        self.add_linked_crates(&mut writer)?;
         add_data(&mut writer, self.project_layout.data.as_deref())?;
         let wheel_path = writer.finish()?;
         Ok((wheel_path, "py3".to_string()))
@@ -1079,18 +1302,16 @@ impl BuildContext {
             ext_libs.push(external_libs);
             artifact_paths.push(artifact);
         }
-        let policy = policies.iter().min_by_key(|p| p.priority).unwrap();
-        let platform_tags = if self.platform_tag.is_empty() {
-            vec![policy.platform_tag()]
-        } else {
-            self.platform_tag.clone()
-        };
+        let prebuilt = self.audit_prebuilt_files(python_interpreter)?;
+        let policy = policies.into_iter().min_by_key(|p| p.priority).unwrap();
+        let platform_tags = self.wheel_platform_tags(prebuilt.restrict(policy));
 
         let (wheel_path, tag) = self.write_bin_wheel(
             python_interpreter,
             &artifact_paths,
             &platform_tags,
             &ext_libs,
+            &prebuilt,
         )?;
         eprintln!("📦 Built wheel to {}", wheel_path.display());
         wheels.push((wheel_path, tag));
@@ -1221,10 +1442,250 @@ fn emcc_version() -> Result<String> {
     Ok(String::from_utf8(version.as_bytes().to_vec())?)
 }
 
+/// Fails in check mode if `artifact` needs to be repaired and turns all problems into
+/// warnings in warn mode
+///
+/// In warn mode, an artifact with external libraries that aren't bundled only complies with the
+/// plain `linux` tag, so a requested manylinux or musllinux tag is refused.
+fn apply_audit_mode(
+    mode: AuditWheelMode,
+    platform_tag: &[PlatformTag],
+    artifact: &Path,
+    result: Result<(Policy, Vec<Library>)>,
+) -> Result<(Policy, Vec<Library>)> {
+    match mode {
+        AuditWheelMode::Repair => result,
+        AuditWheelMode::Check => {
+            let (policy, external_libs) = result?;
+            if !external_libs.is_empty() {
+                let names: Vec<_> = external_libs.iter().map(|lib| lib.name.as_str()).collect();
+                bail!(
+                    "{} links external libraries that need to be bundled into the wheel: {}. \
+                    Use `--auditwheel repair` to bundle them",
+                    artifact.display(),
+                    names.join(", ")
+                );
+            }
+            Ok((policy, external_libs))
+        }
+        AuditWheelMode::Warn => match result {
+            Ok((policy, external_libs)) => {
+                if external_libs.is_empty() {
+                    return Ok((policy, external_libs));
+                }
+                let names: Vec<_> = external_libs.iter().map(|lib| lib.name.as_str()).collect();
+                let compliance = match platform_tag.iter().find(|tag| tag.is_portable()) {
+                    Some(tag) => format!("so it doesn't comply with {tag}, "),
+                    None => String::new(),
+                };
+                eprintln!(
+                    "⚠️  Warning: {} links external libraries that are not bundled into the wheel: {}, \
+                    {compliance}falling back to the linux platform tag",
+                    artifact.display(),
+                    names.join(", ")
+                );
+                Ok((Policy::default(), Vec::new()))
+            }
+            Err(err) => {
+                eprintln!("⚠️  Warning: {err:#}, falling back to the linux platform tag");
+                Ok((Policy::default(), Vec::new()))
+            }
+        },
+        AuditWheelMode::Skip => Ok((Policy::default(), Vec::new())),
+    }
+}
+
+/// Returns the requested platform tags, or the tag of the `policy` all artifacts comply with
+/// if none were requested. In warn mode, non-compliant artifacts fall back to the linux policy,
+/// which then also replaces the requested manylinux/musllinux tags
+fn wheel_platform_tags(
+    mode: AuditWheelMode,
+    platform_tag: &[PlatformTag],
+    policy: Policy,
+) -> Vec<PlatformTag> {
+    if platform_tag.is_empty() {
+        return vec![policy.platform_tag()];
+    }
+    let fell_back = mode == AuditWheelMode::Warn && policy.platform_tag() == PlatformTag::Linux;
+    if fell_back && platform_tag.iter().any(|tag| tag.is_portable()) {
+        vec![PlatformTag::Linux]
+    } else {
+        platform_tag.to_vec()
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use super::macosx_deployment_target;
+    use super::{apply_audit_mode, macosx_deployment_target, wheel_platform_tags};
+    use crate::auditwheel::{AuditWheelMode, PlatformTag, Policy};
+    use anyhow::{bail, Result};
+    use lddtree::Library;
     use pretty_assertions::assert_eq;
+    use std::path::{Path, PathBuf};
+
+    fn external_lib() -> Library {
+        Library {
+            name: "libfoo.so.1".to_string(),
+            path: PathBuf::from("/opt/foo/lib/libfoo.so.1"),
+            realpath: Some(PathBuf::from("/opt/foo/lib/libfoo.so.1")),
+            needed: Vec::new(),
+            rpath: Vec::new(),
+            runpath: Vec::new(),
+        }
+    }
+
+    fn audit_result(external_libs: Vec<Library>) -> Result<(Policy, Vec<Library>)> {
+        Ok((Policy::from_name("manylinux2014").unwrap(), external_libs))
+    }
+
+    fn not_compliant() -> Result<(Policy, Vec<Library>)> {
+        bail!("not compliant")
+    }
+
+    fn policy_name(result: Result<(Policy, Vec<Library>)>) -> (String, usize) {
+        let (policy, external_libs) = result.unwrap();
+        (policy.name, external_libs.len())
+    }
+
+    #[test]
+    fn test_apply_audit_mode_repair() {
+        let artifact = Path::new("foo.so");
+        assert_eq!(
+            policy_name(apply_audit_mode(
+                AuditWheelMode::Repair,
+                &[],
+                artifact,
+                audit_result(vec![external_lib()])
+            )),
+            ("manylinux_2_17".to_string(), 1)
+        );
+    }
+
+    #[test]
+    fn test_apply_audit_mode_check() {
+        let artifact = Path::new("foo.so");
+        assert_eq!(
+            policy_name(apply_audit_mode(
+                AuditWheelMode::Check,
+                &[],
+                artifact,
+                audit_result(Vec::new())
+            )),
+            ("manylinux_2_17".to_string(), 0)
+        );
+        let err = apply_audit_mode(
+            AuditWheelMode::Check,
+            &[],
+            artifact,
+            audit_result(vec![external_lib()]),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("libfoo.so.1"));
+        assert!(apply_audit_mode(AuditWheelMode::Check, &[], artifact, not_compliant()).is_err());
+    }
+
+    #[test]
+    fn test_apply_audit_mode_warn() {
+        let artifact = Path::new("foo.so");
+        assert_eq!(
+            policy_name(apply_audit_mode(
+                AuditWheelMode::Warn,
+                &[],
+                artifact,
+                audit_result(Vec::new())
+            )),
+            ("manylinux_2_17".to_string(), 0)
+        );
+        // The external libraries aren't bundled, so the wheel is only a linux wheel
+        assert_eq!(
+            policy_name(apply_audit_mode(
+                AuditWheelMode::Warn,
+                &[],
+                artifact,
+                audit_result(vec![external_lib()])
+            )),
+            ("linux".to_string(), 0)
+        );
+        assert_eq!(
+            policy_name(apply_audit_mode(
+                AuditWheelMode::Warn,
+                &[PlatformTag::Linux],
+                artifact,
+                audit_result(vec![external_lib()])
+            )),
+            ("linux".to_string(), 0)
+        );
+        // Requesting a portable tag doesn't make warn mode fail, neither with unbundled
+        // libraries nor if the policy check fails
+        let requested = [PlatformTag::manylinux2014()];
+        assert_eq!(
+            policy_name(apply_audit_mode(
+                AuditWheelMode::Warn,
+                &requested,
+                artifact,
+                audit_result(vec![external_lib()])
+            )),
+            ("linux".to_string(), 0)
+        );
+        assert_eq!(
+            policy_name(apply_audit_mode(
+                AuditWheelMode::Warn,
+                &[],
+                artifact,
+                not_compliant()
+            )),
+            ("linux".to_string(), 0)
+        );
+        let (policy, _) =
+            apply_audit_mode(AuditWheelMode::Warn, &requested, artifact, not_compliant()).unwrap();
+        assert_eq!(policy.name, "linux");
+        // The wheel falls back to the linux tag instead of claiming compliance
+        assert_eq!(
+            wheel_platform_tags(AuditWheelMode::Warn, &requested, policy),
+            vec![PlatformTag::Linux]
+        );
+    }
+
+    #[test]
+    fn test_wheel_platform_tags() {
+        let manylinux2014 = Policy::from_name("manylinux2014").unwrap();
+        let requested = [PlatformTag::manylinux2014()];
+        assert_eq!(
+            wheel_platform_tags(AuditWheelMode::Repair, &[], manylinux2014.clone()),
+            vec![PlatformTag::manylinux2014()]
+        );
+        assert_eq!(
+            wheel_platform_tags(AuditWheelMode::Warn, &requested, manylinux2014),
+            requested
+        );
+        assert_eq!(
+            wheel_platform_tags(
+                AuditWheelMode::Warn,
+                &[PlatformTag::Linux],
+                Policy::default()
+            ),
+            vec![PlatformTag::Linux]
+        );
+        // Skipping the audit keeps the requested tags
+        assert_eq!(
+            wheel_platform_tags(AuditWheelMode::Skip, &requested, Policy::default()),
+            requested
+        );
+    }
+
+    #[test]
+    fn test_apply_audit_mode_skip() {
+        let artifact = Path::new("foo.so");
+        assert_eq!(
+            policy_name(apply_audit_mode(
+                AuditWheelMode::Skip,
+                &[PlatformTag::manylinux2014()],
+                artifact,
+                audit_result(vec![external_lib()])
+            )),
+            ("linux".to_string(), 0)
+        );
+    }
 
     #[test]
     fn test_macosx_deployment_target() {
@@ -1,11 +1,11 @@
-use crate::auditwheel::PlatformTag;
+use crate::auditwheel::{AuditWheelMode, PlatformTag, PolicyOverrides};
 use crate::build_context::BridgeModel;
 use crate::compile::{CompileTarget, LIB_CRATE_TYPES};
 use crate::cross_compile::{find_sysconfigdata, parse_sysconfigdata};
 use crate::project_layout::ProjectResolver;
-use crate::pyproject_toml::ToolMaturin;
+use crate::pyproject_toml::{PyProjectToml, ToolMaturin};
 use crate::python_interpreter::{InterpreterConfig, InterpreterKind, MINIMUM_PYTHON_MINOR};
-use crate::{BuildContext, PythonInterpreter, Target};
+use crate::{BuildContext, PythonInterpreter, SbomFormat, Target};
 use anyhow::{bail, format_err, Context, Result};
 use cargo_metadata::{Metadata, Node};
 use pep440_rs::VersionSpecifiers;
@@ -13,7 +13,7 @@ use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::env;
 use std::ops::{Deref, DerefMut};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing::debug;
 
 // This is used for BridgeModel::Bindings("pyo3-ffi") and BridgeModel::Bindings("pyo3").
@@ -172,10 +172,34 @@ pub struct BuildOptions {
     #[arg(short, long)]
     pub out: Option<PathBuf>,
 
-    /// Don't check for manylinux compliance
-    #[arg(long = "skip-auditwheel")]
+    /// Don't check for manylinux compliance, deprecated in favor of `--auditwheel=skip`
+    #[arg(long = "skip-auditwheel", hide = true)]
     pub skip_auditwheel: bool,
 
+    /// What to do with external shared libraries and compliance problems found by auditing the
+    /// wheel, defaults to repair
+    #[arg(long, value_enum, conflicts_with = "skip_auditwheel")]
+    pub auditwheel: Option<AuditWheelMode>,
+
+    /// Add a software bill of materials of the linked Rust crates in this format to
+    /// `.dist-info/sboms`, can be repeated. Defaults to CycloneDX
+    #[arg(long, value_enum, value_name = "FORMAT", action = clap::ArgAction::Append)]
+    pub sbom: Vec<SbomFormat>,
+
+    /// Don't add software bills of materials to the wheels
+    #[arg(long, conflicts_with = "sbom")]
+    pub no_sbom: bool,
+
+    /// Add the license texts of the linked Rust crates and a summary of their licenses to
+    /// `.dist-info/licenses/third-party`
+    #[arg(long)]
+    pub third_party_licenses: bool,
+
+    /// Don't write the build options given on the command line, like features or the
+    /// compatibility, into the `[tool.maturin]` section of the source distribution
+    #[arg(long)]
+    pub no_embed_build_options: bool,
+
     /// For manylinux targets, use zig to ensure compliance for the chosen manylinux version
     ///
     /// Default to manylinux2014/manylinux_2_17 if you do not specify an `--compatibility`
@@ -478,6 +502,7 @@ impl BuildOptions {
             mut pyproject_toml_maturin_options,
         } = ProjectResolver::resolve(self.manifest_path.clone(), self.cargo.clone())?;
         let pyproject = pyproject_toml.as_ref();
+        let sdist_build_options = (!self.no_embed_build_options).then(|| self.clone());
 
         let bridge = find_bridge(
             &cargo_metadata,
@@ -576,8 +601,43 @@ impl BuildOptions {
         }
 
         let strip = pyproject.map(|x| x.strip()).unwrap_or_default() || strip;
-        let skip_auditwheel =
-            pyproject.map(|x| x.skip_auditwheel()).unwrap_or_default() || self.skip_auditwheel;
+        let auditwheel = resolve_auditwheel_mode(self.auditwheel, self.skip_auditwheel, pyproject);
+        let policy_overrides = match pyproject.and_then(|x| x.auditwheel()) {
+            Some(config) => {
+                pyproject_toml_maturin_options.push("auditwheel");
+                let project_root = pyproject_toml_path.parent().unwrap_or(Path::new("."));
+                PolicyOverrides::from_config(config, project_root)
+                    .context("Failed to load [tool.maturin.auditwheel] from pyproject.toml")?
+            }
+            None => PolicyOverrides::default(),
+        };
+        let keep_rpath = pyproject
+            .and_then(|x| x.auditwheel())
+            .map(|config| config.keep_rpath)
+            .unwrap_or_default();
+        let sbom_config = pyproject.and_then(|x| x.sbom());
+        if sbom_config.is_some() {
+            pyproject_toml_maturin_options.push("sbom");
+        }
+        let sbom = if !self.sbom.is_empty() {
+            self.sbom.clone()
+        } else if self.no_sbom || sbom_config.and_then(|config| config.enabled) == Some(false) {
+            Vec::new()
+        } else {
+            sbom_config
+                .and_then(|config| config.formats.clone())
+                .unwrap_or_else(|| vec![SbomFormat::CycloneDx])
+        };
+        let third_party_licenses = self.third_party_licenses
+            || pyproject
+                .map(|x| {
+                    if x.third_party_licenses() {
+                        pyproject_toml_maturin_options.push("third-party-licenses");
+                    }
+                    x.third_party_licenses()
+                })
+                .unwrap_or_default();
+        let sdist_vendor = pyproject.map(|x| x.sdist_vendor()).unwrap_or_default();
         let platform_tags = if self.platform_tag.is_empty() {
             #[cfg(feature = "zig")]
             let use_zig = self.zig;
@@ -664,7 +724,14 @@ impl BuildOptions {
             out: wheel_dir,
             release,
             strip,
-            skip_auditwheel,
+            auditwheel,
+            policy_overrides,
+            keep_rpath,
+            sbom,
+            third_party_licenses,
+            sdist_vendor,
+            sdist_build_options,
+            sdist_strip: strip,
             #[cfg(feature = "zig")]
             zig: self.zig,
             platform_tag: platform_tags,
@@ -677,6 +744,19 @@ impl BuildOptions {
     }
 }
 
+/// `--auditwheel` takes precedence over the deprecated `--skip-auditwheel`, and both over
+/// `[tool.maturin.auditwheel]` in pyproject.toml
+fn resolve_auditwheel_mode(
+    auditwheel: Option<AuditWheelMode>,
+    skip_auditwheel: bool,
+    pyproject: Option<&PyProjectToml>,
+) -> AuditWheelMode {
+    auditwheel
+        .or_else(|| skip_auditwheel.then_some(AuditWheelMode::Skip))
+        .or_else(|| pyproject.and_then(|x| x.auditwheel_mode()))
+        .unwrap_or_default()
+}
+
 /// Checks for bridge/platform type edge cases
 fn validate_bridge_type(
     bridge: &BridgeModel,
@@ -1296,6 +1376,13 @@ impl CargoOptions {
             }
         }
 
+        if let Some(offline) = tool_maturin.offline {
+            if !self.offline {
+                self.offline = offline;
+                args_from_pyproject.push("offline");
+            }
+        }
+
         if let Some(config) = tool_maturin.config {
             if self.config.is_empty() {
                 self.config = config;
@@ -1473,4 +1560,41 @@ mod test {
 
         assert_eq!(extract_cargo_metadata_args(&args).unwrap(), expected);
     }
+
+    #[test]
+    fn test_resolve_auditwheel_mode() {
+        let pyproject = |maturin: &str| -> PyProjectToml {
+            toml::from_str(&format!(
+                "[build-system]\nrequires = [\"maturin\"]\n\n[tool.maturin]\n{maturin}"
+            ))
+            .unwrap()
+        };
+        let check = pyproject("auditwheel = { mode = \"check\" }");
+        let skip = pyproject("skip-auditwheel = true");
+
+        assert_eq!(
+            resolve_auditwheel_mode(None, false, None),
+            AuditWheelMode::Repair
+        );
+        assert_eq!(
+            resolve_auditwheel_mode(None, false, Some(&check)),
+            AuditWheelMode::Check
+        );
+        assert_eq!(
+            resolve_auditwheel_mode(None, false, Some(&skip)),
+            AuditWheelMode::Skip
+        );
+        assert_eq!(
+            resolve_auditwheel_mode(None, true, Some(&check)),
+            AuditWheelMode::Skip
+        );
+        assert_eq!(
+            resolve_auditwheel_mode(Some(AuditWheelMode::Warn), false, Some(&check)),
+            AuditWheelMode::Warn
+        );
+        assert_eq!(
+            resolve_auditwheel_mode(Some(AuditWheelMode::Repair), true, Some(&skip)),
+            AuditWheelMode::Repair
+        );
+    }
 }
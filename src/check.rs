@@ -0,0 +1,495 @@
+//! Validating wheels and source distributions before uploading them, similar to `twine check`
+use crate::inspect::{inspect, DistributionKind, Inspection};
+use crate::spdx::normalize_license_expression;
+use anyhow::Result;
+use pep440_rs::Version;
+use regex::Regex;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::str::FromStr;
+
+/// The metadata versions PyPI accepts
+const METADATA_VERSIONS: &[&str] = &["1.0", "1.1", "1.2", "2.0", "2.1", "2.2", "2.3", "2.4"];
+
+/// The docutils directives, without `raw` and `include` which PyPI disables
+const RST_DIRECTIVES: &[&str] = &[
+    "admonition",
+    "attention",
+    "caution",
+    "class",
+    "code",
+    "code-block",
+    "compound",
+    "container",
+    "contents",
+    "csv-table",
+    "danger",
+    "date",
+    "default-role",
+    "epigraph",
+    "error",
+    "figure",
+    "footer",
+    "header",
+    "highlights",
+    "hint",
+    "image",
+    "important",
+    "line-block",
+    "list-table",
+    "math",
+    "meta",
+    "note",
+    "parsed-literal",
+    "pull-quote",
+    "replace",
+    "role",
+    "rubric",
+    "sectnum",
+    "sidebar",
+    "sourcecode",
+    "table",
+    "target-notes",
+    "tip",
+    "title",
+    "topic",
+    "unicode",
+    "warning",
+];
+
+/// The result of validating a wheel or source distribution
+#[derive(Debug, Clone, Default)]
+pub struct DistributionCheck {
+    /// Problems that make the registry reject the distribution or break installing it
+    pub errors: Vec<String>,
+    /// Problems that don't prevent uploading the distribution
+    pub warnings: Vec<String>,
+    /// Likely reStructuredText rendering failures of the description. These are only
+    /// heuristics, so they are errors in strict mode and warnings otherwise
+    pub render_errors: Vec<String>,
+}
+
+/// Normalizes a distribution name according to PEP 503
+fn normalize_name(name: &str) -> String {
+    Regex::new(r"[-_.]+")
+        .unwrap()
+        .replace_all(name, "-")
+        .to_lowercase()
+}
+
+/// Splits the filename into the escaped name, the escaped version and for wheels the tags
+fn parse_filename(
+    filename: &str,
+    kind: DistributionKind,
+) -> Result<(String, String, Vec<String>), String> {
+    let component = Regex::new(r"^[A-Za-z0-9_.+!]+$").unwrap();
+    let (name, version, tags) = match kind {
+        DistributionKind::Wheel => {
+            let stem = filename.strip_suffix(".whl").unwrap_or(filename);
+            let parts: Vec<&str> = stem.split('-').collect();
+            let (name, version, build_tag, python, abi, platform) = match parts[..] {
+                [name, version, python, abi, platform] => {
+                    (name, version, None, python, abi, platform)
+                }
+                [name, version, build, python, abi, platform] => {
+                    (name, version, Some(build), python, abi, platform)
+                }
+                _ => {
+                    return Err(format!(
+                        "Invalid wheel filename {filename}, expected \
+                         {{distribution}}-{{version}}(-{{build tag}})?-{{python tag}}-{{abi tag}}-{{platform tag}}.whl"
+                    ))
+                }
+            };
+            if let Some(build_tag) = build_tag {
+                if !build_tag.starts_with(|c: char| c.is_ascii_digit()) {
+                    return Err(format!(
+                        "Invalid build tag {build_tag} in {filename}, it must start with a digit"
+                    ));
+                }
+            }
+            let tag = Regex::new(r"^[a-z0-9_]+(\.[a-z0-9_]+)*$").unwrap();
+            for value in [python, abi, platform] {
+                if !tag.is_match(value) {
+                    return Err(format!("Invalid tag {value} in {filename}"));
+                }
+            }
+            let mut tags = Vec::new();
+            for python in python.split('.') {
+                for abi in abi.split('.') {
+                    for platform in platform.split('.') {
+                        tags.push(format!("{python}-{abi}-{platform}"));
+                    }
+                }
+            }
+            (name, version, tags)
+        }
+        DistributionKind::Sdist => {
+            let stem = filename.strip_suffix(".tar.gz").unwrap_or(filename);
+            match stem.rsplit_once('-') {
+                Some((name, version)) => (name, version, Vec::new()),
+                None => {
+                    return Err(format!(
+                        "Invalid sdist filename {filename}, expected {{distribution}}-{{version}}.tar.gz"
+                    ))
+                }
+            }
+        }
+    };
+    if !component.is_match(name) {
+        return Err(format!("Invalid distribution name {name} in {filename}"));
+    }
+    if !component.is_match(version) {
+        return Err(format!("Invalid version {version} in {filename}"));
+    }
+    Ok((name.to_string(), version.to_string(), tags))
+}
+
+/// Checks that a Description-Content-Type is supported by PyPI, returning the mime type
+fn check_content_type(content_type: &str) -> Result<String, String> {
+    let mut parts = content_type.split(';').map(str::trim);
+    let mime_type = parts.next().unwrap_or_default().to_ascii_lowercase();
+    if !["text/plain", "text/x-rst", "text/markdown"].contains(&mime_type.as_str()) {
+        return Err(format!(
+            "Unsupported Description-Content-Type {content_type}, \
+             must be text/plain, text/x-rst or text/markdown"
+        ));
+    }
+    for parameter in parts.filter(|part| !part.is_empty()) {
+        let (key, value) = parameter.split_once('=').ok_or_else(|| {
+            format!("Invalid parameter {parameter} in Description-Content-Type {content_type}")
+        })?;
+        match key.trim().to_ascii_lowercase().as_str() {
+            "charset" if value.trim().eq_ignore_ascii_case("utf-8") => {}
+            "charset" => {
+                return Err(format!(
+                    "Unsupported charset {value} in Description-Content-Type, must be UTF-8"
+                ))
+            }
+            "variant" if mime_type == "text/markdown" => {
+                if !["gfm", "commonmark"].contains(&value.trim().to_ascii_lowercase().as_str()) {
+                    return Err(format!(
+                        "Unsupported markdown variant {value} in Description-Content-Type, \
+                         must be GFM or CommonMark"
+                    ));
+                }
+            }
+            _ => {
+                return Err(format!(
+                    "Unsupported parameter {parameter} in Description-Content-Type {content_type}"
+                ))
+            }
+        }
+    }
+    Ok(mime_type)
+}
+
+/// Whether `line` is a section adornment, i.e. a repeated punctuation character
+fn is_adornment(line: &str) -> bool {
+    let mut chars = line.chars();
+    match chars.next() {
+        Some(first) if first.is_ascii_punctuation() => chars.all(|c| c == first),
+        _ => false,
+    }
+}
+
+/// Finds an inline literal start-string without end-string, which docutils reports as warning
+/// and therefore fails to render on PyPI
+fn has_unclosed_literal(paragraph: &str) -> bool {
+    let mut rest = paragraph;
+    while let Some(start) = rest.find("``") {
+        let preceded_ok = rest[..start]
+            .chars()
+            .last()
+            .map_or(true, |c| c.is_whitespace() || "'\"([{<-/:".contains(c));
+        let after = &rest[start + 2..];
+        if !preceded_ok || after.starts_with(char::is_whitespace) || after.is_empty() {
+            rest = after;
+            continue;
+        }
+        // The end-string must not be preceded by whitespace
+        match after
+            .match_indices("``")
+            .find(|(index, _)| !after[..*index].ends_with(char::is_whitespace))
+        {
+            Some((index, _)) => rest = &after[index + 2..],
+            None => return true,
+        }
+    }
+    false
+}
+
+/// Finds the reStructuredText problems that most commonly make PyPI fail to render a
+/// description: title underlines that are too short, unknown or disabled directives and
+/// unclosed inline literals
+fn rst_errors(text: &str) -> Vec<String> {
+    let directive = Regex::new(r"^\.\.\s+(\|[^|]+\|\s+)?([A-Za-z0-9_:+.-]+)::").unwrap();
+    let mut errors = Vec::new();
+    let lines: Vec<&str> = text.lines().collect();
+    let mut paragraph = String::new();
+    let mut paragraph_start = 0;
+    for (index, line) in lines.iter().enumerate() {
+        let trimmed = line.trim_end();
+        if let Some(captures) = directive.captures(trimmed) {
+            let name = captures[2].to_ascii_lowercase();
+            if name == "raw" || name == "include" {
+                errors.push(format!(
+                    "line {}: The \"{name}\" directive is disabled on PyPI",
+                    index + 1
+                ));
+            } else if !RST_DIRECTIVES.contains(&name.as_str()) {
+                errors.push(format!(
+                    "line {}: Unknown directive type \"{name}\"",
+                    index + 1
+                ));
+            }
+        }
+        if index > 0 && is_adornment(trimmed) {
+            let title = lines[index - 1].trim_end();
+            let title_chars = title.chars().count();
+            let is_title = !title.is_empty()
+                && !title.starts_with(char::is_whitespace)
+                && !is_adornment(title)
+                && (index < 2
+                    || lines[index - 2].trim().is_empty()
+                    || is_adornment(lines[index - 2]));
+            // docutils treats adornments shorter than 4 characters as text
+            if is_title && trimmed.chars().count() < title_chars && trimmed.chars().count() >= 4 {
+                errors.push(format!("line {}: Title underline too short", index + 1));
+            }
+        }
+        // Only unindented paragraphs, indented blocks may be literal blocks or directive content
+        if trimmed.is_empty() || line.starts_with(char::is_whitespace) || trimmed.starts_with("..")
+        {
+            if has_unclosed_literal(&paragraph) {
+                errors.push(format!(
+                    "line {}: Inline literal start-string without end-string",
+                    paragraph_start + 1
+                ));
+            }
+            paragraph.clear();
+        } else {
+            if paragraph.is_empty() {
+                paragraph_start = index;
+            }
+            paragraph.push_str(trimmed);
+            paragraph.push('\n');
+        }
+    }
+    if has_unclosed_literal(&paragraph) {
+        errors.push(format!(
+            "line {}: Inline literal start-string without end-string",
+            paragraph_start + 1
+        ));
+    }
+    errors
+}
+
+/// Checks the metadata of an inspected distribution against its filename and checks that the
+/// description renders
+fn check_inspection(inspection: &Inspection, check: &mut DistributionCheck) {
+    let (name, version, tags) = match parse_filename(&inspection.filename, inspection.kind) {
+        Ok(parsed) => parsed,
+        Err(err) => {
+            check.errors.push(err);
+            return;
+        }
+    };
+    let metadata = &inspection.metadata;
+    if !METADATA_VERSIONS.contains(&metadata.metadata_version.as_str()) {
+        check.errors.push(format!(
+            "Unsupported Metadata-Version {}",
+            metadata.metadata_version
+        ));
+    }
+    if normalize_name(&name) != normalize_name(&metadata.name) {
+        check.errors.push(format!(
+            "The name {} in the filename doesn't match the name {} in the metadata",
+            name, metadata.name
+        ));
+    }
+    match Version::from_str(&version.replace('_', "-")) {
+        Ok(parsed) if parsed == metadata.version => {}
+        Ok(_) => check.errors.push(format!(
+            "The version {} in the filename doesn't match the version {} in the metadata",
+            version, metadata.version
+        )),
+        Err(err) => check
+            .errors
+            .push(format!("Invalid version {version} in the filename: {err}")),
+    }
+    if inspection.kind == DistributionKind::Wheel {
+        let filename_tags: BTreeSet<&String> = tags.iter().collect();
+        let wheel_tags: BTreeSet<&String> = inspection.tags.iter().collect();
+        if filename_tags != wheel_tags {
+            check.errors.push(format!(
+                "The tags {} in the filename don't match the tags {} in the WHEEL file",
+                tags.join(", "),
+                inspection.tags.join(", ")
+            ));
+        }
+    }
+    check
+        .errors
+        .extend(inspection.record_errors.iter().cloned());
+    if let Some(license_expression) = &metadata.license_expression {
+        if let Err(err) = normalize_license_expression(license_expression) {
+            check.errors.push(err.to_string());
+        }
+        if metadata.license.is_some() {
+            check
+                .errors
+                .push("License and License-Expression must not both be set".to_string());
+        }
+    }
+
+    let description = match &metadata.description {
+        Some(description) if !description.trim().is_empty() => description,
+        _ => {
+            check
+                .warnings
+                .push("The long description is missing".to_string());
+            return;
+        }
+    };
+    let mime_type = match &metadata.description_content_type {
+        Some(content_type) => match check_content_type(content_type) {
+            Ok(mime_type) => mime_type,
+            Err(err) => {
+                check.errors.push(err);
+                return;
+            }
+        },
+        None => {
+            check.warnings.push(
+                "Description-Content-Type is missing, PyPI renders the description as reStructuredText"
+                    .to_string(),
+            );
+            "text/x-rst".to_string()
+        }
+    };
+    // Plain text and markdown always render
+    if mime_type == "text/x-rst" {
+        for error in rst_errors(description) {
+            check.render_errors.push(format!(
+                "The description fails to render as reStructuredText, {error}"
+            ));
+        }
+    }
+}
+
+/// Validates a wheel or source distribution like `twine check`: a well-formed filename,
+/// metadata that matches the filename and the tags of the WHEEL file, valid requirements, a
+/// supported Description-Content-Type, a description that renders and matching RECORD hashes
+pub fn check_distribution(path: &Path) -> Result<DistributionCheck> {
+    let mut check = DistributionCheck::default();
+    match inspect(path) {
+        Ok(inspection) => check_inspection(&inspection, &mut check),
+        // Invalid metadata, e.g. a Requires-Dist that can't be parsed
+        Err(err) => check.errors.push(format!("{err:#}")),
+    }
+    Ok(check)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_content_type, check_distribution, rst_errors};
+    use crate::module_writer::{ModuleWriter, WheelWriter};
+    use pretty_assertions::assert_eq;
+    use std::path::{Path, PathBuf};
+
+    fn write_wheel(dir: &Path, filename: &str, metadata: &str) -> PathBuf {
+        let dist_info_dir = Path::new("foo-1.0.dist-info");
+        let mut writer = WheelWriter::create(dir.join(filename), dist_info_dir).unwrap();
+        writer
+            .add_bytes(
+                dist_info_dir.join("WHEEL"),
+                b"Wheel-Version: 1.0\nRoot-Is-Purelib: true\nTag: py3-none-any\n",
+            )
+            .unwrap();
+        writer
+            .add_bytes(dist_info_dir.join("METADATA"), metadata.as_bytes())
+            .unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_check_distribution() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let valid = write_wheel(
+            tmp_dir.path(),
+            "foo-1.0-py3-none-any.whl",
+            "Metadata-Version: 2.1\nName: Foo\nVersion: 1.0\nDescription-Content-Type: text/markdown\n\n# Foo\n",
+        );
+        let check = check_distribution(&valid).unwrap();
+        assert!(check.errors.is_empty(), "{:?}", check.errors);
+        assert!(check.warnings.is_empty(), "{:?}", check.warnings);
+
+        let invalid = write_wheel(
+            tmp_dir.path(),
+            "bar-1.0-py3-none-manylinux_2_17_x86_64.whl",
+            "Metadata-Version: 2.1\nName: foo\nVersion: 1.1\n\nFoo\n=====\n",
+        );
+        let check = check_distribution(&invalid).unwrap();
+        assert_eq!(
+            check.errors,
+            [
+                "The name bar in the filename doesn't match the name foo in the metadata",
+                "The version 1.0 in the filename doesn't match the version 1.1 in the metadata",
+                "The tags py3-none-manylinux_2_17_x86_64 in the filename don't match the tags py3-none-any in the WHEEL file",
+            ]
+        );
+        assert_eq!(check.warnings.len(), 1);
+
+        let invalid_requirement = write_wheel(
+            tmp_dir.path(),
+            "foo-1.0-py3-none-any.whl",
+            "Metadata-Version: 2.1\nName: foo\nVersion: 1.0\nRequires-Dist: bar >>= 1\n",
+        );
+        let check = check_distribution(&invalid_requirement).unwrap();
+        assert_eq!(check.errors.len(), 1);
+        assert!(check.errors[0].contains("Invalid Requires-Dist bar >>= 1"));
+
+        let invalid_rst = write_wheel(
+            tmp_dir.path(),
+            "foo-1.0-py3-none-any.whl",
+            "Metadata-Version: 2.1\nName: foo\nVersion: 1.0\nDescription-Content-Type: text/x-rst\n\nLong title\n=====\n",
+        );
+        let check = check_distribution(&invalid_rst).unwrap();
+        assert!(check.errors.is_empty(), "{:?}", check.errors);
+        assert_eq!(
+            check.render_errors,
+            ["The description fails to render as reStructuredText, line 2: Title underline too short"]
+        );
+    }
+
+    #[test]
+    fn test_check_content_type() {
+        assert_eq!(
+            check_content_type("text/markdown; charset=UTF-8; variant=GFM").unwrap(),
+            "text/markdown"
+        );
+        assert_eq!(check_content_type("text/x-rst").unwrap(), "text/x-rst");
+        assert!(check_content_type("text/html").is_err());
+        assert!(check_content_type("text/plain; charset=latin-1").is_err());
+        assert!(check_content_type("text/x-rst; variant=GFM").is_err());
+    }
+
+    #[test]
+    fn test_rst_errors() {
+        let valid = "Title\n=====\n\nSome ``code`` and `a link <https://example.com>`_.\n\n\
+                     .. code-block:: python\n\n    x = ``\n\n::\n\n    ``\n";
+        assert!(rst_errors(valid).is_empty(), "{:?}", rst_errors(valid));
+        let invalid = "Long title\n=====\n\nSome ``code\nover lines.\n\n.. raw:: html\n\n\
+                       .. foo:: bar\n";
+        assert_eq!(
+            rst_errors(invalid),
+            [
+                "line 2: Title underline too short",
+                "line 4: Inline literal start-string without end-string",
+                "line 7: The \"raw\" directive is disabled on PyPI",
+                "line 9: Unknown directive type \"foo\"",
+            ]
+        );
+    }
+}
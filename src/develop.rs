@@ -58,6 +58,11 @@ pub fn develop(
         bindings,
         out: Some(wheel_dir.path().to_path_buf()),
         skip_auditwheel: false,
+        auditwheel: None,
+        sbom: Vec::new(),
+        no_sbom: false,
+        third_party_licenses: false,
+        no_embed_build_options: true,
         #[cfg(feature = "zig")]
         zig: false,
         cargo: CargoOptions {
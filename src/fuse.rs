@@ -0,0 +1,253 @@
+//! Fusing separately built x86_64 and arm64 macOS wheels into a universal2 wheel
+use crate::unpacked_wheel::UnpackedWheel;
+use anyhow::{anyhow, bail, Context, Result};
+use fat_macho::FatWriter;
+use fs_err as fs;
+use goblin::mach::constants::cputype::{CpuType, CPU_TYPE_ARM64, CPU_TYPE_X86_64};
+use goblin::mach::Mach;
+use regex::Regex;
+use std::path::{Path, PathBuf};
+
+/// Returns the deployment target of a thin macOS platform tag for `arch`, e.g. `11_0` for
+/// `macosx_11_0_arm64`
+fn deployment_target(platform_tag: &str, arch: &str) -> Option<String> {
+    let re = Regex::new(r"^macosx_(\d+)_(\d+)_([a-z0-9_]+)$").unwrap();
+    // Wheels can have multiple platform tags, e.g. `macosx_10_9_x86_64.macosx_11_0_x86_64`
+    let mut targets = Vec::new();
+    for tag in platform_tag.split('.') {
+        let captures = re.captures(tag)?;
+        if &captures[3] != arch {
+            return None;
+        }
+        targets.push((
+            captures[1].parse::<u32>().ok()?,
+            captures[2].parse::<u32>().ok()?,
+        ));
+    }
+    let (major, minor) = targets.into_iter().min()?;
+    Some(format!("{major}_{minor}"))
+}
+
+/// The cpu type of a thin Mach-O file, `None` for other files
+fn thin_macho_cputype(data: &[u8]) -> Option<CpuType> {
+    match Mach::parse(data) {
+        Ok(Mach::Binary(macho)) => Some(macho.header.cputype),
+        _ => None,
+    }
+}
+
+/// Fuses a thin x86_64 and a thin arm64 macOS wheel of the same package into a universal2
+/// wheel in `out_dir`, in either order
+///
+/// Every pair of thin Mach-O files is merged into a fat file, all other files must be identical
+/// in both wheels. The wheel gets the platform tag `maturin build --target universal2-apple-darwin`
+/// would give it.
+pub fn fuse_universal2(first: &Path, second: &Path, out_dir: &Path) -> Result<PathBuf> {
+    let first = UnpackedWheel::open(first)?;
+    let second = UnpackedWheel::open(second)?;
+    let (x86_64, arm64) = if deployment_target(&first.platform_tag, "x86_64").is_some() {
+        (first, second)
+    } else {
+        (second, first)
+    };
+    let x86_64_target = deployment_target(&x86_64.platform_tag, "x86_64").with_context(|| {
+        format!(
+            "{} is not a thin x86_64 macOS wheel, its platform tag must be macosx_*_x86_64",
+            x86_64.filename()
+        )
+    })?;
+    let arm64_target = deployment_target(&arm64.platform_tag, "arm64").with_context(|| {
+        format!(
+            "{} is not a thin arm64 macOS wheel, its platform tag must be macosx_*_arm64",
+            arm64.filename()
+        )
+    })?;
+    if (&x86_64.name, &x86_64.version, &x86_64.build_tag)
+        != (&arm64.name, &arm64.version, &arm64.build_tag)
+    {
+        bail!(
+            "{} and {} are not the same version of the same package",
+            x86_64.filename(),
+            arm64.filename()
+        );
+    }
+    if (&x86_64.python_tag, &x86_64.abi_tag) != (&arm64.python_tag, &arm64.abi_tag) {
+        bail!(
+            "{} and {} have different python or abi tags",
+            x86_64.filename(),
+            arm64.filename()
+        );
+    }
+
+    let x86_64_files = x86_64.files()?;
+    let arm64_files = arm64.files()?;
+    // RECORD is regenerated and the WHEEL files differ in their tags
+    let dist_info_dir = x86_64.dist_info_dir().to_path_buf();
+    let regenerated =
+        |file: &Path| file == dist_info_dir.join("RECORD") || file == dist_info_dir.join("WHEEL");
+    for file in arm64_files.iter().filter(|file| !regenerated(file)) {
+        if !x86_64_files.contains(file) {
+            bail!(
+                "{} is only in {}, both wheels must contain the same files",
+                file.display(),
+                arm64.filename()
+            );
+        }
+    }
+    for file in x86_64_files.iter().filter(|file| !regenerated(file)) {
+        if !arm64_files.contains(file) {
+            bail!(
+                "{} is only in {}, both wheels must contain the same files",
+                file.display(),
+                x86_64.filename()
+            );
+        }
+        let x86_64_path = x86_64.root().join(file);
+        let x86_64_data = fs::read(&x86_64_path)?;
+        let arm64_data = fs::read(arm64.root().join(file))?;
+        if x86_64_data == arm64_data {
+            continue;
+        }
+        match (
+            thin_macho_cputype(&x86_64_data),
+            thin_macho_cputype(&arm64_data),
+        ) {
+            (Some(CPU_TYPE_X86_64), Some(CPU_TYPE_ARM64)) => {}
+            (Some(_), Some(_)) => bail!(
+                "{} must be built for x86_64 in {} and for arm64 in {}",
+                file.display(),
+                x86_64.filename(),
+                arm64.filename()
+            ),
+            _ => bail!(
+                "{} differs between {} and {}, only thin Mach-O files can be fused",
+                file.display(),
+                x86_64.filename(),
+                arm64.filename()
+            ),
+        }
+        let mut writer = FatWriter::new();
+        writer
+            .add(x86_64_data)
+            .map_err(|e| anyhow!("Failed to add x86_64 {}: {:?}", file.display(), e))?;
+        writer
+            .add(arm64_data)
+            .map_err(|e| anyhow!("Failed to add arm64 {}: {:?}", file.display(), e))?;
+        writer
+            .write_to_file(&x86_64_path)
+            .map_err(|e| anyhow!("Failed to create universal {}: {:?}", file.display(), e))?;
+    }
+
+    let wheel_file = |wheel: &UnpackedWheel| -> Result<String> {
+        let contents = fs::read_to_string(wheel.root().join(wheel.dist_info_dir()).join("WHEEL"))?;
+        Ok(contents
+            .lines()
+            .filter(|line| !line.starts_with("Tag:"))
+            .collect::<Vec<_>>()
+            .join("\n"))
+    };
+    if wheel_file(&x86_64)? != wheel_file(&arm64)? {
+        bail!(
+            "The WHEEL files of {} and {} differ in more than the tags",
+            x86_64.filename(),
+            arm64.filename()
+        );
+    }
+
+    let mut universal2 = x86_64;
+    universal2.platform_tag = format!(
+        "macosx_{x86_64_target}_x86_64.macosx_{arm64_target}_arm64.macosx_{x86_64_target}_universal2"
+    );
+    universal2.write(out_dir)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{deployment_target, fuse_universal2};
+    use crate::module_writer::{ModuleWriter, WheelWriter};
+    use crate::Metadata21;
+    use fs_err as fs;
+    use goblin::mach::Mach;
+    use pep440_rs::Version;
+    use pretty_assertions::assert_eq;
+    use std::path::{Path, PathBuf};
+
+    fn write_wheel(dir: &Path, platform: &str, extension: &str, python: &str) -> PathBuf {
+        let metadata = Metadata21::new("foo".to_string(), Version::from_release(vec![1, 0]));
+        let tag = format!("cp311-cp311-{platform}");
+        fs::create_dir_all(dir).unwrap();
+        let mut writer =
+            WheelWriter::new(&tag, dir, &metadata, std::slice::from_ref(&tag), None).unwrap();
+        writer
+            .add_bytes("foo/__init__.py", python.as_bytes())
+            .unwrap();
+        writer
+            .add_file_with_permissions(
+                "foo/ext.so",
+                Path::new("tests/fixtures/universal2").join(extension),
+                0o755,
+            )
+            .unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_deployment_target() {
+        assert_eq!(
+            deployment_target("macosx_10_12_x86_64", "x86_64").as_deref(),
+            Some("10_12")
+        );
+        assert_eq!(
+            deployment_target("macosx_11_0_arm64.macosx_10_9_arm64", "arm64").as_deref(),
+            Some("10_9")
+        );
+        assert_eq!(deployment_target("macosx_11_0_arm64", "x86_64"), None);
+        assert_eq!(deployment_target("macosx_10_12_universal2", "x86_64"), None);
+    }
+
+    #[test]
+    fn test_fuse_universal2() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let x86_64 = write_wheel(
+            &tmp_dir.path().join("x86_64"),
+            "macosx_10_12_x86_64",
+            "x86_64.so",
+            "",
+        );
+        let arm64 = write_wheel(
+            &tmp_dir.path().join("arm64"),
+            "macosx_11_0_arm64",
+            "arm64.so",
+            "",
+        );
+        let out = tmp_dir.path().join("out");
+        let universal2 = fuse_universal2(&arm64, &x86_64, &out).unwrap();
+        assert_eq!(
+            universal2.file_name().unwrap(),
+            "foo-1.0-cp311-cp311-macosx_10_12_x86_64.macosx_11_0_arm64.macosx_10_12_universal2.whl"
+        );
+        let inspection = crate::inspect(&universal2).unwrap();
+        assert!(inspection.record_errors.is_empty());
+        assert_eq!(inspection.tags.len(), 3);
+        assert_eq!(inspection.native_libraries[0].format, "Mach-O");
+
+        let mut archive = zip::ZipArchive::new(fs::File::open(&universal2).unwrap()).unwrap();
+        let mut data = Vec::new();
+        std::io::Read::read_to_end(&mut archive.by_name("foo/ext.so").unwrap(), &mut data).unwrap();
+        match Mach::parse(&data).unwrap() {
+            Mach::Fat(fat) => assert_eq!(fat.narches, 2),
+            Mach::Binary(_) => panic!("foo/ext.so is not a fat binary"),
+        }
+
+        let different = write_wheel(
+            &tmp_dir.path().join("different"),
+            "macosx_11_0_arm64",
+            "arm64.so",
+            "print()",
+        );
+        let err = fuse_universal2(&x86_64, &different, &out).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("foo/__init__.py differs between"));
+    }
+}
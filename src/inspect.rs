@@ -0,0 +1,520 @@
+//! Inspecting built wheels and source distributions
+use crate::Metadata21;
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use flate2::read::GzDecoder;
+use fs_err::File;
+use goblin::mach::{Mach, SingleArch};
+use goblin::Object;
+use indexmap::IndexMap;
+use serde::Serialize;
+use sha2::{Digest, Sha256, Sha384, Sha512};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+/// Whether the inspected file is a wheel or a source distribution
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DistributionKind {
+    /// A `.whl` file
+    Wheel,
+    /// A `.tar.gz` file
+    Sdist,
+}
+
+/// A shared library or executable in a wheel or source distribution
+#[derive(Debug, Clone, Serialize)]
+pub struct NativeLibrary {
+    /// The path in the archive
+    pub path: String,
+    /// `ELF`, `Mach-O` or `PE`
+    pub format: String,
+    /// The libraries it links, i.e. the `DT_NEEDED` entries, the load commands or the imported
+    /// DLLs
+    pub needed: Vec<String>,
+}
+
+/// What maturin knows about a wheel or source distribution
+#[derive(Debug, Clone, Serialize)]
+pub struct Inspection {
+    /// The filename of the wheel or source distribution
+    pub filename: String,
+    /// Whether it is a wheel or a source distribution
+    pub kind: DistributionKind,
+    /// The `Tag` lines of the WHEEL file
+    pub tags: Vec<String>,
+    /// The `Generator` line of the WHEEL file
+    pub generator: Option<String>,
+    /// The `Root-Is-Purelib` line of the WHEEL file
+    pub root_is_purelib: Option<bool>,
+    /// The METADATA or PKG-INFO file, including the entry points of wheels
+    pub metadata: Metadata21,
+    /// The number of files in the archive
+    pub files: usize,
+    /// The number of RECORD entries whose hash and size have been verified
+    pub verified_records: usize,
+    /// The problems found when verifying RECORD, e.g. mismatching hashes or missing files
+    pub record_errors: Vec<String>,
+    /// The shared libraries and executables
+    pub native_libraries: Vec<NativeLibrary>,
+}
+
+/// Reads the regular files of a wheel or a source distribution, sorted by path
+fn read_archive(path: &Path, kind: DistributionKind) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut files = BTreeMap::new();
+    match kind {
+        DistributionKind::Wheel => {
+            let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+            for index in 0..archive.len() {
+                let mut entry = archive.by_index(index)?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content)?;
+                files.insert(entry.name().to_string(), content);
+            }
+        }
+        DistributionKind::Sdist => {
+            let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let name = entry.path()?.to_string_lossy().replace('\\', "/");
+                let mut content = Vec::new();
+                entry.read_to_end(&mut content)?;
+                files.insert(name, content);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Parses the `[section]` and `name = value` lines of entry_points.txt
+fn parse_entry_points(content: &str) -> IndexMap<String, IndexMap<String, String>> {
+    let mut entry_points: IndexMap<String, IndexMap<String, String>> = IndexMap::new();
+    let mut section = None;
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+            continue;
+        }
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            section = Some(name.trim().to_string());
+        } else if let (Some(section), Some((name, value))) = (&section, line.split_once('=')) {
+            entry_points
+                .entry(section.clone())
+                .or_default()
+                .insert(name.trim().to_string(), value.trim().to_string());
+        }
+    }
+    entry_points
+}
+
+/// Splits a RECORD line into path, hash and size. Only the path can contain commas, in which
+/// case it's quoted
+fn parse_record_line(line: &str) -> Option<(String, &str, &str)> {
+    let mut parts = line.rsplitn(3, ',');
+    let size = parts.next()?;
+    let hash = parts.next()?;
+    let path = parts.next()?;
+    let path = match path
+        .strip_prefix('"')
+        .and_then(|path| path.strip_suffix('"'))
+    {
+        Some(quoted) => quoted.replace("\"\"", "\""),
+        None => path.to_string(),
+    };
+    Some((path, hash, size))
+}
+
+/// Computes the hash of `content` in the `algorithm=digest` format of RECORD
+fn record_hash(algorithm: &str, content: &[u8]) -> Option<String> {
+    let digest = match algorithm {
+        "sha256" => Sha256::digest(content).to_vec(),
+        "sha384" => Sha384::digest(content).to_vec(),
+        "sha512" => Sha512::digest(content).to_vec(),
+        _ => return None,
+    };
+    Some(format!("{algorithm}={}", URL_SAFE_NO_PAD.encode(digest)))
+}
+
+/// Checks the hash and the size of every file listed in RECORD and that every file is listed,
+/// returning the number of verified entries and the problems
+fn verify_record(
+    record_path: &str,
+    record: &str,
+    files: &BTreeMap<String, Vec<u8>>,
+) -> (usize, Vec<String>) {
+    let mut verified = 0;
+    let mut errors = Vec::new();
+    let mut listed = BTreeSet::new();
+    for line in record.lines().filter(|line| !line.trim().is_empty()) {
+        let (path, hash, size) = match parse_record_line(line) {
+            Some(entry) => entry,
+            None => {
+                errors.push(format!("Invalid RECORD line `{line}`"));
+                continue;
+            }
+        };
+        listed.insert(path.clone());
+        let content = match files.get(&path) {
+            Some(content) => content,
+            None => {
+                errors.push(format!("{path} is listed in RECORD but missing"));
+                continue;
+            }
+        };
+        if hash.is_empty() {
+            // RECORD can't contain its own hash, neither can the signatures of RECORD
+            let unhashed = path == record_path
+                || path == format!("{record_path}.jws")
+                || path == format!("{record_path}.p7s");
+            if !unhashed {
+                errors.push(format!("{path} has no hash in RECORD"));
+            }
+            continue;
+        }
+        let algorithm = hash.split_once('=').map_or("", |(algorithm, _)| algorithm);
+        match record_hash(algorithm, content) {
+            Some(actual) if actual == hash => {}
+            Some(actual) => {
+                errors.push(format!("{path} has hash {actual}, but RECORD says {hash}"));
+                continue;
+            }
+            None => {
+                errors.push(format!("{path} uses unsupported hash `{hash}`"));
+                continue;
+            }
+        }
+        if size != content.len().to_string() {
+            errors.push(format!(
+                "{path} has a size of {} bytes, but RECORD says {size}",
+                content.len()
+            ));
+            continue;
+        }
+        verified += 1;
+    }
+    for path in files.keys() {
+        if !listed.contains(path) {
+            errors.push(format!("{path} is missing from RECORD"));
+        }
+    }
+    (verified, errors)
+}
+
+/// Returns the format and the linked libraries of shared libraries and executables
+fn native_library(content: &[u8]) -> Option<(&'static str, Vec<String>)> {
+    match Object::parse(content).ok()? {
+        Object::Elf(elf) => Some((
+            "ELF",
+            elf.libraries.iter().map(ToString::to_string).collect(),
+        )),
+        // The first entry of libs is the library itself
+        Object::Mach(Mach::Binary(macho)) => Some((
+            "Mach-O",
+            macho.libs.iter().skip(1).map(ToString::to_string).collect(),
+        )),
+        Object::Mach(Mach::Fat(fat)) => {
+            let mut needed = BTreeSet::new();
+            for index in 0..fat.narches {
+                if let Ok(SingleArch::MachO(macho)) = fat.get(index) {
+                    needed.extend(macho.libs.iter().skip(1).map(ToString::to_string));
+                }
+            }
+            Some(("Mach-O", needed.into_iter().collect()))
+        }
+        Object::PE(pe) => Some(("PE", pe.libraries.iter().map(ToString::to_string).collect())),
+        _ => None,
+    }
+}
+
+/// Opens a `.whl` or `.tar.gz` file, parses its metadata, verifies RECORD and finds the native
+/// libraries in it
+pub fn inspect(path: &Path) -> Result<Inspection> {
+    let filename = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("Invalid path {}", path.display()))?
+        .to_string();
+    let kind = if filename.ends_with(".whl") {
+        DistributionKind::Wheel
+    } else if filename.ends_with(".tar.gz") {
+        DistributionKind::Sdist
+    } else {
+        bail!("{filename} is neither a wheel (.whl) nor a source distribution (.tar.gz)");
+    };
+    let files =
+        read_archive(path, kind).with_context(|| format!("Failed to read {}", path.display()))?;
+
+    let mut tags = Vec::new();
+    let mut generator = None;
+    let mut root_is_purelib = None;
+    let mut verified_records = 0;
+    let mut record_errors = Vec::new();
+    let metadata = match kind {
+        DistributionKind::Wheel => {
+            let dist_info_dir = files
+                .keys()
+                .filter_map(|name| name.strip_suffix(".dist-info/WHEEL"))
+                .find(|dir| !dir.contains('/'))
+                .with_context(|| format!("{filename} doesn't contain a .dist-info/WHEEL file"))?
+                .to_string()
+                + ".dist-info";
+            let read_text = |name: &str| -> Result<Option<String>> {
+                files
+                    .get(&format!("{dist_info_dir}/{name}"))
+                    .map(|content| {
+                        String::from_utf8(content.clone())
+                            .with_context(|| format!("{dist_info_dir}/{name} is not utf-8"))
+                    })
+                    .transpose()
+            };
+
+            let wheel = read_text("WHEEL")?.unwrap_or_default();
+            for line in wheel.lines() {
+                match line.split_once(':') {
+                    Some(("Tag", tag)) => tags.push(tag.trim().to_string()),
+                    Some(("Generator", value)) => generator = Some(value.trim().to_string()),
+                    Some(("Root-Is-Purelib", value)) => {
+                        root_is_purelib = Some(value.trim() == "true")
+                    }
+                    _ => {}
+                }
+            }
+
+            let metadata = read_text("METADATA")?
+                .with_context(|| format!("{filename} doesn't contain {dist_info_dir}/METADATA"))?;
+            let mut metadata = Metadata21::from_file_contents(metadata.as_bytes())
+                .with_context(|| format!("Failed to parse {dist_info_dir}/METADATA"))?;
+            if let Some(entry_points) = read_text("entry_points.txt")? {
+                for (section, entries) in parse_entry_points(&entry_points) {
+                    match section.as_str() {
+                        "console_scripts" => metadata.scripts = entries,
+                        "gui_scripts" => metadata.gui_scripts = entries,
+                        _ => {
+                            metadata.entry_points.insert(section, entries);
+                        }
+                    }
+                }
+            }
+
+            let record_path = format!("{dist_info_dir}/RECORD");
+            match read_text("RECORD")? {
+                Some(record) => {
+                    (verified_records, record_errors) =
+                        verify_record(&record_path, &record, &files);
+                }
+                None => record_errors.push(format!("{record_path} is missing")),
+            }
+            metadata
+        }
+        DistributionKind::Sdist => {
+            let pkg_info = files
+                .iter()
+                .find(|(name, _)| {
+                    name.strip_suffix("/PKG-INFO")
+                        .map_or(false, |dir| !dir.contains('/'))
+                })
+                .map(|(_, content)| content)
+                .with_context(|| format!("{filename} doesn't contain a PKG-INFO file"))?;
+            Metadata21::from_file_contents(pkg_info).context("Failed to parse PKG-INFO")?
+        }
+    };
+
+    let native_libraries = files
+        .iter()
+        .filter_map(|(name, content)| {
+            let (format, needed) = native_library(content)?;
+            Some(NativeLibrary {
+                path: name.clone(),
+                format: format.to_string(),
+                needed,
+            })
+        })
+        .collect();
+    Ok(Inspection {
+        filename,
+        kind,
+        tags,
+        generator,
+        root_is_purelib,
+        metadata,
+        files: files.len(),
+        verified_records,
+        record_errors,
+        native_libraries,
+    })
+}
+
+impl fmt::Display for Inspection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            DistributionKind::Wheel => "wheel",
+            DistributionKind::Sdist => "source distribution",
+        };
+        writeln!(f, "📦 {} ({kind})", self.filename)?;
+        let metadata = &self.metadata;
+        writeln!(f, "Name: {}", metadata.name)?;
+        writeln!(f, "Version: {}", metadata.version)?;
+        writeln!(f, "Metadata-Version: {}", metadata.metadata_version)?;
+        if let Some(summary) = &metadata.summary {
+            writeln!(f, "Summary: {summary}")?;
+        }
+        if let Some(license) = &metadata.license {
+            writeln!(f, "License: {license}")?;
+        }
+        if let Some(license_expression) = &metadata.license_expression {
+            writeln!(f, "License-Expression: {license_expression}")?;
+        }
+        for license_file in &metadata.license_files {
+            writeln!(f, "License-File: {}", license_file.display())?;
+        }
+        if let Some(requires_python) = &metadata.requires_python {
+            writeln!(f, "Requires-Python: {requires_python}")?;
+        }
+        for requirement in &metadata.requires_dist {
+            writeln!(f, "Requires-Dist: {requirement}")?;
+        }
+        for extra in &metadata.provides_extra {
+            writeln!(f, "Provides-Extra: {extra}")?;
+        }
+        for (label, url) in &metadata.project_url {
+            writeln!(f, "Project-URL: {label}, {url}")?;
+        }
+        if self.kind == DistributionKind::Wheel {
+            writeln!(f, "Tags: {}", self.tags.join(", "))?;
+            if let Some(generator) = &self.generator {
+                writeln!(f, "Generator: {generator}")?;
+            }
+            if let Some(root_is_purelib) = self.root_is_purelib {
+                writeln!(f, "Root-Is-Purelib: {root_is_purelib}")?;
+            }
+        }
+        let entry_points = [
+            ("console_scripts", &metadata.scripts),
+            ("gui_scripts", &metadata.gui_scripts),
+        ]
+        .into_iter()
+        .chain(
+            metadata
+                .entry_points
+                .iter()
+                .map(|(section, entries)| (section.as_str(), entries)),
+        )
+        .filter(|(_, entries)| !entries.is_empty());
+        for (section, entries) in entry_points {
+            writeln!(f, "Entry points [{section}]:")?;
+            for (name, value) in entries {
+                writeln!(f, "    {name} = {value}")?;
+            }
+        }
+        writeln!(f, "Files: {}", self.files)?;
+        if self.kind == DistributionKind::Wheel {
+            if self.record_errors.is_empty() {
+                writeln!(f, "RECORD: {} hashes verified", self.verified_records)?;
+            } else {
+                writeln!(f, "RECORD: {} errors", self.record_errors.len())?;
+                for error in &self.record_errors {
+                    writeln!(f, "    {error}")?;
+                }
+            }
+        }
+        for library in &self.native_libraries {
+            writeln!(f, "Native library {} ({}):", library.path, library.format)?;
+            for needed in &library.needed {
+                writeln!(f, "    needs {needed}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{inspect, verify_record, DistributionKind};
+    use crate::module_writer::{ModuleWriter, WheelWriter};
+    use pretty_assertions::assert_eq;
+    use std::collections::BTreeMap;
+    use std::path::Path;
+
+    #[test]
+    fn test_inspect_wheel() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let dist_info_dir = Path::new("foo-1.0.dist-info");
+        let mut writer = WheelWriter::create(
+            tmp_dir
+                .path()
+                .join("foo-1.0-py3-none-manylinux_2_17_x86_64.whl"),
+            dist_info_dir,
+        )
+        .unwrap();
+        writer
+            .add_bytes(
+                dist_info_dir.join("WHEEL"),
+                b"Wheel-Version: 1.0\nGenerator: maturin (1.0.0)\nRoot-Is-Purelib: false\nTag: py3-none-manylinux_2_17_x86_64\n",
+            )
+            .unwrap();
+        writer
+            .add_bytes(
+                dist_info_dir.join("METADATA"),
+                b"Metadata-Version: 2.1\nName: foo\nVersion: 1.0\nRequires-Dist: bar>=1\n",
+            )
+            .unwrap();
+        writer
+            .add_bytes(
+                dist_info_dir.join("entry_points.txt"),
+                b"[console_scripts]\nfoo=foo:main\n",
+            )
+            .unwrap();
+        writer
+            .add_file("foo/ext.so", Path::new("tests/fixtures/abi3/elf.so"))
+            .unwrap();
+        let wheel = writer.finish().unwrap();
+
+        let inspection = inspect(&wheel).unwrap();
+        assert_eq!(inspection.kind, DistributionKind::Wheel);
+        assert_eq!(inspection.tags, ["py3-none-manylinux_2_17_x86_64"]);
+        assert_eq!(inspection.generator.as_deref(), Some("maturin (1.0.0)"));
+        assert_eq!(inspection.metadata.name, "foo");
+        assert_eq!(inspection.metadata.requires_dist[0].name, "bar");
+        assert_eq!(inspection.metadata.scripts["foo"], "foo:main");
+        assert_eq!(inspection.files, 5);
+        assert_eq!(inspection.verified_records, 4);
+        assert!(inspection.record_errors.is_empty());
+        assert_eq!(inspection.native_libraries.len(), 1);
+        assert_eq!(inspection.native_libraries[0].format, "ELF");
+    }
+
+    #[test]
+    fn test_verify_record() {
+        let files = BTreeMap::from([
+            ("a.py".to_string(), b"a".to_vec()),
+            ("b,c.py".to_string(), b"b".to_vec()),
+            ("d.py".to_string(), Vec::new()),
+            ("foo.dist-info/RECORD".to_string(), Vec::new()),
+        ]);
+        let record = "a.py,sha256=ypeBEsobvcr6wjGzmiPcTaeG7_gUfE5yuYB3ha_uSLs,1\n\
+                      \"b,c.py\",sha256=ypeBEsobvcr6wjGzmiPcTaeG7_gUfE5yuYB3ha_uSLs,1\n\
+                      e.py,,\n\
+                      foo.dist-info/RECORD,,\n";
+        let (verified, errors) = verify_record("foo.dist-info/RECORD", record, &files);
+        assert_eq!(verified, 1);
+        assert_eq!(
+            errors,
+            [
+                "b,c.py has hash sha256=PiPoFgA5WUoziU9lZOGxNIu9egCI1CxKy3PurtWcAJ0, but RECORD says sha256=ypeBEsobvcr6wjGzmiPcTaeG7_gUfE5yuYB3ha_uSLs",
+                "e.py is listed in RECORD but missing",
+                "d.py is missing from RECORD",
+            ]
+        );
+    }
+}
@@ -26,31 +26,43 @@
 pub use crate::build_context::{BridgeModel, BuildContext, BuiltWheelMetadata};
 pub use crate::build_options::{BuildOptions, CargoOptions};
 pub use crate::cargo_toml::CargoToml;
+pub use crate::check::{check_distribution, DistributionCheck};
 pub use crate::compile::{compile, BuildArtifact};
 pub use crate::develop::develop;
+pub use crate::fuse::fuse_universal2;
+pub use crate::inspect::{inspect, DistributionKind, Inspection, NativeLibrary};
 pub use crate::metadata::{Metadata21, WheelMetadata};
 pub use crate::module_writer::{
     write_dist_info, ModuleWriter, PathWriter, SDistWriter, WheelWriter,
 };
 #[cfg(feature = "scaffolding")]
 pub use crate::new_project::{init_project, new_project, GenerateProjectOptions};
-pub use crate::pyproject_toml::PyProjectToml;
+pub use crate::pyproject_toml::{AuditwheelConfig, PyProjectToml};
 pub use crate::python_interpreter::PythonInterpreter;
+pub use crate::sbom::SbomFormat;
+pub use crate::source_distribution::check_source_distribution;
 pub use crate::target::Target;
+pub use crate::unpacked_wheel::UnpackedWheel;
 #[cfg(feature = "upload")]
 pub use crate::upload::{upload, upload_ui, PublishOpt, Registry, UploadError};
-pub use auditwheel::PlatformTag;
+pub use auditwheel::{
+    audit_wheel, repair_wheel, report_wheel, retag_wheel, AuditWheelMode, ElfAudit, ElfReport,
+    PlatformTag, PolicyOverrides, ReportFormat, WheelAudit, WheelReport,
+};
 
 mod auditwheel;
 mod build_context;
 mod build_options;
 mod cargo_toml;
+mod check;
 #[cfg(feature = "scaffolding")]
 /// Generate CI configuration
 pub mod ci;
 mod compile;
 mod cross_compile;
 mod develop;
+mod fuse;
+mod inspect;
 mod metadata;
 mod module_writer;
 #[cfg(feature = "scaffolding")]
@@ -58,7 +70,12 @@ mod new_project;
 mod project_layout;
 pub mod pyproject_toml;
 mod python_interpreter;
+mod reproducible;
+mod sbom;
 mod source_distribution;
+mod spdx;
 mod target;
+mod third_party_licenses;
+mod unpacked_wheel;
 #[cfg(feature = "upload")]
 mod upload;
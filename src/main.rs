@@ -9,16 +9,18 @@ use cargo_zigbuild::Zig;
 #[cfg(feature = "cli-completion")]
 use clap::CommandFactory;
 use clap::{Parser, Subcommand};
-#[cfg(feature = "scaffolding")]
-use maturin::{ci::GenerateCI, init_project, new_project, GenerateProjectOptions};
 use maturin::{
-    develop, write_dist_info, BridgeModel, BuildOptions, CargoOptions, PathWriter, PlatformTag,
-    PythonInterpreter, Target,
+    audit_wheel, check_source_distribution, develop, fuse_universal2, inspect, repair_wheel,
+    report_wheel, retag_wheel, write_dist_info, AuditWheelMode, AuditwheelConfig, BridgeModel,
+    BuildOptions, CargoOptions, PathWriter, PlatformTag, PolicyOverrides, PyProjectToml,
+    PythonInterpreter, ReportFormat, SbomFormat, Target,
 };
+#[cfg(feature = "scaffolding")]
+use maturin::{ci::GenerateCI, init_project, new_project, GenerateProjectOptions};
 #[cfg(feature = "upload")]
 use maturin::{upload_ui, PublishOpt};
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use tracing::debug;
 
 #[derive(Debug, Parser)]
@@ -44,6 +46,10 @@ enum Opt {
         /// Build a source distribution
         #[arg(long)]
         sdist: bool,
+        /// Build everything a second time into a temporary directory and fail if the archives
+        /// differ, listing the differing entries
+        #[arg(long)]
+        check_reproducible: bool,
         #[command(flatten)]
         build: BuildOptions,
     },
@@ -60,6 +66,10 @@ enum Opt {
         /// Don't build a source distribution
         #[arg(long = "no-sdist")]
         no_sdist: bool,
+        /// Build wheels from the source distribution before uploading, to check that it
+        /// contains everything needed to build
+        #[arg(long, conflicts_with = "no_sdist")]
+        check_sdist: bool,
         #[command(flatten)]
         publish: PublishOpt,
         #[command(flatten)]
@@ -109,14 +119,164 @@ enum Opt {
     ///
     /// This command is a workaround for [pypa/pip#6041](https://github.com/pypa/pip/issues/6041)
     #[command(name = "sdist")]
+    // The rustc flags of the cargo options can't be embedded, they're rejected below
+    #[command(mut_arg("args", |arg| arg.hide(true)))]
     SDist {
-        #[arg(short = 'm', long = "manifest-path")]
-        /// The path to the Cargo.toml
-        manifest_path: Option<PathBuf>,
-        /// The directory to store the built wheels in. Defaults to a new "wheels"
+        /// Vendor the Rust dependencies with `cargo vendor`, so that the sdist can be built
+        /// without network access
+        #[arg(long)]
+        vendor: bool,
+        /// Build a wheel from the source distribution in a temporary directory, to check that
+        /// it contains everything needed to build
+        #[arg(long)]
+        check: bool,
+        /// The directory to store the built source distribution in. Defaults to a new "wheels"
         /// directory in the project's target directory
         #[arg(short, long)]
         out: Option<PathBuf>,
+        /// Which kind of bindings to use
+        #[arg(short, long, value_parser = ["pyo3", "pyo3-ffi", "rust-cpython", "cffi", "uniffi", "bin"])]
+        bindings: Option<String>,
+        /// Control the platform tag on linux when building from the source distribution
+        #[arg(
+            id = "compatibility",
+            long = "compatibility",
+            alias = "manylinux",
+            num_args = 0..,
+            action = clap::ArgAction::Append
+        )]
+        platform_tag: Vec<PlatformTag>,
+        /// What to do with external shared libraries and compliance problems found by auditing
+        /// the wheels built from the source distribution
+        #[arg(long, value_enum)]
+        auditwheel: Option<AuditWheelMode>,
+        /// Add a software bill of materials in this format to the wheels built from the source
+        /// distribution, can be repeated
+        #[arg(long, value_enum, value_name = "FORMAT", action = clap::ArgAction::Append)]
+        sbom: Vec<SbomFormat>,
+        /// Don't add software bills of materials to the wheels built from the source distribution
+        #[arg(long, conflicts_with = "sbom")]
+        no_sbom: bool,
+        /// Add the license texts of the linked Rust crates to the wheels built from the source
+        /// distribution
+        #[arg(long)]
+        third_party_licenses: bool,
+        /// Don't write the build options given on the command line, like features or the
+        /// compatibility, into the `[tool.maturin]` section of the source distribution
+        #[arg(long)]
+        no_embed_build_options: bool,
+        #[command(flatten)]
+        cargo: CargoOptions,
+    },
+    /// Check existing wheels for manylinux/musllinux compliance
+    ///
+    /// Reports the highest compatible platform tag of every shared library and executable
+    /// in the wheel and which external libraries need to be grafted by `maturin repair`
+    #[command(name = "audit")]
+    Audit {
+        /// Check compliance with the given `manylinux`/`musllinux` tag instead of
+        /// determining the highest compatible tag
+        #[arg(
+            id = "compatibility",
+            long = "compatibility",
+            alias = "manylinux",
+            num_args = 0..,
+            action = clap::ArgAction::Append
+        )]
+        platform_tag: Vec<PlatformTag>,
+        /// Print a detailed report with the needed libraries, the versioned symbols and the
+        /// policies they disqualify for every elf file
+        #[arg(
+            long,
+            value_enum,
+            value_name = "FORMAT",
+            num_args = 0..=1,
+            require_equals = true,
+            default_missing_value = "text"
+        )]
+        report: Option<ReportFormat>,
+        /// The wheels to audit
+        #[arg(value_name = "WHEEL", required = true)]
+        wheels: Vec<PathBuf>,
+    },
+    /// Graft external shared libraries into existing wheels and retag them
+    ///
+    /// This is the same repair `maturin build` does for freshly compiled wheels
+    #[command(name = "repair")]
+    Repair {
+        /// The platform tags for the repaired wheels. Defaults to the highest compatible
+        /// `manylinux`/`musllinux` tag
+        #[arg(
+            id = "compatibility",
+            long = "compatibility",
+            alias = "manylinux",
+            num_args = 0..,
+            action = clap::ArgAction::Append
+        )]
+        platform_tag: Vec<PlatformTag>,
+        /// The directory to store the repaired wheels in
+        #[arg(short, long, default_value = "wheelhouse")]
+        out: PathBuf,
+        /// Keep rpath entries pointing outside of the wheel
+        #[arg(long)]
+        keep_rpath: bool,
+        /// The wheels to repair
+        #[arg(value_name = "WHEEL", required = true)]
+        wheels: Vec<PathBuf>,
+    },
+    /// Change the tags of existing wheels without rebuilding them
+    ///
+    /// Rewrites the filename and the WHEEL file and regenerates RECORD. Platform tags the
+    /// binaries in the wheel don't comply with are refused, use `maturin repair` to graft
+    /// external libraries first
+    #[command(name = "retag")]
+    Retag {
+        /// The new python tags, e.g. `py3` or `cp38.cp39`
+        #[arg(long, value_name = "TAG")]
+        python_tag: Vec<String>,
+        /// The new abi tags, e.g. `abi3` or `none`
+        #[arg(long, value_name = "TAG")]
+        abi_tag: Vec<String>,
+        /// The new platform tags, e.g. `manylinux_2_17_x86_64`
+        #[arg(long, value_name = "TAG")]
+        platform_tag: Vec<String>,
+        /// Add the given tags to the existing ones instead of replacing them
+        #[arg(long)]
+        add: bool,
+        /// The directory to store the retagged wheels in
+        #[arg(short, long, default_value = "wheelhouse")]
+        out: PathBuf,
+        /// The wheels to retag
+        #[arg(value_name = "WHEEL", required = true)]
+        wheels: Vec<PathBuf>,
+    },
+    /// Fuse an x86_64 and an arm64 macOS wheel into a universal2 wheel
+    ///
+    /// The wheels must have been built from the same sources, e.g. on different CI runners.
+    /// Every pair of thin Mach-O files is merged into a universal binary, all other files must be
+    /// identical
+    #[command(name = "fuse")]
+    Fuse {
+        /// The directory to store the universal2 wheel in
+        #[arg(short, long, default_value = "wheelhouse")]
+        out: PathBuf,
+        /// The x86_64 and the arm64 wheel, in any order
+        #[arg(value_name = "WHEEL", num_args = 2, required = true)]
+        wheels: Vec<PathBuf>,
+    },
+    /// Show the metadata, tags, RECORD and native libraries of wheels and sdists
+    ///
+    /// Prints the tags, the METADATA/PKG-INFO fields, the entry points, the result of verifying
+    /// every RECORD hash and the linked libraries of every native library. Fails if the RECORD
+    /// file of a wheel doesn't match its content
+    #[command(name = "inspect")]
+    Inspect {
+        /// Print JSON instead of a summary
+        #[arg(long)]
+        json: bool,
+        /// The wheels and source distributions to inspect
+        #[arg(value_name = "FILE", required = true)]
+        files: Vec<PathBuf>,
     },
     /// Create a new cargo project in an existing directory
     #[cfg(feature = "scaffolding")]
@@ -260,6 +420,18 @@ fn detect_venv(target: &Target) -> Result<PathBuf> {
     )
 }
 
+/// Reads `[tool.maturin.auditwheel]` from the pyproject.toml in the current directory, if any
+fn auditwheel_config() -> Result<AuditwheelConfig> {
+    let pyproject_file = Path::new("pyproject.toml");
+    if !pyproject_file.is_file() {
+        return Ok(AuditwheelConfig::default());
+    }
+    Ok(PyProjectToml::new(pyproject_file)?
+        .auditwheel()
+        .cloned()
+        .unwrap_or_default())
+}
+
 /// Dispatches into the native implementations of the PEP 517 functions
 ///
 /// The last line of stdout is used as return value from the python part of the implementation
@@ -355,15 +527,23 @@ fn run() -> Result<()> {
             release,
             strip,
             sdist,
+            check_reproducible,
         } => {
             let build_context = build.into_build_context(release, strip, false)?;
+            let mut built = Vec::new();
             if sdist {
-                build_context
-                    .build_source_distribution()?
-                    .context("Failed to build source distribution, pyproject.toml not found")?;
+                built.push(
+                    build_context
+                        .build_source_distribution()?
+                        .context("Failed to build source distribution, pyproject.toml not found")?,
+                );
             }
             let wheels = build_context.build_wheels()?;
             assert!(!wheels.is_empty());
+            if check_reproducible {
+                built.extend(wheels);
+                build_context.check_reproducible(&built)?;
+            }
         }
         #[cfg(feature = "upload")]
         Opt::Publish {
@@ -372,8 +552,12 @@ fn run() -> Result<()> {
             debug,
             no_strip,
             no_sdist,
+            check_sdist,
         } => {
-            let build_context = build.into_build_context(!debug, !no_strip, false)?;
+            let mut build_context = build.clone().into_build_context(!debug, !no_strip, false)?;
+            // Stripping is the default when publishing, so only `--strip` of `maturin build`
+            // gets embedded into the source distribution
+            build_context.sdist_strip = false;
 
             if !build_context.release {
                 eprintln!("⚠️  Warning: You're publishing debug wheels");
@@ -382,6 +566,9 @@ fn run() -> Result<()> {
             let mut wheels = build_context.build_wheels()?;
             if !no_sdist {
                 if let Some(sd) = build_context.build_source_distribution()? {
+                    if check_sdist {
+                        check_source_distribution(&sd.0, build, !debug, !no_strip)?;
+                    }
                     wheels.push(sd);
                 }
             }
@@ -415,19 +602,157 @@ fn run() -> Result<()> {
             let venv_dir = detect_venv(&target)?;
             develop(bindings, cargo_options, &venv_dir, release, strip, extras)?;
         }
-        Opt::SDist { manifest_path, out } => {
+        Opt::SDist {
+            vendor,
+            check,
+            out,
+            bindings,
+            platform_tag,
+            auditwheel,
+            sbom,
+            no_sbom,
+            third_party_licenses,
+            no_embed_build_options,
+            cargo,
+        } => {
+            if !cargo.args.is_empty() {
+                bail!(
+                    "`maturin sdist` doesn't take rustc flags, \
+                    set `rustc-args` in `[tool.maturin]` instead"
+                );
+            }
             let build_options = BuildOptions {
+                platform_tag,
+                bindings,
                 out,
-                cargo: CargoOptions {
-                    manifest_path,
-                    ..Default::default()
-                },
+                auditwheel,
+                sbom,
+                no_sbom,
+                third_party_licenses,
+                no_embed_build_options,
+                cargo,
                 ..Default::default()
             };
-            let build_context = build_options.into_build_context(false, false, false)?;
-            build_context
+            let mut build_context = build_options
+                .clone()
+                .into_build_context(false, false, false)?;
+            build_context.sdist_vendor |= vendor;
+            let (sdist_path, _) = build_context
                 .build_source_distribution()?
                 .context("Failed to build source distribution, pyproject.toml not found")?;
+            if check {
+                check_source_distribution(&sdist_path, build_options, false, false)?;
+            }
+        }
+        Opt::Audit {
+            platform_tag,
+            report,
+            wheels,
+        } => {
+            let overrides = PolicyOverrides::from_config(&auditwheel_config()?, Path::new("."))?;
+            let mut reports = Vec::new();
+            let mut failed = Vec::new();
+            for wheel_path in wheels {
+                let audit = audit_wheel(&wheel_path, &platform_tag, &overrides)?;
+                match report {
+                    Some(ReportFormat::Text) => println!("{}", report_wheel(&audit)?),
+                    Some(ReportFormat::Json) => reports.push(report_wheel(&audit)?),
+                    None => {
+                        for elf in &audit.elf_files {
+                            eprintln!("🔍 {}: {}", elf.path.display(), elf.policy);
+                            for lib in &elf.external_libs {
+                                eprintln!("    needs external library {}", lib.name);
+                            }
+                        }
+                    }
+                }
+                if let Err(err) = audit.ensure_compliant() {
+                    eprintln!("💥 {}: {err:#}", wheel_path.display());
+                    failed.push(wheel_path.display().to_string());
+                } else if audit.needs_repair() {
+                    eprintln!(
+                        "⚠️  Warning: {} links external libraries, run `maturin repair` to graft them",
+                        wheel_path.display()
+                    );
+                } else {
+                    eprintln!(
+                        "📦 {} is consistent with the {} platform tag",
+                        wheel_path.display(),
+                        audit.policy()
+                    );
+                }
+            }
+            if report == Some(ReportFormat::Json) {
+                println!("{}", serde_json::to_string_pretty(&reports)?);
+            }
+            if !failed.is_empty() {
+                bail!(
+                    "{} don't comply with the requested platform tag",
+                    failed.join(", ")
+                );
+            }
+        }
+        Opt::Repair {
+            platform_tag,
+            out,
+            keep_rpath,
+            wheels,
+        } => {
+            let config = auditwheel_config()?;
+            let overrides = PolicyOverrides::from_config(&config, Path::new("."))?;
+            let keep_rpath = keep_rpath || config.keep_rpath;
+            for wheel_path in wheels {
+                let audit = audit_wheel(&wheel_path, &platform_tag, &overrides)?;
+                let repaired = repair_wheel(audit, &platform_tag, &out, keep_rpath)?;
+                eprintln!("📦 Repaired wheel written to {}", repaired.display());
+            }
+        }
+        Opt::Retag {
+            python_tag,
+            abi_tag,
+            platform_tag,
+            add,
+            out,
+            wheels,
+        } => {
+            let overrides = PolicyOverrides::from_config(&auditwheel_config()?, Path::new("."))?;
+            for wheel_path in wheels {
+                let retagged = retag_wheel(
+                    &wheel_path,
+                    &python_tag,
+                    &abi_tag,
+                    &platform_tag,
+                    add,
+                    &overrides,
+                    &out,
+                )?;
+                eprintln!("📦 Retagged wheel written to {}", retagged.display());
+            }
+        }
+        Opt::Fuse { out, wheels } => {
+            let universal2 = fuse_universal2(&wheels[0], &wheels[1], &out)?;
+            eprintln!("📦 Universal2 wheel written to {}", universal2.display());
+        }
+        Opt::Inspect { json, files } => {
+            let mut inspections = Vec::new();
+            for path in files {
+                let inspection = inspect(&path)?;
+                if !json {
+                    println!("{inspection}");
+                }
+                inspections.push(inspection);
+            }
+            if json {
+                println!("{}", serde_json::to_string_pretty(&inspections)?);
+            }
+            let invalid: Vec<_> = inspections
+                .iter()
+                .filter(|inspection| !inspection.record_errors.is_empty())
+                .map(|inspection| inspection.filename.as_str())
+                .collect();
+            if !invalid.is_empty() {
+                bail!("RECORD doesn't match the content of {}", invalid.join(", "));
+            }
         }
         Opt::Pep517(subcommand) => pep517(subcommand)?,
         #[cfg(feature = "scaffolding")]
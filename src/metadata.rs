@@ -1,22 +1,26 @@
+use crate::pyproject_toml::VersionProvider;
+use crate::source_distribution::clean_path;
+use crate::spdx::{normalize_cargo_license, normalize_license_expression};
 use crate::PyProjectToml;
 use anyhow::{bail, format_err, Context, Result};
 use fs_err as fs;
 use indexmap::IndexMap;
 use pep440_rs::{Version, VersionSpecifiers};
 use pep508_rs::{MarkerExpression, MarkerOperator, MarkerTree, MarkerValue, Requirement};
-use pyproject_toml::License;
+use pyproject_toml::{License, LicenseFiles};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Write as _;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
 use std::str;
 use std::str::FromStr;
 
 /// The metadata required to generate the .dist-info directory
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct WheelMetadata {
-    /// Python Package Metadata 2.1
+    /// Python Package Metadata 2.4
     pub metadata21: Metadata21,
     /// The `[console_scripts]` for the entry_points.txt
     pub scripts: HashMap<String, String>,
@@ -26,8 +30,10 @@ pub struct WheelMetadata {
     pub module_name: String,
 }
 
-/// Python Package Metadata 2.1 as specified in
+/// Python Package Metadata 2.4 as specified in
 /// https://packaging.python.org/specifications/core-metadata/
+///
+/// The name dates back to metadata version 2.1, which was the first version maturin supported
 #[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 #[allow(missing_docs)]
@@ -50,8 +56,14 @@ pub struct Metadata21 {
     pub maintainer: Option<String>,
     pub maintainer_email: Option<String>,
     pub license: Option<String>,
-    // https://peps.python.org/pep-0639/#license-file-multiple-use
+    // https://peps.python.org/pep-0639/#add-license-expression-field
+    pub license_expression: Option<String>,
+    // https://peps.python.org/pep-0639/#add-license-file-field
     pub license_files: Vec<PathBuf>,
+    /// The project root the `License-File` entries are relative to, `None` if the license files
+    /// are relative paths already
+    #[serde(skip)]
+    pub license_files_root: Option<PathBuf>,
     pub classifiers: Vec<String>,
     pub requires_dist: Vec<Requirement>,
     pub provides_dist: Vec<String>,
@@ -60,6 +72,8 @@ pub struct Metadata21 {
     pub requires_external: Vec<String>,
     pub project_url: IndexMap<String, String>,
     pub provides_extra: Vec<String>,
+    // https://peps.python.org/pep-0643/
+    pub dynamic: Vec<String>,
     pub scripts: IndexMap<String, String>,
     pub gui_scripts: IndexMap<String, String>,
     pub entry_points: IndexMap<String, IndexMap<String, String>>,
@@ -69,7 +83,7 @@ impl Metadata21 {
     /// Initializes with name, version and otherwise the defaults
     pub fn new(name: String, version: Version) -> Self {
         Self {
-            metadata_version: "2.1".to_string(),
+            metadata_version: "2.4".to_string(),
             name,
             version,
             platform: vec![],
@@ -85,7 +99,9 @@ impl Metadata21 {
             maintainer: None,
             maintainer_email: None,
             license: None,
+            license_expression: None,
             license_files: vec![],
+            license_files_root: None,
             classifiers: vec![],
             requires_dist: vec![],
             provides_dist: vec![],
@@ -94,6 +110,7 @@ impl Metadata21 {
             requires_external: vec![],
             project_url: Default::default(),
             provides_extra: vec![],
+            dynamic: vec![],
             scripts: Default::default(),
             gui_scripts: Default::default(),
             entry_points: Default::default(),
@@ -122,9 +139,143 @@ fn path_to_content_type(path: &Path) -> String {
         })
 }
 
+/// Parses the output of `git describe --tags --long --dirty` into a PEP 440 version, e.g.
+/// `v1.2.0-3-g1a2b3c4-dirty` becomes `1.2.0.post3+g1a2b3c4.dirty`
+fn version_from_git_describe(describe: &str, tag_prefix: Option<&str>) -> Result<Version> {
+    let (describe, dirty) = match describe.strip_suffix("-dirty") {
+        Some(describe) => (describe, true),
+        None => (describe, false),
+    };
+    let mut parts = describe.rsplitn(3, '-');
+    let (hash, distance, tag) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(hash), Some(distance), Some(tag)) => (hash, distance, tag),
+        _ => bail!("Unexpected output of `git describe`: {describe}"),
+    };
+    let version = match tag_prefix {
+        Some(tag_prefix) => tag.strip_prefix(tag_prefix).with_context(|| {
+            format!("The git tag {tag} doesn't start with the tag prefix {tag_prefix}")
+        })?,
+        None => tag.strip_prefix('v').unwrap_or(tag),
+    };
+    let tag_version = Version::from_str(version).map_err(|err| {
+        format_err!(
+            "The version {version} from the git tag {tag} is not a valid PEP 440 version: {err}"
+        )
+    })?;
+    // A post release can't have another post release or come after a dev release, and there
+    // can only be one local version
+    if distance != "0" && (tag_version.is_post() || tag_version.is_dev() || tag_version.is_local())
+    {
+        bail!(
+            "The git tag {tag} must be a release or pre-release version such as 1.2.0 or 1.2.0rc1 \
+            without a post, dev or local part, since the commits after it are added as \
+            .post{distance}+{hash}"
+        );
+    }
+    if dirty && tag_version.is_local() {
+        bail!(
+            "The git tag {tag} must not have a local version since uncommitted changes are \
+            added as +dirty"
+        );
+    }
+    let mut version = version.to_string();
+    if distance != "0" {
+        write!(version, ".post{distance}+{hash}")?;
+    }
+    if dirty {
+        version.push_str(if distance == "0" { "+dirty" } else { ".dirty" });
+    }
+    Version::from_str(&version).map_err(|err| {
+        format_err!(
+            "The version {version} from the git tag {tag} is not a valid PEP 440 version: {err}"
+        )
+    })
+}
+
+/// Determines the version with a `[tool.maturin.version]` provider
+fn provided_version(provider: &VersionProvider, pyproject_dir: &Path) -> Result<Version> {
+    match provider {
+        VersionProvider::Git { tag_prefix } => {
+            let mut command = Command::new("git");
+            command
+                .args(["describe", "--tags", "--long", "--dirty"])
+                .current_dir(pyproject_dir);
+            if let Some(tag_prefix) = tag_prefix {
+                command.arg(format!("--match={tag_prefix}*"));
+            }
+            let output = command
+                .output()
+                .context("Failed to run `git describe` for the version")?;
+            if !output.status.success() {
+                bail!(
+                    "Failed to determine the version with `git describe`, is there a tag?\n{}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                );
+            }
+            let describe = str::from_utf8(&output.stdout)
+                .context("git printed invalid utf-8")?
+                .trim();
+            version_from_git_describe(describe, tag_prefix.as_deref())
+        }
+        VersionProvider::File { path, pattern } => {
+            let pattern = pattern
+                .as_deref()
+                .unwrap_or(r#"__version__\s*=\s*["']([^"']+)["']"#);
+            let regex = Regex::new(pattern)
+                .with_context(|| format!("Invalid version pattern `{pattern}`"))?;
+            let contents = fs::read_to_string(pyproject_dir.join(path))
+                .context("Failed to read the file with the version")?;
+            let captures = regex.captures(&contents).with_context(|| {
+                format!(
+                    "Couldn't find the version in {} with the pattern `{pattern}`",
+                    path.display()
+                )
+            })?;
+            let version = captures
+                .name("version")
+                .or_else(|| captures.get(1))
+                .with_context(|| {
+                    format!(
+                        "The version pattern `{pattern}` must have a `version` or another group"
+                    )
+                })?
+                .as_str();
+            Version::from_str(version).map_err(|err| {
+                format_err!(
+                    "The version {version} in {} is not a valid PEP 440 version: {err}",
+                    path.display()
+                )
+            })
+        }
+    }
+}
+
+/// The core metadata fields a `[project]` field of pyproject.toml is written to, for the
+/// `Dynamic` entries of the sdist. The version and the entry points have none.
+fn core_metadata_fields(pyproject_field: &str) -> &'static [&'static str] {
+    match pyproject_field {
+        "description" => &["Summary"],
+        "readme" => &["Description", "Description-Content-Type"],
+        "requires-python" => &["Requires-Python"],
+        "license" => &["License", "License-Expression"],
+        "license-files" => &["License-File"],
+        "authors" => &["Author", "Author-email"],
+        "maintainers" => &["Maintainer", "Maintainer-email"],
+        "keywords" => &["Keywords"],
+        "classifiers" => &["Classifier"],
+        "urls" => &["Home-Page", "Project-URL"],
+        "dependencies" => &["Requires-Dist"],
+        "optional-dependencies" => &["Requires-Dist", "Provides-Extra"],
+        _ => &[],
+    }
+}
+
 impl Metadata21 {
     /// Merge metadata with pyproject.toml, where pyproject.toml takes precedence
     ///
+    /// Following PEP 621, the values from Cargo.toml are only kept for the fields listed in
+    /// `project.dynamic`, which are recorded as `Dynamic` for the sdist.
+    ///
     /// pyproject_dir must be the directory containing pyproject.toml
     pub fn merge_pyproject_toml(
         &mut self,
@@ -132,9 +283,96 @@ impl Metadata21 {
         pyproject_toml: &PyProjectToml,
     ) -> Result<()> {
         let pyproject_dir = pyproject_dir.as_ref();
+        // The directory containing pyproject.toml is the root of the source distribution
+        self.license_files_root = Some(pyproject_dir.to_path_buf());
         if let Some(project) = &pyproject_toml.project {
             self.name = project.name.clone();
 
+            let dynamic = project.dynamic.as_deref().unwrap_or_default();
+            let is_dynamic = |field: &str| dynamic.iter().any(|dynamic| dynamic == field);
+            let static_fields = [
+                ("version", project.version.is_some()),
+                ("description", project.description.is_some()),
+                ("readme", project.readme.is_some()),
+                ("requires-python", project.requires_python.is_some()),
+                ("license", project.license.is_some()),
+                ("license-files", project.license_files.is_some()),
+                ("authors", project.authors.is_some()),
+                ("maintainers", project.maintainers.is_some()),
+                ("keywords", project.keywords.is_some()),
+                ("classifiers", project.classifiers.is_some()),
+                ("urls", project.urls.is_some()),
+                ("dependencies", project.dependencies.is_some()),
+                (
+                    "optional-dependencies",
+                    project.optional_dependencies.is_some(),
+                ),
+                ("scripts", project.scripts.is_some()),
+                ("gui-scripts", project.gui_scripts.is_some()),
+                ("entry-points", project.entry_points.is_some()),
+            ];
+            for field in dynamic {
+                if field == "name" {
+                    bail!("`project.name` must not be listed in `project.dynamic`, it must be set statically in pyproject.toml");
+                }
+                match static_fields.iter().find(|(name, _)| name == field) {
+                    Some((_, true)) => bail!(
+                        "`project.{field}` is listed in `project.dynamic`, but is also set in pyproject.toml. \
+                         A field must be either static or dynamic"
+                    ),
+                    Some((_, false)) => {}
+                    None => bail!("Unknown field `{field}` in `project.dynamic`"),
+                }
+            }
+
+            // Cargo.toml provides these fields, which must stay empty unless they are dynamic
+            if project.version.is_none()
+                && !is_dynamic("version")
+                && pyproject_toml.version_provider().is_none()
+            {
+                eprintln!(
+                    "⚠️  Warning: `project.version` is neither set nor listed in `project.dynamic` \
+                     in pyproject.toml, using the version from Cargo.toml. \
+                     Add `dynamic = [\"version\"]` to the `[project]` table"
+                );
+            }
+            if !is_dynamic("description") {
+                self.summary = None;
+            }
+            if !is_dynamic("readme") {
+                self.description = None;
+                self.description_content_type = None;
+            }
+            if !is_dynamic("authors") {
+                self.author = None;
+                self.author_email = None;
+            }
+            if !is_dynamic("license") {
+                self.license = None;
+                self.license_expression = None;
+            }
+            if !is_dynamic("license-files") {
+                self.license_files.clear();
+            }
+            if !is_dynamic("keywords") {
+                self.keywords = None;
+            }
+            if !is_dynamic("urls") {
+                self.home_page = None;
+                self.project_url.clear();
+            }
+            // https://peps.python.org/pep-0643/, the version can't be dynamic in an sdist
+            let mut dynamic_fields = Vec::new();
+            for field in dynamic {
+                let core_metadata_fields = core_metadata_fields(field);
+                for core_metadata_field in core_metadata_fields {
+                    if !dynamic_fields.iter().any(|f| f == core_metadata_field) {
+                        dynamic_fields.push(core_metadata_field.to_string());
+                    }
+                }
+            }
+            self.dynamic = dynamic_fields;
+
             if let Some(version) = &project.version {
                 self.version = version.clone();
             }
@@ -183,8 +421,13 @@ impl Metadata21 {
 
             if let Some(license) = &project.license {
                 match license {
-                    // TODO: switch to License-Expression core metadata, see https://peps.python.org/pep-0639/#add-license-expression-field
-                    License::String(license_expr) => self.license = Some(license_expr.clone()),
+                    License::String(license_expr) => {
+                        let license_expr = normalize_license_expression(license_expr)
+                            .context("Invalid `project.license` in pyproject.toml")?;
+                        // License must not be set together with License-Expression
+                        self.license = None;
+                        self.license_expression = Some(license_expr);
+                    }
                     License::Table { file, text } => match (file, text) {
                         (Some(_), Some(_)) => {
                             bail!("file and text fields of 'project.license' are mutually-exclusive, only one of them should be specified");
@@ -193,29 +436,52 @@ impl Metadata21 {
                             let license_path = pyproject_dir.join(license_path);
                             self.license_files.push(license_path);
                         }
-                        (None, Some(license_text)) => self.license = Some(license_text.clone()),
+                        (None, Some(license_text)) => {
+                            self.license = Some(license_text.clone());
+                            self.license_expression = None;
+                        }
                         (None, None) => {}
                     },
                 }
             }
 
-            // Until PEP 639 is approved with metadata 2.3, we can assume a
-            // dynamic license-files (also awaiting full 2.2 metadata support)
-            // We're already emitting the License-Files metadata without issue.
-            // license-files.globs = ["LICEN[CS]E*", "COPYING*", "NOTICE*", "AUTHORS*"]
-            let license_include_targets = ["LICEN[CS]E*", "COPYING*", "NOTICE*", "AUTHORS*"];
-            let escaped_manifest_string = glob::Pattern::escape(pyproject_dir.to_str().unwrap());
-            let escaped_manifest_path = Path::new(&escaped_manifest_string);
-            for pattern in license_include_targets.iter() {
-                for license_path in
-                    glob::glob(&escaped_manifest_path.join(pattern).to_string_lossy())?
-                        .filter_map(Result::ok)
-                {
-                    // if the pyproject.toml specified the license file,
-                    // then we won't list it as automatically included
-                    if !self.license_files.contains(&license_path) {
-                        eprintln!("📦 Including license file \"{}\"", license_path.display());
-                        self.license_files.push(license_path);
+            // `project.license-files` defaults to
+            // ["LICEN[CS]E*", "COPYING*", "NOTICE*", "AUTHORS*"]
+            match project.license_files.clone().unwrap_or_default() {
+                LicenseFiles::Paths(paths) => {
+                    for path in paths.unwrap_or_default() {
+                        let license_path = pyproject_dir.join(&path);
+                        if !license_path.is_file() {
+                            bail!(
+                                "License file `{}` from `project.license-files` doesn't exist",
+                                path
+                            );
+                        }
+                        if !self.license_files.contains(&license_path) {
+                            self.license_files.push(license_path);
+                        }
+                    }
+                }
+                LicenseFiles::Globs(patterns) => {
+                    let escaped_manifest_string =
+                        glob::Pattern::escape(pyproject_dir.to_str().unwrap());
+                    let escaped_manifest_path = Path::new(&escaped_manifest_string);
+                    for pattern in patterns.unwrap_or_default() {
+                        for license_path in
+                            glob::glob(&escaped_manifest_path.join(pattern).to_string_lossy())?
+                                .filter_map(Result::ok)
+                                .filter(|path| path.is_file())
+                        {
+                            // if the pyproject.toml specified the license file,
+                            // then we won't list it as automatically included
+                            if !self.license_files.contains(&license_path) {
+                                eprintln!(
+                                    "📦 Including license file \"{}\"",
+                                    license_path.display()
+                                );
+                                self.license_files.push(license_path);
+                            }
+                        }
                     }
                 }
             }
@@ -325,6 +591,20 @@ impl Metadata21 {
                 self.entry_points = entry_points.clone();
             }
         }
+
+        if let Some(provider) = pyproject_toml.version_provider() {
+            if let Some(project) = &pyproject_toml.project {
+                if !project
+                    .dynamic
+                    .iter()
+                    .flatten()
+                    .any(|field| field == "version")
+                {
+                    bail!("`[tool.maturin.version]` requires `version` in `project.dynamic` in pyproject.toml");
+                }
+            }
+            self.version = provided_version(provider, pyproject_dir)?;
+        }
         Ok(())
     }
 
@@ -382,6 +662,13 @@ impl Metadata21 {
         if let Some(repository) = package.repository.as_ref() {
             project_url.insert("Source Code".to_string(), repository.clone());
         }
+        let (license, license_expression) = match package.license.as_deref() {
+            Some(license) => match normalize_cargo_license(license) {
+                Ok(license_expression) => (None, Some(license_expression)),
+                Err(_) => (Some(license.to_string()), None),
+            },
+            None => (None, None),
+        };
         let license_files = if let Some(license_file) = package.license_file.as_ref() {
             vec![manifest_path.as_ref().join(license_file)]
         } else {
@@ -417,8 +704,10 @@ impl Metadata21 {
                 Some(authors)
             },
             author_email,
-            license: package.license.clone(),
+            license,
+            license_expression,
             license_files,
+            license_files_root: Some(manifest_path.as_ref().to_path_buf()),
             project_url,
             ..Metadata21::new(name, version)
         };
@@ -456,12 +745,17 @@ impl Metadata21 {
         add_vec("Obsoletes-Dist", &self.obsoletes_dist);
         add_vec("Requires-External", &self.requires_external);
         add_vec("Provides-Extra", &self.provides_extra);
-
-        let license_files: Vec<String> = self
-            .license_files
-            .iter()
-            .map(|path| path.file_name().unwrap().to_str().unwrap().to_string())
-            .collect();
+        add_vec("Dynamic", &self.dynamic);
+
+        let mut license_files: Vec<String> = Vec::new();
+        for path in &self.license_files {
+            if let Some(license_file) = self.license_file_path(path) {
+                let license_file = license_file_field(&license_file);
+                if !license_files.contains(&license_file) {
+                    license_files.push(license_file);
+                }
+            }
+        }
         add_vec("License-File", &license_files);
 
         let mut add_option = |name, value: &Option<String>| {
@@ -479,6 +773,7 @@ impl Metadata21 {
         add_option("Maintainer", &self.maintainer);
         add_option("Maintainer-email", &self.maintainer_email);
         add_option("License", &self.license.as_deref().map(fold_header));
+        add_option("License-Expression", &self.license_expression);
         add_option(
             "Requires-Python",
             &self
@@ -532,6 +827,99 @@ impl Metadata21 {
         Ok(out)
     }
 
+    /// Parses the format of the metadata file inside wheels and of PKG-INFO in source
+    /// distributions, the inverse of [Metadata21::to_file_contents]
+    ///
+    /// The entry points are not part of the metadata file and stay empty
+    pub fn from_file_contents(content: &[u8]) -> Result<Self> {
+        let pkginfo = python_pkginfo::Metadata::parse(content)
+            .map_err(|err| format_err!("Failed to parse the metadata: {}", err))?;
+        let version = Version::from_str(&pkginfo.version)
+            .map_err(|err| format_err!("Invalid version {}: {}", pkginfo.version, err))?;
+        let requires_dist = pkginfo
+            .requires_dist
+            .iter()
+            .map(|requirement| {
+                Requirement::from_str(requirement)
+                    .with_context(|| format!("Invalid Requires-Dist {requirement}"))
+            })
+            .collect::<Result<_>>()?;
+        let requires_python = pkginfo
+            .requires_python
+            .as_deref()
+            .map(|requires_python| {
+                VersionSpecifiers::from_str(requires_python)
+                    .map_err(|err| format_err!("Invalid Requires-Python {requires_python}: {err}"))
+            })
+            .transpose()?;
+        let mut project_url = IndexMap::new();
+        for value in &pkginfo.project_urls {
+            match value.split_once(',') {
+                Some((label, url)) => {
+                    project_url.insert(label.trim().to_string(), url.trim().to_string())
+                }
+                None => bail!("Invalid Project-URL {value}, expected a label and a URL"),
+            };
+        }
+        // python-pkginfo doesn't know about PEP 639 yet
+        let headers = |name: &str| -> Vec<String> {
+            str::from_utf8(content)
+                .unwrap_or_default()
+                .lines()
+                .take_while(|line| !line.is_empty())
+                .filter_map(|line| line.split_once(':'))
+                .filter(|(key, _)| key.eq_ignore_ascii_case(name))
+                .map(|(_, value)| value.trim().to_string())
+                .collect()
+        };
+        let license_expression = headers("License-Expression").into_iter().next();
+        let license_files = headers("License-File")
+            .into_iter()
+            .map(PathBuf::from)
+            .collect();
+
+        Ok(Self {
+            metadata_version: pkginfo.metadata_version,
+            name: pkginfo.name,
+            version,
+            platform: pkginfo.platforms,
+            supported_platform: pkginfo.supported_platforms,
+            summary: pkginfo.summary,
+            // to_file_contents adds a newline after the description
+            description: pkginfo.description.map(|description| {
+                description
+                    .strip_suffix('\n')
+                    .unwrap_or(&description)
+                    .to_string()
+            }),
+            description_content_type: pkginfo.description_content_type,
+            keywords: pkginfo.keywords,
+            home_page: pkginfo.home_page,
+            download_url: pkginfo.download_url,
+            author: pkginfo.author,
+            author_email: pkginfo.author_email,
+            maintainer: pkginfo.maintainer,
+            maintainer_email: pkginfo.maintainer_email,
+            license: pkginfo.license,
+            license_expression,
+            license_files,
+            // The `License-File` entries are relative to the project root already
+            license_files_root: None,
+            classifiers: pkginfo.classifiers,
+            requires_dist,
+            provides_dist: pkginfo.provides_dist,
+            obsoletes_dist: pkginfo.obsoletes_dist,
+            requires_python,
+            requires_external: pkginfo.requires_external,
+            project_url,
+            provides_extra: pkginfo.provides_extras,
+            dynamic: pkginfo.dynamic,
+            scripts: Default::default(),
+            gui_scripts: Default::default(),
+            entry_points: Default::default(),
+        })
+    }
+
     /// Returns the distribution name according to PEP 427, Section "Escaping
     /// and Unicode"
     pub fn get_distribution_escaped(&self) -> String {
@@ -553,6 +941,63 @@ impl Metadata21 {
             &self.get_version_escaped()
         ))
     }
+
+    /// Returns the path of a license file relative to the project root, which is where it's
+    /// placed in the source distribution and in `.dist-info/licenses`
+    ///
+    /// License files outside of the project root, e.g. a `license-file = "../LICENSE"` in
+    /// Cargo.toml, are placed at the root under their file name.
+    fn license_file_path(&self, path: &Path) -> Option<PathBuf> {
+        let relative = match &self.license_files_root {
+            Some(root) => clean_path(path)
+                .strip_prefix(clean_path(root))
+                .ok()
+                .map(Path::to_path_buf),
+            None => Some(path.to_path_buf()),
+        };
+        relative
+            .filter(|relative| {
+                relative
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+            })
+            .or_else(|| path.file_name().map(PathBuf::from))
+    }
+
+    /// Returns the license files with their path relative to the project root, failing if two
+    /// different files end up at the same path
+    ///
+    /// https://peps.python.org/pep-0639/#add-license-file-field
+    pub fn license_file_paths(&self) -> Result<Vec<(PathBuf, &Path)>> {
+        let mut license_files: Vec<(PathBuf, &Path)> = Vec::new();
+        for path in &self.license_files {
+            let relative = self.license_file_path(path).with_context(|| {
+                format!("missing file name for license file {}", path.display())
+            })?;
+            if let Some((_, other)) = license_files.iter().find(|(other, _)| *other == relative) {
+                if fs::canonicalize(other)? == fs::canonicalize(path)? {
+                    continue;
+                }
+                bail!(
+                    "The license files {} and {} would both be included as {}, \
+                    only one of them can be included",
+                    other.display(),
+                    path.display(),
+                    relative.display()
+                );
+            }
+            license_files.push((relative, path));
+        }
+        Ok(license_files)
+    }
+}
+
+/// Formats a relative path as `License-File` entry, which always uses `/` as separator
+fn license_file_field(path: &Path) -> String {
+    path.components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
 }
 
 /// Fold long header field according to RFC 5322 section 2.2.3
@@ -655,6 +1100,7 @@ mod test {
             homepage = "https://example.org"
             readme = "REPLACE_README_PATH"
             keywords = ["ffi", "test"]
+            license = "MIT/Apache-2.0"
 
             [lib]
             crate-type = ["cdylib"]
@@ -664,7 +1110,7 @@ mod test {
 
         let expected = indoc!(
             r#"
-            Metadata-Version: 2.1
+            Metadata-Version: 2.4
             Name: info-project
             Version: 0.1.0
             Summary: A test project
@@ -672,6 +1118,7 @@ mod test {
             Home-Page: https://example.org
             Author: konstin <konstin@mailbox.org>
             Author-email: konstin <konstin@mailbox.org>
+            License-Expression: MIT OR Apache-2.0
             Description-Content-Type: text/markdown; charset=UTF-8; variant=GFM
 
             # Some test package
@@ -744,7 +1191,8 @@ mod test {
                     .unwrap(),
             ]
         );
-        assert_eq!(metadata.license.as_ref().unwrap(), "MIT");
+        // `project.license` is static, so the license from Cargo.toml isn't used
+        assert_eq!(metadata.license_expression, None);
 
         let license_file = &metadata.license_files[0];
         assert_eq!(license_file.file_name().unwrap(), "LICENSE");
@@ -752,6 +1200,18 @@ mod test {
         let content = metadata.to_file_contents().unwrap();
         let pkginfo: Result<python_pkginfo::Metadata, _> = content.parse();
         assert!(pkginfo.is_ok());
+
+        let parsed = Metadata21::from_file_contents(content.as_bytes()).unwrap();
+        // Only the relative paths of the license files and no entry points are in the metadata file
+        let expected = Metadata21 {
+            license_files: vec![PathBuf::from("LICENSE")],
+            license_files_root: None,
+            scripts: Default::default(),
+            gui_scripts: Default::default(),
+            entry_points: Default::default(),
+            ..metadata
+        };
+        assert_eq!(parsed, expected);
     }
 
     #[test]
@@ -803,8 +1263,8 @@ mod test {
             .merge_pyproject_toml(&manifest_dir, &pyproject_toml)
             .unwrap();
 
-        // verify Cargo.toml value came through
-        assert_eq!(metadata.license.as_ref().unwrap(), "MIT");
+        // `project.license` is static, so the license from Cargo.toml isn't used
+        assert_eq!(metadata.license_expression, None);
 
         // verify we have the total number of expected licenses
         assert_eq!(4, metadata.license_files.len());
@@ -817,4 +1277,196 @@ mod test {
         assert_eq!(metadata.license_files[2], manifest_dir.join("NOTICE.md"));
         assert_eq!(metadata.license_files[3], manifest_dir.join("AUTHORS.txt"));
     }
+
+    #[test]
+    fn test_merge_metadata_from_pyproject_dynamic() {
+        let manifest_dir = PathBuf::from("test-crates").join("pyo3-mixed-py-subdir");
+        let cargo_metadata = MetadataCommand::new()
+            .manifest_path(manifest_dir.join("Cargo.toml"))
+            .exec()
+            .unwrap();
+        let merge = |project: &str| {
+            let pyproject_dir = tempfile::tempdir().unwrap();
+            let pyproject_file = pyproject_dir.path().join("pyproject.toml");
+            let build_system = "[build-system]\nrequires = [\"maturin\"]\n";
+            fs::write(&pyproject_file, format!("{build_system}{project}")).unwrap();
+            let pyproject_toml = PyProjectToml::new(&pyproject_file).unwrap();
+            let mut metadata = Metadata21::from_cargo_toml(&manifest_dir, &cargo_metadata).unwrap();
+            metadata
+                .merge_pyproject_toml(pyproject_dir.path(), &pyproject_toml)
+                .map(|_| metadata)
+        };
+
+        let metadata = merge(indoc!(
+            r#"
+            [project]
+            name = "pyo3-mixed-py-subdir"
+            dynamic = ["version", "readme", "classifiers"]
+            description = "A static summary"
+            "#
+        ))
+        .unwrap();
+        assert_eq!(metadata.version.to_string(), "2.1.3");
+        assert_eq!(metadata.summary.as_deref(), Some("A static summary"));
+        assert!(metadata.description.unwrap().starts_with("# pyo3-mixed"));
+        // Not dynamic, so not taken from Cargo.toml
+        assert_eq!(metadata.author, None);
+        assert_eq!(
+            metadata.dynamic,
+            ["Description", "Description-Content-Type", "Classifier"]
+        );
+
+        let err = merge(indoc!(
+            r#"
+            [project]
+            name = "pyo3-mixed-py-subdir"
+            version = "1.0.0"
+            dynamic = ["version"]
+            "#
+        ))
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`project.version` is listed in `project.dynamic`, but is also set in pyproject.toml. \
+             A field must be either static or dynamic"
+        );
+        for dynamic in ["name", "unknown"] {
+            let project =
+                format!("[project]\nname = \"pyo3-mixed-py-subdir\"\ndynamic = [\"{dynamic}\"]\n");
+            assert!(merge(&project).is_err());
+        }
+    }
+
+    #[test]
+    fn test_version_providers() {
+        let cases = [
+            ("v1.2.0-0-g1a2b3c4", None, "1.2.0"),
+            ("1.2.0-3-g1a2b3c4", None, "1.2.0.post3+g1a2b3c4"),
+            ("v1.2.0-0-g1a2b3c4-dirty", None, "1.2.0+dirty"),
+            (
+                "v1.2.0-3-g1a2b3c4-dirty",
+                None,
+                "1.2.0.post3+g1a2b3c4.dirty",
+            ),
+            ("foo-v0.1.0rc1-0-g1a2b3c4", Some("foo-v"), "0.1.0rc1"),
+        ];
+        for (describe, tag_prefix, version) in cases {
+            assert_eq!(
+                version_from_git_describe(describe, tag_prefix)
+                    .unwrap()
+                    .to_string(),
+                version
+            );
+        }
+        assert!(version_from_git_describe("release-0-g1a2b3c4", None).is_err());
+        assert!(version_from_git_describe("v1.0-0-g1a2b3c4", Some("foo-v")).is_err());
+        // Post, dev and local versions are fine as long as nothing conflicting is appended
+        for (describe, version) in [
+            ("v1.0.post1-0-g1a2b3c4", "1.0.post1"),
+            ("v1.0+foo-0-g1a2b3c4", "1.0+foo"),
+            ("v1.0.post1-0-g1a2b3c4-dirty", "1.0.post1+dirty"),
+        ] {
+            assert_eq!(
+                version_from_git_describe(describe, None)
+                    .unwrap()
+                    .to_string(),
+                version
+            );
+        }
+        for describe in [
+            "v1.0.post1-3-g1a2b3c4",
+            "v1.0.dev1-3-g1a2b3c4",
+            "v1.0+foo-3-g1a2b3c4",
+            "v1.0+foo-0-g1a2b3c4-dirty",
+        ] {
+            let err = version_from_git_describe(describe, None).unwrap_err();
+            assert!(
+                err.to_string().starts_with(&format!(
+                    "The git tag {} must",
+                    describe.split('-').next().unwrap()
+                )),
+                "{describe}: {err}"
+            );
+        }
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        fs::write(
+            tmp_dir.path().join("__about__.py"),
+            "__version__ = '2.0.1'\nVERSION_INFO = (2, 0, 1)\n",
+        )
+        .unwrap();
+        let file = |pattern: Option<&str>| VersionProvider::File {
+            path: PathBuf::from("__about__.py"),
+            pattern: pattern.map(ToString::to_string),
+        };
+        assert_eq!(
+            provided_version(&file(None), tmp_dir.path())
+                .unwrap()
+                .to_string(),
+            "2.0.1"
+        );
+        assert_eq!(
+            provided_version(
+                &file(Some(r"VERSION_INFO = \((?P<version>\d+, \d+)")),
+                tmp_dir.path()
+            )
+            .unwrap_err()
+            .to_string(),
+            "The version 2, 0 in __about__.py is not a valid PEP 440 version: \
+             Version `2, 0` doesn't match PEP 440 rules"
+        );
+    }
+
+    #[test]
+    fn test_license_file_paths() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let project_root = temp_dir.path().join("project");
+        fs::create_dir_all(project_root.join("licenses")).unwrap();
+        fs::write(temp_dir.path().join("LICENSE"), "workspace license").unwrap();
+        fs::write(project_root.join("LICENSE"), "project license").unwrap();
+        fs::write(project_root.join("licenses").join("MIT.txt"), "MIT").unwrap();
+
+        let metadata = Metadata21 {
+            license_files: vec![
+                project_root.join("licenses").join("MIT.txt"),
+                // A Cargo.toml `license-file = "../LICENSE"`
+                project_root.join("..").join("LICENSE"),
+            ],
+            license_files_root: Some(project_root.clone()),
+            ..Metadata21::new("foo".to_string(), Version::from_str("0.1.0").unwrap())
+        };
+        let paths: Vec<_> = metadata
+            .license_file_paths()
+            .unwrap()
+            .into_iter()
+            .map(|(relative, _)| relative)
+            .collect();
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("licenses").join("MIT.txt"),
+                PathBuf::from("LICENSE")
+            ]
+        );
+        let license_files: Vec<_> = metadata
+            .to_vec()
+            .into_iter()
+            .filter(|(key, _)| key == "License-File")
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(license_files, ["licenses/MIT.txt", "LICENSE"]);
+
+        // The workspace license would overwrite the project license
+        let metadata = Metadata21 {
+            license_files: vec![
+                project_root.join("LICENSE"),
+                project_root.join("..").join("LICENSE"),
+            ],
+            ..metadata
+        };
+        let err = metadata.license_file_paths().unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("would both be included as LICENSE"));
+    }
 }
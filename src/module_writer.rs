@@ -16,7 +16,8 @@ use ignore::WalkBuilder;
 use indexmap::IndexMap;
 use normpath::PathExt as _;
 use sha2::{Digest, Sha256};
-use std::collections::{HashMap, HashSet};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap};
 use std::env;
 use std::ffi::OsStr;
 use std::fmt::Write as _;
@@ -79,6 +80,9 @@ pub trait ModuleWriter {
             .context(format!("Failed to write to {}", target.display()))?;
         Ok(())
     }
+
+    /// Keeps a temporary directory alive until the files added from it have been written
+    fn keep_temp_dir(&mut self, _temp_dir: TempDir) {}
 }
 
 /// A [ModuleWriter] that adds the module somewhere in the filesystem, e.g. in a virtualenv
@@ -204,13 +208,139 @@ impl ModuleWriter for PathWriter {
     }
 }
 
+/// A [ModuleWriter] that doesn't write anything, but collects the elf files that would be
+/// added, so they can be audited before the wheel is written
+pub(crate) struct ElfCollector {
+    /// The target path in the wheel and the source path of every elf file
+    pub files: Vec<(PathBuf, PathBuf)>,
+    excludes: Option<Override>,
+}
+
+impl ElfCollector {
+    /// Creates a collector which skips the files excluded from the wheel
+    pub fn new(excludes: Option<Override>) -> Self {
+        Self {
+            files: Vec::new(),
+            excludes,
+        }
+    }
+}
+
+impl ModuleWriter for ElfCollector {
+    fn add_directory(&mut self, _path: impl AsRef<Path>) -> Result<()> {
+        Ok(())
+    }
+
+    fn add_bytes_with_permissions(
+        &mut self,
+        _target: impl AsRef<Path>,
+        _bytes: &[u8],
+        _permissions: u32,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    fn add_file_with_permissions(
+        &mut self,
+        target: impl AsRef<Path>,
+        source: impl AsRef<Path>,
+        _permissions: u32,
+    ) -> Result<()> {
+        let target = target.as_ref();
+        if let Some(excludes) = &self.excludes {
+            if excludes.matched(target, false).is_whitelist() {
+                return Ok(());
+            }
+        }
+        let mut magic = [0; 4];
+        let mut file = File::open(source.as_ref())?;
+        if file.read_exact(&mut magic).is_ok() && magic == *b"\x7fELF" {
+            self.files
+                .push((target.to_path_buf(), source.as_ref().to_path_buf()));
+        }
+        Ok(())
+    }
+}
+
+/// The content of a file in a wheel or source distribution
+#[derive(Debug, PartialEq, Eq)]
+enum ArchiveSource {
+    /// Generated content, e.g. the metadata
+    Bytes(Vec<u8>),
+    /// A file on disk, which is only read when the archive is written
+    File(PathBuf),
+}
+
+impl ArchiveSource {
+    /// Opens the content for streaming it into the archive
+    fn open(&self) -> io::Result<Box<dyn Read + '_>> {
+        match self {
+            ArchiveSource::Bytes(bytes) => Ok(Box::new(bytes.as_slice())),
+            ArchiveSource::File(path) => Ok(Box::new(File::open(path)?)),
+        }
+    }
+
+    /// Returns the size of the content
+    fn len(&self) -> io::Result<u64> {
+        match self {
+            ArchiveSource::Bytes(bytes) => Ok(bytes.len() as u64),
+            ArchiveSource::File(path) => Ok(fs::metadata(path)?.len()),
+        }
+    }
+
+    /// Describes where the content comes from for error messages
+    fn describe(&self) -> String {
+        match self {
+            ArchiveSource::Bytes(_) => "generated content".to_string(),
+            ArchiveSource::File(path) => path.display().to_string(),
+        }
+    }
+
+    /// Adds the source path to errors reading the file
+    fn read_error(&self, err: io::Error) -> io::Error {
+        match self {
+            ArchiveSource::Bytes(_) => err,
+            ArchiveSource::File(path) => io::Error::new(
+                err.kind(),
+                format!("Failed to read {}: {}", path.display(), err),
+            ),
+        }
+    }
+}
+
+/// Copies `reader` to `writer`, returning the urlsafe base64 encoded sha256 hash and the size
+/// of the content
+fn copy_hashed(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<(String, u64)> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 64 * 1024];
+    let mut len = 0;
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        writer.write_all(&buffer[..read])?;
+        len += read as u64;
+    }
+    Ok((URL_SAFE_NO_PAD.encode(hasher.finalize()), len))
+}
+
 /// A glorified zip builder, mostly useful for writing the record file of a wheel
+///
+/// The files are only written in [WheelWriter::finish], sorted by path with the .dist-info
+/// directory last, so the wheel doesn't depend on the order in which files were added. Files
+/// added from disk are only read at that point, so they must not change or be removed before.
 pub struct WheelWriter {
     zip: ZipWriter<File>,
-    record: Vec<(String, String, usize)>,
+    /// The content and the unix permissions of every file by its zip path
+    files: BTreeMap<String, (ArchiveSource, u32)>,
     record_file: PathBuf,
     wheel_path: PathBuf,
     excludes: Option<Override>,
+    replacements: HashMap<PathBuf, PathBuf>,
+    injections: BTreeMap<PathBuf, String>,
+    temp_dirs: Vec<TempDir>,
 }
 
 impl ModuleWriter for WheelWriter {
@@ -228,32 +358,49 @@ impl ModuleWriter for WheelWriter {
         if self.exclude(target) {
             return Ok(());
         }
-        // The zip standard mandates using unix style paths
-        let target = target.to_str().unwrap().replace('\\', "/");
-
-        // Unlike users which can use the develop subcommand, the tests have to go through
-        // packing a zip which pip than has to unpack. This makes this 2-3 times faster
-        let compression_method = if cfg!(feature = "faster-tests") {
-            zip::CompressionMethod::Stored
-        } else {
-            zip::CompressionMethod::Deflated
-        };
-
-        let mut options = zip::write::FileOptions::default()
-            .unix_permissions(permissions)
-            .compression_method(compression_method);
-        let mtime = self.mtime().ok();
-        if let Some(mtime) = mtime {
-            options = options.last_modified_time(mtime);
+        if let Some(code) = self.injections.remove(target) {
+            let source = String::from_utf8_lossy(bytes);
+            let bytes = insert_python_code(&source, &code);
+            return self.add_bytes_with_permissions(target, bytes.as_bytes(), permissions);
         }
+        self.insert(target, ArchiveSource::Bytes(bytes.to_vec()), permissions)
+    }
 
-        self.zip.start_file(target.clone(), options)?;
-        self.zip.write_all(bytes)?;
+    fn add_file_with_permissions(
+        &mut self,
+        target: impl AsRef<Path>,
+        source: impl AsRef<Path>,
+        permissions: u32,
+    ) -> Result<()> {
+        let target = target.as_ref();
+        let source = self
+            .replacements
+            .get(target)
+            .map_or(source.as_ref(), PathBuf::as_path);
+        debug!("Adding {} from {}", target.display(), source.display());
 
-        let hash = URL_SAFE_NO_PAD.encode(Sha256::digest(bytes));
-        self.record.push((target, hash, bytes.len()));
+        if self.exclude(target) {
+            return Ok(());
+        }
+        let read_failed_context = format!("Failed to read {}", source.display());
+        if self.injections.contains_key(target) {
+            // The code is inserted into the content, so we have to read it now
+            let buffer = fs::read(source).context(read_failed_context)?;
+            self.add_bytes_with_permissions(target, &buffer, permissions)
+                .context(format!("Failed to write to {}", target.display()))?;
+            return Ok(());
+        }
+        // Fail early instead of in `finish` if the file can't be read
+        File::open(source).context(read_failed_context)?;
+        self.insert(
+            target,
+            ArchiveSource::File(source.to_path_buf()),
+            permissions,
+        )
+    }
 
-        Ok(())
+    fn keep_temp_dir(&mut self, temp_dir: TempDir) {
+        self.temp_dirs.push(temp_dir);
     }
 }
 
@@ -279,10 +426,13 @@ impl WheelWriter {
 
         let mut builder = WheelWriter {
             zip: ZipWriter::new(file),
-            record: Vec::new(),
+            files: BTreeMap::new(),
             record_file: metadata21.get_dist_info_dir().join("RECORD"),
             wheel_path,
             excludes,
+            replacements: HashMap::new(),
+            injections: BTreeMap::new(),
+            temp_dirs: Vec::new(),
         };
 
         write_dist_info(&mut builder, metadata21, tags)?;
@@ -290,6 +440,35 @@ impl WheelWriter {
         Ok(builder)
     }
 
+    /// Create a new wheel file without writing a .dist-info directory, for rewriting wheels
+    /// that already contain their metadata
+    pub(crate) fn create(wheel_path: PathBuf, dist_info_dir: &Path) -> Result<WheelWriter> {
+        let file = File::create(&wheel_path)?;
+        Ok(WheelWriter {
+            zip: ZipWriter::new(file),
+            files: BTreeMap::new(),
+            record_file: dist_info_dir.join("RECORD"),
+            wheel_path,
+            excludes: None,
+            replacements: HashMap::new(),
+            injections: BTreeMap::new(),
+            temp_dirs: Vec::new(),
+        })
+    }
+
+    /// Adds `source` instead whenever a file is added at `target`, e.g. the repaired copy of
+    /// a prebuilt shared library
+    pub(crate) fn replace_file(&mut self, target: impl Into<PathBuf>, source: impl Into<PathBuf>) {
+        self.replacements.insert(target.into(), source.into());
+    }
+
+    /// Inserts python `code` at the top of the python file at `target`, after the docstring and
+    /// `__future__` imports, e.g. to set up the DLL search path in `__init__.py`. The file is
+    /// created if the wheel doesn't contain it.
+    pub(crate) fn inject_code(&mut self, target: impl Into<PathBuf>, code: impl Into<String>) {
+        self.injections.insert(target.into(), code.into());
+    }
+
     /// Add a pth file to wheel root for editable installs
     pub fn add_pth(
         &mut self,
@@ -328,57 +507,172 @@ impl WheelWriter {
         }
     }
 
-    /// Returns a DateTime representing the value SOURCE_DATE_EPOCH environment variable
-    /// Note that the earliest timestamp a zip file can represent is 1980-01-01
-    fn mtime(&self) -> Result<DateTime> {
-        let epoch: i64 = env::var("SOURCE_DATE_EPOCH")?.parse()?;
-        let dt = time::OffsetDateTime::from_unix_timestamp(epoch)?;
-        let min_dt = time::Date::from_calendar_date(1980, time::Month::January, 1)
-            .unwrap()
-            .midnight()
-            .assume_offset(time::UtcOffset::UTC);
-        let dt = dt.max(min_dt);
-
-        let dt = DateTime::try_from(dt).map_err(|_| anyhow!("Failed to build zip DateTime"))?;
-        Ok(dt)
+    /// Adds the file at `target`, failing if different content was already added there
+    fn insert(&mut self, target: &Path, source: ArchiveSource, permissions: u32) -> Result<()> {
+        // The zip standard mandates using unix style paths
+        let target = target.to_str().unwrap().replace('\\', "/");
+        match self.files.entry(target) {
+            Entry::Vacant(entry) => {
+                entry.insert((source, normalize_permissions(permissions)));
+            }
+            // Adding the same file twice is harmless
+            Entry::Occupied(entry) if entry.get().0 == source => {}
+            Entry::Occupied(entry) => bail!(
+                "{} was already added to the wheel from {}, can't add it again from {}",
+                entry.key(),
+                entry.get().0.describe(),
+                source.describe()
+            ),
+        }
+        Ok(())
     }
 
-    /// Creates the record file and finishes the zip
+    /// Writes the files sorted by path with the .dist-info directory and the record file last,
+    /// creates the record file and finishes the zip
     pub fn finish(mut self) -> Result<PathBuf, io::Error> {
+        for (target, code) in std::mem::take(&mut self.injections) {
+            self.add_bytes(&target, code.as_bytes())
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+        }
+
+        // Unlike users which can use the develop subcommand, the tests have to go through
+        // packing a zip which pip than has to unpack. This makes this 2-3 times faster
         let compression_method = if cfg!(feature = "faster-tests") {
             zip::CompressionMethod::Stored
         } else {
             zip::CompressionMethod::Deflated
         };
-
-        let mut options = zip::write::FileOptions::default().compression_method(compression_method);
-        let mtime = self.mtime().ok();
-        if let Some(mtime) = mtime {
-            options = options.last_modified_time(mtime);
-        }
+        let options = zip::write::FileOptions::default()
+            .compression_method(compression_method)
+            .last_modified_time(zip_mtime());
 
         let record_filename = self.record_file.to_str().unwrap().replace('\\', "/");
-        debug!("Adding {}", record_filename);
-        self.zip.start_file(&record_filename, options)?;
-        for (filename, hash, len) in self.record {
+        let dist_info_dir = record_filename
+            .rsplit_once('/')
+            .map_or("", |(dir, _)| dir)
+            .to_string();
+        // The files are already sorted by path, PEP 427 recommends putting the .dist-info
+        // directory at the end of the archive
+        let mut files: Vec<_> = std::mem::take(&mut self.files).into_iter().collect();
+        files.sort_by_key(|(target, _)| {
+            target
+                .strip_prefix(&dist_info_dir)
+                .map_or(false, |rest| rest.starts_with('/'))
+        });
+
+        let mut record = String::new();
+        for (target, (source, permissions)) in files {
+            if target == record_filename {
+                continue;
+            }
+            let mut reader = source.open().map_err(|err| source.read_error(err))?;
             self.zip
-                .write_all(format!("{filename},sha256={hash},{len}\n").as_bytes())?;
+                .start_file(&target, options.unix_permissions(permissions))?;
+            let (hash, len) =
+                copy_hashed(&mut reader, &mut self.zip).map_err(|err| source.read_error(err))?;
+            record.push_str(&format!("{target},sha256={hash},{len}\n"));
         }
         // Write the record for the RECORD file itself
+        record.push_str(&format!("{record_filename},,\n"));
+
+        debug!("Adding {}", record_filename);
         self.zip
-            .write_all(format!("{record_filename},,\n").as_bytes())?;
+            .start_file(&record_filename, options.unix_permissions(0o644))?;
+        self.zip.write_all(record.as_bytes())?;
 
         self.zip.finish()?;
         Ok(self.wheel_path)
     }
 }
 
+/// Only keeps whether a file is executable, so the archives don't depend on the umask or the
+/// permissions of the source files
+fn normalize_permissions(permissions: u32) -> u32 {
+    if permissions & 0o111 != 0 {
+        0o755
+    } else {
+        0o644
+    }
+}
+
+/// Returns the timestamp of all archive entries, the value of the SOURCE_DATE_EPOCH environment
+/// variable or otherwise 1980-01-01, so that builds are reproducible.
+/// Note that the earliest timestamp a zip file can represent is 1980-01-01
+pub(crate) fn archive_mtime() -> time::OffsetDateTime {
+    let min_dt = time::Date::from_calendar_date(1980, time::Month::January, 1)
+        .unwrap()
+        .midnight()
+        .assume_offset(time::UtcOffset::UTC);
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse().ok())
+        .and_then(|epoch| time::OffsetDateTime::from_unix_timestamp(epoch).ok())
+        .map_or(min_dt, |dt| dt.max(min_dt))
+}
+
+/// [archive_mtime] as zip DateTime
+fn zip_mtime() -> DateTime {
+    // Years up to 2107 are representable, which the fallback and any sane SOURCE_DATE_EPOCH are
+    DateTime::try_from(archive_mtime()).unwrap_or_default()
+}
+
+/// Inserts `code` into the python `source` after the module docstring, the leading comments
+/// and the `__future__` imports, which must come first
+fn insert_python_code(source: &str, code: &str) -> String {
+    let lines: Vec<&str> = source.split_inclusive('\n').collect();
+    let mut position = 0;
+    let mut in_docstring: Option<&str> = None;
+    let mut seen_docstring = false;
+    for (index, line) in lines.iter().enumerate() {
+        let trimmed = line.trim();
+        if let Some(quote) = in_docstring {
+            if trimmed.contains(quote) {
+                in_docstring = None;
+                position = index + 1;
+            }
+            continue;
+        }
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let docstring = trimmed.trim_start_matches(['r', 'R', 'u', 'U']);
+        if !seen_docstring && (docstring.starts_with("\"\"\"") || docstring.starts_with("'''")) {
+            seen_docstring = true;
+            let quote = &docstring[..3];
+            if docstring.len() < 6 || !docstring[3..].contains(quote) {
+                in_docstring = Some(quote);
+            } else {
+                position = index + 1;
+            }
+            continue;
+        }
+        if trimmed.starts_with("from __future__ import") {
+            position = index + 1;
+            continue;
+        }
+        break;
+    }
+    let mut result: String = lines[..position].concat();
+    if !result.is_empty() && !result.ends_with('\n') {
+        result.push('\n');
+    }
+    result.push_str(code);
+    result.push_str(&lines[position..].concat());
+    result
+}
+
 /// Creates a .tar.gz archive containing the source distribution
+///
+/// Like with [WheelWriter], the files are only written in [SDistWriter::finish], sorted by path
+/// and with normalized tar headers, so the archive doesn't depend on the order in which files
+/// were added or on the file system. Files added from disk are also only read at that point.
 pub struct SDistWriter {
     tar: tar::Builder<GzEncoder<File>>,
     path: PathBuf,
-    files: HashSet<PathBuf>,
+    /// The content and the unix permissions of every file by its path in the archive
+    files: BTreeMap<PathBuf, (ArchiveSource, u32)>,
     excludes: Option<Override>,
+    temp_dirs: Vec<TempDir>,
 }
 
 impl ModuleWriter for SDistWriter {
@@ -397,23 +691,18 @@ impl ModuleWriter for SDistWriter {
             return Ok(());
         }
 
-        if self.files.contains(target) {
+        if self.files.contains_key(target) {
             // Ignore duplicate files
             return Ok(());
         }
 
-        let mut header = tar::Header::new_gnu();
-        header.set_size(bytes.len() as u64);
-        header.set_mode(permissions);
-        header.set_cksum();
-        self.tar
-            .append_data(&mut header, target, bytes)
-            .context(format!(
-                "Failed to add {} bytes to sdist as {}",
-                bytes.len(),
-                target.display()
-            ))?;
-        self.files.insert(target.to_path_buf());
+        self.files.insert(
+            target.to_path_buf(),
+            (
+                ArchiveSource::Bytes(bytes.to_vec()),
+                normalize_permissions(permissions),
+            ),
+        );
         Ok(())
     }
 
@@ -430,22 +719,35 @@ impl ModuleWriter for SDistWriter {
             );
             return Ok(());
         }
-        if self.files.contains(target) {
+        if self.files.contains_key(target) {
             // Ignore duplicate files
             return Ok(());
         }
         debug!("Adding {} from {}", target.display(), source.display());
 
-        self.tar
-            .append_path_with_name(source, target)
-            .context(format!(
-                "Failed to add file from {} to sdist as {}",
-                source.display(),
-                target.display(),
-            ))?;
-        self.files.insert(target.to_path_buf());
+        let read_failed_context = format!("Failed to read {}", source.display());
+        // Fail early instead of in `finish` if the file can't be read
+        File::open(source).context(read_failed_context.clone())?;
+        #[cfg(unix)]
+        let permissions = fs::metadata(source)
+            .context(read_failed_context)?
+            .permissions()
+            .mode();
+        #[cfg(not(unix))]
+        let permissions = 0o644;
+        self.files.insert(
+            target.to_path_buf(),
+            (
+                ArchiveSource::File(source.to_path_buf()),
+                normalize_permissions(permissions),
+            ),
+        );
         Ok(())
     }
+
+    fn keep_temp_dir(&mut self, temp_dir: TempDir) {
+        self.temp_dirs.push(temp_dir);
+    }
 }
 
 impl SDistWriter {
@@ -468,13 +770,14 @@ impl SDistWriter {
         Ok(Self {
             tar,
             path,
-            files: HashSet::new(),
+            files: BTreeMap::new(),
             excludes,
+            temp_dirs: Vec::new(),
         })
     }
 
     /// Returns `true` if the given path should be excluded
-    fn exclude(&self, path: impl AsRef<Path>) -> bool {
+    pub fn exclude(&self, path: impl AsRef<Path>) -> bool {
         if let Some(excludes) = &self.excludes {
             excludes.matched(path.as_ref(), false).is_whitelist()
         } else {
@@ -482,8 +785,61 @@ impl SDistWriter {
         }
     }
 
-    /// Finished the .tar.gz archive
+    /// Changes the content of a file that has already been added, e.g. to rewrite the
+    /// pyproject.toml that came from `cargo package --list` or `git ls-files`
+    pub fn rewrite_file(
+        &mut self,
+        target: impl AsRef<Path>,
+        rewrite: impl FnOnce(&str) -> Result<String>,
+    ) -> Result<()> {
+        let target = target.as_ref();
+        let (source, _) = self.files.get_mut(target).with_context(|| {
+            format!(
+                "{} is not part of the source distribution",
+                target.display()
+            )
+        })?;
+        let mut bytes = Vec::new();
+        source
+            .open()
+            .and_then(|mut reader| reader.read_to_end(&mut bytes))
+            .map_err(|err| source.read_error(err))?;
+        let contents = str::from_utf8(&bytes)
+            .with_context(|| format!("{} is not valid utf-8", target.display()))?;
+        *source = ArchiveSource::Bytes(rewrite(contents)?.into_bytes());
+        Ok(())
+    }
+
+    /// Writes the files sorted by path and finishes the .tar.gz archive
     pub fn finish(mut self) -> Result<PathBuf, io::Error> {
+        let mtime = archive_mtime().unix_timestamp().max(0) as u64;
+        for (target, (source, permissions)) in std::mem::take(&mut self.files) {
+            let size = source.len().map_err(|err| source.read_error(err))?;
+            let reader = source.open().map_err(|err| source.read_error(err))?;
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(size);
+            header.set_mode(permissions);
+            header.set_mtime(mtime);
+            header.set_uid(0);
+            header.set_gid(0);
+            header.set_username("")?;
+            header.set_groupname("")?;
+            header.set_cksum();
+            self.tar
+                .append_data(&mut header, &target, reader)
+                .map_err(|err| {
+                    io::Error::new(
+                        err.kind(),
+                        format!(
+                            "Failed to add {} bytes to sdist as {}: {}",
+                            size,
+                            target.display(),
+                            err
+                        ),
+                    )
+                })?;
+        }
         self.tar.finish()?;
         Ok(self.path)
     }
@@ -1186,9 +1542,14 @@ pub fn write_dist_info(
 
     writer.add_directory(&dist_info_dir)?;
 
+    // `Dynamic` only has a meaning in the PKG-INFO of source distributions
+    let wheel_metadata = Metadata21 {
+        dynamic: Vec::new(),
+        ..metadata21.clone()
+    };
     writer.add_bytes(
         &dist_info_dir.join("METADATA"),
-        metadata21.to_file_contents()?.as_bytes(),
+        wheel_metadata.to_file_contents()?.as_bytes(),
     )?;
 
     writer.add_bytes(&dist_info_dir.join("WHEEL"), wheel_file(tags)?.as_bytes())?;
@@ -1210,14 +1571,15 @@ pub fn write_dist_info(
         )?;
     }
 
+    // https://peps.python.org/pep-0639/#add-license-file-field
     if !metadata21.license_files.is_empty() {
-        let license_files_dir = dist_info_dir.join("license_files");
+        let license_files_dir = dist_info_dir.join("licenses");
         writer.add_directory(&license_files_dir)?;
-        for path in &metadata21.license_files {
-            let filename = path.file_name().with_context(|| {
-                format!("missing file name for license file {}", path.display())
-            })?;
-            writer.add_file(license_files_dir.join(filename), path)?;
+        // The `License-File` entries are relative to the licenses directory
+        for (relative, path) in metadata21.license_file_paths()? {
+            let target = license_files_dir.join(relative);
+            writer.add_directory(target.parent().unwrap())?;
+            writer.add_file(target, path)?;
         }
     }
 
@@ -1324,4 +1686,129 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn wheel_writer_streams_files() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = TempDir::new()?;
+        let dist_info_dir = Path::new("foo-1.0.dist-info");
+        let mut writer = WheelWriter::create(tmp_dir.path().join("foo.whl"), dist_info_dir)?;
+        // Larger than the copy buffer
+        let content = "x".repeat(100_000);
+        let source_dir = TempDir::new()?;
+        let source = source_dir.path().join("data.txt");
+        fs::write(&source, &content)?;
+        writer.add_file("foo/data.txt", &source)?;
+        assert!(writer.add_file("foo/missing.txt", "missing.txt").is_err());
+        // The file is only read in `finish`, so the writer has to keep the directory alive
+        writer.keep_temp_dir(source_dir);
+        let wheel_path = writer.finish()?;
+
+        let mut archive = zip::ZipArchive::new(File::open(wheel_path)?)?;
+        let mut data = String::new();
+        archive.by_name("foo/data.txt")?.read_to_string(&mut data)?;
+        assert_eq!(data, content);
+        let mut record = String::new();
+        archive
+            .by_name("foo-1.0.dist-info/RECORD")?
+            .read_to_string(&mut record)?;
+        let hash = URL_SAFE_NO_PAD.encode(Sha256::digest(content.as_bytes()));
+        assert_eq!(
+            record,
+            format!("foo/data.txt,sha256={hash},100000\nfoo-1.0.dist-info/RECORD,,\n")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn wheel_writer_rejects_duplicates() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = TempDir::new()?;
+        let dist_info_dir = Path::new("foo-1.0.dist-info");
+        let mut writer = WheelWriter::create(tmp_dir.path().join("foo.whl"), dist_info_dir)?;
+        writer.add_bytes("foo/__init__.py", b"first")?;
+        // The same content again is fine, different content would silently be lost
+        writer.add_bytes("foo/__init__.py", b"first")?;
+        let err = writer.add_bytes("foo/__init__.py", b"second").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "foo/__init__.py was already added to the wheel from generated content, can't add it again from generated content"
+        );
+        let wheel_path = writer.finish()?;
+
+        let mut archive = zip::ZipArchive::new(File::open(wheel_path)?)?;
+        let mut data = String::new();
+        archive
+            .by_name("foo/__init__.py")?
+            .read_to_string(&mut data)?;
+        assert_eq!(data, "first");
+        Ok(())
+    }
+
+    #[test]
+    fn sdist_writer_rewrites_streamed_files() -> Result<(), Box<dyn std::error::Error>> {
+        let metadata = Metadata21::new("dummy".to_string(), Version::from_release(vec![1, 0]));
+        let tmp_dir = TempDir::new()?;
+        let source = tmp_dir.path().join("pyproject.toml");
+        fs::write(&source, "[project]\n")?;
+        let mut writer = SDistWriter::new(&tmp_dir, &metadata, None)?;
+        writer.add_file("dummy-1.0/pyproject.toml", &source)?;
+        writer.rewrite_file("dummy-1.0/pyproject.toml", |contents| {
+            Ok(format!("{contents}name = \"dummy\"\n"))
+        })?;
+        let sdist_path = writer.finish()?;
+
+        let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(File::open(sdist_path)?));
+        let mut entry = archive.entries()?.next().unwrap()?;
+        assert_eq!(entry.path()?, Path::new("dummy-1.0/pyproject.toml"));
+        let mut contents = String::new();
+        entry.read_to_string(&mut contents)?;
+        assert_eq!(contents, "[project]\nname = \"dummy\"\n");
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn elf_collector_only_collects_elf_files() -> Result<(), Box<dyn std::error::Error>> {
+        let tmp_dir = TempDir::new()?;
+        let text_file = tmp_dir.path().join("hello.txt");
+        fs::write(&text_file, "hello")?;
+        let elf_file = env::current_exe()?;
+
+        let mut collector = ElfCollector::new(None);
+        collector.add_file("foo/hello.txt", &text_file)?;
+        collector.add_file_with_permissions("foo/native", &elf_file, 0o755)?;
+        collector.add_bytes("foo/__init__.py", b"")?;
+        assert_eq!(
+            collector.files,
+            vec![(PathBuf::from("foo/native"), elf_file.clone())]
+        );
+
+        let mut excludes = OverrideBuilder::new(&tmp_dir);
+        excludes.add("foo/native")?;
+        let mut collector = ElfCollector::new(Some(excludes.build()?));
+        collector.add_file("foo/native", &elf_file)?;
+        assert!(collector.files.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn insert_python_code_after_docstring_and_future_imports() {
+        let code = "import os\n";
+        assert_eq!(insert_python_code("", code), "import os\n");
+        assert_eq!(
+            insert_python_code("from .foo import *", code),
+            "import os\nfrom .foo import *"
+        );
+        assert_eq!(
+            insert_python_code(
+                "#!/usr/bin/env python\n\"\"\"Docs\n\nmore docs\"\"\"\nfrom __future__ import annotations\n\nx = 1\n",
+                code
+            ),
+            "#!/usr/bin/env python\n\"\"\"Docs\n\nmore docs\"\"\"\nfrom __future__ import annotations\nimport os\n\nx = 1\n"
+        );
+        assert_eq!(
+            insert_python_code("r'''Docs'''", code),
+            "r'''Docs'''\nimport os\n"
+        );
+    }
 }
@@ -1,6 +1,7 @@
 //! A pyproject.toml as specified in PEP 517
 
-use crate::PlatformTag;
+use crate::auditwheel::AuditWheelMode;
+use crate::{PlatformTag, SbomFormat};
 use anyhow::{Context, Result};
 use fs_err as fs;
 use pyproject_toml::{BuildSystem, Project};
@@ -105,6 +106,36 @@ pub struct TargetConfig {
     pub macos_deployment_target: Option<String>,
 }
 
+/// Changes to the manylinux/musllinux policies from `[tool.maturin.auditwheel]`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct AuditwheelConfig {
+    /// Whether to repair the wheel, only check it, only warn about problems or skip auditing
+    pub mode: Option<AuditWheelMode>,
+    /// Glob patterns of sonames that are never bundled into the wheel, e.g. drivers
+    /// that are installed on every target machine
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Libraries that are allowed to be linked in addition to the policy's whitelist
+    #[serde(default)]
+    pub whitelist: Vec<String>,
+    /// A JSON file in the format of auditwheel's policy files with additional policies
+    pub policy_file: Option<PathBuf>,
+    /// Keep rpath entries pointing outside of the wheel when grafting external libraries
+    #[serde(default)]
+    pub keep_rpath: bool,
+}
+
+/// Software bills of materials of the linked Rust crates from `[tool.maturin.sbom]`
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "kebab-case")]
+pub struct SbomConfig {
+    /// Whether to add SBOMs to `.dist-info/sboms` of the wheels, defaults to true
+    pub enabled: Option<bool>,
+    /// The SBOM formats, defaults to CycloneDX
+    pub formats: Option<Vec<SbomFormat>>,
+}
+
 /// Source distribution generator
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
 #[serde(rename_all = "kebab-case")]
@@ -114,6 +145,31 @@ pub enum SdistGenerator {
     Cargo,
     /// Use `git ls-files`
     Git,
+    /// Use the sources of the cargo targets and `include` in `[tool.maturin]`
+    Manifest,
+}
+
+/// Where to take the version from when `version` is in `project.dynamic`, from
+/// `[tool.maturin.version]`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "provider", rename_all = "kebab-case")]
+pub enum VersionProvider {
+    /// The latest tag from `git describe`, with the number of commits since the tag and the
+    /// commit hash for untagged commits
+    #[serde(rename_all = "kebab-case")]
+    Git {
+        /// Only use tags with this prefix, e.g. `foo-v` in a monorepo. Defaults to tags with an
+        /// optional `v` prefix
+        tag_prefix: Option<String>,
+    },
+    /// A version in a file, e.g. `__version__ = "1.0.0"` in `__about__.py`
+    File {
+        /// The path of the file relative to pyproject.toml
+        path: PathBuf,
+        /// A regex matching the version as `version` group or first group. Defaults to a
+        /// `__version__` assignment
+        pattern: Option<String>,
+    },
 }
 
 /// The `[tool.maturin]` section of a pyproject.toml
@@ -132,15 +188,27 @@ pub struct ToolMaturin {
     /// Platform compatibility
     #[serde(alias = "manylinux")]
     pub compatibility: Option<PlatformTag>,
-    /// Skip audit wheel
+    /// Skip audit wheel, deprecated in favor of `mode = "skip"` in `[tool.maturin.auditwheel]`
     #[serde(default)]
     pub skip_auditwheel: bool,
+    /// Changes to the manylinux/musllinux policies
+    pub auditwheel: Option<AuditwheelConfig>,
     /// Strip the final binary
     #[serde(default)]
     pub strip: bool,
+    /// Software bills of materials of the linked Rust crates
+    pub sbom: Option<SbomConfig>,
+    /// Bundle the license texts of the linked Rust crates
+    #[serde(default)]
+    pub third_party_licenses: bool,
     /// Source distribution generator
     #[serde(default)]
     pub sdist_generator: SdistGenerator,
+    /// Vendor the Rust dependencies into the source distribution
+    #[serde(default)]
+    pub sdist_vendor: bool,
+    /// Where to take the version from if it's dynamic
+    pub version: Option<VersionProvider>,
     /// The directory with python module, contains `<module_name>/__init__.py`
     pub python_source: Option<PathBuf>,
     /// Python packages to include
@@ -167,6 +235,8 @@ pub struct ToolMaturin {
     pub frozen: Option<bool>,
     /// Require Cargo.lock is up to date
     pub locked: Option<bool>,
+    /// Run without accessing the network
+    pub offline: Option<bool>,
     /// Override a configuration value (unstable)
     pub config: Option<Vec<String>>,
     /// Unstable (nightly-only) flags to Cargo, see 'cargo -Z help' for details
@@ -251,6 +321,31 @@ impl PyProjectToml {
             .unwrap_or_default()
     }
 
+    /// Returns the value of `[tool.maturin.auditwheel.mode]` in pyproject.toml, falling back
+    /// to `skip` if `[tool.maturin.skip-auditwheel]` is set
+    pub fn auditwheel_mode(&self) -> Option<AuditWheelMode> {
+        self.auditwheel()
+            .and_then(|config| config.mode)
+            .or_else(|| self.skip_auditwheel().then_some(AuditWheelMode::Skip))
+    }
+
+    /// Returns the value of `[tool.maturin.auditwheel]` in pyproject.toml
+    pub fn auditwheel(&self) -> Option<&AuditwheelConfig> {
+        self.maturin()?.auditwheel.as_ref()
+    }
+
+    /// Returns the value of `[tool.maturin.sbom]` in pyproject.toml
+    pub fn sbom(&self) -> Option<&SbomConfig> {
+        self.maturin()?.sbom.as_ref()
+    }
+
+    /// Returns the value of `[tool.maturin.third-party-licenses]` in pyproject.toml
+    pub fn third_party_licenses(&self) -> bool {
+        self.maturin()
+            .map(|maturin| maturin.third_party_licenses)
+            .unwrap_or_default()
+    }
+
     /// Returns the value of `[tool.maturin.strip]` in pyproject.toml
     pub fn strip(&self) -> bool {
         self.maturin()
@@ -265,6 +360,18 @@ impl PyProjectToml {
             .unwrap_or_default()
     }
 
+    /// Returns the value of `[tool.maturin.sdist-vendor]` in pyproject.toml
+    pub fn sdist_vendor(&self) -> bool {
+        self.maturin()
+            .map(|maturin| maturin.sdist_vendor)
+            .unwrap_or_default()
+    }
+
+    /// Returns the value of `[tool.maturin.version]` in pyproject.toml
+    pub fn version_provider(&self) -> Option<&VersionProvider> {
+        self.maturin()?.version.as_ref()
+    }
+
     /// Returns the value of `[tool.maturin.python-source]` in pyproject.toml
     pub fn python_source(&self) -> Option<&Path> {
         self.maturin()
@@ -348,7 +455,7 @@ impl PyProjectToml {
 mod tests {
     use crate::{
         pyproject_toml::{Format, Formats, GlobPattern, ToolMaturin},
-        PyProjectToml,
+        AuditWheelMode, PyProjectToml,
     };
     use fs_err as fs;
     use indoc::indoc;
@@ -384,6 +491,12 @@ mod tests {
 
             [tool.maturin.target."x86_64-apple-darwin"]
             macos-deployment-target = "10.12"
+
+            [tool.maturin.auditwheel]
+            mode = "check"
+            exclude = ["libcuda.so.*"]
+            policy-file = "policy.json"
+            keep-rpath = true
             "#,
         )
         .unwrap();
@@ -415,6 +528,14 @@ mod tests {
             target_config.macos_deployment_target.as_deref(),
             Some("10.12")
         );
+        let auditwheel = pyproject.auditwheel().unwrap();
+        assert_eq!(auditwheel.exclude, vec!["libcuda.so.*".to_string()]);
+        assert!(auditwheel.whitelist.is_empty());
+        assert_eq!(
+            auditwheel.policy_file.as_deref(),
+            Some(Path::new("policy.json"))
+        );
+        assert_eq!(pyproject.auditwheel_mode(), Some(AuditWheelMode::Check));
     }
 
     #[test]
@@ -0,0 +1,196 @@
+//! Comparing the wheels and source distributions of two builds to check that they are
+//! reproducible
+use anyhow::{bail, Context, Result};
+use flate2::read::GzDecoder;
+use fs_err as fs;
+use fs_err::File;
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use zip::ZipArchive;
+
+/// An archive entry, with everything but its content summarized as `metadata`
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    metadata: String,
+    content: Vec<u8>,
+}
+
+/// Reads the entries of a wheel in archive order
+fn read_zip(path: &Path) -> Result<Vec<(String, Entry)>> {
+    let mut archive = ZipArchive::new(File::open(path)?)?;
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let mtime = file.last_modified();
+        let metadata = format!(
+            "mode {:o}, mtime {}-{:02}-{:02} {:02}:{:02}:{:02}, compression {}",
+            file.unix_mode().unwrap_or_default(),
+            mtime.year(),
+            mtime.month(),
+            mtime.day(),
+            mtime.hour(),
+            mtime.minute(),
+            mtime.second(),
+            file.compression(),
+        );
+        let mut content = Vec::new();
+        file.read_to_end(&mut content)?;
+        entries.push((file.name().to_string(), Entry { metadata, content }));
+    }
+    Ok(entries)
+}
+
+/// Reads the entries of a source distribution in archive order
+fn read_tar_gz(path: &Path) -> Result<Vec<(String, Entry)>> {
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let header = entry.header();
+        let metadata = format!(
+            "type {:?}, mode {:o}, mtime {}, owner {}:{} ({}:{})",
+            header.entry_type(),
+            header.mode()?,
+            header.mtime()?,
+            header.uid()?,
+            header.gid()?,
+            header.username().ok().flatten().unwrap_or_default(),
+            header.groupname().ok().flatten().unwrap_or_default(),
+        );
+        let name = entry.path()?.display().to_string();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        entries.push((name, Entry { metadata, content }));
+    }
+    Ok(entries)
+}
+
+/// Lists the differences between two wheels or two source distributions, which is empty if
+/// they are identical
+pub fn archive_differences(first: &Path, second: &Path) -> Result<Vec<String>> {
+    if fs::read(first)? == fs::read(second)? {
+        return Ok(Vec::new());
+    }
+    let file_name = first.file_name().unwrap_or_default().to_string_lossy();
+    let read = if file_name.ends_with(".whl") {
+        read_zip
+    } else if file_name.ends_with(".tar.gz") {
+        read_tar_gz
+    } else {
+        bail!(
+            "Can't compare {}, it's neither a wheel nor an sdist",
+            file_name
+        );
+    };
+    let first_entries =
+        read(first).with_context(|| format!("Failed to read {}", first.display()))?;
+    let second_entries =
+        read(second).with_context(|| format!("Failed to read {}", second.display()))?;
+
+    let mut differences = Vec::new();
+    let first_names: Vec<&str> = first_entries
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let second_names: Vec<&str> = second_entries
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect();
+    let second_map: BTreeMap<&str, &Entry> = second_entries
+        .iter()
+        .map(|(name, entry)| (name.as_str(), entry))
+        .collect();
+    let first_map: BTreeMap<&str, &Entry> = first_entries
+        .iter()
+        .map(|(name, entry)| (name.as_str(), entry))
+        .collect();
+    for (name, entry) in &first_map {
+        match second_map.get(name) {
+            None => differences.push(format!("{name}: only in the first build")),
+            Some(other) => {
+                if entry.content != other.content {
+                    differences.push(format!("{name}: content differs"));
+                }
+                if entry.metadata != other.metadata {
+                    differences.push(format!(
+                        "{name}: metadata differs ({} != {})",
+                        entry.metadata, other.metadata
+                    ));
+                }
+            }
+        }
+    }
+    for name in second_map.keys() {
+        if !first_map.contains_key(name) {
+            differences.push(format!("{name}: only in the second build"));
+        }
+    }
+    if differences.is_empty() {
+        if first_names != second_names {
+            differences.push("the order of the entries differs".to_string());
+        } else {
+            differences.push("the archive headers differ".to_string());
+        }
+    }
+    Ok(differences)
+}
+
+#[cfg(test)]
+mod test {
+    use super::archive_differences;
+    use crate::module_writer::{ModuleWriter, WheelWriter};
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    fn write_wheel(dir: &Path, files: &[(&str, &str, u32)]) -> std::path::PathBuf {
+        std::fs::create_dir_all(dir).unwrap();
+        let mut writer = WheelWriter::create(
+            dir.join("foo-1.0-py3-none-any.whl"),
+            Path::new("foo-1.0.dist-info"),
+        )
+        .unwrap();
+        for (target, content, permissions) in files {
+            writer
+                .add_bytes_with_permissions(target, content.as_bytes(), *permissions)
+                .unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn test_archive_differences() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let first = write_wheel(
+            &tmp_dir.path().join("first"),
+            &[("foo/a.py", "a", 0o644), ("foo/b.py", "b", 0o600)],
+        );
+        // The order and the non-executable permissions don't matter
+        let second = write_wheel(
+            &tmp_dir.path().join("second"),
+            &[("foo/b.py", "b", 0o664), ("foo/a.py", "a", 0o644)],
+        );
+        assert!(archive_differences(&first, &second).unwrap().is_empty());
+
+        let third = write_wheel(
+            &tmp_dir.path().join("third"),
+            &[
+                ("foo/a.py", "a", 0o755),
+                ("foo/b.py", "c", 0o644),
+                ("foo/c.py", "", 0o644),
+            ],
+        );
+        let differences = archive_differences(&first, &third).unwrap();
+        assert_eq!(differences.len(), 4);
+        assert_eq!(differences[0], "foo-1.0.dist-info/RECORD: content differs");
+        assert!(differences[1].starts_with("foo/a.py: metadata differs (mode 100644"));
+        assert!(differences[1].contains("!= mode 100755"));
+        assert_eq!(
+            differences[2..],
+            [
+                "foo/b.py: content differs",
+                "foo/c.py: only in the second build"
+            ]
+        );
+    }
+}
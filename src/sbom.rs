@@ -0,0 +1,415 @@
+//! Software bills of materials of the Rust crates linked into a wheel, which are written to
+//! `.dist-info/sboms` as specified by [PEP 770](https://peps.python.org/pep-0770/)
+use crate::module_writer::{archive_mtime, ModuleWriter};
+use crate::spdx::normalize_cargo_license;
+use anyhow::{bail, Context, Result};
+use cargo_metadata::{DependencyKind, Metadata, Node, Package, PackageId};
+use cargo_platform::Cfg;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+/// The format of a software bill of materials
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+pub enum SbomFormat {
+    /// CycloneDX 1.5 as JSON
+    #[serde(rename = "cyclonedx")]
+    #[value(name = "cyclonedx")]
+    CycloneDx,
+    /// SPDX 2.3 as JSON
+    #[serde(rename = "spdx")]
+    #[value(name = "spdx")]
+    Spdx,
+}
+
+impl SbomFormat {
+    fn file_name(self, name: &str) -> String {
+        match self {
+            SbomFormat::CycloneDx => format!("{name}.cdx.json"),
+            SbomFormat::Spdx => format!("{name}.spdx.json"),
+        }
+    }
+}
+
+/// The crates that are linked into the artifacts of the root package, i.e. its normal
+/// dependencies for the target without build dependencies and proc macros
+#[derive(Debug)]
+pub struct LinkedCrates<'a> {
+    root: &'a Package,
+    /// The dependencies of the root package, sorted by name and version
+    packages: Vec<&'a Package>,
+    /// The linked dependencies of each package
+    dependencies: BTreeMap<&'a PackageId, Vec<&'a PackageId>>,
+}
+
+/// Evaluates the `cfg` values of a target with `rustc --print cfg`, for the target specific
+/// dependencies
+pub fn target_cfgs(target_triple: &str) -> Result<Vec<Cfg>> {
+    let output = Command::new("rustc")
+        .args(["--print", "cfg", "--target", target_triple])
+        .output()
+        .context("Failed to run `rustc --print cfg`")?;
+    if !output.status.success() {
+        bail!(
+            "`rustc --print cfg --target {target_triple}` failed:\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    String::from_utf8(output.stdout)
+        .context("rustc output is not valid utf-8")?
+        .lines()
+        .map(|line| Cfg::from_str(line).with_context(|| format!("Invalid rustc cfg `{line}`")))
+        .collect()
+}
+
+impl<'a> LinkedCrates<'a> {
+    /// Walks the resolved dependency graph from the root package, keeping the dependencies
+    /// that are enabled for any of the targets given as triple and cfg values
+    pub fn resolve(cargo_metadata: &'a Metadata, targets: &[(&str, Vec<Cfg>)]) -> Result<Self> {
+        let root = cargo_metadata
+            .root_package()
+            .context("Expected cargo to return metadata with root_package")?;
+        let resolve = cargo_metadata
+            .resolve
+            .as_ref()
+            .context("Expected cargo to return metadata with resolve")?;
+        let nodes: HashMap<&PackageId, &Node> =
+            resolve.nodes.iter().map(|node| (&node.id, node)).collect();
+        let packages: HashMap<&PackageId, &Package> = cargo_metadata
+            .packages
+            .iter()
+            .map(|package| (&package.id, package))
+            .collect();
+
+        let mut dependencies = BTreeMap::new();
+        let mut seen = HashSet::from([&root.id]);
+        let mut queue = VecDeque::from([&root.id]);
+        while let Some(id) = queue.pop_front() {
+            let node = nodes
+                .get(id)
+                .with_context(|| format!("{id} is missing in the cargo metadata resolve"))?;
+            let mut linked = Vec::new();
+            for dep in &node.deps {
+                let enabled = dep.dep_kinds.iter().any(|info| {
+                    info.kind == DependencyKind::Normal
+                        && info.target.as_ref().map_or(true, |platform| {
+                            targets
+                                .iter()
+                                .any(|(triple, cfgs)| platform.matches(triple, cfgs))
+                        })
+                });
+                // Proc macros run in the compiler and aren't part of the artifact
+                let is_proc_macro = packages.get(&dep.pkg).map_or(false, |package| {
+                    package
+                        .targets
+                        .iter()
+                        .any(|target| target.kind.iter().any(|kind| kind == "proc-macro"))
+                });
+                if enabled && !is_proc_macro {
+                    linked.push(&dep.pkg);
+                    if seen.insert(&dep.pkg) {
+                        queue.push_back(&dep.pkg);
+                    }
+                }
+            }
+            linked.sort();
+            dependencies.insert(id, linked);
+        }
+
+        let mut linked_packages: Vec<&Package> = seen
+            .into_iter()
+            .filter(|id| *id != &root.id)
+            .filter_map(|id| packages.get(id).copied())
+            .collect();
+        linked_packages.sort_by(|a, b| (&a.name, &a.version).cmp(&(&b.name, &b.version)));
+        Ok(Self {
+            root,
+            packages: linked_packages,
+            dependencies,
+        })
+    }
+
+    /// The package whose artifacts are built
+    pub fn root(&self) -> &'a Package {
+        self.root
+    }
+
+    /// The linked dependencies of the root package, sorted by name and version
+    pub fn packages(&self) -> &[&'a Package] {
+        &self.packages
+    }
+
+    /// The root package followed by its dependencies
+    fn all_packages(&self) -> impl Iterator<Item = &'a Package> + '_ {
+        [self.root].into_iter().chain(self.packages.iter().copied())
+    }
+
+    /// The CycloneDX document, see https://cyclonedx.org/docs/1.5/json/
+    fn cyclonedx(&self) -> Value {
+        let component = |package: &Package| {
+            let mut component = json!({
+                "type": "library",
+                "bom-ref": package.id.repr,
+                "name": package.name,
+                "version": package.version.to_string(),
+                "purl": purl(package),
+            });
+            if let Some(description) = &package.description {
+                component["description"] = json!(description.trim());
+            }
+            if let Some(license) = &package.license {
+                component["licenses"] = match normalize_cargo_license(license) {
+                    Ok(expression) => json!([{ "expression": expression }]),
+                    Err(_) => json!([{ "license": { "name": license } }]),
+                };
+            }
+            let mut references = Vec::new();
+            if let Some(repository) = &package.repository {
+                references.push(json!({ "type": "vcs", "url": repository }));
+            }
+            if let Some(homepage) = &package.homepage {
+                references.push(json!({ "type": "website", "url": homepage }));
+            }
+            if !references.is_empty() {
+                component["externalReferences"] = json!(references);
+            }
+            component
+        };
+
+        let components: Vec<Value> = self
+            .packages
+            .iter()
+            .map(|package| component(package))
+            .collect();
+        let dependencies: Vec<Value> = self
+            .dependencies
+            .iter()
+            .map(|(id, dependencies)| {
+                let depends_on: Vec<&str> = dependencies
+                    .iter()
+                    .map(|dependency| dependency.repr.as_str())
+                    .collect();
+                json!({ "ref": id.repr, "dependsOn": depends_on })
+            })
+            .collect();
+
+        json!({
+            "bomFormat": "CycloneDX",
+            "specVersion": "1.5",
+            "version": 1,
+            "metadata": {
+                "timestamp": timestamp(),
+                "tools": {
+                    "components": [{
+                        "type": "application",
+                        "name": "maturin",
+                        "version": env!("CARGO_PKG_VERSION"),
+                    }],
+                },
+                "component": component(self.root),
+            },
+            "components": components,
+            "dependencies": dependencies,
+        })
+    }
+
+    /// The SPDX document, see https://spdx.github.io/spdx-spec/v2.3/
+    fn spdx(&self) -> Value {
+        let spdx_ids: HashMap<&PackageId, String> = self
+            .all_packages()
+            .map(|package| (&package.id, spdx_id(package)))
+            .collect();
+        let package = |package: &Package| {
+            let download_location = match &package.source {
+                Some(source) if source.is_crates_io() => format!(
+                    "https://crates.io/api/v1/crates/{}/{}/download",
+                    package.name, package.version
+                ),
+                _ => "NOASSERTION".to_string(),
+            };
+            let license_declared = package
+                .license
+                .as_deref()
+                .and_then(|license| normalize_cargo_license(license).ok())
+                .unwrap_or_else(|| "NOASSERTION".to_string());
+            let mut spdx_package = json!({
+                "SPDXID": spdx_ids[&package.id],
+                "name": package.name,
+                "versionInfo": package.version.to_string(),
+                "downloadLocation": download_location,
+                "filesAnalyzed": false,
+                "licenseConcluded": "NOASSERTION",
+                "licenseDeclared": license_declared,
+                "copyrightText": "NOASSERTION",
+                "externalRefs": [{
+                    "referenceCategory": "PACKAGE-MANAGER",
+                    "referenceType": "purl",
+                    "referenceLocator": purl(package),
+                }],
+            });
+            if let Some(description) = &package.description {
+                spdx_package["summary"] = json!(description.trim());
+            }
+            if let Some(homepage) = &package.homepage {
+                spdx_package["homepage"] = json!(homepage);
+            }
+            spdx_package
+        };
+
+        let mut relationships = vec![json!({
+            "spdxElementId": "SPDXRef-DOCUMENT",
+            "relationshipType": "DESCRIBES",
+            "relatedSpdxElement": spdx_ids[&self.root.id],
+        })];
+        for (id, dependencies) in &self.dependencies {
+            for dependency in dependencies {
+                relationships.push(json!({
+                    "spdxElementId": spdx_ids[id],
+                    "relationshipType": "DEPENDS_ON",
+                    "relatedSpdxElement": spdx_ids[dependency],
+                }));
+            }
+        }
+
+        // The namespace has to be unique for each document, but the same for reproducible builds
+        let mut hasher = Sha256::new();
+        for package in self.all_packages() {
+            hasher.update(package.id.repr.as_bytes());
+            hasher.update(b"\n");
+        }
+        let namespace = format!(
+            "https://spdx.org/spdxdocs/{}-{}-{:x}",
+            self.root.name,
+            self.root.version,
+            hasher.finalize()
+        );
+
+        json!({
+            "spdxVersion": "SPDX-2.3",
+            "dataLicense": "CC0-1.0",
+            "SPDXID": "SPDXRef-DOCUMENT",
+            "name": format!("{}-{}", self.root.name, self.root.version),
+            "documentNamespace": namespace,
+            "creationInfo": {
+                "created": timestamp(),
+                "creators": [format!("Tool: maturin-{}", env!("CARGO_PKG_VERSION"))],
+            },
+            "packages": self.all_packages().map(package).collect::<Vec<_>>(),
+            "relationships": relationships,
+        })
+    }
+
+    /// Serializes the document in the given format
+    pub fn to_document(&self, format: SbomFormat) -> Result<String> {
+        let document = match format {
+            SbomFormat::CycloneDx => self.cyclonedx(),
+            SbomFormat::Spdx => self.spdx(),
+        };
+        Ok(serde_json::to_string_pretty(&document)? + "\n")
+    }
+}
+
+/// The package URL, see https://github.com/package-url/purl-spec
+fn purl(package: &Package) -> String {
+    format!("pkg:cargo/{}@{}", package.name, package.version)
+}
+
+/// SPDX identifiers may only contain letters, numbers, `.` and `-`
+fn spdx_id(package: &Package) -> String {
+    let id: String = format!("{}-{}", package.name, package.version)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    format!("SPDXRef-Package-{id}")
+}
+
+/// The creation time of the documents, which is the timestamp of the archive entries so that
+/// builds stay reproducible
+fn timestamp() -> String {
+    let mtime = archive_mtime();
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        mtime.year(),
+        u8::from(mtime.month()),
+        mtime.day(),
+        mtime.hour(),
+        mtime.minute(),
+        mtime.second()
+    )
+}
+
+/// Writes the SBOMs in the given formats to `<dist_info_dir>/sboms`
+pub fn write_sboms(
+    writer: &mut impl ModuleWriter,
+    dist_info_dir: &Path,
+    linked_crates: &LinkedCrates,
+    formats: &[SbomFormat],
+) -> Result<()> {
+    let sboms_dir = dist_info_dir.join("sboms");
+    for format in formats {
+        writer.add_bytes(
+            sboms_dir.join(format.file_name(&linked_crates.root.name)),
+            linked_crates.to_document(*format)?.as_bytes(),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cargo_metadata::MetadataCommand;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_linked_crates() {
+        let cargo_metadata = MetadataCommand::new()
+            .manifest_path("test-crates/pyo3-mixed/Cargo.toml")
+            .exec()
+            .unwrap();
+        let linux = [(
+            "x86_64-unknown-linux-gnu",
+            vec![Cfg::from_str("unix").unwrap()],
+        )];
+        let linked_crates = LinkedCrates::resolve(&cargo_metadata, &linux).unwrap();
+        let names: Vec<&str> = linked_crates
+            .packages
+            .iter()
+            .map(|package| package.name.as_str())
+            .collect();
+        assert!(names.contains(&"pyo3"));
+        assert!(names.contains(&"libc"));
+        // Build dependencies and proc macros aren't linked
+        assert!(!names.contains(&"pyo3-build-config"));
+        assert!(!names.contains(&"pyo3-macros"));
+        // Windows only dependencies
+        assert!(!names.contains(&"windows-sys"));
+        assert!(!names.contains(&"windows_x86_64_msvc"));
+
+        let cyclonedx: Value =
+            serde_json::from_str(&linked_crates.to_document(SbomFormat::CycloneDx).unwrap())
+                .unwrap();
+        assert_eq!(cyclonedx["metadata"]["component"]["name"], "pyo3-mixed");
+        assert_eq!(
+            cyclonedx["components"].as_array().unwrap().len(),
+            names.len()
+        );
+        let spdx: Value =
+            serde_json::from_str(&linked_crates.to_document(SbomFormat::Spdx).unwrap()).unwrap();
+        assert_eq!(
+            spdx["packages"][0]["SPDXID"],
+            "SPDXRef-Package-pyo3-mixed-2.1.3"
+        );
+        assert_eq!(spdx["packages"].as_array().unwrap().len(), names.len() + 1);
+    }
+}
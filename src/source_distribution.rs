@@ -1,19 +1,29 @@
+use crate::auditwheel::AuditWheelMode;
 use crate::module_writer::{add_data, ModuleWriter};
 use crate::pyproject_toml::SdistGenerator;
-use crate::{pyproject_toml::Format, BuildContext, PyProjectToml, SDistWriter};
+use crate::{pyproject_toml::Format, BuildContext, BuildOptions, PyProjectToml, SDistWriter};
 use anyhow::{bail, Context, Result};
 use cargo_metadata::{Metadata, MetadataCommand};
+use clap::ValueEnum;
+use flate2::read::GzDecoder;
 use fs_err as fs;
 use ignore::overrides::Override;
 use normpath::PathExt as _;
-use std::collections::HashMap;
-use std::path::{Path, PathBuf};
+use pep440_rs::Version;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
 use std::str;
 use tracing::debug;
 
 const LOCAL_DEPENDENCIES_FOLDER: &str = "local_dependencies";
 const RUST_SRC_FOLDER: &str = "rust_src";
+const VENDOR_FOLDER: &str = "vendor";
+
+/// The rule that selected each file of the source distribution, by its path in the source
+/// distribution
+type SdistReport = BTreeMap<PathBuf, String>;
 
 /// Inheritable workspace fields, see
 /// https://github.com/rust-lang/cargo/blob/13ae438cf079da58272edc71f4d4968043dbd27b/src/cargo/util/toml/mod.rs#L1140-L1158
@@ -47,6 +57,7 @@ const WORKSPACE_INHERITABLE_FIELDS: &[&str] = &[
 fn rewrite_cargo_toml(
     manifest_path: impl AsRef<Path>,
     workspace_manifest: &toml_edit::Document,
+    workspace_root: &Path,
     known_path_deps: &HashMap<String, PathBuf>,
     local_deps_folder: String,
     root_crate: bool,
@@ -119,7 +130,29 @@ fn rewrite_cargo_toml(
         }
     }
 
+    // Update workspace inherited lints
+    let lints_inherited = document
+        .get("lints")
+        .and_then(|x| x.get("workspace"))
+        .and_then(|x| x.as_bool())
+        .unwrap_or_default();
+    if lints_inherited {
+        if let Some(workspace_lints) = workspace.and_then(|x| x.get("lints")) {
+            document["lints"] = workspace_lints.clone();
+            rewritten = true;
+        }
+    }
+
     if root_crate {
+        // The root crate is the workspace root in the source distribution
+        rewritten |= inline_workspace_build_config(
+            &mut document,
+            workspace_manifest,
+            workspace_root,
+            known_path_deps,
+            &local_deps_folder,
+        );
+
         // Update workspace members
         if let Some(workspace) = document.get_mut("workspace").and_then(|x| x.as_table_mut()) {
             if let Some(members) = workspace.get_mut("members").and_then(|x| x.as_array_mut()) {
@@ -306,6 +339,95 @@ fn rewrite_dependencies_path(
     Ok(rewritten)
 }
 
+/// Copies `[profile]` and `[patch]` of the workspace into the manifest of the root crate,
+/// pointing path patches at the path dependencies packaged into the source distribution
+fn inline_workspace_build_config(
+    document: &mut toml_edit::Document,
+    workspace_manifest: &toml_edit::Document,
+    workspace_root: &Path,
+    known_path_deps: &HashMap<String, PathBuf>,
+    local_deps_folder: &str,
+) -> bool {
+    let mut rewritten = false;
+    if let Some(profile) = workspace_manifest.get("profile") {
+        document["profile"] = profile.clone();
+        rewritten = true;
+    }
+    if let Some(patch) = workspace_manifest.get("patch") {
+        let mut patch = patch.clone();
+        if let Some(sources) = patch.as_table_like_mut() {
+            for (source, patches) in sources.iter_mut() {
+                let patches = match patches.as_table_like_mut() {
+                    Some(patches) => patches,
+                    None => continue,
+                };
+                let names: Vec<_> = patches.iter().map(|(name, _)| name.to_string()).collect();
+                for name in names {
+                    let path = match patches
+                        .get(&name)
+                        .and_then(|x| x.get("path"))
+                        .and_then(|x| x.as_str())
+                    {
+                        Some(path) => path.to_string(),
+                        None => continue,
+                    };
+                    // Paths in `[patch]` are relative to the workspace root
+                    let patch_manifest = clean_path(&workspace_root.join(&path).join("Cargo.toml"));
+                    let packaged = known_path_deps
+                        .iter()
+                        .find(|(_, dep_manifest)| clean_path(dep_manifest) == patch_manifest);
+                    if let Some((dep_name, _)) = packaged {
+                        patches.get_mut(&name).unwrap()["path"] =
+                            toml_edit::value(format!("{local_deps_folder}/{dep_name}"));
+                    } else {
+                        eprintln!(
+                            "⚠️  Warning: The patch of {} for {} points at {}, which is not a path \
+                            dependency of the crate and thus not part of the source distribution, \
+                            ignoring it",
+                            name,
+                            source.get(),
+                            path
+                        );
+                        patches.remove(&name);
+                    }
+                }
+            }
+            let empty: Vec<_> = sources
+                .iter()
+                .filter(|(_, patches)| patches.as_table_like().map_or(false, |x| x.is_empty()))
+                .map(|(source, _)| source.to_string())
+                .collect();
+            for source in empty {
+                sources.remove(&source);
+            }
+        }
+        if patch.as_table_like().map_or(false, |x| x.is_empty()) {
+            document.remove("patch");
+        } else {
+            document["patch"] = patch;
+        }
+        rewritten = true;
+    }
+    rewritten
+}
+
+/// Normalizes `.` and `..` components of a path without accessing the file system
+pub(crate) fn clean_path(path: &Path) -> PathBuf {
+    let mut cleaned = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !cleaned.pop() {
+                    cleaned.push(component);
+                }
+            }
+            component => cleaned.push(component),
+        }
+    }
+    cleaned
+}
+
 /// Make sure that the dep entry is an inline table
 /// e.g. in the form of `{ version = "..." }`
 /// so that we can add entries for `optional` and `features`
@@ -352,21 +474,538 @@ fn rewrite_pyproject_toml(pyproject_toml_path: &Path) -> Result<String> {
     Ok(data.to_string())
 }
 
-/// Copies the files of a crate to a source distribution, recursively adding path dependencies
-/// and rewriting path entries in Cargo.toml
+/// Replaces `[tool.maturin.version]` with the version it provided, so building from the sdist
+/// neither needs git nor can yield a different version
+fn freeze_version(pyproject_toml: &str, version: &Version) -> Result<String> {
+    let mut data = pyproject_toml
+        .parse::<toml_edit::Document>()
+        .context("Failed to parse pyproject.toml")?;
+    if let Some(project) = data.get_mut("project").and_then(|x| x.as_table_mut()) {
+        if let Some(dynamic) = project.get_mut("dynamic").and_then(|x| x.as_array_mut()) {
+            let index = dynamic
+                .iter()
+                .position(|field| field.as_str() == Some("version"));
+            if let Some(index) = index {
+                dynamic.remove(index);
+                dynamic.fmt();
+            }
+            if dynamic.is_empty() {
+                project.remove("dynamic");
+            }
+        }
+        project.insert("version", toml_edit::value(version.to_string()));
+    }
+    if let Some(maturin) = data
+        .get_mut("tool")
+        .and_then(|tool| tool.get_mut("maturin"))
+        .and_then(|x| x.as_table_like_mut())
+    {
+        maturin.remove("version");
+    }
+    Ok(data.to_string())
+}
+
+/// Returns `[tool.maturin]` of a pyproject.toml, adding it if it's missing
+fn tool_maturin_mut(data: &mut toml_edit::Document) -> Result<&mut dyn toml_edit::TableLike> {
+    if data.get("tool").is_none() {
+        let mut tool = toml_edit::Table::new();
+        tool.set_implicit(true);
+        data.insert("tool", toml_edit::Item::Table(tool));
+    }
+    let maturin = data["tool"]
+        .as_table_like_mut()
+        .context("`tool` in pyproject.toml must be a table")?
+        .entry("maturin")
+        .or_insert_with(toml_edit::table);
+    if let Some(maturin) = maturin.as_table_mut() {
+        // `[tool.maturin.sbom]` alone makes `[tool.maturin]` implicit
+        maturin.set_implicit(false);
+    }
+    maturin
+        .as_table_like_mut()
+        .context("`tool.maturin` in pyproject.toml must be a table")
+}
+
+/// Makes building from a source distribution with vendored dependencies pass `--offline` to
+/// cargo, and keeps building a source distribution from it from vendoring again
+fn freeze_offline(pyproject_toml: &str) -> Result<String> {
+    let mut data = pyproject_toml
+        .parse::<toml_edit::Document>()
+        .context("Failed to parse pyproject.toml")?;
+    let maturin = tool_maturin_mut(&mut data)?;
+    maturin.remove("sdist-vendor");
+    maturin.insert("offline", toml_edit::value(true));
+    Ok(data.to_string())
+}
+
+/// Writes the build options given on the command line into `[tool.maturin]`, so that building
+/// from the source distribution, e.g. with pip, uses the same features, profile, bindings etc.
 ///
-/// Runs `cargo package --list --allow-dirty` to obtain a list of files to package.
-fn add_crate_to_source_distribution(
+/// Options that depend on the machine that builds, like the interpreters, the target triple or
+/// `--offline`, are not embedded.
+fn embed_build_options(
+    pyproject_toml: &str,
+    build_options: &BuildOptions,
+    strip: bool,
+) -> Result<String> {
+    let cargo = &build_options.cargo;
+    let mut options = toml_edit::Table::new();
+    if let Some(bindings) = &build_options.bindings {
+        options.insert("bindings", toml_edit::value(bindings.as_str()));
+    }
+    match build_options.platform_tag.as_slice() {
+        [] => {}
+        [platform_tag] => {
+            options.insert("compatibility", toml_edit::value(platform_tag.to_string()));
+        }
+        platform_tags => eprintln!(
+            "⚠️  Warning: Only a single compatibility can be embedded into the source \
+            distribution, ignoring {}",
+            platform_tags
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+    if let Some(profile) = &cargo.profile {
+        options.insert("profile", toml_edit::value(profile.as_str()));
+    }
+    for (key, values) in [
+        ("features", &cargo.features),
+        ("config", &cargo.config),
+        ("unstable-flags", &cargo.unstable_flags),
+        ("rustc-args", &cargo.args),
+    ] {
+        if !values.is_empty() {
+            let values: toml_edit::Array = values.iter().map(String::as_str).collect();
+            options.insert(key, toml_edit::value(values));
+        }
+    }
+    for (key, enabled) in [
+        ("all-features", cargo.all_features),
+        ("no-default-features", cargo.no_default_features),
+        ("locked", cargo.locked),
+        ("strip", strip),
+        ("third-party-licenses", build_options.third_party_licenses),
+    ] {
+        if enabled {
+            options.insert(key, toml_edit::value(true));
+        }
+    }
+    let auditwheel = build_options.auditwheel.or_else(|| {
+        build_options
+            .skip_auditwheel
+            .then_some(AuditWheelMode::Skip)
+    });
+    let sbom_enabled = if !build_options.sbom.is_empty() {
+        Some(true)
+    } else if build_options.no_sbom {
+        Some(false)
+    } else {
+        None
+    };
+    if let Some(target) = &cargo.target {
+        eprintln!(
+            "⚠️  Warning: The target {target} is not embedded into the source distribution, \
+            building from it targets the platform it is installed on"
+        );
+    }
+    if options.is_empty() && auditwheel.is_none() && sbom_enabled.is_none() {
+        return Ok(pyproject_toml.to_string());
+    }
+
+    let mut data = pyproject_toml
+        .parse::<toml_edit::Document>()
+        .context("Failed to parse pyproject.toml")?;
+    let maturin = tool_maturin_mut(&mut data)?;
+    for (key, value) in options.iter() {
+        maturin.insert(key, value.clone());
+    }
+    if let Some(auditwheel) = auditwheel {
+        let mode = auditwheel.to_possible_value().unwrap();
+        maturin
+            .entry("auditwheel")
+            .or_insert_with(toml_edit::table)
+            .as_table_like_mut()
+            .context("`tool.maturin.auditwheel` in pyproject.toml must be a table")?
+            .insert("mode", toml_edit::value(mode.get_name()));
+    }
+    if let Some(enabled) = sbom_enabled {
+        let sbom = maturin
+            .entry("sbom")
+            .or_insert_with(toml_edit::table)
+            .as_table_like_mut()
+            .context("`tool.maturin.sbom` in pyproject.toml must be a table")?;
+        sbom.insert("enabled", toml_edit::value(enabled));
+        if enabled {
+            let formats: toml_edit::Array = build_options
+                .sbom
+                .iter()
+                .map(|format| format.to_possible_value().unwrap().get_name().to_string())
+                .collect();
+            sbom.insert("formats", toml_edit::value(formats));
+        }
+    }
+    Ok(data.to_string())
+}
+
+/// Points the sources in the configuration printed by `cargo vendor` at the vendor folder of
+/// the source distribution
+fn vendored_cargo_config(cargo_vendor_output: &str) -> Result<String> {
+    let mut config = cargo_vendor_output
+        .parse::<toml_edit::Document>()
+        .context("Failed to parse the source replacement printed by `cargo vendor`")?;
+    if let Some(sources) = config.get_mut("source").and_then(|x| x.as_table_like_mut()) {
+        for (_, source) in sources.iter_mut() {
+            if let Some(directory) = source
+                .as_table_like_mut()
+                .and_then(|source| source.get_mut("directory"))
+            {
+                // Relative to the directory containing `.cargo`
+                *directory = toml_edit::value(VENDOR_FOLDER);
+            }
+        }
+    }
+    Ok(config.to_string())
+}
+
+/// Finds the directories whose `.cargo/config.toml` and `rust-toolchain.toml` apply when building
+/// in the project directory, from the project directory up to the first one that contains the
+/// workspace root. Configuration above that, e.g. in the home directory, is user specific.
+fn project_config_dirs<'a>(pyproject_dir: &'a Path, workspace_root: &Path) -> Vec<&'a Path> {
+    let mut dirs = Vec::new();
+    for dir in pyproject_dir.ancestors() {
+        dirs.push(dir);
+        if workspace_root.starts_with(dir) {
+            break;
+        }
+    }
+    dirs
+}
+
+/// Rewrites a path setting of a cargo configuration in `config_dir` to be relative to the root of
+/// the source distribution, which corresponds to `pyproject_dir`, removing it if it points outside
+/// of the project. Programs without a path separator are looked up in `PATH` and kept as they are.
+fn rewrite_cargo_config_path(
+    table: &mut dyn toml_edit::TableLike,
+    key: &str,
+    setting: &str,
+    program: bool,
+    config_dir: &Path,
+    pyproject_dir: &Path,
+) {
+    let value = match table.get(key).and_then(|x| x.as_str()) {
+        Some(value) => value.to_string(),
+        None => return,
+    };
+    let path = Path::new(&value);
+    if path.is_absolute() || (program && !value.contains(['/', '\\'])) {
+        return;
+    }
+    match clean_path(&config_dir.join(path)).strip_prefix(pyproject_dir) {
+        Ok(relative) => {
+            let relative = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            if relative.is_empty() {
+                table.insert(key, toml_edit::value("."));
+            } else {
+                table.insert(key, toml_edit::value(relative));
+            }
+        }
+        Err(_) => {
+            eprintln!(
+                "⚠️  Warning: `{}` in {} points outside of the project at {}, \
+                which can't be represented in the source distribution, ignoring it",
+                setting,
+                config_dir.join(".cargo").display(),
+                value
+            );
+            table.remove(key);
+        }
+    }
+}
+
+/// Rewrites the relative paths of the cargo configuration in `config_dir` for the root of the
+/// source distribution, see [rewrite_cargo_config_path]
+fn rewrite_cargo_config(
+    config: &str,
+    config_dir: &Path,
+    pyproject_dir: &Path,
+) -> Result<toml_edit::Document> {
+    let mut config = config.parse::<toml_edit::Document>().with_context(|| {
+        format!(
+            "Failed to parse the cargo configuration in {}",
+            config_dir.join(".cargo").display()
+        )
+    })?;
+    if config.remove("paths").is_some() {
+        eprintln!(
+            "⚠️  Warning: `paths` overrides in {} can't be represented in the source distribution, \
+            ignoring them",
+            config_dir.join(".cargo").display()
+        );
+    }
+    if let Some(build) = config.get_mut("build").and_then(|x| x.as_table_like_mut()) {
+        rewrite_cargo_config_path(
+            build,
+            "target-dir",
+            "build.target-dir",
+            false,
+            config_dir,
+            pyproject_dir,
+        );
+    }
+    if let Some(host) = config.get_mut("host").and_then(|x| x.as_table_like_mut()) {
+        rewrite_cargo_config_path(
+            host,
+            "linker",
+            "host.linker",
+            true,
+            config_dir,
+            pyproject_dir,
+        );
+    }
+    if let Some(targets) = config.get_mut("target").and_then(|x| x.as_table_like_mut()) {
+        for (target, target_config) in targets.iter_mut() {
+            if let Some(target_config) = target_config.as_table_like_mut() {
+                rewrite_cargo_config_path(
+                    target_config,
+                    "linker",
+                    &format!("target.{}.linker", target.get()),
+                    true,
+                    config_dir,
+                    pyproject_dir,
+                );
+            }
+        }
+    }
+    if let Some(sources) = config.get_mut("source").and_then(|x| x.as_table_like_mut()) {
+        for (source, source_config) in sources.iter_mut() {
+            if let Some(source_config) = source_config.as_table_like_mut() {
+                for key in ["directory", "local-registry"] {
+                    rewrite_cargo_config_path(
+                        source_config,
+                        key,
+                        &format!("source.{}.{}", source.get(), key),
+                        false,
+                        config_dir,
+                        pyproject_dir,
+                    );
+                }
+            }
+        }
+    }
+    if let Some(env) = config.get_mut("env").and_then(|x| x.as_table_like_mut()) {
+        for (name, value) in env.iter_mut() {
+            if let Some(value) = value.as_table_like_mut() {
+                let relative = value
+                    .get("relative")
+                    .and_then(|x| x.as_bool())
+                    .unwrap_or_default();
+                if relative {
+                    rewrite_cargo_config_path(
+                        value,
+                        "value",
+                        &format!("env.{}", name.get()),
+                        false,
+                        config_dir,
+                        pyproject_dir,
+                    );
+                }
+            }
+        }
+    }
+    Ok(config)
+}
+
+/// Merges cargo configuration like cargo does for the configuration files of nested directories:
+/// `overlay` takes precedence, except for arrays, which are concatenated
+fn merge_cargo_config(base: &mut dyn toml_edit::TableLike, overlay: &dyn toml_edit::TableLike) {
+    for (key, value) in overlay.iter() {
+        match base.get_mut(key) {
+            Some(existing) if existing.is_table_like() && value.is_table_like() => {
+                merge_cargo_config(
+                    existing.as_table_like_mut().unwrap(),
+                    value.as_table_like().unwrap(),
+                );
+            }
+            Some(existing) if existing.is_array() && value.is_array() => {
+                let array = existing.as_array_mut().unwrap();
+                array.extend(value.as_array().unwrap().iter().cloned());
+                array.fmt();
+            }
+            _ => {
+                base.insert(key, value.clone());
+            }
+        }
+    }
+}
+
+/// Adds the `.cargo/config.toml` and `rust-toolchain.toml` that apply when building in the
+/// project directory to the root of the source distribution, so that rustflags, linkers, source
+/// replacement and the toolchain are the same when building from it
+fn add_build_config_to_sdist(
+    build_context: &BuildContext,
+    pyproject_dir: &Path,
     writer: &mut SDistWriter,
-    pyproject_toml_path: impl AsRef<Path>,
-    manifest_path: impl AsRef<Path>,
-    workspace_manifest: &toml_edit::Document,
-    prefix: impl AsRef<Path>,
-    known_path_deps: &HashMap<String, PathBuf>,
-    root_crate: bool,
+    report: &mut SdistReport,
+    root_dir: &Path,
 ) -> Result<()> {
-    let manifest_path = manifest_path.as_ref();
-    let pyproject_toml_path = pyproject_toml_path.as_ref();
+    let workspace_root = build_context.cargo_metadata.workspace_root.as_std_path();
+    let config_dirs = project_config_dirs(pyproject_dir, workspace_root);
+
+    let mut cargo_config = toml_edit::Document::new();
+    // Configuration in the deeper directories takes precedence
+    for config_dir in config_dirs.iter().rev() {
+        let config_path = [
+            config_dir.join(".cargo").join("config.toml"),
+            config_dir.join(".cargo").join("config"),
+        ]
+        .into_iter()
+        .find(|path| path.is_file());
+        if let Some(config_path) = config_path {
+            let config = rewrite_cargo_config(
+                &fs::read_to_string(&config_path)?,
+                config_dir,
+                pyproject_dir,
+            )?;
+            merge_cargo_config(cargo_config.as_table_mut(), config.as_table());
+        }
+    }
+    if !cargo_config.is_empty() {
+        let target = root_dir.join(".cargo").join("config.toml");
+        report
+            .entry(target.clone())
+            .or_insert_with(|| "cargo configuration of the project".to_string());
+        if build_context.sdist_vendor {
+            // The source replacement of the vendored dependencies takes precedence
+            writer.rewrite_file(target, |vendored| {
+                let vendored = vendored
+                    .parse::<toml_edit::Document>()
+                    .context("Failed to parse the vendored cargo configuration")?;
+                merge_cargo_config(cargo_config.as_table_mut(), vendored.as_table());
+                Ok(cargo_config.to_string())
+            })?;
+        } else {
+            writer.add_bytes(target, cargo_config.to_string().as_bytes())?;
+        }
+    }
+
+    // rustup uses the closest toolchain file
+    let toolchain_path = config_dirs
+        .iter()
+        .flat_map(|dir| [dir.join("rust-toolchain.toml"), dir.join("rust-toolchain")])
+        .find(|path| path.is_file());
+    if let Some(toolchain_path) = toolchain_path {
+        let toolchain = fs::read_to_string(&toolchain_path)?;
+        // The legacy `rust-toolchain` file may also contain only the name of the toolchain
+        let custom_toolchain = toolchain
+            .parse::<toml_edit::Document>()
+            .ok()
+            .and_then(|toolchain| toolchain.get("toolchain")?.get("path").cloned());
+        if custom_toolchain.is_some() {
+            eprintln!(
+                "⚠️  Warning: {} uses a custom toolchain from a local path, \
+                which can't be represented in the source distribution, ignoring it",
+                toolchain_path.display()
+            );
+        } else {
+            add_reported_file(
+                writer,
+                report,
+                root_dir.join(toolchain_path.file_name().unwrap()),
+                &toolchain_path,
+                "rust toolchain of the project",
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Runs `cargo vendor` for the locked dependencies and adds them to the source distribution with
+/// a `.cargo/config.toml` that replaces crates.io and git sources with the vendored copies
+fn add_vendored_dependencies(
+    build_context: &BuildContext,
+    writer: &mut SDistWriter,
+    report: &mut SdistReport,
+    root_dir: &Path,
+) -> Result<()> {
+    let temp_dir = tempfile::tempdir()?;
+    let vendor_dir = temp_dir.path().join(VENDOR_FOLDER);
+    let mut command = Command::new("cargo");
+    command
+        .arg("vendor")
+        .arg("--manifest-path")
+        .arg(&build_context.manifest_path);
+    // Path dependencies outside of the workspace have their own dependencies
+    let workspace_root = build_context.cargo_metadata.workspace_root.as_std_path();
+    let mut path_deps: Vec<PathBuf> = find_path_deps(&build_context.cargo_metadata)?
+        .into_values()
+        .filter(|path_dep| !path_dep.starts_with(workspace_root))
+        .collect();
+    path_deps.sort();
+    for path_dep in path_deps {
+        command.arg("--sync").arg(path_dep);
+    }
+    let cargo_options = &build_context.cargo_options;
+    if cargo_options.frozen {
+        command.arg("--frozen");
+    }
+    if cargo_options.locked {
+        command.arg("--locked");
+    }
+    if cargo_options.offline {
+        command.arg("--offline");
+    }
+    command.arg(&vendor_dir);
+    eprintln!("📦 Vendoring the Rust dependencies with `cargo vendor`");
+    let output = command.output().context("Failed to run `cargo vendor`")?;
+    if !output.status.success() {
+        bail!(
+            "Failed to vendor the dependencies: {}\n--- Manifest path: {}\n--- Stderr:\n{}",
+            output.status,
+            build_context.manifest_path.display(),
+            String::from_utf8_lossy(&output.stderr),
+        );
+    }
+
+    let cargo_config = vendored_cargo_config(
+        str::from_utf8(&output.stdout).context("Cargo printed invalid utf-8 ಠ_ಠ")?,
+    )?;
+    report.insert(
+        root_dir.join(".cargo").join("config.toml"),
+        "`cargo vendor`".to_string(),
+    );
+    writer.add_bytes(
+        root_dir.join(".cargo").join("config.toml"),
+        cargo_config.as_bytes(),
+    )?;
+    // The vendored crates contain hidden files like `.cargo-checksum.json`
+    for entry in ignore::WalkBuilder::new(&vendor_dir)
+        .standard_filters(false)
+        .build()
+    {
+        let entry = entry?;
+        if entry
+            .file_type()
+            .map_or(false, |file_type| file_type.is_file())
+        {
+            let target = root_dir.join(entry.path().strip_prefix(temp_dir.path()).unwrap());
+            add_reported_file(writer, report, target, entry.path(), "`cargo vendor`")?;
+        }
+    }
+    // The vendored crates are only read when the source distribution is written
+    writer.keep_temp_dir(temp_dir);
+    Ok(())
+}
+
+/// Runs `cargo package --list --allow-dirty` to obtain the files of a crate relative to its
+/// manifest directory
+fn cargo_package_file_list(manifest_path: &Path) -> Result<Vec<(PathBuf, String)>> {
     let output = Command::new("cargo")
         .args(["package", "--list", "--allow-dirty", "--manifest-path"])
         .arg(manifest_path)
@@ -387,11 +1026,134 @@ fn add_crate_to_source_distribution(
         );
     }
 
-    let file_list: Vec<&Path> = str::from_utf8(&output.stdout)
+    Ok(str::from_utf8(&output.stdout)
         .context("Cargo printed invalid utf-8 ಠ_ಠ")?
         .lines()
-        .map(Path::new)
-        .collect();
+        .map(|file| (PathBuf::from(file), "`cargo package --list`".to_string()))
+        .collect())
+}
+
+/// Lists the sources of the cargo targets of a crate relative to its manifest directory, for
+/// [SdistGenerator::Manifest]: the directory of the root source file of each target, or only the
+/// root source file if it is next to `Cargo.toml` like `build.rs`, and the readme and license file
+fn cargo_target_file_list(package: &cargo_metadata::Package) -> Result<Vec<(PathBuf, String)>> {
+    let manifest_dir = package.manifest_path.parent().unwrap().as_std_path();
+    let relative = |path: &Path| -> Result<PathBuf> {
+        Ok(path
+            .strip_prefix(manifest_dir)
+            .with_context(|| {
+                format!(
+                    "{} of crate {} is outside of its directory {}, \
+                    which is not supported by the manifest sdist generator",
+                    path.display(),
+                    package.name,
+                    manifest_dir.display()
+                )
+            })?
+            .to_path_buf())
+    };
+
+    // Targets of the same crate often share a directory, the first target wins
+    let mut files = BTreeMap::new();
+    for target in &package.targets {
+        let rule = format!(
+            "sources of the {} target `{}` of {}",
+            target.kind.join(", "),
+            target.name,
+            package.name
+        );
+        let src_path = target.src_path.as_std_path();
+        let src_dir = src_path.parent().unwrap();
+        if src_dir == manifest_dir {
+            files
+                .entry(relative(src_path)?)
+                .or_insert_with(|| rule.clone());
+            continue;
+        }
+        for entry in ignore::Walk::new(src_dir) {
+            let source = entry?.into_path();
+            if !source.is_file()
+                || matches!(source.extension(), Some(ext) if ext == "pyc" || ext == "pyd" || ext == "so")
+            {
+                continue;
+            }
+            files
+                .entry(relative(&source)?)
+                .or_insert_with(|| rule.clone());
+        }
+    }
+    // maturin reads them for the metadata
+    for (file, field) in [
+        (package.readme.as_ref(), "readme"),
+        (package.license_file.as_ref(), "license-file"),
+    ] {
+        if let Some(file) = file {
+            // Files outside of the crate, e.g. the readme of the workspace, can't be packaged
+            if let Ok(relative) = clean_path(&manifest_dir.join(file)).strip_prefix(manifest_dir) {
+                files
+                    .entry(relative.to_path_buf())
+                    .or_insert_with(|| format!("package.{} of {}", field, package.name));
+            } else {
+                debug!(
+                    "Skipping {} of {} outside of the crate",
+                    field, package.name
+                );
+            }
+        }
+    }
+    Ok(files.into_iter().collect())
+}
+
+/// Adds a file to the source distribution, recording the rule that added it in the report
+fn add_reported_file(
+    writer: &mut SDistWriter,
+    report: &mut SdistReport,
+    target: PathBuf,
+    source: &Path,
+    rule: impl Into<String>,
+) -> Result<()> {
+    if !writer.exclude(source) {
+        report.entry(target.clone()).or_insert_with(|| rule.into());
+    }
+    writer.add_file(target, source)
+}
+
+/// The workspace a crate belongs to
+struct Workspace {
+    /// The directory containing the workspace Cargo.toml
+    root: PathBuf,
+    /// The parsed workspace Cargo.toml
+    manifest: toml_edit::Document,
+}
+
+impl Workspace {
+    /// Reads the Cargo.toml in the workspace root
+    fn read(root: impl Into<PathBuf>) -> Result<Self> {
+        let root = root.into();
+        let manifest = fs::read_to_string(root.join("Cargo.toml"))?.parse()?;
+        Ok(Self { root, manifest })
+    }
+}
+
+/// Copies the files of a crate to a source distribution, recursively adding path dependencies
+/// and rewriting path entries in Cargo.toml
+///
+/// `file_list` contains the files to package relative to the manifest directory, with the rule
+/// that selected them.
+#[allow(clippy::too_many_arguments)]
+fn add_crate_to_source_distribution(
+    writer: &mut SDistWriter,
+    report: &mut SdistReport,
+    pyproject_toml_path: impl AsRef<Path>,
+    manifest_path: impl AsRef<Path>,
+    file_list: Vec<(PathBuf, String)>,
+    workspace: &Workspace,
+    prefix: impl AsRef<Path>,
+    known_path_deps: &HashMap<String, PathBuf>,
+    root_crate: bool,
+) -> Result<()> {
+    let manifest_path = manifest_path.as_ref();
+    let pyproject_toml_path = pyproject_toml_path.as_ref();
 
     let abs_manifest_path = manifest_path
         .normalize()
@@ -405,22 +1167,22 @@ fn add_crate_to_source_distribution(
 
     // manifest_dir should be a relative path
     let manifest_dir = manifest_path.parent().unwrap();
-    let mut target_source: Vec<(PathBuf, PathBuf)> = file_list
-        .iter()
-        .map(|relative_to_manifests| {
-            let relative_to_cwd = manifest_dir.join(relative_to_manifests);
+    let mut target_source: Vec<(PathBuf, PathBuf, String)> = file_list
+        .into_iter()
+        .map(|(relative_to_manifests, rule)| {
+            let relative_to_cwd = manifest_dir.join(&relative_to_manifests);
             if root_crate && cargo_toml_in_subdir {
                 let relative_to_project_root = abs_manifest_dir
                     .strip_prefix(pyproject_dir)
                     .unwrap()
                     .join(relative_to_manifests);
-                (relative_to_project_root, relative_to_cwd)
+                (relative_to_project_root, relative_to_cwd, rule)
             } else {
-                (relative_to_manifests.to_path_buf(), relative_to_cwd)
+                (relative_to_manifests, relative_to_cwd, rule)
             }
         })
         // We rewrite Cargo.toml and add it separately
-        .filter(|(target, source)| {
+        .filter(|(target, source, _)| {
             #[allow(clippy::if_same_then_else)]
             // Skip generated files. See https://github.com/rust-lang/cargo/issues/7938#issuecomment-593280660
             // and https://github.com/PyO3/maturin/issues/449
@@ -447,7 +1209,7 @@ fn add_crate_to_source_distribution(
     if root_crate
         && !target_source
             .iter()
-            .any(|(target, _)| target == Path::new("pyproject.toml"))
+            .any(|(target, _, _)| target == Path::new("pyproject.toml"))
     {
         // Add pyproject.toml to the source distribution
         // `pyproject.toml` may not be included in `cargo package --list`
@@ -461,6 +1223,7 @@ fn add_crate_to_source_distribution(
             target_source.push((
                 PathBuf::from("pyproject.toml"),
                 pyproject_toml_path.to_path_buf(),
+                "pyproject.toml".to_string(),
             ));
         } else {
             // if pyproject.toml was not included by `cargo package --list`
@@ -472,7 +1235,7 @@ fn add_crate_to_source_distribution(
             let rust_src = Path::new(RUST_SRC_FOLDER).join(crate_name);
             if target_source
                 .iter()
-                .any(|(target, _)| target.starts_with(&rust_src))
+                .any(|(target, _, _)| target.starts_with(&rust_src))
             {
                 bail!(
                     "Cannot add crate {} to source distribution because there is already a {} folder, consider rename it to avoid conflicts",
@@ -480,11 +1243,14 @@ fn add_crate_to_source_distribution(
                     rust_src.display(),
                 );
             }
-            target_source.iter_mut().for_each(|(target, _)| {
+            target_source.iter_mut().for_each(|(target, _, _)| {
                 *target = rust_src.join(&target);
             });
             // rewrite `tool.maturin.manifest-path` in pyproject.toml
             let rewritten_pyproject_toml = rewrite_pyproject_toml(pyproject_toml_path)?;
+            report
+                .entry(prefix.join("pyproject.toml"))
+                .or_insert_with(|| "pyproject.toml".to_string());
             writer.add_bytes(
                 prefix.join("pyproject.toml"),
                 rewritten_pyproject_toml.as_bytes(),
@@ -520,16 +1286,22 @@ fn add_crate_to_source_distribution(
     };
     let rewritten_cargo_toml = rewrite_cargo_toml(
         manifest_path,
-        workspace_manifest,
+        &workspace.manifest,
+        &workspace.root,
         known_path_deps,
         local_deps_folder,
         root_crate,
     )?;
 
+    if !writer.exclude(&cargo_toml_path) {
+        report
+            .entry(cargo_toml_path.clone())
+            .or_insert_with(|| "Cargo.toml".to_string());
+    }
     writer.add_bytes(cargo_toml_path, rewritten_cargo_toml.as_bytes())?;
 
-    for (target, source) in target_source {
-        writer.add_file(prefix.join(target), source)?;
+    for (target, source, rule) in target_source {
+        add_reported_file(writer, report, prefix.join(target), &source, rule)?;
     }
 
     Ok(())
@@ -615,24 +1387,55 @@ fn add_git_tracked_files_to_sdist(
 
 /// Copies the files of a crate to a source distribution, recursively adding path dependencies
 /// and rewriting path entries in Cargo.toml
+///
+/// The files of the crates are the output of `cargo package --list`, or the sources of their
+/// cargo targets with [SdistGenerator::Manifest].
 fn add_cargo_package_files_to_sdist(
     build_context: &BuildContext,
     pyproject_toml_path: &Path,
     writer: &mut SDistWriter,
+    report: &mut SdistReport,
     root_dir: &Path,
+    generator: SdistGenerator,
 ) -> Result<()> {
     let manifest_path = &build_context.manifest_path;
-    let workspace_manifest_path = build_context
-        .cargo_metadata
-        .workspace_root
-        .join("Cargo.toml");
-    let workspace_manifest: toml_edit::Document =
-        fs::read_to_string(workspace_manifest_path)?.parse()?;
+    let workspace = Workspace::read(&build_context.cargo_metadata.workspace_root)?;
 
     let known_path_deps = find_path_deps(&build_context.cargo_metadata)?;
+    let crate_file_list = |manifest_path: &Path| -> Result<Vec<(PathBuf, String)>> {
+        match generator {
+            SdistGenerator::Manifest => {
+                let package = build_context
+                    .cargo_metadata
+                    .packages
+                    .iter()
+                    .find(|package| {
+                        same_file::is_same_file(&package.manifest_path, manifest_path)
+                            .unwrap_or(false)
+                    })
+                    .with_context(|| {
+                        format!(
+                            "{} is not part of the dependency graph ಠ_ಠ",
+                            manifest_path.display()
+                        )
+                    })?;
+                cargo_target_file_list(package)
+            }
+            SdistGenerator::Cargo | SdistGenerator::Git => cargo_package_file_list(manifest_path),
+        }
+    };
+
+    // Added first so that they take precedence over the files of the crates with the same path
+    add_build_config_to_sdist(
+        build_context,
+        pyproject_toml_path.parent().unwrap(),
+        writer,
+        report,
+        root_dir,
+    )?;
 
     // Add local path dependencies
-    let mut path_dep_workspace_manifests = HashMap::new();
+    let mut path_dep_workspaces = HashMap::new();
     for (name, path_dep) in known_path_deps.iter() {
         // Path dependencies may not be in the same workspace as the root crate,
         // thus we need to find out its workspace root from `cargo metadata`
@@ -649,24 +1452,31 @@ fn add_cargo_package_files_to_sdist(
                     path_dep.display()
                 )
             })?;
-        let path_dep_workspace_manifest =
+        let path_dep_workspace =
             if path_dep_metadata.workspace_root == build_context.cargo_metadata.workspace_root {
-                &workspace_manifest
+                &workspace
             } else {
-                if !path_dep_workspace_manifests.contains_key(&path_dep_metadata.workspace_root) {
-                    let manifest: toml_edit::Document =
-                        fs::read_to_string(path_dep_metadata.workspace_root.join("Cargo.toml"))?
-                            .parse()?;
-                    path_dep_workspace_manifests
-                        .insert(path_dep_metadata.workspace_root.clone(), manifest);
+                if !path_dep_workspaces.contains_key(&path_dep_metadata.workspace_root) {
+                    let path_dep_workspace = Workspace::read(&path_dep_metadata.workspace_root)?;
+                    path_dep_workspaces
+                        .insert(path_dep_metadata.workspace_root.clone(), path_dep_workspace);
                 }
-                &path_dep_workspace_manifests[&path_dep_metadata.workspace_root]
+                &path_dep_workspaces[&path_dep_metadata.workspace_root]
             };
+        let file_list = crate_file_list(path_dep).with_context(|| {
+            format!(
+                "Failed to list the files of local dependency {} at {}",
+                name,
+                path_dep.display()
+            )
+        })?;
         add_crate_to_source_distribution(
             writer,
+            report,
             pyproject_toml_path,
             path_dep,
-            path_dep_workspace_manifest,
+            file_list,
+            path_dep_workspace,
             &root_dir.join(LOCAL_DEPENDENCIES_FOLDER).join(name),
             &known_path_deps,
             false,
@@ -681,9 +1491,11 @@ fn add_cargo_package_files_to_sdist(
     // Add the main crate
     add_crate_to_source_distribution(
         writer,
+        report,
         pyproject_toml_path,
         manifest_path,
-        &workspace_manifest,
+        crate_file_list(manifest_path)?,
+        &workspace,
         root_dir,
         &known_path_deps,
         true,
@@ -711,10 +1523,22 @@ fn add_cargo_package_files_to_sdist(
             cargo_lock_path.strip_prefix(abs_manifest_dir).unwrap()
         };
         if cargo_lock_exists {
-            writer.add_file(root_dir.join(relative_cargo_lock), &cargo_lock_path)?;
+            add_reported_file(
+                writer,
+                report,
+                root_dir.join(relative_cargo_lock),
+                &cargo_lock_path,
+                "Cargo.lock",
+            )?;
         } else {
             // Fallback to workspace Cargo lock file
-            writer.add_file(root_dir.join(relative_cargo_lock), workspace_cargo_lock)?;
+            add_reported_file(
+                writer,
+                report,
+                root_dir.join(relative_cargo_lock),
+                workspace_cargo_lock.as_std_path(),
+                "Cargo.lock of the workspace",
+            )?;
         }
     } else {
         eprintln!(
@@ -738,7 +1562,14 @@ fn add_cargo_package_files_to_sdist(
     }
 
     for package in python_packages {
-        for entry in ignore::Walk::new(package) {
+        let rule = format!(
+            "python package {}",
+            package
+                .strip_prefix(pyproject_dir)
+                .unwrap_or(&package)
+                .display()
+        );
+        for entry in ignore::Walk::new(&package) {
             let source = entry?.into_path();
             // Technically, `ignore` crate should handle this,
             // but somehow it doesn't on Alpine Linux running in GitHub Actions,
@@ -756,7 +1587,7 @@ fn add_cargo_package_files_to_sdist(
             if source.is_dir() {
                 writer.add_directory(target)?;
             } else {
-                writer.add_file(target, &source)?;
+                add_reported_file(writer, report, target, &source, &rule)?;
             }
         }
     }
@@ -793,42 +1624,75 @@ pub fn source_distribution(
         &metadata21.get_version_escaped()
     ));
 
-    match pyproject.sdist_generator() {
-        SdistGenerator::Cargo => add_cargo_package_files_to_sdist(
+    let mut report = SdistReport::new();
+    // Vendor first, since `cargo vendor` writes a missing Cargo.lock
+    if build_context.sdist_vendor {
+        add_vendored_dependencies(build_context, &mut writer, &mut report, &root_dir)?;
+    }
+    let sdist_generator = pyproject.sdist_generator();
+    match sdist_generator {
+        SdistGenerator::Cargo | SdistGenerator::Manifest => add_cargo_package_files_to_sdist(
             build_context,
             &pyproject_toml_path,
             &mut writer,
+            &mut report,
             &root_dir,
+            sdist_generator,
         )?,
         SdistGenerator::Git => {
             add_git_tracked_files_to_sdist(&pyproject_toml_path, &mut writer, &root_dir)?
         }
     }
+    if pyproject.version_provider().is_some() {
+        writer.rewrite_file(root_dir.join("pyproject.toml"), |pyproject_toml| {
+            freeze_version(pyproject_toml, &metadata21.version)
+        })?;
+    }
+    if build_context.sdist_vendor {
+        writer.rewrite_file(root_dir.join("pyproject.toml"), freeze_offline)?;
+    }
+    if let Some(build_options) = &build_context.sdist_build_options {
+        writer.rewrite_file(root_dir.join("pyproject.toml"), |pyproject_toml| {
+            embed_build_options(pyproject_toml, build_options, build_context.sdist_strip)
+        })?;
+    }
 
-    let pyproject_toml_path = build_context
-        .pyproject_toml_path
-        .normalize()
-        .with_context(|| {
-            format!(
-                "failed to normalize path `{}`",
-                build_context.pyproject_toml_path.display()
-            )
-        })?
-        .into_path_buf();
     let pyproject_dir = pyproject_toml_path.parent().unwrap();
     // Add readme, license
     if let Some(project) = pyproject.project.as_ref() {
         if let Some(pyproject_toml::ReadMe::RelativePath(readme)) = project.readme.as_ref() {
-            writer.add_file(root_dir.join(readme), pyproject_dir.join(readme))?;
+            add_reported_file(
+                &mut writer,
+                &mut report,
+                root_dir.join(readme),
+                &pyproject_dir.join(readme),
+                "project.readme",
+            )?;
         }
         if let Some(pyproject_toml::License::Table {
             file: Some(license),
             text: None,
         }) = project.license.as_ref()
         {
-            writer.add_file(root_dir.join(license), pyproject_dir.join(license))?;
+            add_reported_file(
+                &mut writer,
+                &mut report,
+                root_dir.join(license),
+                &pyproject_dir.join(license),
+                "project.license",
+            )?;
         }
     }
+    // The `License-File` entries in PKG-INFO are relative to the sdist root
+    for (relative, license_file) in metadata21.license_file_paths()? {
+        add_reported_file(
+            &mut writer,
+            &mut report,
+            root_dir.join(relative),
+            license_file,
+            "license files",
+        )?;
+    }
 
     let mut include = |pattern| -> Result<()> {
         eprintln!("📦 Including files matching \"{pattern}\"");
@@ -840,7 +1704,13 @@ pub fn source_distribution(
             if source.is_dir() {
                 writer.add_directory(target)?;
             } else {
-                writer.add_file(target, source)?;
+                add_reported_file(
+                    &mut writer,
+                    &mut report,
+                    target,
+                    &source,
+                    format!("include \"{pattern}\""),
+                )?;
             }
         }
         Ok(())
@@ -863,6 +1733,13 @@ pub fn source_distribution(
     add_data(&mut writer, build_context.project_layout.data.as_deref())?;
     let source_distribution_path = writer.finish()?;
 
+    if matches!(sdist_generator, SdistGenerator::Manifest) {
+        eprintln!("📋 Files selected for the source distribution:");
+        for (target, rule) in &report {
+            eprintln!("    {} ({})", target.display(), rule);
+        }
+    }
+
     eprintln!(
         "📦 Built source distribution to {}",
         source_distribution_path.display()
@@ -870,3 +1747,456 @@ pub fn source_distribution(
 
     Ok(source_distribution_path)
 }
+
+/// Restores the current directory when dropped
+struct CurrentDirGuard(PathBuf);
+
+impl Drop for CurrentDirGuard {
+    fn drop(&mut self) {
+        let _ = env::set_current_dir(&self.0);
+    }
+}
+
+/// Unpacks a source distribution into `dir`, returning the directory it was unpacked to and the
+/// files it contains
+fn unpack_source_distribution(sdist_path: &Path, dir: &Path) -> Result<(PathBuf, Vec<PathBuf>)> {
+    let mut archive = tar::Archive::new(GzDecoder::new(fs::File::open(sdist_path)?));
+    let mut files = Vec::new();
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        if !entry.unpack_in(dir)? {
+            bail!(
+                "{} contains an invalid path {}",
+                sdist_path.display(),
+                path.display()
+            );
+        }
+        if entry.header().entry_type().is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    let root_dir = files
+        .first()
+        .and_then(|file| file.components().next())
+        .map(|root| dir.join(root))
+        .with_context(|| format!("{} is empty", sdist_path.display()))?;
+    Ok((root_dir, files))
+}
+
+/// Checks that wheels can be built from a source distribution by unpacking it into a temporary
+/// directory and building them there with `build_options`, like pip does when installing from
+/// the source distribution. The manifest path is resolved from inside the unpacked source
+/// distribution and the wheels are discarded.
+///
+/// This temporarily changes the current directory of the process, so that cargo picks up the
+/// `.cargo/config.toml` of the source distribution.
+pub fn check_source_distribution(
+    sdist_path: &Path,
+    mut build_options: BuildOptions,
+    release: bool,
+    strip: bool,
+) -> Result<()> {
+    eprintln!(
+        "🔍 Checking that wheels can be built from {}",
+        sdist_path.display()
+    );
+    let temp_dir = tempfile::tempdir()?;
+    let (root_dir, files) = unpack_source_distribution(sdist_path, temp_dir.path())?;
+
+    let current_dir = env::current_dir().context("Failed to detect current directory ಠ_ಠ")?;
+    // Relative paths given on the command line are relative to the original directory
+    for interpreter in &mut build_options.interpreter {
+        if interpreter.is_relative() && interpreter.components().count() > 1 {
+            *interpreter = current_dir.join(&*interpreter);
+        }
+    }
+    if let Some(target_dir) = build_options.cargo.target_dir.as_mut() {
+        if target_dir.is_relative() {
+            *target_dir = current_dir.join(&*target_dir);
+        }
+    }
+    build_options.cargo.manifest_path = None;
+    build_options.out = Some(temp_dir.path().join("wheels"));
+
+    let result = {
+        let _guard = CurrentDirGuard(current_dir);
+        env::set_current_dir(&root_dir)?;
+        build_options
+            .into_build_context(release, strip, false)
+            .and_then(|build_context| build_context.build_wheels())
+    };
+    let wheels = result.with_context(|| {
+        format!(
+            "Failed to build a wheel from the source distribution {}, which contains these files:\n    {}",
+            sdist_path.display(),
+            files
+                .iter()
+                .map(|file| file.display().to_string())
+                .collect::<Vec<_>>()
+                .join("\n    ")
+        )
+    })?;
+    eprintln!(
+        "✔ Built {} wheel(s) from the source distribution",
+        wheels.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        embed_build_options, freeze_offline, freeze_version, inline_workspace_build_config,
+        merge_cargo_config, project_config_dirs, rewrite_cargo_config, unpack_source_distribution,
+        vendored_cargo_config,
+    };
+    use crate::auditwheel::AuditWheelMode;
+    use crate::{BuildOptions, CargoOptions, PlatformTag};
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use fs_err::File;
+    use indoc::indoc;
+    use pep440_rs::Version;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_freeze_version() {
+        let pyproject_toml = indoc!(
+            r#"
+            [project]
+            name = "foo"
+            dynamic = ["version", "readme"]
+
+            [tool.maturin]
+            bindings = "pyo3"
+
+            [tool.maturin.version]
+            provider = "git"
+            "#
+        );
+        let version = Version::from_str("1.2.0.post3+g1a2b3c4").unwrap();
+        assert_eq!(
+            freeze_version(pyproject_toml, &version).unwrap(),
+            indoc!(
+                r#"
+                [project]
+                name = "foo"
+                dynamic = ["readme"]
+                version = "1.2.0.post3+g1a2b3c4"
+
+                [tool.maturin]
+                bindings = "pyo3"
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn test_freeze_offline() {
+        let pyproject_toml = indoc!(
+            r#"
+            [project]
+            name = "foo"
+
+            [tool.maturin]
+            bindings = "pyo3"
+            sdist-vendor = true
+            "#
+        );
+        assert_eq!(
+            freeze_offline(pyproject_toml).unwrap(),
+            indoc!(
+                r#"
+                [project]
+                name = "foo"
+
+                [tool.maturin]
+                bindings = "pyo3"
+                offline = true
+                "#
+            )
+        );
+        assert_eq!(
+            freeze_offline("[project]\nname = \"foo\"\n").unwrap(),
+            "[project]\nname = \"foo\"\n\n[tool.maturin]\noffline = true\n"
+        );
+    }
+
+    #[test]
+    fn test_embed_build_options() {
+        let pyproject_toml = indoc!(
+            r#"
+            [project]
+            name = "foo"
+
+            [tool.maturin]
+            bindings = "pyo3"
+            features = ["bar"]
+
+            [tool.maturin.auditwheel]
+            exclude-libs = ["libcuda.so.*"]
+            "#
+        );
+        let build_options = BuildOptions {
+            platform_tag: vec![PlatformTag::Manylinux { x: 2, y: 28 }],
+            auditwheel: Some(AuditWheelMode::Check),
+            cargo: CargoOptions {
+                profile: Some("dist".to_string()),
+                features: vec!["baz".to_string(), "qux".to_string()],
+                no_default_features: true,
+                quiet: true,
+                offline: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(
+            embed_build_options(pyproject_toml, &build_options, true).unwrap(),
+            indoc!(
+                r#"
+                [project]
+                name = "foo"
+
+                [tool.maturin]
+                bindings = "pyo3"
+                features = ["baz", "qux"]
+                compatibility = "manylinux_2_28"
+                profile = "dist"
+                no-default-features = true
+                strip = true
+
+                [tool.maturin.auditwheel]
+                exclude-libs = ["libcuda.so.*"]
+                mode = "check"
+                "#
+            )
+        );
+        // Nothing to embed
+        assert_eq!(
+            embed_build_options(pyproject_toml, &BuildOptions::default(), false).unwrap(),
+            pyproject_toml
+        );
+    }
+
+    #[test]
+    fn test_vendored_cargo_config() {
+        let cargo_vendor_output = indoc!(
+            r#"
+            [source.crates-io]
+            replace-with = "vendored-sources"
+
+            [source."git+https://github.com/PyO3/pyo3?branch=main"]
+            git = "https://github.com/PyO3/pyo3"
+            branch = "main"
+            replace-with = "vendored-sources"
+
+            [source.vendored-sources]
+            directory = "/tmp/.tmpa1b2c3/vendor"
+            "#
+        );
+        assert_eq!(
+            vendored_cargo_config(cargo_vendor_output).unwrap(),
+            cargo_vendor_output.replace("/tmp/.tmpa1b2c3/vendor", "vendor")
+        );
+    }
+
+    #[test]
+    fn test_unpack_source_distribution() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let sdist_path = temp_dir.path().join("foo-0.1.0.tar.gz");
+        let mut builder = tar::Builder::new(GzEncoder::new(
+            File::create(&sdist_path).unwrap(),
+            Compression::default(),
+        ));
+        for (path, content) in [
+            ("foo-0.1.0/src/lib.rs", "pub fn foo() {}\n"),
+            ("foo-0.1.0/Cargo.toml", "[package]\nname = \"foo\"\n"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+
+        let unpack_dir = temp_dir.path().join("unpacked");
+        fs_err::create_dir(&unpack_dir).unwrap();
+        let (root_dir, files) = unpack_source_distribution(&sdist_path, &unpack_dir).unwrap();
+        assert_eq!(root_dir, unpack_dir.join("foo-0.1.0"));
+        assert_eq!(
+            files,
+            [
+                PathBuf::from("foo-0.1.0/Cargo.toml"),
+                PathBuf::from("foo-0.1.0/src/lib.rs")
+            ]
+        );
+        assert!(root_dir.join("src").join("lib.rs").is_file());
+    }
+
+    #[test]
+    fn test_inline_workspace_build_config() {
+        let workspace_manifest = indoc!(
+            r#"
+            [workspace]
+            members = ["python", "crates/*"]
+
+            [profile.release]
+            lto = true
+
+            [patch.crates-io]
+            bar = { path = "crates/bar" }
+            baz = { path = "../baz" }
+            pyo3 = { git = "https://github.com/PyO3/pyo3" }
+            "#
+        )
+        .parse::<toml_edit::Document>()
+        .unwrap();
+        let mut document = "[package]\nname = \"foo\"\n"
+            .parse::<toml_edit::Document>()
+            .unwrap();
+        let known_path_deps = HashMap::from([(
+            "bar".to_string(),
+            PathBuf::from("/repo/python/../crates/bar/Cargo.toml"),
+        )]);
+        assert!(inline_workspace_build_config(
+            &mut document,
+            &workspace_manifest,
+            Path::new("/repo"),
+            &known_path_deps,
+            "local_dependencies",
+        ));
+        assert_eq!(
+            document.to_string(),
+            indoc!(
+                r#"
+                [package]
+                name = "foo"
+
+                [profile.release]
+                lto = true
+
+                [patch.crates-io]
+                bar = { path = "local_dependencies/bar" }
+                pyo3 = { git = "https://github.com/PyO3/pyo3" }
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn test_project_config_dirs() {
+        assert_eq!(
+            project_config_dirs(Path::new("/repo/python/foo"), Path::new("/repo/rust")),
+            [
+                Path::new("/repo/python/foo"),
+                Path::new("/repo/python"),
+                Path::new("/repo")
+            ]
+        );
+        assert_eq!(
+            project_config_dirs(Path::new("/repo/foo"), Path::new("/repo/foo")),
+            [Path::new("/repo/foo")]
+        );
+    }
+
+    #[test]
+    fn test_rewrite_cargo_config() {
+        let config = indoc!(
+            r#"
+            paths = ["../patched"]
+
+            [build]
+            rustflags = ["-C", "target-cpu=native"]
+            target-dir = "../target"
+
+            [target.x86_64-unknown-linux-gnu]
+            linker = "tools/linker.sh"
+
+            [target.aarch64-unknown-linux-gnu]
+            linker = "aarch64-linux-gnu-gcc"
+
+            [source.crates-io]
+            replace-with = "mirror"
+
+            [source.mirror]
+            directory = "python/mirror"
+
+            [env]
+            FOO_DIR = { value = "python/data", relative = true }
+            "#
+        );
+        let config =
+            rewrite_cargo_config(config, Path::new("/repo"), Path::new("/repo/python")).unwrap();
+        assert_eq!(
+            config.to_string(),
+            indoc!(
+                r#"
+
+                [build]
+                rustflags = ["-C", "target-cpu=native"]
+
+                [target.x86_64-unknown-linux-gnu]
+
+                [target.aarch64-unknown-linux-gnu]
+                linker = "aarch64-linux-gnu-gcc"
+
+                [source.crates-io]
+                replace-with = "mirror"
+
+                [source.mirror]
+                directory = "mirror"
+
+                [env]
+                FOO_DIR = { value = "data", relative = true }
+                "#
+            )
+        );
+    }
+
+    #[test]
+    fn test_merge_cargo_config() {
+        let mut config = indoc!(
+            r#"
+            [build]
+            rustflags = ["-C", "target-cpu=native"]
+
+            [source.crates-io]
+            replace-with = "mirror"
+            "#
+        )
+        .parse::<toml_edit::Document>()
+        .unwrap();
+        let overlay = indoc!(
+            r#"
+            [build]
+            rustflags = ["--cfg", "foo"]
+
+            [source.crates-io]
+            replace-with = "vendored-sources"
+            "#
+        )
+        .parse::<toml_edit::Document>()
+        .unwrap();
+        merge_cargo_config(config.as_table_mut(), overlay.as_table());
+        assert_eq!(
+            config.to_string(),
+            indoc!(
+                r#"
+                [build]
+                rustflags = ["-C", "target-cpu=native", "--cfg", "foo"]
+
+                [source.crates-io]
+                replace-with = "vendored-sources"
+                "#
+            )
+        );
+    }
+}
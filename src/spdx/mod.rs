@@ -0,0 +1,294 @@
+//! Validating and normalizing SPDX license expressions for the `License-Expression` field of
+//! [PEP 639](https://peps.python.org/pep-0639/)
+use anyhow::{bail, Result};
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::HashMap;
+
+#[derive(Deserialize)]
+struct LicenseList {
+    licenses: Vec<String>,
+    exceptions: Vec<String>,
+}
+
+/// The license and the exception identifiers of the SPDX license list by their lowercase form,
+/// since the identifiers are matched case-insensitively
+static LICENSE_LIST: Lazy<(HashMap<String, String>, HashMap<String, String>)> = Lazy::new(|| {
+    // https://github.com/spdx/license-list-data
+    let list: LicenseList = serde_json::from_slice(include_bytes!("license-list.json"))
+        .expect("invalid license-list.json file");
+    let by_lowercase = |ids: Vec<String>| {
+        ids.into_iter()
+            .map(|id| (id.to_lowercase(), id))
+            .collect::<HashMap<_, _>>()
+    };
+    (by_lowercase(list.licenses), by_lowercase(list.exceptions))
+});
+
+/// A recursive descent parser for the SPDX license expression grammar, which writes the
+/// normalized expression while parsing
+struct Parser<'a> {
+    expression: &'a str,
+    tokens: Vec<&'a str>,
+    position: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(expression: &'a str) -> Self {
+        let mut tokens = Vec::new();
+        for word in expression.split_whitespace() {
+            let mut rest = word;
+            while !rest.is_empty() {
+                match rest.find(['(', ')']) {
+                    Some(0) => {
+                        tokens.push(&rest[..1]);
+                        rest = &rest[1..];
+                    }
+                    Some(index) => {
+                        tokens.push(&rest[..index]);
+                        rest = &rest[index..];
+                    }
+                    None => {
+                        tokens.push(rest);
+                        rest = "";
+                    }
+                }
+            }
+        }
+        Self {
+            expression,
+            tokens,
+            position: 0,
+        }
+    }
+
+    fn peek_operator(&self, operator: &str) -> bool {
+        self.tokens
+            .get(self.position)
+            .map_or(false, |token| token.eq_ignore_ascii_case(operator))
+    }
+
+    fn next(&mut self) -> Result<&'a str> {
+        match self.tokens.get(self.position) {
+            Some(token) => {
+                self.position += 1;
+                Ok(token)
+            }
+            None => bail!(
+                "Invalid license expression `{}`, it ends unexpectedly",
+                self.expression
+            ),
+        }
+    }
+
+    /// `or-expression := and-expression ("OR" and-expression)*`
+    fn or_expression(&mut self) -> Result<String> {
+        let mut normalized = self.and_expression()?;
+        while self.peek_operator("OR") {
+            self.position += 1;
+            normalized.push_str(" OR ");
+            normalized.push_str(&self.and_expression()?);
+        }
+        Ok(normalized)
+    }
+
+    /// `and-expression := with-expression ("AND" with-expression)*`
+    fn and_expression(&mut self) -> Result<String> {
+        let mut normalized = self.with_expression()?;
+        while self.peek_operator("AND") {
+            self.position += 1;
+            normalized.push_str(" AND ");
+            normalized.push_str(&self.with_expression()?);
+        }
+        Ok(normalized)
+    }
+
+    /// `with-expression := simple-expression ["WITH" exception-id]`
+    fn with_expression(&mut self) -> Result<String> {
+        let mut normalized = self.simple_expression()?;
+        if self.peek_operator("WITH") {
+            self.position += 1;
+            let exception = self.next()?;
+            match LICENSE_LIST.1.get(&exception.to_lowercase()) {
+                Some(id) => {
+                    normalized.push_str(" WITH ");
+                    normalized.push_str(id);
+                }
+                None => bail!(
+                    "Invalid license expression `{}`, `{exception}` is not a SPDX license exception",
+                    self.expression
+                ),
+            }
+        }
+        Ok(normalized)
+    }
+
+    /// `simple-expression := "(" or-expression ")" | license-id ["+"] | license-ref`
+    fn simple_expression(&mut self) -> Result<String> {
+        let token = self.next()?;
+        if token == "(" {
+            let inner = self.or_expression()?;
+            if self.next()? != ")" {
+                bail!(
+                    "Invalid license expression `{}`, expected a closing parenthesis",
+                    self.expression
+                );
+            }
+            return Ok(format!("({inner})"));
+        }
+
+        let is_idstring = |id: &str| {
+            !id.is_empty()
+                && id
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        };
+        if let Some(reference) = token.strip_prefix("LicenseRef-") {
+            if is_idstring(reference) {
+                return Ok(token.to_string());
+            }
+        } else if let Some((document, license)) = token
+            .strip_prefix("DocumentRef-")
+            .and_then(|reference| reference.split_once(":LicenseRef-"))
+        {
+            if is_idstring(document) && is_idstring(license) {
+                return Ok(token.to_string());
+            }
+        } else {
+            let (id, or_later) = match token.strip_suffix('+') {
+                Some(id) => (id, "+"),
+                None => (token, ""),
+            };
+            if let Some(id) = LICENSE_LIST.0.get(&id.to_lowercase()) {
+                return Ok(format!("{id}{or_later}"));
+            }
+        }
+        bail!(
+            "Invalid license expression `{}`, `{token}` is not a SPDX license identifier",
+            self.expression
+        )
+    }
+}
+
+/// Validates a SPDX license expression and returns it with the identifiers and the operators in
+/// their canonical case, e.g. `mit or apache-2.0` becomes `MIT OR Apache-2.0`
+pub fn normalize_license_expression(expression: &str) -> Result<String> {
+    let mut parser = Parser::new(expression);
+    let normalized = parser.or_expression()?;
+    if let Some(token) = parser.tokens.get(parser.position) {
+        bail!(
+            "Invalid license expression `{expression}`, unexpected `{token}` after `{normalized}`"
+        );
+    }
+    Ok(normalized)
+}
+
+/// Normalizes the `license` of a Cargo package, which is a SPDX license expression except that
+/// older crates still use `/` for `OR`
+pub fn normalize_cargo_license(license: &str) -> Result<String> {
+    normalize_license_expression(&license.replace('/', " OR "))
+}
+
+/// The license identifiers in a normalized license expression, without operators, exceptions
+/// and the `+` suffix
+pub fn license_ids(normalized: &str) -> Vec<&str> {
+    let mut ids = Vec::new();
+    let mut after_with = false;
+    for token in normalized.split_whitespace() {
+        let token = token.trim_matches(|c| c == '(' || c == ')');
+        match token {
+            "AND" | "OR" => {}
+            "WITH" => after_with = true,
+            _ if after_with => after_with = false,
+            _ => {
+                let id = token.strip_suffix('+').unwrap_or(token);
+                if !ids.contains(&id) {
+                    ids.push(id);
+                }
+            }
+        }
+    }
+    ids
+}
+
+/// The text of a license from https://github.com/spdx/license-list-data, for crates that don't
+/// include their license files. Only the licenses most used by Rust crates are bundled
+pub fn license_text(id: &str) -> Option<&'static str> {
+    let text = match id {
+        "0BSD" => include_str!("texts/0BSD.txt"),
+        "Apache-2.0" => include_str!("texts/Apache-2.0.txt"),
+        "BSD-2-Clause" => include_str!("texts/BSD-2-Clause.txt"),
+        "BSD-3-Clause" => include_str!("texts/BSD-3-Clause.txt"),
+        "BSL-1.0" => include_str!("texts/BSL-1.0.txt"),
+        "CC0-1.0" => include_str!("texts/CC0-1.0.txt"),
+        "ISC" => include_str!("texts/ISC.txt"),
+        "MIT" => include_str!("texts/MIT.txt"),
+        "MPL-2.0" => include_str!("texts/MPL-2.0.txt"),
+        "Unicode-3.0" => include_str!("texts/Unicode-3.0.txt"),
+        "Unicode-DFS-2016" => include_str!("texts/Unicode-DFS-2016.txt"),
+        "Unlicense" => include_str!("texts/Unlicense.txt"),
+        "Zlib" => include_str!("texts/Zlib.txt"),
+        _ => return None,
+    };
+    Some(text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{license_ids, normalize_license_expression};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_normalize_license_expression() {
+        let cases = [
+            ("MIT", "MIT"),
+            ("mit or apache-2.0", "MIT OR Apache-2.0"),
+            (
+                "(MIT OR Apache-2.0) AND BSD-3-Clause",
+                "(MIT OR Apache-2.0) AND BSD-3-Clause",
+            ),
+            (
+                "GPL-2.0-or-later with classpath-exception-2.0",
+                "GPL-2.0-or-later WITH Classpath-exception-2.0",
+            ),
+            ("Apache-2.0+", "Apache-2.0+"),
+            ("LicenseRef-Proprietary", "LicenseRef-Proprietary"),
+            (
+                "DocumentRef-spdx:LicenseRef-MIT",
+                "DocumentRef-spdx:LicenseRef-MIT",
+            ),
+        ];
+        for (expression, normalized) in cases {
+            assert_eq!(
+                normalize_license_expression(expression).unwrap(),
+                normalized
+            );
+        }
+
+        for expression in [
+            "",
+            "MIT/Apache-2.0",
+            "Not a license",
+            "MIT OR",
+            "(MIT",
+            "MIT)",
+            "MIT WITH MIT",
+            "LicenseRef-",
+        ] {
+            assert!(
+                normalize_license_expression(expression).is_err(),
+                "{expression} should be invalid"
+            );
+        }
+        assert_eq!(
+            license_ids("(MIT OR Apache-2.0 WITH LLVM-exception) AND GPL-2.0+ AND MIT"),
+            ["MIT", "Apache-2.0", "GPL-2.0"]
+        );
+        assert_eq!(
+            normalize_license_expression("MIT AND Foo")
+                .unwrap_err()
+                .to_string(),
+            "Invalid license expression `MIT AND Foo`, `Foo` is not a SPDX license identifier"
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Bundling the license texts of the Rust crates that are statically linked into a wheel
+use crate::module_writer::ModuleWriter;
+use crate::sbom::LinkedCrates;
+use crate::spdx::{license_ids, license_text, normalize_cargo_license};
+use anyhow::{Context, Result};
+use cargo_metadata::Package;
+use fs_err as fs;
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+/// File names starting with these, compared case insensitive, are license files. This matches
+/// the default `project.license-files` globs `LICEN[CS]E*`, `COPYING*`, `NOTICE*` and `AUTHORS*`,
+/// and additionally the `UNLICENSE` file
+const LICENSE_FILE_PREFIXES: &[&str] = &[
+    "license",
+    "licence",
+    "copying",
+    "notice",
+    "authors",
+    "unlicense",
+];
+
+/// Where the license text of a crate comes from
+#[derive(Debug, PartialEq, Eq)]
+enum LicenseText {
+    /// A license file in the crate's package
+    File(PathBuf),
+    /// The SPDX text of a license identifier from the crate's `license` field
+    Spdx(String),
+}
+
+/// The license of a linked crate
+#[derive(Debug)]
+struct CrateLicense<'a> {
+    package: &'a Package,
+    /// The `license` field, normalized if it's a valid SPDX expression
+    license: Option<String>,
+    /// The license texts, empty if neither license files nor a bundled SPDX text were found
+    texts: Vec<LicenseText>,
+}
+
+impl<'a> CrateLicense<'a> {
+    fn collect(package: &'a Package) -> Result<Self> {
+        let package_dir = package
+            .manifest_path
+            .parent()
+            .context("Cargo.toml has no parent directory")?
+            .as_std_path();
+        let mut files = Vec::new();
+        if let Some(license_file) = &package.license_file {
+            let license_file = package_dir.join(license_file);
+            if license_file.is_file() {
+                files.push(license_file);
+            }
+        }
+        for entry in fs::read_dir(package_dir)? {
+            let path = entry?.path();
+            let is_license_file = path.file_name().map_or(false, |name| {
+                let name = name.to_string_lossy().to_lowercase();
+                LICENSE_FILE_PREFIXES
+                    .iter()
+                    .any(|prefix| name.starts_with(prefix))
+            });
+            if is_license_file && path.is_file() && !files.contains(&path) {
+                files.push(path);
+            }
+        }
+        files.sort();
+
+        let normalized = package
+            .license
+            .as_deref()
+            .and_then(|license| normalize_cargo_license(license).ok());
+        let texts = if files.is_empty() {
+            // Crates often only declare their license, so we fall back to the license texts
+            normalized
+                .as_deref()
+                .map(license_ids)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|id| license_text(id).is_some())
+                .map(|id| LicenseText::Spdx(id.to_string()))
+                .collect()
+        } else {
+            files.into_iter().map(LicenseText::File).collect()
+        };
+        Ok(Self {
+            package,
+            license: normalized.or_else(|| package.license.clone()),
+            texts,
+        })
+    }
+
+    /// The directory for the license texts of the crate
+    fn dir_name(&self) -> String {
+        format!("{}-{}", self.package.name, self.package.version)
+    }
+}
+
+/// Writes the license texts of all linked crates except the root package to
+/// `<dist_info_dir>/licenses/third-party/<crate>-<version>` and a summary of the crates and
+/// their licenses to `<dist_info_dir>/licenses/third-party/SUMMARY.txt`
+pub fn write_third_party_licenses(
+    writer: &mut impl ModuleWriter,
+    dist_info_dir: &Path,
+    linked_crates: &LinkedCrates,
+) -> Result<()> {
+    let third_party_dir = dist_info_dir.join("licenses").join("third-party");
+    let root = linked_crates.root();
+    let mut summary = format!(
+        "Licenses of the third-party Rust crates linked into {} {}\n",
+        root.name, root.version
+    );
+    for package in linked_crates.packages() {
+        let crate_license = CrateLicense::collect(package)
+            .with_context(|| format!("Failed to collect the license files of {}", package.name))?;
+        let crate_dir = crate_license.dir_name();
+        writeln!(summary, "\n{} {}", package.name, package.version)?;
+        writeln!(
+            summary,
+            "  License: {}",
+            crate_license.license.as_deref().unwrap_or("unknown")
+        )?;
+        if let Some(repository) = &package.repository {
+            writeln!(summary, "  Repository: {repository}")?;
+        }
+        if crate_license.texts.is_empty() {
+            eprintln!(
+                "⚠️  Warning: Found no license text for {} {}",
+                package.name, package.version
+            );
+            writeln!(summary, "  License texts: none found")?;
+        }
+        for text in &crate_license.texts {
+            let (file_name, note) = match text {
+                LicenseText::File(path) => {
+                    (path.file_name().unwrap().to_string_lossy().to_string(), "")
+                }
+                LicenseText::Spdx(id) => (format!("{id}.txt"), " (SPDX license text)"),
+            };
+            let target = third_party_dir.join(&crate_dir).join(&file_name);
+            match text {
+                LicenseText::File(path) => writer.add_file(target, path)?,
+                LicenseText::Spdx(id) => {
+                    writer.add_bytes(target, license_text(id).unwrap().as_bytes())?
+                }
+            }
+            writeln!(summary, "  License text: {crate_dir}/{file_name}{note}")?;
+        }
+    }
+    writer.add_bytes(third_party_dir.join("SUMMARY.txt"), summary.as_bytes())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cargo_metadata::MetadataCommand;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn test_crate_license() {
+        let cargo_metadata = MetadataCommand::new()
+            .manifest_path("test-crates/license-test/Cargo.toml")
+            .exec()
+            .unwrap();
+        let package = cargo_metadata.root_package().unwrap();
+        let crate_license = CrateLicense::collect(package).unwrap();
+        let package_dir = package.manifest_path.parent().unwrap().as_std_path();
+        assert_eq!(
+            crate_license.texts,
+            [
+                LicenseText::File(package_dir.join("AUTHORS.txt")),
+                LicenseText::File(package_dir.join("LICENCE.txt")),
+                LicenseText::File(package_dir.join("LICENSE")),
+                LicenseText::File(package_dir.join("NOTICE.md")),
+            ]
+        );
+        assert_eq!(crate_license.license.as_deref(), Some("MIT"));
+        assert_eq!(crate_license.dir_name(), "license-test-0.1.0");
+    }
+}
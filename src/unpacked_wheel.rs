@@ -0,0 +1,270 @@
+//! Support for reading and rewriting wheels that have been built before, e.g. by an older
+//! maturin version or another build backend
+use crate::module_writer::{ModuleWriter, WheelWriter};
+use anyhow::{bail, Context, Result};
+use fs_err as fs;
+use fs_err::File;
+use std::collections::HashMap;
+#[cfg(unix)]
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+/// A wheel extracted to a temporary directory
+///
+/// The filename tags can be changed before writing it out again with [UnpackedWheel::write],
+/// which updates the `Tag` lines of the WHEEL file and regenerates RECORD.
+#[derive(Debug)]
+pub struct UnpackedWheel {
+    /// The escaped distribution name from the filename
+    pub name: String,
+    /// The escaped version from the filename
+    pub version: String,
+    /// The optional build tag from the filename
+    pub build_tag: Option<String>,
+    /// The python tag, e.g. `cp38` or `py3`, possibly compressed like `py2.py3`
+    pub python_tag: String,
+    /// The abi tag, e.g. `abi3` or `none`
+    pub abi_tag: String,
+    /// The platform tag, e.g. `manylinux_2_17_x86_64.manylinux2014_x86_64`
+    pub platform_tag: String,
+    dir: TempDir,
+    dist_info_dir: PathBuf,
+    permissions: HashMap<PathBuf, u32>,
+}
+
+impl UnpackedWheel {
+    /// Extracts the wheel at `path` into a temporary directory
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let filename = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .with_context(|| format!("Invalid wheel path {}", path.display()))?;
+        let stem = filename
+            .strip_suffix(".whl")
+            .with_context(|| format!("{filename} is not a wheel, it must end with `.whl`"))?;
+        let parts: Vec<&str> = stem.split('-').collect();
+        let (name, version, build_tag, python_tag, abi_tag, platform_tag) = match parts[..] {
+            [name, version, python, abi, platform] => (name, version, None, python, abi, platform),
+            [name, version, build, python, abi, platform] => {
+                (name, version, Some(build), python, abi, platform)
+            }
+            _ => bail!(
+                "Invalid wheel filename {filename}, expected \
+                 {{distribution}}-{{version}}(-{{build tag}})?-{{python tag}}-{{abi tag}}-{{platform tag}}.whl"
+            ),
+        };
+
+        let dir = tempfile::tempdir()?;
+        let mut permissions = HashMap::new();
+        let mut archive = zip::ZipArchive::new(File::open(path)?)
+            .with_context(|| format!("Failed to read {} as zip archive", path.display()))?;
+        for i in 0..archive.len() {
+            let mut entry = archive.by_index(i)?;
+            let relative = entry
+                .enclosed_name()
+                .with_context(|| format!("Invalid file name {} in wheel", entry.name()))?
+                .to_path_buf();
+            let target = dir.path().join(&relative);
+            if entry.is_dir() {
+                fs::create_dir_all(&target)?;
+                continue;
+            }
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut file = File::create(&target)?;
+            std::io::copy(&mut entry, &mut file)
+                .with_context(|| format!("Failed to extract {}", relative.display()))?;
+            if let Some(mode) = entry.unix_mode() {
+                permissions.insert(relative, mode & 0o777);
+            }
+        }
+
+        let dist_info_dir = fs::read_dir(dir.path())?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| PathBuf::from(entry.file_name()))
+            .find(|name| {
+                name.extension().map_or(false, |ext| ext == "dist-info")
+                    && dir.path().join(name).join("WHEEL").is_file()
+            })
+            .with_context(|| format!("{filename} doesn't contain a .dist-info/WHEEL file"))?;
+
+        Ok(Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            build_tag: build_tag.map(ToString::to_string),
+            python_tag: python_tag.to_string(),
+            abi_tag: abi_tag.to_string(),
+            platform_tag: platform_tag.to_string(),
+            dir,
+            dist_info_dir,
+            permissions,
+        })
+    }
+
+    /// The directory the wheel has been extracted to
+    pub fn root(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// The `.dist-info` directory, relative to [UnpackedWheel::root]
+    pub fn dist_info_dir(&self) -> &Path {
+        &self.dist_info_dir
+    }
+
+    /// All files in the wheel relative to [UnpackedWheel::root], sorted
+    pub fn files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for entry in ignore::WalkBuilder::new(self.root())
+            .standard_filters(false)
+            .build()
+        {
+            let entry = entry?;
+            if entry.file_type().map_or(false, |ty| ty.is_file()) {
+                files.push(entry.path().strip_prefix(self.root())?.to_path_buf());
+            }
+        }
+        files.sort();
+        Ok(files)
+    }
+
+    /// The expanded tags for the WHEEL file, e.g. `py2.py3-none-any` becomes
+    /// `py2-none-any` and `py3-none-any`
+    pub fn tags(&self) -> Vec<String> {
+        let mut tags = Vec::new();
+        for python in self.python_tag.split('.') {
+            for abi in self.abi_tag.split('.') {
+                for platform in self.platform_tag.split('.') {
+                    tags.push(format!("{python}-{abi}-{platform}"));
+                }
+            }
+        }
+        tags
+    }
+
+    /// The filename of the wheel with the current tags
+    pub fn filename(&self) -> String {
+        let build_tag = self
+            .build_tag
+            .as_ref()
+            .map(|build| format!("-{build}"))
+            .unwrap_or_default();
+        format!(
+            "{}-{}{}-{}-{}-{}.whl",
+            self.name, self.version, build_tag, self.python_tag, self.abi_tag, self.platform_tag
+        )
+    }
+
+    /// Packs the wheel into `out_dir` with the current tags, rewriting the WHEEL file
+    /// and the RECORD
+    pub fn write(&self, out_dir: &Path) -> Result<PathBuf> {
+        fs::create_dir_all(out_dir)?;
+        let wheel_file = self.root().join(&self.dist_info_dir).join("WHEEL");
+        let wheel_contents = fs::read_to_string(&wheel_file)?;
+        let mut new_contents = String::new();
+        for line in wheel_contents.lines() {
+            if !line.starts_with("Tag:") {
+                new_contents.push_str(line);
+                new_contents.push('\n');
+            }
+        }
+        for tag in self.tags() {
+            new_contents.push_str(&format!("Tag: {tag}\n"));
+        }
+        fs::write(&wheel_file, new_contents)?;
+
+        let wheel_path = out_dir.join(self.filename());
+        let mut writer = WheelWriter::create(wheel_path, &self.dist_info_dir)?;
+        for file in self.files()? {
+            // RECORD is regenerated and signatures of the old RECORD are no longer valid
+            if file.parent() == Some(self.dist_info_dir.as_path())
+                && file
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .map_or(false, |name| name.starts_with("RECORD"))
+            {
+                continue;
+            }
+            let permissions = match self.permissions.get(&file) {
+                Some(mode) => *mode,
+                None => self.fs_permissions(&file)?,
+            };
+            writer.add_file_with_permissions(&file, self.root().join(&file), permissions)?;
+        }
+        Ok(writer.finish()?)
+    }
+
+    /// Permissions of files that have been added after extracting the wheel
+    #[cfg(unix)]
+    fn fs_permissions(&self, file: &Path) -> Result<u32> {
+        Ok(fs::metadata(self.root().join(file))?.permissions().mode() & 0o777)
+    }
+
+    /// Permissions of files that have been added after extracting the wheel
+    #[cfg(not(unix))]
+    fn fs_permissions(&self, _file: &Path) -> Result<u32> {
+        Ok(0o644)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::UnpackedWheel;
+    use crate::module_writer::{ModuleWriter, WheelWriter};
+    use crate::Metadata21;
+    use pep440_rs::Version;
+    use pretty_assertions::assert_eq;
+    use std::path::Path;
+
+    #[test]
+    fn test_unpack_and_retag() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let metadata = Metadata21::new("foo".to_string(), Version::from_release(vec![0, 1, 0]));
+        let tag = "py3-none-linux_x86_64";
+        let mut writer =
+            WheelWriter::new(tag, tmp_dir.path(), &metadata, &[tag.to_string()], None).unwrap();
+        writer.add_bytes("foo/__init__.py", b"").unwrap();
+        let wheel_path = writer.finish().unwrap();
+
+        let mut wheel = UnpackedWheel::open(&wheel_path).unwrap();
+        assert_eq!(wheel.name, "foo");
+        assert_eq!(wheel.platform_tag, "linux_x86_64");
+        assert_eq!(wheel.dist_info_dir(), Path::new("foo-0.1.0.dist-info"));
+
+        wheel.platform_tag = "manylinux_2_17_x86_64.manylinux2014_x86_64".to_string();
+        assert_eq!(
+            wheel.tags(),
+            [
+                "py3-none-manylinux_2_17_x86_64",
+                "py3-none-manylinux2014_x86_64"
+            ]
+        );
+        let out_dir = tmp_dir.path().join("out");
+        let new_path = wheel.write(&out_dir).unwrap();
+        assert_eq!(
+            new_path.file_name().unwrap().to_str().unwrap(),
+            "foo-0.1.0-py3-none-manylinux_2_17_x86_64.manylinux2014_x86_64.whl"
+        );
+        let rewritten = UnpackedWheel::open(&new_path).unwrap();
+        let wheel_file = fs_err::read_to_string(
+            rewritten
+                .root()
+                .join(rewritten.dist_info_dir())
+                .join("WHEEL"),
+        )
+        .unwrap();
+        assert!(wheel_file.contains("Tag: py3-none-manylinux2014_x86_64\n"));
+        assert!(!wheel_file.contains("Tag: py3-none-linux_x86_64"));
+        let record = fs_err::read_to_string(
+            rewritten
+                .root()
+                .join(rewritten.dist_info_dir())
+                .join("RECORD"),
+        )
+        .unwrap();
+        assert!(record.contains("foo/__init__.py,sha256="));
+        assert_eq!(record.matches("RECORD").count(), 1);
+    }
+}
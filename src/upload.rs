@@ -2,6 +2,7 @@
 //! documentation at https://warehouse.readthedocs.io/api-reference/legacy/#upload-api
 
 use crate::build_context::hash_file;
+use crate::check::check_distribution;
 use anyhow::{bail, Context, Result};
 use base64::engine::general_purpose::STANDARD;
 use base64::Engine;
@@ -55,6 +56,13 @@ pub struct PublishOpt {
     /// (Only valid when uploading to PyPI. Other implementations may not support this.)
     #[arg(long = "skip-existing")]
     skip_existing: bool,
+    /// Only validate the files like `twine check` without uploading them.
+    /// Likely reStructuredText rendering failures of the description are errors instead of warnings
+    #[arg(long)]
+    check_only: bool,
+    /// Don't validate the files before uploading them
+    #[arg(long, conflicts_with = "check_only")]
+    skip_check: bool,
 }
 
 impl PublishOpt {
@@ -478,6 +486,7 @@ pub fn upload(registry: &Registry, wheel_path: &Path) -> Result<(), UploadError>
     add_vec("obsoletes_dist", &metadata.obsoletes_dist);
     add_vec("requires_external", &metadata.requires_external);
     add_vec("project_urls", &metadata.project_urls);
+    add_vec("dynamic", &metadata.dynamic);
 
     let wheel = File::open(wheel_path)?;
     let wheel_name = wheel_path
@@ -551,8 +560,49 @@ pub fn upload(registry: &Registry, wheel_path: &Path) -> Result<(), UploadError>
     }
 }
 
+/// Validates the files before uploading them, failing if any of them would be rejected by the
+/// registry. The description rendering heuristics only fail in strict mode
+fn check_ui(items: &[PathBuf], strict: bool) -> Result<()> {
+    let mut failed = Vec::new();
+    for item in items {
+        let mut check = check_distribution(item)?;
+        if strict {
+            check.errors.append(&mut check.render_errors);
+        } else {
+            check.warnings.append(&mut check.render_errors);
+        }
+        let filename = item
+            .file_name()
+            .unwrap_or(item.as_os_str())
+            .to_string_lossy();
+        for warning in &check.warnings {
+            eprintln!("⚠️  Warning: {filename}: {warning}");
+        }
+        if check.errors.is_empty() {
+            eprintln!("✅ {filename} passed the checks");
+        } else {
+            eprintln!("💥 {filename} failed the checks:");
+            for error in &check.errors {
+                eprintln!("    {error}");
+            }
+            failed.push(filename);
+        }
+    }
+    if !failed.is_empty() {
+        bail!("Validation failed for {}", failed.join(", "));
+    }
+    Ok(())
+}
+
 /// Handles authentication/keyring integration and retrying of the publish subcommand
 pub fn upload_ui(items: &[PathBuf], publish: &PublishOpt) -> Result<()> {
+    if !publish.skip_check {
+        check_ui(items, publish.check_only)?;
+    }
+    if publish.check_only {
+        return Ok(());
+    }
+
     let registry = complete_registry(publish)?;
 
     eprintln!("🚀 Uploading {} packages", items.len());
@@ -0,0 +1,33 @@
+#!/usr/bin/env bash
+# Regenerates the abi3 test fixtures on any platform with llvm-mc, llvm-dlltool and rust-lld
+#
+# Every extension module uses PyObject_GetAttr, which is part of the stable ABI since python 3.2,
+# PyType_GetName, which was added in python 3.11, and _PyObject_GetDictPtr, which isn't part of it.
+set -euo pipefail
+
+cd "$(dirname "$0")"
+LLD=${LLD:-$(find "$(rustc --print sysroot)" -name rust-lld | head -n 1)}
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+# ELF
+printf '.text\n.globl PyInit_ext\nPyInit_ext:\n  call PyObject_GetAttr@PLT\n  call PyType_GetName@PLT\n  jmp _PyObject_GetDictPtr@PLT\n' \
+  | llvm-mc -triple x86_64-unknown-linux-gnu -filetype=obj -o "$tmp/elf.o"
+$LLD -flavor gnu -shared "$tmp/elf.o" -o elf.so
+
+# Mach-O
+printf '.text\n.globl dyld_stub_binder\ndyld_stub_binder:\n  ret\n' \
+  | llvm-mc -triple x86_64-apple-macos10.12 -filetype=obj -o "$tmp/system.o"
+printf '.text\n.globl _PyInit_ext\n_PyInit_ext:\n  callq _PyObject_GetAttr\n  callq _PyType_GetName\n  jmp __PyObject_GetDictPtr\n' \
+  | llvm-mc -triple x86_64-apple-macos10.12 -filetype=obj -o "$tmp/macho.o"
+link="$LLD -flavor darwin -arch x86_64 -platform_version macos 10.12 10.12"
+$link -dylib -install_name /usr/lib/libSystem.B.dylib "$tmp/system.o" -o "$tmp/libSystem.dylib"
+$link -bundle -undefined dynamic_lookup "$tmp/macho.o" "$tmp/libSystem.dylib" -o macho.so
+
+# PE
+printf 'LIBRARY python3.dll\nEXPORTS\nPyObject_GetAttr\nPyType_GetName\n_PyObject_GetDictPtr\n' > "$tmp/python3.def"
+llvm-dlltool -m i386:x86-64 -d "$tmp/python3.def" -l "$tmp/python3.lib"
+printf '.text\n.globl PyInit_ext\nPyInit_ext:\n  callq *__imp_PyObject_GetAttr(%%rip)\n  callq *__imp_PyType_GetName(%%rip)\n  jmp *__imp__PyObject_GetDictPtr(%%rip)\n' \
+  | llvm-mc -triple x86_64-pc-windows-msvc -filetype=obj -o "$tmp/pe.obj"
+$LLD -flavor link /nologo /dll /noentry /machine:x64 /export:PyInit_ext /implib:"$tmp/pe.lib" \
+  "$tmp/pe.obj" "$tmp/python3.lib" /out:pe.pyd
@@ -0,0 +1,45 @@
+#!/usr/bin/env bash
+# Regenerates the elf test fixtures on any platform with llvm-mc and rust-lld
+#
+# For every architecture, libfoo.so has the soname libfoo.so, links libbar.so with a version
+# requirement on BAR_1.0 and has the runpath /build/lib. x86_64 is the little-endian 64-bit,
+# i686 the little-endian 32-bit, powerpc64 the big-endian 64-bit and powerpc the big-endian
+# 32-bit case. x86_64/exp.so calls exp@GLIBC_2.29 from libm.so.6 for testing the audit.
+set -euo pipefail
+
+cd "$(dirname "$0")"
+LLD=${LLD:-$(find "$(rustc --print sysroot)" -name rust-lld | head -n 1)}
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+printf 'BAR_1.0 { global: bar; local: *; };\n' > "$tmp/bar.map"
+
+# directory, llvm-mc triple, pointer directive
+archs=(
+  "x86_64|x86_64-unknown-linux-gnu|.quad"
+  "i686|i686-unknown-linux-gnu|.long"
+  "powerpc64|powerpc64-unknown-linux-gnu|.quad"
+  "powerpc|powerpc-unknown-linux-gnu|.long"
+)
+for arch in "${archs[@]}"; do
+  IFS='|' read -r dir triple pointer <<< "$arch"
+  mkdir -p "$dir"
+  printf '.data\n.globl bar\n.type bar, @object\n.size bar, 4\nbar:\n  .long 42\n' \
+    | llvm-mc -triple "$triple" -filetype=obj -o "$tmp/bar.o"
+  printf '.data\n.globl foo\nfoo:\n  %s bar\n' "$pointer" \
+    | llvm-mc -triple "$triple" -filetype=obj -o "$tmp/foo.o"
+  $LLD -flavor gnu -shared -soname libbar.so --version-script "$tmp/bar.map" \
+    "$tmp/bar.o" -o "$dir/libbar.so"
+  $LLD -flavor gnu -shared -soname libfoo.so -rpath /build/lib --enable-new-dtags \
+    "$tmp/foo.o" "$dir/libbar.so" -o "$dir/libfoo.so"
+done
+
+# exp.so calls exp@GLIBC_2.29 from libm.so.6, which manylinux_2_28 and older don't allow
+printf 'GLIBC_2.29 { global: exp; local: *; };\n' > "$tmp/libm.map"
+printf '.text\n.globl exp\n.type exp, @function\nexp:\n  ret\n' \
+  | llvm-mc -triple x86_64-unknown-linux-gnu -filetype=obj -o "$tmp/libm.o"
+$LLD -flavor gnu -shared -soname libm.so.6 --version-script "$tmp/libm.map" \
+  "$tmp/libm.o" -o "$tmp/libm.so.6"
+printf '.text\n.globl PyInit_exp\nPyInit_exp:\n  jmp exp@PLT\n' \
+  | llvm-mc -triple x86_64-unknown-linux-gnu -filetype=obj -o "$tmp/exp.o"
+$LLD -flavor gnu -shared "$tmp/exp.o" "$tmp/libm.so.6" -o x86_64/exp.so
@@ -0,0 +1,38 @@
+#!/usr/bin/env bash
+# Regenerates the Mach-O test fixtures on any platform with llvm-mc, llvm-lipo and rust-lld
+#
+# ext.so links /opt/homebrew/opt/foo/lib/libfoo.1.dylib, which links @rpath/libbar.dylib.
+# All files are universal2 binaries and the arm64 slices are ad-hoc signed by the linker.
+set -euo pipefail
+
+cd "$(dirname "$0")"
+LLD=${LLD:-$(find "$(rustc --print sysroot)" -name rust-lld | head -n 1)}
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+# name, x86_64 code, arm64 code
+sources=(
+  "system|.globl dyld_stub_binder\ndyld_stub_binder:\n  ret|.globl dyld_stub_binder\n.p2align 2\ndyld_stub_binder:\n  ret"
+  "bar|.globl _bar\n_bar:\n  movl \$42, %eax\n  ret|.globl _bar\n.p2align 2\n_bar:\n  mov w0, #42\n  ret"
+  "foo|.globl _foo\n_foo:\n  jmp _bar|.globl _foo\n.p2align 2\n_foo:\n  b _bar"
+  "ext|.globl _PyInit_ext\n_PyInit_ext:\n  jmp _foo|.globl _PyInit_ext\n.p2align 2\n_PyInit_ext:\n  b _foo"
+)
+
+for arch in x86_64 arm64; do
+  if [ "$arch" = x86_64 ]; then triple=x86_64-apple-macos10.12; version=10.12; field=2; else triple=arm64-apple-macos11; version=11.0; field=3; fi
+  for source in "${sources[@]}"; do
+    name=${source%%|*}
+    printf '.text\n%b\n' "$(echo "$source" | cut -d'|' -f$field)" | llvm-mc -triple $triple -filetype=obj -o "$tmp/$name-$arch.o"
+  done
+  link="$LLD -flavor darwin -arch $arch -platform_version macos $version $version"
+  $link -dylib -install_name /usr/lib/libSystem.B.dylib "$tmp/system-$arch.o" -o "$tmp/libSystem-$arch.dylib"
+  $link -dylib -install_name @rpath/libbar.dylib "$tmp/bar-$arch.o" "$tmp/libSystem-$arch.dylib" -o "$tmp/libbar-$arch.dylib"
+  $link -dylib -install_name /opt/homebrew/opt/foo/lib/libfoo.1.dylib -rpath @loader_path \
+    "$tmp/foo-$arch.o" "$tmp/libbar-$arch.dylib" "$tmp/libSystem-$arch.dylib" -o "$tmp/libfoo-$arch.dylib"
+  $link -bundle -rpath /opt/homebrew/lib \
+    "$tmp/ext-$arch.o" "$tmp/libfoo-$arch.dylib" "$tmp/libSystem-$arch.dylib" -o "$tmp/ext-$arch.so"
+done
+
+llvm-lipo -create "$tmp/libbar-x86_64.dylib" "$tmp/libbar-arm64.dylib" -output libbar.dylib
+llvm-lipo -create "$tmp/libfoo-x86_64.dylib" "$tmp/libfoo-arm64.dylib" -output libfoo.1.dylib
+llvm-lipo -create "$tmp/ext-x86_64.so" "$tmp/ext-arm64.so" -output ext.so
@@ -0,0 +1,34 @@
+#!/usr/bin/env bash
+# Regenerates the PE test fixtures on any platform with llvm-mc, llvm-dlltool and rust-lld
+#
+# ext.pyd links foo.dll, python3.dll and KERNEL32.dll and delay loads bar.dll,
+# foo.dll links bar.dll and KERNEL32.dll.
+set -euo pipefail
+
+cd "$(dirname "$0")"
+LLD=${LLD:-$(find "$(rustc --print sysroot)" -name rust-lld | head -n 1)}
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+printf 'LIBRARY KERNEL32.dll\nEXPORTS\nGetLastError\n' > "$tmp/kernel32.def"
+printf 'LIBRARY python3.dll\nEXPORTS\nPyModule_Create2\n' > "$tmp/python3.def"
+for name in kernel32 python3; do
+  llvm-dlltool -m i386:x86-64 -d "$tmp/$name.def" -l "$tmp/$name.lib"
+done
+
+# name, code
+sources=(
+  "bar|.globl bar\nbar:\n  movl \$42, %eax\n  ret"
+  "foo|.globl foo\nfoo:\n  callq *__imp_GetLastError(%rip)\n  jmp *__imp_bar(%rip)"
+  "ext|.globl PyInit_ext\nPyInit_ext:\n  callq *__imp_PyModule_Create2(%rip)\n  callq *__imp_GetLastError(%rip)\n  callq *__imp_bar(%rip)\n  jmp *__imp_foo(%rip)\n.globl __delayLoadHelper2\n__delayLoadHelper2:\n  ret"
+)
+for source in "${sources[@]}"; do
+  name=${source%%|*}
+  printf '.text\n%b\n' "${source#*|}" | llvm-mc -triple x86_64-pc-windows-msvc -filetype=obj -o "$tmp/$name.obj"
+done
+
+link="$LLD -flavor link /nologo /dll /noentry /machine:x64"
+$link /export:bar /implib:"$tmp/bar.lib" "$tmp/bar.obj" /out:bar.dll
+$link /export:foo /implib:"$tmp/foo.lib" "$tmp/foo.obj" "$tmp/bar.lib" "$tmp/kernel32.lib" /out:foo.dll
+$link /export:PyInit_ext /implib:"$tmp/ext.lib" /delayload:bar.dll \
+  "$tmp/ext.obj" "$tmp/foo.lib" "$tmp/bar.lib" "$tmp/python3.lib" "$tmp/kernel32.lib" /out:ext.pyd
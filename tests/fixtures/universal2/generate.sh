@@ -0,0 +1,19 @@
+#!/usr/bin/env bash
+# Regenerates the thin x86_64 and arm64 extension modules that are fused into a universal2
+# wheel in the tests, on any platform with llvm-mc and rust-lld
+set -euo pipefail
+
+cd "$(dirname "$0")"
+LLD=${LLD:-$(find "$(rustc --print sysroot)" -name rust-lld | head -n 1)}
+tmp=$(mktemp -d)
+trap 'rm -rf "$tmp"' EXIT
+
+for arch in x86_64 arm64; do
+  printf '.text\n.globl dyld_stub_binder\ndyld_stub_binder:\n  ret\n' \
+    | llvm-mc -triple $arch-apple-macos11.0 -filetype=obj -o "$tmp/system.o"
+  printf '.text\n.globl _PyInit_ext\n_PyInit_ext:\n  ret\n' \
+    | llvm-mc -triple $arch-apple-macos11.0 -filetype=obj -o "$tmp/ext.o"
+  link="$LLD -flavor darwin -arch $arch -platform_version macos 11.0 11.0"
+  $link -dylib -install_name /usr/lib/libSystem.B.dylib "$tmp/system.o" -o "$tmp/libSystem.dylib"
+  $link -bundle -undefined dynamic_lookup "$tmp/ext.o" "$tmp/libSystem.dylib" -o $arch.so
+done
@@ -624,6 +624,30 @@ fn pyo3_mixed_include_exclude_git_sdist_generator() {
     ))
 }
 
+#[test]
+fn pyo3_mixed_include_exclude_manifest_sdist_generator() {
+    handle_result(other::test_source_distribution(
+        "test-crates/pyo3-mixed-include-exclude",
+        SdistGenerator::Manifest,
+        vec![
+            "pyo3_mixed_include_exclude-2.1.3/Cargo.lock",
+            "pyo3_mixed_include_exclude-2.1.3/Cargo.toml",
+            "pyo3_mixed_include_exclude-2.1.3/PKG-INFO",
+            "pyo3_mixed_include_exclude-2.1.3/README.md", // included
+            // "pyo3_mixed_include_exclude-2.1.3/check_installed/check_installed.py", not a source
+            "pyo3_mixed_include_exclude-2.1.3/pyo3_mixed_include_exclude/__init__.py",
+            "pyo3_mixed_include_exclude-2.1.3/pyo3_mixed_include_exclude/include_this_file", // included
+            "pyo3_mixed_include_exclude-2.1.3/pyo3_mixed_include_exclude/python_module/__init__.py",
+            "pyo3_mixed_include_exclude-2.1.3/pyo3_mixed_include_exclude/python_module/double.py",
+            "pyo3_mixed_include_exclude-2.1.3/pyproject.toml",
+            "pyo3_mixed_include_exclude-2.1.3/src/lib.rs",
+            // "pyo3_mixed_include_exclude-2.1.3/tox.ini", not a source
+        ],
+        None,
+        "sdist-pyo3-mixed-include-exclude-manifest",
+    ))
+}
+
 #[test]
 fn pyo3_mixed_include_exclude_wheel_files() {
     handle_result(other::check_wheel_files(
@@ -633,6 +657,7 @@ fn pyo3_mixed_include_exclude_wheel_files() {
             "pyo3_mixed_include_exclude-2.1.3.dist-info/RECORD",
             "pyo3_mixed_include_exclude-2.1.3.dist-info/WHEEL",
             "pyo3_mixed_include_exclude-2.1.3.dist-info/entry_points.txt",
+            "pyo3_mixed_include_exclude-2.1.3.dist-info/sboms/pyo3-mixed-include-exclude.cdx.json",
             "pyo3_mixed_include_exclude/__init__.py",
             "pyo3_mixed_include_exclude/include_this_file",
             "pyo3_mixed_include_exclude/python_module/__init__.py",